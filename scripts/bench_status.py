@@ -0,0 +1,143 @@
+#!/usr/bin/env python3
+"""管理接口 /status 负载下的转发延迟基准测试 (只依赖 Python 3 标准库)
+
+本机启动一个回显服务和一个转发器, 经转发器做 N 次串行的 1 字节回显往返 (每次新建连接),
+分别在空闲和多个进程持续请求 /status 时测量, 输出 p50 / p99 / max。
+
+用法:
+    cargo build --release
+    python3 scripts/bench_status.py [--binary target/release/forward-optimal] [--rounds 1500] [--hammers 4]
+
+对比两个版本时分别构建后用 --binary 指定, 在同一台空闲的机器上各运行几次取中位数。
+压测进程与转发器在同一台机器上争用 CPU, 负载下的延迟不会降到空闲水平。
+"""
+import argparse
+import multiprocessing
+import os
+import socket
+import subprocess
+import sys
+import tempfile
+import threading
+import time
+
+CONFIG = """bind_addr: "127.0.0.1:{forward}"
+admin_addr: "127.0.0.1:{admin}"
+update_interval: 1
+targets:
+  - name: "echo"
+    addr: "127.0.0.1:{echo}"
+  - name: "echo2"
+    addr: "127.0.0.1:{echo}"
+"""
+
+
+def free_port():
+    with socket.socket() as s:
+        s.bind(("127.0.0.1", 0))
+        return s.getsockname()[1]
+
+
+def echo_server(listener):
+    def handle(conn):
+        with conn:
+            while True:
+                data = conn.recv(65536)
+                if not data:
+                    return
+                conn.sendall(data)
+
+    while True:
+        conn, _ = listener.accept()
+        threading.Thread(target=handle, args=(conn,), daemon=True).start()
+
+
+def hammer(admin, stop, done):
+    # 持续请求 /status, 每次新建连接; done 累计完成的请求数
+    request = b"GET /status HTTP/1.1\r\nHost: bench\r\nConnection: close\r\n\r\n"
+    while not stop.is_set():
+        try:
+            with socket.create_connection(("127.0.0.1", admin)) as c:
+                c.sendall(request)
+                while c.recv(65536):
+                    pass
+            with done.get_lock():
+                done.value += 1
+        except OSError:
+            pass
+
+
+def wait_ready(port, timeout=10.0):
+    deadline = time.monotonic() + timeout
+    while time.monotonic() < deadline:
+        try:
+            with socket.create_connection(("127.0.0.1", port)) as c:
+                c.sendall(b"x")
+                if c.recv(1) == b"x":
+                    return
+        except OSError:
+            pass
+        time.sleep(0.2)
+    sys.exit("转发器 {} 秒内没有就绪".format(timeout))
+
+
+def measure(forward, rounds):
+    samples = []
+    for _ in range(rounds):
+        start = time.perf_counter()
+        with socket.create_connection(("127.0.0.1", forward)) as c:
+            c.setsockopt(socket.IPPROTO_TCP, socket.TCP_NODELAY, 1)
+            c.sendall(b"x")
+            c.recv(1)
+        samples.append((time.perf_counter() - start) * 1000)
+    samples.sort()
+    return samples[len(samples) // 2], samples[int(len(samples) * 0.99)], samples[-1]
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("--binary", default="target/release/forward-optimal")
+    parser.add_argument("--rounds", type=int, default=1500)
+    parser.add_argument("--hammers", type=int, default=4, help="请求 /status 的进程数")
+    args = parser.parse_args()
+    if not os.path.exists(args.binary):
+        sys.exit("找不到 {}, 先运行 cargo build --release".format(args.binary))
+
+    listener = socket.socket()
+    listener.bind(("127.0.0.1", 0))
+    listener.listen(128)
+    threading.Thread(target=echo_server, args=(listener,), daemon=True).start()
+    ports = {"echo": listener.getsockname()[1], "forward": free_port(), "admin": free_port()}
+
+    with tempfile.NamedTemporaryFile("w", suffix=".yaml", delete=False) as f:
+        f.write(CONFIG.format(**ports))
+        config_path = f.name
+    env = dict(os.environ, RUST_LOG="warn")
+    proc = subprocess.Popen([args.binary, "-c", config_path], env=env, stdout=subprocess.DEVNULL)
+    try:
+        wait_ready(ports["forward"])
+        idle = measure(ports["forward"], args.rounds)
+
+        stop, done = multiprocessing.Event(), multiprocessing.Value("L", 0)
+        workers = [multiprocessing.Process(target=hammer, args=(ports["admin"], stop, done)) for _ in range(args.hammers)]
+        for w in workers:
+            w.start()
+        time.sleep(0.5)
+        start, before = time.monotonic(), done.value
+        loaded = measure(ports["forward"], args.rounds)
+        rate = (done.value - before) / (time.monotonic() - start)
+        stop.set()
+        for w in workers:
+            w.join()
+    finally:
+        proc.terminate()
+        proc.wait()
+        os.unlink(config_path)
+
+    print("往返次数: {}, /status 压测进程: {}, 负载期间 /status 约 {:.0f} 次/秒".format(args.rounds, args.hammers, rate))
+    for name, (p50, p99, worst) in [("空闲", idle), ("负载", loaded)]:
+        print("{}: p50 {:.2f}ms  p99 {:.2f}ms  max {:.2f}ms".format(name, p50, p99, worst))
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,314 @@
+use anyhow::Result;
+use arc_swap::ArcSwap;
+use serde::Serialize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Notify, RwLock};
+
+use crate::config::Config;
+use crate::relay;
+use crate::selfprobe;
+use crate::state::{BestTarget, Snapshot, State};
+
+// 请求头最大长度, 超出直接断开
+const MAX_REQUEST_SIZE: usize = 8192;
+
+#[derive(Serialize)]
+struct StatusResponse {
+    service: Option<String>,
+    best: Option<BestInfo>,
+    pools: Vec<PoolInfo>,
+    switch_deferred: Option<String>,
+    probing_paused: bool,
+    paused_secs: Option<u64>,
+    pinned: Option<String>,
+    maintenance: Vec<String>,
+    mirror_drops: u64,
+    self_probe: Option<SelfProbeInfo>,
+}
+
+#[derive(Serialize)]
+struct SelfProbeInfo {
+    ok: bool,
+    target: Option<String>,
+    rtt_ms: Option<u128>,
+    error: Option<String>,
+    age_secs: u64,
+}
+
+#[derive(Serialize)]
+struct PoolInfo {
+    name: String,
+    sni: Vec<String>,
+    countries: Vec<String>,
+    best: Option<BestInfo>,
+    targets: Vec<BestInfo>,
+}
+
+#[derive(Serialize)]
+struct BestInfo {
+    name: String,
+    addr: String,
+    fallback_addr: Option<String>,
+    outbound_bind: Option<String>,
+    score: u128,
+    raw_score: u128,
+    rtt_score: u128,
+    jitter_score: u128,
+    jitter_ms: f64,
+    queue_score: u128,
+    queue_depth: Option<f64>,
+    priority: u32,
+    weight: f64,
+    dns_ms: f64,
+    active_connections: usize,
+    max_connections: Option<usize>,
+    success_rate: Option<f64>,
+    traffic_bytes: u64,
+}
+
+/// 管理接口 (简易 HTTP)
+/// 查询类请求只读取快照, 不获取 State 的锁
+pub async fn serve(
+    addr: String,
+    state: Arc<RwLock<State>>,
+    published: Arc<ArcSwap<Snapshot>>,
+    wakeup: Arc<Notify>,
+    live: Arc<ArcSwap<Config>>,
+) -> Result<()> {
+    let listener = TcpListener::bind(&addr).await?;
+    log::info!("管理接口启动: {}", addr);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let state = state.clone();
+        let published = published.clone();
+        let wakeup = wakeup.clone();
+        let live = live.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(stream, state, published, wakeup, live).await {
+                log::debug!("管理接口请求处理失败: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_request(
+    mut stream: TcpStream,
+    state: Arc<RwLock<State>>,
+    published: Arc<ArcSwap<Snapshot>>,
+    wakeup: Arc<Notify>,
+    live: Arc<ArcSwap<Config>>,
+) -> Result<()> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 || buf.len() + n > MAX_REQUEST_SIZE {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let head = String::from_utf8_lossy(&buf);
+    let mut parts = head.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("");
+
+    let (code, body) = match (method, path) {
+        ("GET", "/status") => (200, status_json(&published.load())),
+        ("POST", "/pause-probing") => {
+            let mut s = state.write().await;
+            if s.paused_since.is_none() {
+                s.paused_since = Some(std::time::Instant::now());
+                log::warn!(">>> 已暂停探测, 冻结当前最优节点");
+                s.publish();
+            }
+            (200, status_json(&s.snapshot()))
+        }
+        ("POST", "/resume-probing") => {
+            let mut s = state.write().await;
+            if let Some(since) = s.paused_since.take() {
+                log::info!(">>> 已恢复探测 (暂停了 {} 秒)", since.elapsed().as_secs());
+                wakeup.notify_one();
+                s.publish();
+            }
+            (200, status_json(&s.snapshot()))
+        }
+        ("POST", "/reprobe") => {
+            let mut s = state.write().await;
+            s.reprobe = true;
+            wakeup.notify_one();
+            log::info!(">>> 管理接口要求立即重新探测");
+            (200, status_json(&s.snapshot()))
+        }
+        ("POST", "/unpin") => {
+            let mut s = state.write().await;
+            if let Some(name) = s.pinned.take() {
+                log::info!(">>> 已取消固定节点 [{}]", name);
+                s.publish();
+            }
+            (200, status_json(&s.snapshot()))
+        }
+        ("POST", p) if p.starts_with("/pin/") => {
+            control(&state, &live, &p["/pin/".len()..], |s, name| {
+                log::warn!(">>> 已固定节点 [{}], 该节点可用时总是选择它", name);
+                s.pinned = Some(name.to_string());
+            })
+            .await
+        }
+        ("POST", p) if p.starts_with("/start-maintenance/") => {
+            control(&state, &live, &p["/start-maintenance/".len()..], |s, name| {
+                log::warn!(">>> 节点 [{}] 进入维护, 不再接收新连接", name);
+                s.set_maintenance(name, true);
+            })
+            .await
+        }
+        ("POST", p) if p.starts_with("/end-maintenance/") => {
+            control(&state, &live, &p["/end-maintenance/".len()..], |s, name| {
+                log::info!(">>> 节点 [{}] 结束维护, 立即重新探测", name);
+                s.set_maintenance(name, false);
+                s.reprobe = true;
+                wakeup.notify_one();
+            })
+            .await
+        }
+        ("POST", p) if p.starts_with("/reset-quota/") => {
+            control(&state, &live, &p["/reset-quota/".len()..], |s, name| {
+                log::info!(">>> 节点 [{}] 流量配额计数已清零, 立即重新探测", name);
+                s.usage.reset(name);
+                s.reprobe = true;
+                wakeup.notify_one();
+            })
+            .await
+        }
+        ("GET", _) | ("POST", _) => (404, r#"{"error":"not found"}"#.to_string()),
+        _ => (405, r#"{"error":"method not allowed"}"#.to_string()),
+    };
+
+    write_response(&mut stream, code, &body).await
+}
+
+/// 对单个节点执行管理操作; 节点需出现在当前配置、可用节点或已注册的隧道中
+async fn control(
+    state: &RwLock<State>,
+    live: &ArcSwap<Config>,
+    raw_name: &str,
+    apply: impl FnOnce(&mut State, &str),
+) -> (u16, String) {
+    let name = percent_decode(raw_name);
+    let mut s = state.write().await;
+    // probe_all_addresses / srv 展开的候选在可用或维护中时可以单独操作
+    let configured = live.load().pool_list().iter().any(|p| p.targets.iter().any(|t| t.name == name))
+        || s.find(&name).is_some()
+        || s.maintenance.contains(&name);
+    if !configured && !s.tunnels.contains_key(&name) {
+        return (404, r#"{"error":"unknown target"}"#.to_string());
+    }
+    apply(&mut s, &name);
+    s.publish();
+    (200, status_json(&s.snapshot()))
+}
+
+/// 路径中的节点名称按 %XX 解码
+fn percent_decode(raw: &str) -> String {
+    let bytes = raw.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = (bytes[i] == b'%').then(|| raw.get(i + 1..i + 3)).flatten();
+        match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+            Some(b) => {
+                out.push(b);
+                i += 3;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+impl BestInfo {
+    fn new(b: &BestTarget, s: &Snapshot) -> Self {
+        BestInfo {
+            name: b.name.clone(),
+            addr: b.addr.to_string(),
+            fallback_addr: b.fallback.map(|a| a.to_string()),
+            outbound_bind: b.outbound_bind.as_ref().map(ToString::to_string),
+            score: b.score,
+            raw_score: b.raw_score,
+            rtt_score: b.rtt_score,
+            jitter_score: b.jitter_score,
+            jitter_ms: b.jitter_ms,
+            queue_score: b.queue_score,
+            queue_depth: b.queue_depth,
+            priority: b.priority,
+            weight: b.weight,
+            dns_ms: b.dns_ms,
+            active_connections: s.conns.get(&b.name),
+            max_connections: b.max_connections,
+            success_rate: s.traffic.success_rate(&b.name),
+            traffic_bytes: s.usage.bytes(&b.group),
+        }
+    }
+}
+
+fn status_json(s: &Snapshot) -> String {
+    status(s).to_string()
+}
+
+/// 服务的当前状态, 管理接口 /status 与控制套接字的 status 命令共用
+pub fn status(s: &Snapshot) -> serde_json::Value {
+    let resp = StatusResponse {
+        service: Some(s.service.clone()).filter(|n| !n.is_empty()),
+        best: s.select().map(|b| BestInfo::new(b, s)),
+        pools: s
+            .pools
+            .iter()
+            .map(|p| PoolInfo {
+                name: p.name.clone(),
+                sni: p.sni.clone(),
+                countries: p.countries.clone(),
+                best: p.select().map(|b| BestInfo::new(b, s)),
+                targets: p.ranked.iter().map(|b| BestInfo::new(b, s)).collect(),
+            })
+            .collect(),
+        switch_deferred: s.hold.clone(),
+        probing_paused: s.paused_since.is_some(),
+        paused_secs: s.paused_since.map(|t| t.elapsed().as_secs()),
+        pinned: s.pinned.clone(),
+        maintenance: s.maintenance.iter().cloned().collect(),
+        mirror_drops: relay::MIRROR_DROPS.load(Ordering::Relaxed),
+        self_probe: selfprobe::last(&s.service).map(|o| SelfProbeInfo {
+            ok: o.result.is_ok(),
+            target: o.target,
+            rtt_ms: o.result.as_ref().ok().copied(),
+            error: o.result.err(),
+            age_secs: o.at.elapsed().as_secs(),
+        }),
+    };
+    serde_json::to_value(&resp).unwrap_or_default()
+}
+
+async fn write_response(stream: &mut TcpStream, code: u16, body: &str) -> Result<()> {
+    let reason = match code {
+        200 => "OK",
+        404 => "Not Found",
+        _ => "Method Not Allowed",
+    };
+    let resp = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        code,
+        reason,
+        body.len(),
+        body
+    );
+    stream.write_all(resp.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
@@ -0,0 +1,1491 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use crate::{dns, geoip, http, net, notify, otlp, proxy, queue, relay, score, toml};
+
+// --config-format 指定的主配置文件格式
+static FORMAT: OnceLock<Format> = OnceLock::new();
+// 命令行上覆盖配置文件的取值
+static OVERRIDES: OnceLock<Overrides> = OnceLock::new();
+
+// 写合并窗口上限, 避免误配置引入明显延迟
+const MAX_WRITE_COALESCE_US: u64 = 100_000;
+// 转发拷贝缓冲区上限, 避免误配置使每条连接占用过多内存
+const MAX_RELAY_BUFFER: usize = 16 << 20;
+// 每轮探测次数上限, 超出后一轮耗时过长
+const MAX_PROBE_COUNT: u32 = 1000;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TargetConfig {
+    pub name: String,
+    pub addr: String,
+    pub fault_inject: Option<FaultConfig>,
+    #[serde(default, rename = "type")]
+    pub kind: LinkType,
+    pub max_connections: Option<usize>,
+    pub rate_limit_kbps: Option<u64>,       // 覆盖服务的每连接限速
+    pub outbound_bind: Option<net::OutboundBind>, // 覆盖服务的出站源地址 / 网卡
+    #[serde(default)]
+    pub prefer: FamilyPreference,           // 使用解析结果中的哪个协议族
+    #[serde(default)]
+    pub probe_all_addresses: bool,          // 每个解析到的地址单独探测, 作为独立候选 "名称 (IP)" 参与选择
+    #[serde(default)]
+    pub srv: bool,                          // addr 为 SRV 记录名 (不带端口), 每轮按记录展开为候选 "名称 (主机:端口)"
+    pub bandwidth_cap_kbps: Option<u64>,    // 所有连接合计每个方向的带宽上限 (千比特/秒)
+    pub traffic_quota_mb: Option<u64>,      // 累计流量配额 (双向合计, MB), 用完后不再接收新连接
+    pub quota_reset_days: Option<u64>,      // 配额计数每隔多少天清零, 默认只能经管理接口清零
+    #[serde(default)]
+    pub priority: u32,                      // 优先级分层, 数值越小越优先; 同一池内更优先的层全部不可用时才使用下一层
+    #[serde(default = "default_target_weight")]
+    pub weight: f64,                        // 评分乘数, 小于 1 使该节点更容易被选中
+    pub proxy_tlvs: Option<Vec<TlvConfig>>, // 覆盖节点池/全局的 TLV 模板
+    pub queue_metric_url: Option<String>,   // 后端上报当前队列深度的 http 地址
+    #[serde(default)]
+    pub probe: ProbeKind,
+    pub probe_path: Option<String>,         // HTTP 探测请求的路径, 默认 "/"
+    pub probe_count: Option<u32>,           // 覆盖全局的每轮探测次数
+    pub penalty_ms: Option<u128>,           // 覆盖全局的丢包惩罚分
+    pub probe_timeout_ms: Option<u64>,      // 覆盖全局的单次探测超时
+    #[serde(default)]
+    pub tls: bool,                          // 转发时向目标发起 TLS
+    pub tls_server_name: Option<String>,    // 校验证书和 SNI 使用的主机名, 默认取 addr 中的主机
+    pub tls_ca: Option<String>,             // 校验目标证书的 CA (PEM), 默认使用公共根证书
+    pub tls_cert: Option<String>,           // 客户端证书 (PEM), 用于双向 TLS
+    pub tls_key: Option<String>,            // 客户端私钥 (PEM)
+    #[serde(default = "default_tls_verify")]
+    pub tls_verify: bool,                   // 校验目标证书, false 时只要求握手成功
+}
+
+impl TargetConfig {
+    /// 是否展开为多个候选节点 (名称带括号后缀)
+    pub fn expands(&self) -> bool {
+        self.probe_all_addresses || self.srv
+    }
+
+    /// 转发或探测时是否需要向目标发起 TLS
+    pub fn uses_tls(&self) -> bool {
+        self.tls || matches!(self.probe, ProbeKind::Https | ProbeKind::Tls)
+    }
+}
+
+fn default_tls_verify() -> bool {
+    true
+}
+
+fn default_target_weight() -> f64 {
+    1.0
+}
+
+/// 出站 PROXY v2 头附带的一个 TLV; value 按 UTF-8 原样写入, 或用 hex 指定二进制内容
+#[derive(Debug, Deserialize, Clone)]
+pub struct TlvConfig {
+    #[serde(rename = "type")]
+    pub kind: u8,
+    pub value: Option<String>,
+    pub hex: Option<String>,
+}
+
+/// 连接类型: 普通 TCP, 或两个转发器之间的压缩链路
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum LinkType {
+    #[default]
+    Tcp,
+    /// LZ4 分帧压缩, 只能用于转发器之间
+    ForwardLink,
+}
+
+/// 探测方式: TCP 建连, ICMP 回显, HTTP(S) 健康检查, 或 TLS 握手
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ProbeKind {
+    #[default]
+    Tcp,
+    Icmp,
+    Http,
+    Https,
+    Tls,
+}
+
+/// 故障注入参数, 只有启动时带 --fault-inject 才生效
+#[derive(Debug, Deserialize, Clone)]
+pub struct FaultConfig {
+    #[serde(default)]
+    pub latency_ms: u128, // 每次探测额外增加的延迟
+    #[serde(default)]
+    pub loss: f64,        // 人为丢包概率 [0, 1]
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Config {
+    #[serde(default)]
+    pub name: String, // 服务名称, 只有 services 中的服务才有
+    pub bind_addr: String,
+    #[serde(default)]
+    pub listen_type: LinkType,
+    #[serde(default)]
+    pub targets: Vec<TargetConfig>,
+    #[serde(default)]
+    pub mode: SelectionMode,
+    pub sticky: Option<StickyMode>,
+    #[serde(default)]
+    pub pools: Vec<PoolConfig>,
+    #[serde(default)]
+    pub pool_policy: PoolPolicy,
+    #[serde(default)]
+    pub empty_targets_policy: EmptyTargetsPolicy,
+    pub update_interval: u64,
+    #[serde(default)]
+    pub report_interval: u64,
+    #[serde(default = "default_probe_count")]
+    pub probe_count: u32, // 每轮探测次数
+    #[serde(default = "default_penalty_ms")]
+    pub penalty_ms: u128, // 每次丢包计入的惩罚分 (视为该次延迟 ms)
+    #[serde(default = "default_probe_timeout_ms")]
+    pub probe_timeout_ms: u64, // 单次探测超时, 超时计为丢包
+    #[serde(default = "default_connection_log_sample_rate")]
+    pub connection_log_sample_rate: u64,
+    pub access_log: Option<String>, // 访问日志文件, 每条转发连接一行 (不抽样)
+    #[serde(default)]
+    pub startup_delay: u64,
+    pub proxy_protocol: Option<String>,
+    #[serde(default)]
+    pub proxy_tlvs: Vec<TlvConfig>,
+    #[serde(default)]
+    pub forward_sni_as_authority: bool,
+    #[serde(default)]
+    pub cross_family_policy: CrossFamilyPolicy,
+    pub admin_addr: Option<String>,
+    pub control_socket: Option<String>, // 本地控制套接字 (Unix 套接字路径 / Windows 命名管道名), status 子命令通过它查询
+    pub statsd_addr: Option<String>,
+    #[serde(default = "default_statsd_prefix")]
+    pub statsd_prefix: String,
+    #[serde(default = "default_statsd_interval")]
+    pub statsd_interval: u64,
+    pub otlp_endpoint: Option<otlp::Endpoint>, // OTLP/HTTP (JSON) 接收地址, 导出探测轮和转发连接的 span
+    #[serde(default = "default_otlp_service_name")]
+    pub otlp_service_name: String,
+    #[serde(default)]
+    pub otlp_headers: HashMap<String, String>, // 附加到导出请求的 HTTP 头, 如认证
+    pub metrics_addr: Option<String>,
+    pub mirror_addr: Option<String>,
+    #[serde(default)]
+    pub mirror_both_directions: bool,
+    pub tcp_congestion: Option<String>,
+    #[serde(default)]
+    pub tcp_congestion_probe: bool,
+    #[serde(default, alias = "score_metric")]
+    pub round_aggregation: score::RoundAggregation,
+    #[serde(default = "default_trim_fraction")]
+    pub trim_fraction: f64,
+    pub local_port_range: Option<net::PortRange>,
+    #[serde(default)]
+    pub local_port_range_forward: bool,
+    #[serde(default)]
+    pub probe_same_egress: bool,
+    #[serde(default)]
+    pub resolve_pin_secs: u64,
+    #[serde(default)]
+    pub resolve_per_connection: bool, // 每条转发连接建连前重新解析目标域名, 解析结果已变化时使用新地址
+    pub dns_server: Option<dns::Server>, // 只有一个服务器时的简写, 加载时并入 dns_servers
+    #[serde(default)]
+    pub dns_servers: Vec<dns::Server>, // 解析目标域名使用的服务器, 依次尝试; 为空时使用系统解析
+    #[serde(default)]
+    pub verify_reload: bool,
+    #[serde(default)]
+    pub watch_config: bool,
+    pub max_connections: Option<usize>,        // 入站连接总数上限, 超出时直接关闭新连接
+    pub max_connections_per_ip: Option<usize>, // 每个客户端 IP 的入站连接数上限
+    #[serde(default)]
+    pub allow: Vec<net::Cidr>,                 // 入站来源地址白名单, 为空时不限制
+    #[serde(default)]
+    pub deny: Vec<net::Cidr>,                  // 入站来源地址黑名单, 优先于白名单
+    pub geoip_db: Option<String>,              // MaxMind DB (GeoLite2-Country 等) 文件路径
+    #[serde(default)]
+    pub allow_countries: Vec<String>,          // 入站来源国家白名单 (ISO 代码), 与 allow 合并判断
+    #[serde(default)]
+    pub deny_countries: Vec<String>,           // 入站来源国家黑名单, 与 deny 合并判断
+    pub rate_limit_kbps: Option<u64>,          // 每条转发连接每个方向的速率上限 (千比特/秒)
+    #[serde(default = "default_shutdown_drain_timeout")]
+    pub shutdown_drain_timeout: u64, // 退出时等待已有连接结束的最长时间 (秒)
+    #[serde(default)]
+    pub self_probe: bool,
+    #[serde(default = "default_self_probe_interval")]
+    pub self_probe_interval: u64,
+    pub reject_response: Option<RejectResponse>,
+    #[serde(default)]
+    pub accept_proxy_protocol: bool,
+    #[serde(default = "default_proxy_header_max_size")]
+    pub proxy_header_max_size: usize,
+    #[serde(default)]
+    pub proxy_protocol_trusted: Vec<net::Cidr>, // 只解析这些来源发来的入站 PROXY 头, 开启 accept_proxy_protocol 时必填
+    pub tunnel: Option<TunnelConfig>,
+    pub udp: Option<UdpConfig>,
+    pub tls: Option<TlsConfig>,
+    #[serde(default = "default_decay")]
+    pub score_decay_up: f64,
+    #[serde(default = "default_decay")]
+    pub score_decay_down: f64,
+    pub ewma_alpha: Option<f64>, // 对称平滑 (EWMA) 的简写, 加载时同时作为 score_decay_up 和 score_decay_down
+    #[serde(default)]
+    pub switch_connection_threshold: usize,
+    #[serde(default = "default_switch_marginal_ms")]
+    pub switch_marginal_ms: u128,
+    #[serde(default)]
+    pub switch_threshold_ms: u128, // 新节点至少领先这么多才切换
+    #[serde(default)]
+    pub switch_threshold_percent: f64, // 新节点至少领先当前节点评分的这个百分比才切换
+    #[serde(default)]
+    pub switch_existing: SwitchExisting,
+    #[serde(default = "default_switch_drain_timeout")]
+    pub switch_drain_timeout: u64, // switch_existing: drain 时旧连接最多继续转发的时间 (秒)
+    #[serde(default)]
+    pub traffic_weight: f64,
+    #[serde(default)]
+    pub hedged_connect: bool,
+    #[serde(default = "default_hedge_delay_ms")]
+    pub hedge_delay_ms: u64,
+    #[serde(default)]
+    pub happy_eyeballs: bool, // 双栈目标同时探测 IPv4 和 IPv6, 使用评分更低的协议族, 转发建连时两者竞速
+    #[serde(default = "default_happy_eyeballs_delay_ms")]
+    pub happy_eyeballs_delay_ms: u64, // 首选地址多久没连上后开始连接另一协议族 (RFC 8305 建议 250ms)
+    #[serde(default = "default_connect_retries")]
+    pub connect_retries: usize,
+    #[serde(default)]
+    pub min_success_ratio: f64,
+    #[serde(default)]
+    pub jitter_weight: f64, // 每 1ms 抖动 (RTT 标准差) 折算的评分
+    #[serde(default = "default_queue_weight")]
+    pub queue_weight: f64,
+    pub adaptive_probing: Option<AdaptiveProbing>,
+    pub webhook: Option<Webhook>,
+    pub telegram: Option<Telegram>,
+    pub slack: Option<Slack>,
+    pub read_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub idle_timeout: u64, // 转发连接双向都没有数据超过该时间 (秒) 后关闭, 0 表示不限制
+    pub write_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub write_coalesce_us: u64,
+    pub outbound_bind: Option<net::OutboundBind>, // 探测和转发出站连接的源地址或网卡 (SO_BINDTODEVICE)
+    pub fwmark: Option<u32>, // 探测和转发出站连接的 SO_MARK (仅 Linux), 供策略路由选择路由表
+    pub dscp: Option<net::Dscp>, // 转发流量 (客户端与目标两侧) 的 DSCP 标记
+    pub so_rcvbuf: Option<u32>,          // 入站及转发出站连接的 SO_RCVBUF (字节), 默认由内核自动调整
+    pub so_sndbuf: Option<u32>,          // 入站及转发出站连接的 SO_SNDBUF (字节)
+    pub relay_buffer_size: Option<usize>, // 转发时每个方向的拷贝缓冲区 (字节), zero_copy 时为管道容量, io_uring 时为读写缓冲区
+    #[serde(default = "default_listen_workers")]
+    pub listen_workers: usize, // 监听套接字数, 大于 1 时使用 SO_REUSEPORT, 各自由独立的任务接受连接
+    #[serde(default)]
+    pub zero_copy: bool, // Linux 上用 splice 在两个明文 TCP 连接之间直接转发, 不经过用户态缓冲区
+    #[serde(default)]
+    pub io_uring: bool, // Linux 上用 io_uring 在两个明文 TCP 连接之间转发数据 (需以 io-uring 特性编译), 接受连接和连接目标仍用 tokio
+    pub tcp_keepalive: Option<Keepalive>,
+    #[serde(default)]
+    pub tcp_fastopen: bool, // 监听套接字开启 TCP Fast Open (仅 Linux)
+    #[serde(default)]
+    pub tcp_fastopen_connect: bool, // 转发到目标的连接使用 TCP Fast Open (仅 Linux), 探测连接不使用
+    #[serde(default)]
+    pub transparent: bool, // 透明代理 (仅 Linux): 监听接受 TPROXY 重定向的连接, 转发时以客户端地址为源地址
+    /// 故障注入开关, 由命令行 --fault-inject 设置, 不能写在配置文件中
+    #[serde(skip)]
+    pub fault_seed: Option<u64>,
+    /// 由 geoip_db 加载的数据库, 加载配置时读入
+    #[serde(skip)]
+    pub geoip: Option<Arc<geoip::Db>>,
+}
+
+fn default_listen_workers() -> usize {
+    1
+}
+
+fn default_probe_count() -> u32 {
+    10
+}
+
+fn default_penalty_ms() -> u128 {
+    300
+}
+
+fn default_probe_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_statsd_prefix() -> String {
+    "forward_optimal".to_string()
+}
+
+fn default_statsd_interval() -> u64 {
+    10
+}
+
+fn default_otlp_service_name() -> String {
+    "forward-optimal".to_string()
+}
+
+fn default_connection_log_sample_rate() -> u64 {
+    1
+}
+
+fn default_happy_eyeballs_delay_ms() -> u64 {
+    250
+}
+
+fn default_hedge_delay_ms() -> u64 {
+    50
+}
+
+fn default_connect_retries() -> usize {
+    2
+}
+
+fn default_queue_weight() -> f64 {
+    1.0
+}
+
+fn default_self_probe_interval() -> u64 {
+    30
+}
+
+fn default_switch_marginal_ms() -> u128 {
+    20
+}
+
+fn default_switch_drain_timeout() -> u64 {
+    30
+}
+
+fn default_shutdown_drain_timeout() -> u64 {
+    30
+}
+
+fn default_decay() -> f64 {
+    1.0
+}
+
+/// 反向隧道: 后端主动连到 bind_addr 注册, 作为独立节点池参与选择
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct TunnelConfig {
+    pub bind_addr: String,
+    pub token: String,
+    #[serde(default = "default_tunnel_pool")]
+    pub pool: String,
+}
+
+/// 在监听端终止 TLS, 以明文转发给目标
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    pub cert: String, // PEM 证书链
+    pub key: String,  // PEM 私钥
+}
+
+/// UDP 转发: 按客户端源地址维护会话, 与 TCP 共用探测结果
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct UdpConfig {
+    pub bind_addr: String,
+    #[serde(default = "default_udp_idle_timeout")]
+    pub idle_timeout: u64, // 会话空闲超时 (秒)
+}
+
+fn default_udp_idle_timeout() -> u64 {
+    60
+}
+
+fn default_tunnel_pool() -> String {
+    "tunnel".to_string()
+}
+
+fn default_proxy_header_max_size() -> usize {
+    4096
+}
+
+/// 拒绝连接时写回给客户端的内容
+#[derive(Debug, Deserialize, Clone)]
+pub struct RejectResponse {
+    /// 设置后按 HTTP 响应包装 body, 否则原样发送 body
+    pub http_status: Option<u16>,
+    #[serde(default)]
+    pub body: String,
+}
+
+impl RejectResponse {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self.http_status {
+            Some(code) => {
+                let reason = match code {
+                    403 => "Forbidden",
+                    429 => "Too Many Requests",
+                    502 => "Bad Gateway",
+                    503 => "Service Unavailable",
+                    _ => "Error",
+                };
+                format!(
+                    "HTTP/1.1 {} {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    code,
+                    reason,
+                    self.body.len(),
+                    self.body
+                )
+                .into_bytes()
+            }
+            None => self.body.clone().into_bytes(),
+        }
+    }
+}
+
+/// 节点池: 池内独立选择, 池之间按 pool_policy 决定使用哪个池
+#[derive(Debug, Deserialize, Clone)]
+pub struct PoolConfig {
+    pub name: String,
+    #[serde(default)]
+    pub mode: SelectionMode,
+    pub sticky: Option<StickyMode>,
+    pub targets: Vec<TargetConfig>,
+    pub proxy_tlvs: Option<Vec<TlvConfig>>, // 覆盖全局的 TLV 模板
+    #[serde(default)]
+    pub sni: Vec<String>, // 按 TLS SNI 路由到本池的主机名, 支持 *.example.com; 为空时参与默认选择
+    #[serde(default)]
+    pub countries: Vec<String>, // 优先路由到本池的客户端国家代码 (需要 geoip_db)
+}
+
+/// 池内节点选择模式
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionMode {
+    /// 只走评分最低的节点
+    #[default]
+    Best,
+    /// 每条连接按评分加权随机分配到池内的可用节点, 评分越低概率越高
+    Weighted,
+    /// 每条连接分配到活跃连接数最少的可用节点, 连接数相同时评分低的优先
+    LeastConn,
+}
+
+/// 会话保持: 同一客户端的新连接固定分配到同一节点
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StickyMode {
+    /// 按客户端 IP 在可用节点间一致性哈希, 只有节点不可用时才会重新分配
+    ClientIp,
+}
+
+/// 池间策略
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PoolPolicy {
+    /// 按配置顺序优先使用前面的池, 池内无可用节点时切到下一个池
+    #[default]
+    Failover,
+    /// 不区分池, 使用所有池中选出的评分最低的节点
+    Best,
+}
+
+/// 自适应探测: 稳定后只探测当前最优和前几名竞争者, 其余目标每隔几轮探测一次
+#[derive(Debug, Deserialize, Clone)]
+pub struct AdaptiveProbing {
+    #[serde(default = "default_contenders")]
+    pub contenders: usize, // 每轮都探测的前几名 (含当前最优)
+    #[serde(default = "default_full_every")]
+    pub full_every: u64, // 每隔多少轮完整探测一次
+    #[serde(default = "default_degrade_ms")]
+    pub degrade_ms: u128, // 当前最优评分变差超过该值时立即完整探测
+}
+
+fn default_contenders() -> usize {
+    2
+}
+
+fn default_full_every() -> u64 {
+    5
+}
+
+fn default_degrade_ms() -> u128 {
+    50
+}
+
+/// 路由切换、全部节点不可用及恢复时 POST JSON 通知
+#[derive(Debug, Deserialize, Clone)]
+pub struct Webhook {
+    pub url: http::Url,
+    #[serde(default)]
+    pub headers: HashMap<String, String>, // 附加到请求的 HTTP 头, 如认证
+    #[serde(default = "default_webhook_retries")]
+    pub retries: u32, // 发送失败后的重试次数, 间隔依次加倍
+}
+
+fn default_webhook_retries() -> u32 {
+    3
+}
+
+/// 通过 Telegram 机器人发送通知
+#[derive(Debug, Deserialize, Clone)]
+pub struct Telegram {
+    pub bot_token: String,
+    pub chat_id: ChatId,
+    pub api_url: Option<http::Url>, // Bot API 地址, 默认 https://api.telegram.org; 自建 Bot API 服务或反向代理时修改
+    #[serde(default)]
+    pub templates: HashMap<String, String>, // 事件名称 -> 消息模板, 未设置的事件使用默认说明
+    #[serde(default = "default_webhook_retries")]
+    pub retries: u32,
+}
+
+/// 群组的数字 ID 或频道的 "@用户名"
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum ChatId {
+    Id(i64),
+    Name(String),
+}
+
+/// 通过 Slack Incoming Webhook 发送通知
+#[derive(Debug, Deserialize, Clone)]
+pub struct Slack {
+    pub webhook_url: http::Url,
+    #[serde(default)]
+    pub templates: HashMap<String, String>,
+    #[serde(default = "default_webhook_retries")]
+    pub retries: u32,
+}
+
+/// 转发连接两端 (客户端与目标) 的 TCP keepalive, 防止路径上的 NAT / 状态防火墙清除长时间空闲的会话
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    #[serde(default = "default_keepalive_idle")]
+    pub idle: u64, // 空闲多少秒后开始发送探测
+    #[serde(default = "default_keepalive_interval")]
+    pub interval: u64, // 探测间隔 (秒)
+    #[serde(default = "default_keepalive_count")]
+    pub count: u32, // 连续多少次无响应后断开
+}
+
+fn default_keepalive_idle() -> u64 {
+    60
+}
+
+fn default_keepalive_interval() -> u64 {
+    10
+}
+
+fn default_keepalive_count() -> u32 {
+    6
+}
+
+/// 路由切换后旧节点上已有连接的处理
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SwitchExisting {
+    /// 保持不动, 直到连接自行结束
+    #[default]
+    Leave,
+    /// 继续转发 switch_drain_timeout 秒后正常关闭
+    Drain,
+    /// 立即以 RST 断开, 客户端重连后使用新节点
+    Reset,
+}
+
+/// 目标列表为空 (如重新加载出错) 时的处理
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EmptyTargetsPolicy {
+    /// 继续使用上次可用的节点, 直到目标恢复
+    #[default]
+    FailOpen,
+    /// 清空可用节点, 拒绝所有连接
+    FailClosed,
+}
+
+fn default_trim_fraction() -> f64 {
+    0.1
+}
+
+/// "主机:端口" 或 "[IPv6]:端口"
+pub fn is_host_port(addr: &str) -> bool {
+    addr.rsplit_once(':').is_some_and(|(host, port)| {
+        let host_ok = match host.strip_prefix('[') {
+            Some(v6) => v6.strip_suffix(']').is_some_and(|ip| ip.parse::<std::net::Ipv6Addr>().is_ok()),
+            None => !host.is_empty() && !host.contains([':', '[', ']']) && !host.contains(char::is_whitespace),
+        };
+        host_ok && port.parse::<u16>().is_ok()
+    })
+}
+
+/// 展开 ${NAME} 为环境变量 NAME 的值; 变量未定义或写法不完整时返回错误
+fn expand_env(value: &str) -> Result<String> {
+    let mut out = String::new();
+    let mut rest = value;
+    while let Some(open) = rest.find("${") {
+        out.push_str(&rest[..open]);
+        let Some(len) = rest[open..].find('}') else { anyhow::bail!("缺少右括号: {:?}", value) };
+        let name = &rest[open + 2..open + len];
+        if name.is_empty() {
+            anyhow::bail!("环境变量名为空: {:?}", value);
+        }
+        let Ok(var) = std::env::var(name) else { anyhow::bail!("环境变量 {} 未定义: {:?}", name, value) };
+        out.push_str(&var);
+        rest = &rest[open + len + 1..];
+    }
+    Ok(out + rest)
+}
+
+impl Config {
+    /// 展开 bind_addr 和各目标 addr 中的环境变量, 返回展开失败的地址 (保持原值)
+    fn expand_env(&mut self) -> Vec<anyhow::Error> {
+        let bind = std::iter::once(("bind_addr".to_string(), &mut self.bind_addr));
+        let targets = self.targets.iter_mut().chain(self.pools.iter_mut().flat_map(|p| &mut p.targets));
+        let addrs = bind.chain(targets.map(|t| (format!("[{}] addr", t.name), &mut t.addr)));
+        let mut errors = Vec::new();
+        for (key, addr) in addrs {
+            match expand_env(addr) {
+                Ok(expanded) => *addr = expanded,
+                Err(e) => errors.push(e.context(key)),
+            }
+        }
+        errors
+    }
+
+    /// 校验配置取值, 返回发现的所有问题
+    pub fn problems(&self) -> Vec<anyhow::Error> {
+        let mut errors = Vec::new();
+        let pools = self.pool_list();
+        if pools.iter().all(|p| p.targets.is_empty()) && self.tunnel.is_none() {
+            errors.push(anyhow::anyhow!("没有配置任何目标 (targets 或 pools)"));
+        }
+        let mut names = std::collections::HashSet::new();
+        for name in pools.iter().map(|p| &p.name).chain(self.tunnel.as_ref().map(|t| &t.pool)) {
+            if !names.insert(name.as_str()) {
+                errors.push(anyhow::anyhow!("节点池名称重复: [{}]", name));
+            }
+        }
+        // 监听和对端地址为 "主机:端口"; 留空的可选地址表示不启用, 环境变量展开失败的已由 expand_env 报告
+        let addrs = [
+            Some(("bind_addr", &self.bind_addr)),
+            self.admin_addr.as_ref().map(|a| ("admin_addr", a)),
+            self.metrics_addr.as_ref().map(|a| ("metrics_addr", a)),
+            self.statsd_addr.as_ref().map(|a| ("statsd_addr", a)),
+            self.mirror_addr.as_ref().map(|a| ("mirror_addr", a)),
+            self.udp.as_ref().map(|u| ("udp.bind_addr", &u.bind_addr)),
+            self.tunnel.as_ref().map(|t| ("tunnel.bind_addr", &t.bind_addr)),
+        ];
+        for (key, addr) in addrs.into_iter().flatten().filter(|(k, a)| (*k == "bind_addr" || !a.is_empty()) && !a.contains("${")) {
+            if !is_host_port(addr) {
+                errors.push(anyhow::anyhow!("{} 应为 主机:端口, 当前: {:?}", key, addr));
+            }
+        }
+        for t in pools.iter().flat_map(|p| &p.targets).filter(|t| !t.srv && !t.addr.contains("${") && !is_host_port(&t.addr)) {
+            errors.push(anyhow::anyhow!("[{}] addr 应为 主机:端口, 当前: {:?}", t.name, t.addr));
+        }
+        if let Some(p) = pools.iter().find(|p| p.sticky.is_some() && p.mode != SelectionMode::Best) {
+            errors.push(anyhow::anyhow!("节点池 [{}] 的 sticky 只能与 mode: best 同时使用", p.name));
+        }
+        let mut hostnames = std::collections::HashSet::new();
+        for (p, host) in pools.iter().flat_map(|p| p.sni.iter().map(move |h| (p, h))) {
+            let valid = host.strip_prefix("*.").unwrap_or(host);
+            if valid.is_empty() || valid.contains('*') {
+                errors.push(anyhow::anyhow!("节点池 [{}] 的 sni 主机名无效: {}", p.name, host));
+            }
+            if !hostnames.insert(host.to_ascii_lowercase()) {
+                errors.push(anyhow::anyhow!("sni 主机名重复: {}", host));
+            }
+        }
+        let mut countries = std::collections::HashSet::new();
+        for (p, code) in pools.iter().flat_map(|p| p.countries.iter().map(move |c| (p, c))) {
+            if !countries.insert(code.to_ascii_uppercase()) {
+                errors.push(anyhow::anyhow!("countries 国家代码重复: {} (节点池 [{}])", code, p.name));
+            }
+        }
+        let codes = countries.iter().chain(&self.allow_countries).chain(&self.deny_countries);
+        if let Some(code) = codes.clone().find(|c| c.len() != 2 || !c.bytes().all(|b| b.is_ascii_alphabetic())) {
+            errors.push(anyhow::anyhow!("国家代码无效: {} (应为两个字母的 ISO 代码, 如 CN)", code));
+        }
+        if codes.count() > 0 && self.geoip_db.is_none() {
+            errors.push(anyhow::anyhow!("countries / allow_countries / deny_countries 需要设置 geoip_db"));
+        }
+        if self.listen_workers == 0 {
+            errors.push(anyhow::anyhow!("listen_workers 必须大于 0"));
+        }
+        if self.telegram.as_ref().is_some_and(|t| t.bot_token.is_empty() || t.bot_token.contains(['/', ' '])) {
+            errors.push(anyhow::anyhow!("telegram.bot_token 无效"));
+        }
+        let templates = self.telegram.iter().flat_map(|t| &t.templates).chain(self.slack.iter().flat_map(|s| &s.templates));
+        if let Some((event, _)) = templates.clone().find(|(e, _)| !notify::EVENTS.contains(&e.as_str())) {
+            errors.push(anyhow::anyhow!("未知的通知事件: {} (可选: {})", event, notify::EVENTS.join(" / ")));
+        }
+        if self.listen_workers > 1 && !cfg!(unix) {
+            errors.push(anyhow::anyhow!("listen_workers 大于 1 (SO_REUSEPORT) 只支持 Unix 系统"));
+        }
+        if self.zero_copy && !cfg!(target_os = "linux") {
+            errors.push(anyhow::anyhow!("zero_copy 只支持 Linux"));
+        }
+        if self.io_uring && !cfg!(all(target_os = "linux", feature = "io-uring")) {
+            errors.push(anyhow::anyhow!("io_uring 只支持 Linux, 且需要以 --features io-uring 编译"));
+        }
+        if self.io_uring && self.zero_copy {
+            errors.push(anyhow::anyhow!("zero_copy 与 io_uring 只能开启一个"));
+        }
+        if self.transparent && !cfg!(target_os = "linux") {
+            errors.push(anyhow::anyhow!("transparent 只支持 Linux"));
+        }
+        if self.fwmark.is_some() && !cfg!(target_os = "linux") {
+            errors.push(anyhow::anyhow!("fwmark 只支持 Linux"));
+        }
+        if self.sni_routing() && self.listen_type != LinkType::Tcp {
+            errors.push(anyhow::anyhow!("按 SNI 路由只支持 listen_type: tcp"));
+        }
+        if self.tls.is_some() && self.listen_type != LinkType::Tcp {
+            errors.push(anyhow::anyhow!("tls 只支持 listen_type: tcp"));
+        }
+        if self.tls.is_some() && self.self_probe {
+            errors.push(anyhow::anyhow!("开启 tls 时不支持 self_probe"));
+        }
+        if let Some(ref t) = self.tunnel {
+            if t.token.is_empty() {
+                errors.push(anyhow::anyhow!("tunnel.token 不能为空"));
+            }
+        }
+        if let Some(v) = self.proxy_protocol.as_deref().filter(|v| !matches!(*v, "" | "v1" | "v2")) {
+            errors.push(anyhow::anyhow!("proxy_protocol 只能是 v1 / v2 或留空, 当前: {}", v));
+        }
+        if self.udp.as_ref().is_some_and(|u| u.idle_timeout == 0) {
+            errors.push(anyhow::anyhow!("udp.idle_timeout 必须大于 0"));
+        }
+        if !(16..=proxy::MAX_HEADER_CEILING).contains(&self.proxy_header_max_size) {
+            errors.push(anyhow::anyhow!(
+                "proxy_header_max_size 必须在 [16, {}] 范围内, 当前: {}",
+                proxy::MAX_HEADER_CEILING,
+                self.proxy_header_max_size
+            ));
+        }
+        if !self.proxy_protocol_trusted.is_empty() && !self.accept_proxy_protocol {
+            errors.push(anyhow::anyhow!("proxy_protocol_trusted 需要同时开启 accept_proxy_protocol"));
+        }
+        if self.accept_proxy_protocol && self.proxy_protocol_trusted.is_empty() {
+            errors.push(anyhow::anyhow!(
+                "开启 accept_proxy_protocol 时必须设置 proxy_protocol_trusted, 确需信任所有来源可设为 [0.0.0.0/0, \"::/0\"]"
+            ));
+        }
+        let rates = [("score_decay_up", self.score_decay_up), ("score_decay_down", self.score_decay_down)];
+        for (key, rate) in rates.into_iter().chain(self.ewma_alpha.map(|a| ("ewma_alpha", a))) {
+            if !(rate > 0.0 && rate <= 1.0) {
+                errors.push(anyhow::anyhow!("{} 必须在 (0, 1] 范围内, 当前: {}", key, rate));
+            }
+        }
+        if !(self.jitter_weight >= 0.0 && self.jitter_weight.is_finite()) {
+            errors.push(anyhow::anyhow!("jitter_weight 不能为负数, 当前: {}", self.jitter_weight));
+        }
+        if !(self.queue_weight >= 0.0 && self.queue_weight.is_finite()) {
+            errors.push(anyhow::anyhow!("queue_weight 不能为负数, 当前: {}", self.queue_weight));
+        }
+        // 全局取值只在有目标沿用时校验, 且只报告一次
+        let mut inherits = pools.iter().flat_map(|p| &p.targets).map(|t| (t.probe_count, t.probe_timeout_ms));
+        if !(1..=MAX_PROBE_COUNT).contains(&self.probe_count) && inherits.clone().any(|(n, _)| n.is_none()) {
+            errors.push(anyhow::anyhow!("probe_count 必须在 1 ~ {} 范围内, 当前: {}", MAX_PROBE_COUNT, self.probe_count));
+        }
+        if self.probe_timeout_ms == 0 && inherits.any(|(_, ms)| ms.is_none()) {
+            errors.push(anyhow::anyhow!("probe_timeout_ms 必须大于 0"));
+        }
+        for t in pools.iter().flat_map(|p| &p.targets) {
+            if let Some(n) = t.probe_count.filter(|n| !(1..=MAX_PROBE_COUNT).contains(n)) {
+                errors.push(anyhow::anyhow!("[{}] probe_count 必须在 1 ~ {} 范围内, 当前: {}", t.name, MAX_PROBE_COUNT, n));
+            }
+            if t.probe_timeout_ms == Some(0) {
+                errors.push(anyhow::anyhow!("[{}] probe_timeout_ms 必须大于 0", t.name));
+            }
+            if !(t.weight > 0.0 && t.weight.is_finite()) {
+                errors.push(anyhow::anyhow!("[{}] weight 必须大于 0, 当前: {}", t.name, t.weight));
+            }
+            if t.srv && t.addr.contains(':') {
+                errors.push(anyhow::anyhow!("[{}] srv: true 时 addr 为 SRV 记录名, 不带端口: {}", t.name, t.addr));
+            }
+            if let Some(ref url) = t.queue_metric_url {
+                if let Err(e) = queue::parse_url(url) {
+                    errors.push(e.context(format!("[{}] queue_metric_url: {}", t.name, url)));
+                }
+            }
+            let tls_options = [&t.tls_server_name, &t.tls_ca, &t.tls_cert, &t.tls_key];
+            if !t.uses_tls() && (tls_options.iter().any(|o| o.is_some()) || !t.tls_verify) {
+                errors.push(anyhow::anyhow!("[{}] tls_* 需要同时设置 tls: true 或 probe: https / tls", t.name));
+            }
+            if !t.tls_verify && t.tls_ca.is_some() {
+                errors.push(anyhow::anyhow!("[{}] tls_verify: false 时 tls_ca 不生效", t.name));
+            }
+            if let Some(ref path) = t.probe_path {
+                if !matches!(t.probe, ProbeKind::Http | ProbeKind::Https) {
+                    errors.push(anyhow::anyhow!("[{}] probe_path 只用于 probe: http / https", t.name));
+                }
+                if !path.starts_with('/') || path.contains(char::is_whitespace) {
+                    errors.push(anyhow::anyhow!("[{}] probe_path 必须以 / 开头且不含空白: {}", t.name, path));
+                }
+            }
+            if t.tls_cert.is_some() != t.tls_key.is_some() {
+                errors.push(anyhow::anyhow!("[{}] tls_cert 和 tls_key 必须同时设置", t.name));
+            }
+            if let Some(ref f) = t.fault_inject {
+                if !(0.0..=1.0).contains(&f.loss) {
+                    errors.push(anyhow::anyhow!("[{}] fault_inject.loss 必须在 [0, 1] 范围内, 当前: {}", t.name, f.loss));
+                }
+            }
+        }
+        if self.statsd_interval == 0 {
+            errors.push(anyhow::anyhow!("statsd_interval 必须大于 0"));
+        }
+        if self.so_rcvbuf == Some(0) || self.so_sndbuf == Some(0) {
+            errors.push(anyhow::anyhow!("so_rcvbuf 和 so_sndbuf 必须大于 0"));
+        }
+        if let Some(n) = self.relay_buffer_size.filter(|n| !(1..=MAX_RELAY_BUFFER).contains(n)) {
+            errors.push(anyhow::anyhow!("relay_buffer_size 必须在 1 到 {} (16MB) 之间, 当前: {}", MAX_RELAY_BUFFER, n));
+        }
+        if self.write_coalesce_us > MAX_WRITE_COALESCE_US {
+            errors.push(anyhow::anyhow!("write_coalesce_us 不能超过 {} (100ms), 当前: {}", MAX_WRITE_COALESCE_US, self.write_coalesce_us));
+        }
+        if self.self_probe_interval == 0 {
+            errors.push(anyhow::anyhow!("self_probe_interval 必须大于 0"));
+        }
+        if let Some(k) = self.tcp_keepalive {
+            if k.idle == 0 || k.interval == 0 || k.count == 0 {
+                errors.push(anyhow::anyhow!("tcp_keepalive.idle、interval 和 count 必须大于 0"));
+            }
+        }
+        if let Some(ref a) = self.adaptive_probing {
+            if a.contenders == 0 || a.full_every == 0 {
+                errors.push(anyhow::anyhow!("adaptive_probing.contenders 和 full_every 必须大于 0"));
+            }
+        }
+        if !(0.0..=100.0).contains(&self.switch_threshold_percent) {
+            errors.push(anyhow::anyhow!("switch_threshold_percent 必须在 [0, 100] 范围内, 当前: {}", self.switch_threshold_percent));
+        }
+        if !(0.0..=1.0).contains(&self.min_success_ratio) {
+            errors.push(anyhow::anyhow!("min_success_ratio 必须在 [0, 1] 范围内, 当前: {}", self.min_success_ratio));
+        }
+        if !(self.traffic_weight >= 0.0 && self.traffic_weight.is_finite()) {
+            errors.push(anyhow::anyhow!("traffic_weight 不能为负数, 当前: {}", self.traffic_weight));
+        }
+        // 目标的模板已按继承关系补全, 逐个校验即可覆盖节点池的模板
+        if let Err(e) = proxy::encode_tlvs(&self.proxy_tlvs) {
+            errors.push(e.context("proxy_tlvs"));
+        }
+        for t in pools.iter().flat_map(|p| &p.targets) {
+            if let Some(Err(e)) = t.proxy_tlvs.as_ref().map(|tlvs| proxy::encode_tlvs(tlvs)) {
+                errors.push(e.context(format!("[{}] proxy_tlvs", t.name)));
+            }
+        }
+        let targets = pools.iter().flat_map(|p| &p.targets);
+        if self.rate_limit_kbps == Some(0) || targets.clone().any(|t| t.rate_limit_kbps == Some(0)) {
+            errors.push(anyhow::anyhow!("rate_limit_kbps 必须大于 0"));
+        }
+        for t in targets {
+            if [t.bandwidth_cap_kbps, t.traffic_quota_mb, t.quota_reset_days].contains(&Some(0)) {
+                errors.push(anyhow::anyhow!("[{}] bandwidth_cap_kbps / traffic_quota_mb / quota_reset_days 必须大于 0", t.name));
+            }
+            if t.quota_reset_days.is_some() && t.traffic_quota_mb.is_none() {
+                errors.push(anyhow::anyhow!("[{}] quota_reset_days 需要同时设置 traffic_quota_mb", t.name));
+            }
+        }
+        if self.max_connections == Some(0) || self.max_connections_per_ip == Some(0) {
+            errors.push(anyhow::anyhow!("max_connections 和 max_connections_per_ip 必须大于 0"));
+        }
+        if !(0.0..0.5).contains(&self.trim_fraction) {
+            errors.push(anyhow::anyhow!("trim_fraction 必须在 [0, 0.5) 范围内, 当前: {}", self.trim_fraction));
+        }
+        errors
+    }
+
+    /// 所有节点池; 顶层 targets 作为名为 "default" 的池排在最前
+    /// 各目标的 proxy_tlvs 按 目标 > 节点池 > 全局 的优先级补全, outbound_bind 按 目标 > 服务 补全
+    pub fn pool_list(&self) -> Vec<PoolConfig> {
+        let mut pools = Vec::with_capacity(self.pools.len() + 1);
+        if !self.targets.is_empty() {
+            pools.push(PoolConfig {
+                name: "default".to_string(),
+                mode: self.mode,
+                sticky: self.sticky,
+                targets: self.targets.clone(),
+                proxy_tlvs: None,
+                sni: Vec::new(),
+                countries: Vec::new(),
+            });
+        }
+        pools.extend(self.pools.iter().cloned());
+        for p in &mut pools {
+            let template = p.proxy_tlvs.as_ref().unwrap_or(&self.proxy_tlvs);
+            for t in &mut p.targets {
+                t.proxy_tlvs.get_or_insert_with(|| template.clone());
+                if t.outbound_bind.is_none() {
+                    t.outbound_bind = self.outbound_bind.clone();
+                }
+            }
+        }
+        pools
+    }
+
+    /// 是否有节点池按 SNI 路由, 需要先读取 ClientHello 再选择节点
+    pub fn sni_routing(&self) -> bool {
+        self.pools.iter().any(|p| !p.sni.is_empty())
+    }
+
+    /// 来源地址是否允许接入: 命中 deny / deny_countries 拒绝,
+    /// 设置了 allow 或 allow_countries 时必须命中其一; 查不到国家的地址不算命中
+    pub fn admits(&self, ip: std::net::IpAddr) -> bool {
+        let country = || self.country(ip);
+        let listed = |codes: &[String]| !codes.is_empty() && country().is_some_and(|c| codes.iter().any(|x| x.eq_ignore_ascii_case(c)));
+        if self.deny.iter().any(|c| c.contains(ip)) || listed(&self.deny_countries) {
+            return false;
+        }
+        (self.allow.is_empty() && self.allow_countries.is_empty())
+            || self.allow.iter().any(|c| c.contains(ip))
+            || listed(&self.allow_countries)
+    }
+
+    /// 是否解析来自 ip 的入站 PROXY 头; 只信任 proxy_protocol_trusted 中的来源, 其他来源按直连客户端处理
+    pub fn trusts_proxy_header(&self, ip: std::net::IpAddr) -> bool {
+        self.accept_proxy_protocol && self.proxy_protocol_trusted.iter().any(|c| c.contains(ip))
+    }
+
+    /// 按 geoip_db 查询地址所在国家, 没有配置数据库或查不到时为 None
+    pub fn country(&self, ip: std::net::IpAddr) -> Option<&str> {
+        self.geoip.as_ref()?.country(ip)
+    }
+
+    /// 是否有节点池按客户端国家路由
+    pub fn geo_routing(&self) -> bool {
+        self.pools.iter().any(|p| !p.countries.is_empty())
+    }
+
+    /// 转发连接使用的套接字参数
+    pub fn socket_options(&self) -> net::SocketOptions {
+        net::SocketOptions {
+            tcp_congestion: self.tcp_congestion.clone().filter(|a| !a.is_empty()),
+            local_ports: self.local_port_range.filter(|_| self.local_port_range_forward),
+            recv_buffer: self.so_rcvbuf,
+            send_buffer: self.so_sndbuf,
+            bind: None, // 按目标设置, pool_list 中已用服务的 outbound_bind 补全
+            fastopen: self.tcp_fastopen_connect,
+            spoof_source: None, // 按连接设置
+            mark: self.fwmark,
+            dscp: self.dscp,
+        }
+    }
+
+    /// 转发时单次读/写的超时
+    pub fn op_timeouts(&self) -> relay::OpTimeouts {
+        let ms = |v: Option<u64>| v.filter(|&v| v > 0).map(Duration::from_millis);
+        relay::OpTimeouts { read: ms(self.read_timeout_ms), write: ms(self.write_timeout_ms) }
+    }
+
+    /// 转发时合并小块写入的时间窗口, 0 表示立即转发
+    pub fn write_coalesce(&self) -> Option<Duration> {
+        Some(Duration::from_micros(self.write_coalesce_us)).filter(|_| self.write_coalesce_us > 0)
+    }
+
+    /// 探测连接使用的套接字参数
+    pub fn probe_socket_options(&self) -> net::SocketOptions {
+        let mut opts = self.socket_options();
+        // 探测只建立连接, 缓冲区大小不影响结果
+        opts.recv_buffer = None;
+        opts.send_buffer = None;
+        // TFO 推迟 SYN 到首次写入, 建连耗时不可测
+        opts.fastopen = false;
+        if !self.tcp_congestion_probe {
+            opts.tcp_congestion = None;
+        }
+        // probe_same_egress 时出站相关参数与转发完全一致, 只有拥塞控制仍按 tcp_congestion_probe
+        if !self.probe_same_egress {
+            opts.local_ports = self.local_port_range;
+            opts.dscp = None;
+        }
+        opts
+    }
+}
+
+/// 目标域名解析结果的协议族偏好
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FamilyPreference {
+    /// 使用解析结果中的第一个地址, 开启 happy_eyeballs 时两个协议族都使用
+    #[default]
+    Auto,
+    /// 只使用 IPv4 地址
+    Ipv4,
+    /// 只使用 IPv6 地址
+    Ipv6,
+}
+
+impl FamilyPreference {
+    /// 按偏好筛选候选地址, 强制的协议族没有解析结果时返回空
+    pub fn filter(self, addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+        match self {
+            FamilyPreference::Auto => addrs.to_vec(),
+            FamilyPreference::Ipv4 => addrs.iter().copied().filter(SocketAddr::is_ipv4).collect(),
+            FamilyPreference::Ipv6 => addrs.iter().copied().filter(SocketAddr::is_ipv6).collect(),
+        }
+    }
+}
+
+/// 客户端与目标地址协议族不一致 (IPv4 <-> IPv6) 时的处理策略
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CrossFamilyPolicy {
+    /// 照常转发, PROXY 头退化为 UNSPEC
+    #[default]
+    Allow,
+    /// 直接拒绝连接
+    Deny,
+    /// 转换为 v4-mapped 地址后再构造 PROXY 头
+    Map,
+}
+
+/// 配置文件格式, 默认按扩展名判断: .toml 为 TOML, .json 为 JSON, 其余为 YAML
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl Format {
+    fn of(path: &Path) -> Option<Format> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "yaml" | "yml" => Some(Format::Yaml),
+            "toml" => Some(Format::Toml),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+
+    // 统一转换为 YAML 的值, 之后的合并和校验与格式无关
+    fn parse(self, content: &str) -> Result<Value> {
+        Ok(match self {
+            Format::Yaml => serde_yaml::from_str(content)?,
+            Format::Toml => Value::Mapping(toml::parse(content).map_err(anyhow::Error::msg)?),
+            Format::Json => serde_yaml::to_value(serde_json::from_str::<serde_json::Value>(content)?)?,
+        })
+    }
+}
+
+/// 指定主配置文件的格式, 不再按扩展名判断; include 的子文件仍按各自的扩展名, 无法判断时与主配置相同
+pub fn set_format(format: Format) {
+    let _ = FORMAT.set(format);
+}
+
+/// 命令行参数 --bind / --target / --interval, 覆盖配置文件 (含 include 的子文件) 和每个服务中的对应取值
+#[derive(Debug)]
+pub struct Overrides {
+    pub bind_addr: Option<String>,
+    pub targets: Vec<(String, String)>, // (名称, 地址), 替换 targets 和 pools
+    pub update_interval: Option<u64>,
+    pub without_file: bool,             // 不读取配置文件, 只使用命令行参数和默认值
+}
+
+impl Overrides {
+    fn apply(&self, map: &mut Mapping) {
+        if let Some(ref addr) = self.bind_addr {
+            map.insert("bind_addr".into(), addr.as_str().into());
+        }
+        if let Some(secs) = self.update_interval {
+            map.insert("update_interval".into(), secs.into());
+        }
+        if !self.targets.is_empty() {
+            let targets = self.targets.iter().map(|(name, addr)| {
+                let mut t = Mapping::new();
+                t.insert("name".into(), name.as_str().into());
+                t.insert("addr".into(), addr.as_str().into());
+                Value::Mapping(t)
+            });
+            map.insert("targets".into(), Value::Sequence(targets.collect()));
+            map.remove("pools");
+        }
+    }
+}
+
+pub fn set_overrides(overrides: Overrides) {
+    let _ = OVERRIDES.set(overrides);
+}
+
+/// 加载配置文件, 展开 include 引用的子文件后再校验; 每个服务一份配置
+pub fn load(path: &str) -> Result<Vec<Config>> {
+    load_with_files(path).map(|(services, _)| services)
+}
+
+/// 同 load, 额外返回读取过的所有文件 (主配置及 include 的子文件)
+pub fn load_with_files(path: &str) -> Result<(Vec<Config>, Vec<PathBuf>)> {
+    let mut errors = Vec::new();
+    let loaded = load_checked(path, &mut errors);
+    match errors.into_iter().next() {
+        Some(e) => Err(e),
+        None => loaded,
+    }
+}
+
+/// 检查配置文件, 不在第一个问题处停止: 返回能够解析的服务以及发现的所有问题 (为空表示配置有效);
+/// 文件无法读取或格式错误导致无法继续时只有这一个问题
+pub fn check(path: &str) -> (Vec<Config>, Vec<anyhow::Error>) {
+    let mut errors = Vec::new();
+    match load_checked(path, &mut errors) {
+        Ok((services, _)) => (services, errors),
+        Err(e) => {
+            errors.push(e);
+            (Vec::new(), errors)
+        }
+    }
+}
+
+// 无法继续时返回错误, 其余问题记入 errors 后继续检查
+fn load_checked(path: &str, errors: &mut Vec<anyhow::Error>) -> Result<(Vec<Config>, Vec<PathBuf>)> {
+    let mut stack = Vec::new();
+    let mut origins = Vec::new();
+    let mut files = Vec::new();
+    let format = FORMAT.get().copied().or_else(|| Format::of(Path::new(path))).unwrap_or(Format::Yaml);
+    let overrides = OVERRIDES.get();
+    let mut root = match overrides {
+        Some(o) if o.without_file => Mapping::new(),
+        _ => load_file(Path::new(path), format, &mut stack, &mut origins, &mut files)?,
+    };
+
+    // 检查跨文件的目标名称冲突; 目标被 --target 替换时不检查
+    let mut seen: HashMap<&str, &Path> = HashMap::new();
+    for (name, file) in origins.iter().filter(|_| overrides.is_none_or(|o| o.targets.is_empty())) {
+        if let Some(prev) = seen.insert(name.as_str(), file.as_path()) {
+            errors.push(anyhow::anyhow!(
+                "目标名称重复: [{}] (出现在 {} 和 {})",
+                name,
+                prev.display(),
+                file.display()
+            ));
+        }
+    }
+
+    let mappings = match root.remove("services") {
+        None => vec![root],
+        Some(Value::Sequence(list)) if !list.is_empty() => {
+            list.into_iter().map(|svc| service_mapping(&root, svc)).collect::<Result<_>>()?
+        }
+        Some(_) => anyhow::bail!("services 必须是非空的服务列表: {}", path),
+    };
+    let mut services = Vec::with_capacity(mappings.len());
+    let mut geoip_dbs: HashMap<String, Arc<geoip::Db>> = HashMap::new();
+    for mut map in mappings {
+        if let Some(o) = overrides {
+            o.apply(&mut map);
+        }
+        let explicit_decay = ["score_decay_up", "score_decay_down"].iter().any(|k| map.contains_key(*k));
+        let mut config: Config = match serde_yaml::from_value(Value::Mapping(map)) {
+            Ok(config) => config,
+            Err(e) => {
+                errors.push(anyhow::Error::new(e).context(format!("配置文件格式错误: {}", path)));
+                continue;
+            }
+        };
+        let label = if config.name.is_empty() { String::new() } else { format!("服务 [{}]: ", config.name) };
+        errors.extend(config.expand_env().into_iter().map(|e| e.context(format!("{}配置无效", label))));
+        errors.extend(config.problems().into_iter().map(|e| e.context(format!("{}配置无效", label))));
+        if let Some(server) = config.dns_server.take() {
+            config.dns_servers.insert(0, server);
+        }
+        if let Some(alpha) = config.ewma_alpha {
+            if explicit_decay {
+                errors.push(anyhow::anyhow!("{}配置无效: ewma_alpha 不能与 score_decay_up / score_decay_down 同时设置", label));
+            }
+            config.score_decay_up = alpha;
+            config.score_decay_down = alpha;
+        }
+        // 多个服务使用同一数据库文件时只读取一次
+        if let Some(ref db_path) = config.geoip_db {
+            let db = match geoip_dbs.get(db_path) {
+                Some(db) => Some(db.clone()),
+                None => geoip::Db::open(Path::new(db_path))
+                    .map(Arc::new)
+                    .inspect_err(|e| errors.push(anyhow::anyhow!("{}配置无效: {:#}", label, e)))
+                    .ok(),
+            };
+            if let Some(db) = db {
+                geoip_dbs.insert(db_path.clone(), db.clone());
+                config.geoip = Some(db);
+            }
+        }
+        services.push(config);
+    }
+    validate_services(&services, errors);
+    Ok((services, files))
+}
+
+/// 服务配置: 以顶层配置为默认值, 服务中出现的键整体覆盖 (列表不合并)
+fn service_mapping(root: &Mapping, svc: Value) -> Result<Mapping> {
+    let Value::Mapping(svc) = svc else { anyhow::bail!("services 中的每一项必须是键值表") };
+    if svc.get("name").and_then(Value::as_str).is_none_or(|n| n.is_empty()) {
+        anyhow::bail!("services 中的每一项都必须设置 name");
+    }
+    let mut map = root.clone();
+    for (key, value) in svc {
+        map.insert(key, value);
+    }
+    Ok(map)
+}
+
+/// 服务之间不能重名, 也不能监听相同的地址
+fn validate_services(services: &[Config], errors: &mut Vec<anyhow::Error>) {
+    let mut names = std::collections::HashSet::new();
+    let mut addrs = HashMap::new();
+    for svc in services {
+        if !names.insert(svc.name.as_str()) {
+            errors.push(anyhow::anyhow!("服务名称重复: [{}]", svc.name));
+        }
+        let listens = [
+            Some(("bind_addr", &svc.bind_addr)),
+            svc.admin_addr.as_ref().filter(|a| !a.is_empty()).map(|a| ("admin_addr", a)),
+            svc.metrics_addr.as_ref().filter(|a| !a.is_empty()).map(|a| ("metrics_addr", a)),
+            svc.udp.as_ref().map(|u| ("udp.bind_addr", &u.bind_addr)),
+            svc.tunnel.as_ref().map(|t| ("tunnel.bind_addr", &t.bind_addr)),
+        ];
+        for (key, addr) in listens.into_iter().flatten() {
+            if let Some(prev) = addrs.insert(addr.as_str(), svc.name.as_str()) {
+                errors.push(anyhow::anyhow!("服务 [{}] 的 {} 与服务 [{}] 的监听地址重复: {}", svc.name, key, prev, addr));
+            }
+        }
+    }
+}
+
+/// 读取单个文件并递归合并其 include, 顺带记录每个目标来自哪个文件
+fn load_file(
+    path: &Path,
+    format: Format,
+    stack: &mut Vec<PathBuf>,
+    origins: &mut Vec<(String, PathBuf)>,
+    files: &mut Vec<PathBuf>,
+) -> Result<Mapping> {
+    let real = path
+        .canonicalize()
+        .with_context(|| format!("无法读取配置文件: {}", path.display()))?;
+    if stack.contains(&real) {
+        anyhow::bail!("配置文件循环 include: {}", path.display());
+    }
+    files.push(path.to_path_buf());
+
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("无法读取配置文件: {}", path.display()))?;
+    let mut map = match format.parse(&content).with_context(|| format!("配置文件格式错误: {}", path.display()))? {
+        Value::Mapping(m) => m,
+        Value::Null => Mapping::new(),
+        _ => anyhow::bail!("配置文件顶层必须是键值表: {}", path.display()),
+    };
+
+    let pool_targets = match map.get("pools") {
+        Some(Value::Sequence(pools)) => pools.iter().filter_map(|p| p.get("targets")).collect(),
+        _ => Vec::new(),
+    };
+    for list in map.get("targets").into_iter().chain(pool_targets) {
+        for t in list.as_sequence().into_iter().flatten() {
+            if let Some(name) = t.get("name").and_then(Value::as_str) {
+                origins.push((name.to_string(), path.to_path_buf()));
+            }
+        }
+    }
+
+    let includes: Vec<String> = match map.remove("include") {
+        Some(v) => serde_yaml::from_value(v)
+            .with_context(|| format!("include 必须是文件路径列表: {}", path.display()))?,
+        None => Vec::new(),
+    };
+
+    stack.push(real);
+    let base = path.parent().unwrap_or_else(|| Path::new("."));
+    for inc in includes {
+        let inc_path = base.join(&inc);
+        let fragment = load_file(&inc_path, Format::of(&inc_path).unwrap_or(format), stack, origins, files)?;
+        merge(&mut map, fragment, &inc_path)?;
+    }
+    stack.pop();
+
+    Ok(map)
+}
+
+/// 合并子文件: 列表追加, 其他键不允许以不同取值重复定义
+fn merge(into: &mut Mapping, from: Mapping, from_path: &Path) -> Result<()> {
+    for (key, value) in from {
+        match (into.get_mut(&key), value) {
+            (None, value) => {
+                into.insert(key, value);
+            }
+            (Some(Value::Sequence(existing)), Value::Sequence(more)) => {
+                existing.extend(more);
+            }
+            (Some(existing), value) => {
+                if *existing != value {
+                    anyhow::bail!(
+                        "配置项冲突: {} 在 {} 中与已有取值不一致",
+                        key.as_str().unwrap_or("?"),
+                        from_path.display()
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(yaml: &str) -> Config {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn proxy_tlvs_precedence() {
+        let config = parse(
+            r#"
+bind_addr: 127.0.0.1:0
+update_interval: 1
+proxy_tlvs:
+  - { type: 0xE0, value: "global" }
+targets:
+  - { name: t1, addr: "127.0.0.1:1" }
+  - { name: t2, addr: "127.0.0.1:2", proxy_tlvs: [{ type: 0xE0, value: "target" }] }
+pools:
+  - name: p
+    proxy_tlvs: [{ type: 0xE1, hex: "01" }]
+    targets:
+      - { name: p1, addr: "127.0.0.1:3" }
+      - { name: p2, addr: "127.0.0.1:4", proxy_tlvs: [] }
+"#,
+        );
+        let encoded: HashMap<String, Vec<u8>> = config
+            .pool_list()
+            .into_iter()
+            .flat_map(|p| p.targets)
+            .map(|t| (t.name.clone(), proxy::encode_tlvs(t.proxy_tlvs.as_deref().unwrap()).unwrap()))
+            .collect();
+        assert_eq!(encoded["t1"], [&[0xE0, 0x00, 0x06][..], b"global"].concat());
+        assert_eq!(encoded["t2"], [&[0xE0, 0x00, 0x06][..], b"target"].concat());
+        // 节点池的模板整组替换顶层模板, 不合并
+        assert_eq!(encoded["p1"], [0xE1, 0x00, 0x01, 0x01]);
+        // 目标显式设为空列表时不附加 TLV
+        assert!(encoded["p2"].is_empty());
+    }
+
+    fn load_as(name: &str, ext: &str, content: &str) -> Vec<Config> {
+        let path = std::env::temp_dir().join(format!("forward-optimal-{}-{}.{}", name, std::process::id(), ext));
+        std::fs::write(&path, content).unwrap();
+        let services = load(path.to_str().unwrap());
+        std::fs::remove_file(&path).ok();
+        services.unwrap()
+    }
+
+    #[test]
+    fn toml_matches_yaml() {
+        let yaml = r#"
+bind_addr: "127.0.0.1:18000"
+update_interval: 15
+probe_count: 4
+penalty_ms: 800
+min_success_ratio: 0.5
+allow: ["10.0.0.0/8", "192.168.1.1"]
+proxy_protocol: "v2"
+proxy_tlvs:
+  - { type: 0xE0, value: "edge" }
+tcp_keepalive: { idle: 30 }
+targets:
+  - { name: "a", addr: "127.0.0.1:1", priority: 1 }
+  - name: "b"
+    addr: "127.0.0.1:2"
+    probe: http
+    probe_path: "/health"
+pools:
+  - name: "backup"
+    mode: "least_conn"
+    targets:
+      - { name: "c", addr: "127.0.0.1:3", proxy_tlvs: [{ type: 0x05, hex: "0102" }] }
+"#;
+        let toml = r#"
+bind_addr = "127.0.0.1:18000"
+update_interval = 15
+probe_count = 4
+penalty_ms = 800
+min_success_ratio = 0.5
+allow = ["10.0.0.0/8", "192.168.1.1"]
+proxy_protocol = "v2"
+proxy_tlvs = [{ type = 0xE0, value = "edge" }]
+tcp_keepalive = { idle = 30 }
+
+[[targets]]
+name = "a"
+addr = "127.0.0.1:1"
+priority = 1
+
+[[targets]]
+name = "b"
+addr = "127.0.0.1:2"
+probe = "http"
+probe_path = "/health"
+
+[[pools]]
+name = "backup"
+mode = "least_conn"
+
+[[pools.targets]]
+name = "c"
+addr = "127.0.0.1:3"
+proxy_tlvs = [{ type = 0x05, hex = "0102" }]
+"#;
+        let from_yaml = load_as("equivalent", "yaml", yaml);
+        let from_toml = load_as("equivalent", "toml", toml);
+        assert_eq!(from_yaml.len(), 1);
+        assert_eq!(format!("{:?}", from_toml), format!("{:?}", from_yaml));
+        assert_eq!(from_toml[0].pools[0].targets[0].name, "c");
+    }
+
+    #[test]
+    fn trusted_proxy_sources() {
+        let base = "bind_addr: 127.0.0.1:0\nupdate_interval: 1\ntargets: []\n";
+        let ip = |s: &str| s.parse::<std::net::IpAddr>().unwrap();
+        let off = parse(base);
+        assert!(!off.trusts_proxy_header(ip("10.0.0.1")));
+        // 开启但没有列出受信任来源时不信任任何来源, 并且配置检查报错
+        let unlisted = parse(&format!("{}accept_proxy_protocol: true\n", base));
+        assert!(!unlisted.trusts_proxy_header(ip("203.0.113.9")));
+        let missing = |c: &Config| c.problems().iter().any(|e| e.to_string().contains("必须设置 proxy_protocol_trusted"));
+        assert!(missing(&unlisted));
+        let all = parse(&format!("{}accept_proxy_protocol: true\nproxy_protocol_trusted: [0.0.0.0/0, \"::/0\"]\n", base));
+        assert!(all.trusts_proxy_header(ip("203.0.113.9")));
+        assert!(all.trusts_proxy_header(ip("2001:db8::1")));
+        assert!(!missing(&all));
+        let listed = parse(&format!("{}accept_proxy_protocol: true\nproxy_protocol_trusted: [10.0.0.0/8, \"::1\"]\n", base));
+        assert!(listed.trusts_proxy_header(ip("10.1.2.3")));
+        assert!(listed.trusts_proxy_header(ip("::ffff:10.1.2.3")));
+        assert!(listed.trusts_proxy_header(ip("::1")));
+        assert!(!listed.trusts_proxy_header(ip("127.0.0.1")));
+        assert!(!listed.trusts_proxy_header(ip("203.0.113.9")));
+    }
+
+    #[test]
+    fn ewma_alpha_sets_both_rates() {
+        let base = "bind_addr: 127.0.0.1:0\nupdate_interval: 1\ntargets: [{ name: a, addr: \"127.0.0.1:1\" }]\n";
+        let config = &load_as("ewma", "yaml", &format!("{}ewma_alpha: 0.3\n", base))[0];
+        assert_eq!((config.score_decay_up, config.score_decay_down), (0.3, 0.3));
+        let config = &load_as("ewma", "yaml", base)[0];
+        assert_eq!((config.score_decay_up, config.score_decay_down), (1.0, 1.0));
+
+        for bad in ["ewma_alpha: 0\n", "ewma_alpha: 1.5\n", "ewma_alpha: 0.3\nscore_decay_up: 0.5\n"] {
+            let path = std::env::temp_dir().join(format!("forward-optimal-ewma-bad-{}.yaml", std::process::id()));
+            std::fs::write(&path, format!("{}{}", base, bad)).unwrap();
+            assert!(load(path.to_str().unwrap()).is_err(), "{}", bad);
+            std::fs::remove_file(&path).ok();
+        }
+    }
+
+    #[test]
+    fn io_uring_validation() {
+        let base = "bind_addr: 127.0.0.1:0\nupdate_interval: 1\ntargets: [{ name: a, addr: \"127.0.0.1:1\" }]\n";
+        let has = |yaml: &str, key: &str| {
+            serde_yaml::from_str::<Config>(yaml).unwrap().problems().iter().any(|e| e.to_string().contains(key))
+        };
+        let supported = cfg!(all(target_os = "linux", feature = "io-uring"));
+        assert_eq!(has(&format!("{}io_uring: true\n", base), "--features io-uring"), !supported);
+        assert!(has(&format!("{}io_uring: true\nzero_copy: true\n", base), "只能开启一个"));
+        assert!(!has(base, "io_uring"));
+    }
+}
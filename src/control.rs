@@ -0,0 +1,123 @@
+use anyhow::{Context, Result};
+use arc_swap::ArcSwap;
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+use crate::admin;
+use crate::state::Snapshot;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5); // 一次请求 (含建连) 的超时
+const MAX_REQUEST: u64 = 1024; // 命令行的长度上限
+const MAX_RESPONSE: u64 = 16 * 1024 * 1024; // 客户端读取的响应长度上限
+
+// 已启动的控制套接字, 第一个配置了 control_socket 的服务启动时设置
+static STARTED: OnceLock<String> = OnceLock::new();
+// 各服务的快照, 按启动顺序
+static SERVICES: Mutex<Vec<Arc<ArcSwap<Snapshot>>>> = Mutex::new(Vec::new());
+
+/// 登记一个服务的快照, status 命令按登记顺序返回所有服务的状态
+pub fn register(published: Arc<ArcSwap<Snapshot>>) {
+    SERVICES.lock().unwrap().push(published);
+}
+
+/// 启动本地控制套接字 (Unix 套接字 / Windows 命名管道), 整个进程只有一个: 多个服务都配置时以第一个为准
+/// 每个请求为一行命令, 响应为一行 JSON 后关闭连接; 目前只有 status: 各服务的状态, 格式与管理接口 /status 相同
+pub fn start(path: String) {
+    if STARTED.set(path.clone()).is_err() {
+        return;
+    }
+    tokio::spawn(async move {
+        if let Err(e) = serve(&path).await {
+            log::error!("控制套接字 {} 异常退出: {:#}", path, e);
+        }
+    });
+}
+
+#[cfg(unix)]
+async fn serve(path: &str) -> Result<()> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use tokio::net::{UnixListener, UnixStream};
+
+    // 上次异常退出留下的套接字文件: 没有进程在监听时删除
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            anyhow::bail!("文件已存在且不是套接字");
+        }
+        if UnixStream::connect(path).await.is_ok() {
+            anyhow::bail!("已有其他进程在监听");
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    // 只允许同一用户 (及 root) 访问
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    log::info!("控制套接字启动: {}", path);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream).await {
+                log::debug!("控制套接字请求处理失败: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(windows)]
+async fn serve(path: &str) -> Result<()> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let mut server = ServerOptions::new().first_pipe_instance(true).create(path)?;
+    log::info!("控制套接字启动: {}", path);
+    loop {
+        server.connect().await?;
+        // 先创建下一个实例再处理当前连接, 期间到来的客户端不会找不到管道
+        let connected = std::mem::replace(&mut server, ServerOptions::new().create(path)?);
+        tokio::spawn(async move {
+            if let Err(e) = handle(connected).await {
+                log::debug!("控制套接字请求处理失败: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle<S: AsyncRead + AsyncWrite + Unpin>(stream: S) -> Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    tokio::time::timeout(REQUEST_TIMEOUT, (&mut reader).take(MAX_REQUEST).read_line(&mut line)).await??;
+    let reply = match line.trim() {
+        "status" => {
+            let services = SERVICES.lock().unwrap().clone();
+            Value::Array(services.iter().map(|p| admin::status(&p.load())).collect())
+        }
+        command => json!({ "error": format!("未知的命令: {}", command) }),
+    };
+    let mut stream = reader.into_inner();
+    stream.write_all(format!("{}\n", reply).as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// 向运行中的实例发送一条命令, 返回响应的 JSON
+pub async fn request(path: &str, command: &str) -> Result<Value> {
+    let exchange = async {
+        #[cfg(unix)]
+        let mut stream = tokio::net::UnixStream::connect(path).await?;
+        #[cfg(windows)]
+        let mut stream = tokio::net::windows::named_pipe::ClientOptions::new().open(path)?;
+        stream.write_all(format!("{}\n", command).as_bytes()).await?;
+        let mut reply = String::new();
+        (&mut stream).take(MAX_RESPONSE).read_to_string(&mut reply).await?;
+        Ok::<_, std::io::Error>(reply)
+    };
+    let reply = tokio::time::timeout(REQUEST_TIMEOUT, exchange)
+        .await
+        .unwrap_or_else(|_| Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "超时")))
+        .with_context(|| format!("无法连接控制套接字 {} (实例是否在运行?)", path))?;
+    let reply: Value = serde_json::from_str(&reply).context("控制套接字返回了无效的响应")?;
+    if let Some(e) = reply.get("error").and_then(Value::as_str) {
+        anyhow::bail!("{}", e);
+    }
+    Ok(reply)
+}
@@ -0,0 +1,115 @@
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+// 通知前台进程启动结果的管道写端, 报告后关闭
+static READY: Mutex<Option<io::PipeWriter>> = Mutex::new(None);
+// 尚未开始监听的服务数, 归零时报告启动成功
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+// 已转入后台, 标准错误不再可见
+static DETACHED: AtomicBool = AtomicBool::new(false);
+// 写入的 PID 文件, 退出时删除
+static PID_FILE: Mutex<Option<String>> = Mutex::new(None);
+
+fn fork() -> io::Result<libc::pid_t> {
+    // SAFETY: 调用时进程只有一个线程 (tokio 运行时尚未启动)
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        pid => Ok(pid),
+    }
+}
+
+/// 转入后台运行: 两次 fork 脱离终端和会话, 标准输入输出重定向到 /dev/null, 工作目录不变 (配置中的相对路径仍然有效)
+/// 前台进程等待所有服务开始监听后以 0 退出; 启动失败时输出错误信息并以 1 退出
+/// 必须在创建任何线程 (tokio 运行时) 之前调用
+pub fn detach() -> Result<()> {
+    let (mut read, write) = io::pipe()?;
+    let child = fork().context("fork 失败")?;
+    if child != 0 {
+        drop(write);
+        // SAFETY: child 为刚创建的子进程, 它在第二次 fork 后立即退出
+        unsafe { libc::waitpid(child, std::ptr::null_mut(), 0) };
+        let mut report = Vec::new();
+        let _ = read.read_to_end(&mut report);
+        match report.split_first() {
+            Some((0, _)) => std::process::exit(0),
+            Some(_) => eprintln!("Error: {}", String::from_utf8_lossy(&report)),
+            None => eprintln!("Error: 后台进程启动失败"),
+        }
+        std::process::exit(1);
+    }
+    drop(read);
+    // SAFETY: setsid 没有参数; 子进程不是进程组首进程, 不会失败
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error()).context("setsid 失败");
+    }
+    // 会话首进程退出, 后台进程不会再获得控制终端
+    if fork().context("fork 失败")? != 0 {
+        // SAFETY: 直接退出, 不运行析构和 atexit, 避免重复刷新继承自父进程的缓冲区
+        unsafe { libc::_exit(0) };
+    }
+    let null = File::options().read(true).write(true).open("/dev/null")?;
+    for fd in 0..=2 {
+        // SAFETY: 两个描述符都有效, dup2 原子地替换标准输入输出
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+            return Err(io::Error::last_os_error()).context("无法重定向标准输入输出");
+        }
+    }
+    *READY.lock().unwrap() = Some(write);
+    DETACHED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// 写入 PID 文件; 文件中记录的进程仍在运行时拒绝启动
+pub fn write_pid_file(path: &str) -> Result<()> {
+    let running = std::fs::read_to_string(path).ok().and_then(|s| s.trim().parse::<libc::pid_t>().ok());
+    if let Some(pid) = running.filter(|&p| p > 0 && p as u32 != std::process::id()) {
+        // SAFETY: 信号 0 只检查进程是否存在
+        let alive = unsafe { libc::kill(pid, 0) } == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+        if alive {
+            bail!("PID 文件 {} 记录的进程 {} 仍在运行", path, pid);
+        }
+    }
+    std::fs::write(path, format!("{}\n", std::process::id())).with_context(|| format!("无法写入 PID 文件 {}", path))?;
+    *PID_FILE.lock().unwrap() = Some(path.to_string());
+    Ok(())
+}
+
+/// 记录需要开始监听的服务数, 为 0 时 (隧道代理) 立即通知前台进程启动成功
+pub fn expect_services(n: usize) {
+    PENDING.store(n, Ordering::Relaxed);
+    if n == 0 {
+        report_started();
+    }
+}
+
+/// 一个服务已开始监听; 全部开始后通知前台进程启动成功
+pub fn listening() {
+    if PENDING.fetch_sub(1, Ordering::Relaxed) == 1 {
+        report_started();
+    }
+}
+
+fn report_started() {
+    if let Some(mut ready) = READY.lock().unwrap().take() {
+        let _ = ready.write_all(&[0]);
+    }
+}
+
+/// 进程退出前调用: 删除 PID 文件; 启动阶段失败时把错误交给前台进程输出, 运行中失败时写入日志
+pub fn exited(result: &Result<()>) {
+    if let Some(path) = PID_FILE.lock().unwrap().take() {
+        let _ = std::fs::remove_file(path);
+    }
+    let Err(e) = result else { return };
+    match READY.lock().unwrap().take() {
+        Some(mut ready) => {
+            let _ = ready.write_all(format!("{:#}", e).as_bytes());
+        }
+        None if DETACHED.load(Ordering::Relaxed) => log::error!("异常退出: {:#}", e),
+        None => {}
+    }
+}
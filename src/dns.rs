@@ -0,0 +1,665 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use rustls_pki_types::ServerName;
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+const SERVER_TIMEOUT: Duration = Duration::from_secs(3); // 单个服务器一次查询 (含建连和握手) 的超时, 超时后换下一个
+const MAX_RESPONSE: usize = 64 * 1024; // DoH 响应最大字节数
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const UDP_ATTEMPTS: u32 = 2; // 普通 DNS 的 UDP 发送次数
+const UDP_RETRY: Duration = Duration::from_millis(1000); // 重发间隔
+const MAX_POINTERS: usize = 16; // 解码域名时最多跟随的压缩指针数
+const MAX_TTL: u32 = 3600; // 缓存时长上限 (秒)
+const NEGATIVE_TTL: u32 = 30; // 没有记录的响应的缓存时长 (秒)
+const MAX_CACHE: usize = 4096; // 缓存条目上限
+
+// (域名, 记录类型) -> (过期时间, 响应报文)
+type Cache = HashMap<(String, u16), (Instant, Vec<u8>)>;
+static CACHE: LazyLock<Mutex<Cache>> = LazyLock::new(Default::default);
+
+/// DNS 服务器, 配置写法:
+/// "8.8.8.8" / "[2001:4860:4860::8888]:53" / "udp://8.8.8.8" (普通 DNS, 只能写 IP),
+/// "https://1.1.1.1/dns-query" (DoH), "tls://1.1.1.1" (DoT, 默认端口 853)
+/// DoH / DoT 服务器的主机名经系统 DNS 解析, 建议直接写 IP (证书需包含该 IP)
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Server {
+    url: String,
+    transport: Transport,
+}
+
+#[derive(Clone)]
+enum Transport {
+    Udp(SocketAddr),
+    Https(Encrypted),
+    Tls(Encrypted),
+}
+
+#[derive(Clone)]
+struct Encrypted {
+    host: String, // 含端口
+    server_name: ServerName<'static>,
+    path: String, // DoH 请求路径
+    connector: TlsConnector,
+}
+
+impl fmt::Debug for Server {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Server({})", self.url)
+    }
+}
+
+impl fmt::Display for Server {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.url)
+    }
+}
+
+impl TryFrom<String> for Server {
+    type Error = String;
+
+    fn try_from(url: String) -> Result<Self, Self::Error> {
+        let (https, rest, port) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest, 443)
+        } else if let Some(rest) = url.strip_prefix("tls://") {
+            (false, rest, 853)
+        } else {
+            let rest = url.strip_prefix("udp://").unwrap_or(&url);
+            let addr = rest
+                .parse::<SocketAddr>()
+                .or_else(|_| rest.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+                .map_err(|_| format!("DNS 服务器应为 IP[:端口]、https:// 或 tls:// 地址: {}", url))?;
+            return Ok(Server { url: url.clone(), transport: Transport::Udp(addr) });
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) if https => rest.split_at(i),
+            Some(_) => return Err(format!("tls:// 地址不能带路径: {}", url)),
+            None => (rest, "/dns-query"),
+        };
+        if authority.is_empty() || authority.contains(char::is_whitespace) || path.contains(char::is_whitespace) {
+            return Err(format!("DNS 服务器地址格式无效: {}", url));
+        }
+        // 带端口: "1.1.1.1:8443", "[2606:4700::1111]:853"; IPv6 不带端口时需要方括号
+        let (name, host) = match authority.rsplit_once(':') {
+            Some((h, p)) if p.parse::<u16>().is_ok() && (!h.contains(':') || h.starts_with('[')) => {
+                (h, authority.to_string())
+            }
+            _ => (authority, format!("{}:{}", authority, port)),
+        };
+        let name = name.trim_start_matches('[').trim_end_matches(']');
+        let server_name =
+            ServerName::try_from(name.to_string()).map_err(|_| format!("DNS 服务器主机名无效: {}", url))?;
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let mut config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+        if https {
+            config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        }
+        let encrypted = Encrypted {
+            host,
+            server_name,
+            path: path.to_string(),
+            connector: TlsConnector::from(Arc::new(config)),
+        };
+        let transport = if https { Transport::Https(encrypted) } else { Transport::Tls(encrypted) };
+        Ok(Server { url: url.clone(), transport })
+    }
+}
+
+/// 解析 "主机:端口"; servers 为空时使用系统解析, 否则依次向 servers 查询 A 和 AAAA 记录 (A 记录在前)
+/// IP 地址直接返回, 不发起查询
+pub async fn lookup(host: &str, servers: &[Server]) -> io::Result<Vec<SocketAddr>> {
+    if servers.is_empty() {
+        return Ok(tokio::net::lookup_host(host).await?.collect());
+    }
+    if let Ok(addr) = host.parse::<SocketAddr>() {
+        return Ok(vec![addr]);
+    }
+    let (name, port) = host
+        .rsplit_once(':')
+        .and_then(|(h, p)| Some((h, p.parse::<u16>().ok()?)))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "地址缺少端口"))?;
+    let mut addrs = Vec::new();
+    for (msg, qtype) in query(servers, name, &[TYPE_A, TYPE_AAAA]).await?.iter().zip([TYPE_A, TYPE_AAAA]) {
+        for (_, _, data) in answers(msg)?.into_iter().filter(|(t, _, _)| *t == qtype) {
+            let ip = match (<[u8; 4]>::try_from(data), <[u8; 16]>::try_from(data)) {
+                (Ok(a), _) => IpAddr::V4(Ipv4Addr::from(a)),
+                (_, Ok(a)) => IpAddr::V6(Ipv6Addr::from(a)),
+                _ => continue,
+            };
+            addrs.push(SocketAddr::new(ip, port));
+        }
+    }
+    Ok(addrs)
+}
+
+/// SRV 记录; weight 用于按比例分配负载, 与评分选择无关, 不保留
+#[derive(Debug, Clone)]
+pub struct Srv {
+    pub priority: u16,
+    pub port: u16,
+    pub target: String, // 不带结尾的 "."
+}
+
+/// 查询 SRV 记录, 按 priority 排序; 目标为 "." (服务明确不可用) 的记录不返回
+/// servers 为空时向 /etc/resolv.conf 中的第一个 nameserver 发送普通 DNS 查询
+pub async fn srv(name: &str, servers: &[Server]) -> io::Result<Vec<Srv>> {
+    let system;
+    let servers = if servers.is_empty() {
+        system = [system_server()?];
+        &system[..]
+    } else {
+        servers
+    };
+    let msgs = query(servers, name, &[TYPE_SRV]).await?;
+    let msg = &msgs[0];
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "SRV 记录格式错误");
+    let mut records = Vec::new();
+    for (_, _, data) in answers(msg)?.into_iter().filter(|(t, _, _)| *t == TYPE_SRV) {
+        let field = |i: usize| data.get(i..i + 2).map(|b| u16::from_be_bytes([b[0], b[1]])).ok_or_else(invalid);
+        let (priority, port) = (field(0)?, field(4)?);
+        // 目标域名可能使用压缩指针, 需按整个报文解码
+        let offset = data.as_ptr() as usize - msg.as_ptr() as usize + 6;
+        let target = read_name(msg, offset).ok_or_else(invalid)?;
+        if !target.is_empty() {
+            records.push(Srv { priority, port, target });
+        }
+    }
+    records.sort_by_key(|r| r.priority);
+    Ok(records)
+}
+
+/// /etc/resolv.conf 中的第一个 nameserver
+fn system_server() -> io::Result<Server> {
+    let resolv = std::fs::read_to_string(RESOLV_CONF)?;
+    let ns: IpAddr = resolv
+        .lines()
+        .find_map(|l| l.trim().strip_prefix("nameserver")?.split_whitespace().next()?.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} 中没有 nameserver", RESOLV_CONF)))?;
+    Ok(Server { url: ns.to_string(), transport: Transport::Udp(SocketAddr::new(ns, 53)) })
+}
+
+/// 依次查询 qtypes, 返回对应的响应报文; 优先使用缓存, 否则按顺序尝试 servers, 第一个成功的结果按 TTL 缓存
+async fn query(servers: &[Server], name: &str, qtypes: &[u16]) -> io::Result<Vec<Vec<u8>>> {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    let cached: Vec<Option<Vec<u8>>> = {
+        let cache = CACHE.lock().unwrap();
+        let now = Instant::now();
+        qtypes
+            .iter()
+            .map(|&t| cache.get(&(name.clone(), t)).filter(|(exp, _)| *exp > now).map(|(_, msg)| msg.clone()))
+            .collect()
+    };
+    let missing: Vec<u16> = qtypes.iter().zip(&cached).filter(|(_, c)| c.is_none()).map(|(&t, _)| t).collect();
+    if missing.is_empty() {
+        return Ok(cached.into_iter().flatten().collect());
+    }
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "没有配置 DNS 服务器");
+    let mut fetched = None;
+    for server in servers {
+        match tokio::time::timeout(SERVER_TIMEOUT, exchange(server, &name, &missing)).await {
+            Ok(Ok(msgs)) => {
+                fetched = Some(msgs);
+                break;
+            }
+            Ok(Err(e)) => {
+                log::debug!("DNS 服务器 {} 查询 {} 失败: {}", server, name, e);
+                last_err = io::Error::new(e.kind(), format!("{}: {}", server, e));
+            }
+            Err(_) => {
+                log::debug!("DNS 服务器 {} 查询 {} 超时", server, name);
+                last_err = io::Error::new(io::ErrorKind::TimedOut, format!("{} 查询超时", server));
+            }
+        }
+    }
+    let mut fetched = fetched.ok_or(last_err)?.into_iter();
+    let mut cache = CACHE.lock().unwrap();
+    if cache.len() >= MAX_CACHE {
+        let now = Instant::now();
+        cache.retain(|_, (exp, _)| *exp > now);
+        if cache.len() >= MAX_CACHE {
+            cache.clear();
+        }
+    }
+    let msgs = qtypes
+        .iter()
+        .zip(cached)
+        .map(|(&t, c)| {
+            c.unwrap_or_else(|| {
+                let msg = fetched.next().unwrap_or_default();
+                if let Some(ttl) = cache_ttl(&msg).filter(|&ttl| ttl > 0) {
+                    let expires = Instant::now() + Duration::from_secs(ttl as u64);
+                    cache.insert((name.clone(), t), (expires, msg.clone()));
+                }
+                msg
+            })
+        })
+        .collect();
+    Ok(msgs)
+}
+
+/// 响应的缓存时长: 回答中最小的 TTL, 没有记录时为 NEGATIVE_TTL; 出错的响应不缓存
+fn cache_ttl(msg: &[u8]) -> Option<u32> {
+    let records = answers(msg).ok()?;
+    let ttl = records.iter().map(|(_, ttl, _)| *ttl).min().unwrap_or(NEGATIVE_TTL);
+    Some(ttl.min(MAX_TTL))
+}
+
+async fn exchange(server: &Server, name: &str, qtypes: &[u16]) -> io::Result<Vec<Vec<u8>>> {
+    match server.transport {
+        Transport::Udp(addr) => futures::future::try_join_all(qtypes.iter().map(|&t| udp(addr, name, t))).await,
+        Transport::Https(ref e) => futures::future::try_join_all(qtypes.iter().map(|&t| https(e, name, t))).await,
+        Transport::Tls(ref e) => tls(e, name, qtypes).await,
+    }
+}
+
+async fn connect(server: &Encrypted) -> io::Result<tokio_rustls::client::TlsStream<TcpStream>> {
+    let tcp = TcpStream::connect(&server.host).await?;
+    tcp.set_nodelay(true)?;
+    server.connector.connect(server.server_name.clone(), tcp).await
+}
+
+/// DoH (RFC 8484): 每种记录一个 POST 请求, 响应以连接关闭或 Content-Length 结束
+async fn https(server: &Encrypted, name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let query = encode_query(0, name, qtype)?;
+    let mut stream = connect(server).await?;
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: forward-optimal\r\nAccept: application/dns-message\r\n\
+         Content-Type: application/dns-message\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        server.path,
+        server.host,
+        query.len()
+    );
+    stream.write_all(&[head.as_bytes(), &query].concat()).await?;
+    let mut resp = Vec::new();
+    if let Err(e) = (&mut stream).take(MAX_RESPONSE as u64 + 1).read_to_end(&mut resp).await {
+        // 部分服务器发完响应后不发送 close_notify 直接断开
+        if e.kind() != io::ErrorKind::UnexpectedEof || resp.is_empty() {
+            return Err(e);
+        }
+    }
+    check_reply(doh_body(&resp)?, &query)
+}
+
+/// 从 DoH 的 HTTP 响应中取出 DNS 报文; resp 最多读取 MAX_RESPONSE + 1 字节, 超出上限的视为无效
+fn doh_body(resp: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("响应无效: {}", msg));
+    if resp.len() > MAX_RESPONSE {
+        return Err(invalid(&format!("超过 {} 字节", MAX_RESPONSE)));
+    }
+    let end = resp.windows(4).position(|w| w == b"\r\n\r\n").ok_or_else(|| invalid("响应不完整"))?;
+    let head = String::from_utf8_lossy(&resp[..end]).to_ascii_lowercase();
+    let body = &resp[end + 4..];
+    let status = head.split_whitespace().nth(1).unwrap_or("");
+    if status != "200" {
+        return Err(invalid(&format!("HTTP 状态码 {}", status)));
+    }
+    let msg = if head.contains("transfer-encoding: chunked") {
+        dechunk(body).ok_or_else(|| invalid("分块编码无效"))?
+    } else {
+        let length = head.lines().find_map(|l| l.strip_prefix("content-length:")).and_then(|v| v.trim().parse().ok());
+        match length {
+            Some(n) => body.get(..n).ok_or_else(|| invalid("响应不完整"))?.to_vec(),
+            None => body.to_vec(),
+        }
+    };
+    Ok(msg)
+}
+
+/// DoT (RFC 7858): 同一连接上依次发送所有查询, 每条消息前有 2 字节长度, 按 ID 匹配响应
+async fn tls(server: &Encrypted, name: &str, qtypes: &[u16]) -> io::Result<Vec<Vec<u8>>> {
+    let mut stream = connect(server).await?;
+    let queries: Vec<Vec<u8>> =
+        qtypes.iter().enumerate().map(|(i, &t)| encode_query(i as u16 + 1, name, t)).collect::<io::Result<_>>()?;
+    let mut out = Vec::new();
+    for query in &queries {
+        out.extend_from_slice(&(query.len() as u16).to_be_bytes());
+        out.extend_from_slice(query);
+    }
+    stream.write_all(&out).await?;
+    let mut msgs: Vec<Option<Vec<u8>>> = vec![None; qtypes.len()];
+    while msgs.iter().any(Option::is_none) {
+        let msg = read_message(&mut stream).await?;
+        let id = msg.get(..2).map_or(0, |b| u16::from_be_bytes([b[0], b[1]])) as usize;
+        if let Some(i) = id.checked_sub(1).filter(|&i| i < queries.len()) {
+            msgs[i] = Some(check_reply(msg, &queries[i])?);
+        }
+    }
+    Ok(msgs.into_iter().flatten().collect())
+}
+
+/// 普通 DNS: 经 UDP 查询, 响应被截断时改用 TCP
+async fn udp(server: SocketAddr, name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let query = encode_query(random_id()?, name, qtype)?;
+    let socket = UdpSocket::bind(if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+    socket.connect(server).await?;
+    let mut buf = vec![0u8; 4096];
+    let mut msg = None;
+    // 丢包时按固定间隔重发
+    for _ in 0..UDP_ATTEMPTS {
+        socket.send(&query).await?;
+        if let Ok(n) = tokio::time::timeout(UDP_RETRY, socket.recv(&mut buf)).await {
+            msg = Some(buf[..n?].to_vec());
+            break;
+        }
+    }
+    let msg = msg.ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "没有响应"))?;
+    if msg.get(2).is_some_and(|flags| flags & 0x02 != 0) {
+        let mut tcp = TcpStream::connect(server).await?;
+        tcp.write_all(&[&(query.len() as u16).to_be_bytes()[..], &query].concat()).await?;
+        return check_reply(read_message(&mut tcp).await?, &query);
+    }
+    check_reply(msg, &query)
+}
+
+/// 普通 DNS 没有加密, 查询 ID 必须不可预测, 否则容易被伪造响应
+fn random_id() -> io::Result<u16> {
+    let mut id = [0u8; 2];
+    getrandom::getrandom(&mut id).map_err(|e| io::Error::other(format!("无法生成随机查询 ID: {}", e)))?;
+    Ok(u16::from_be_bytes(id))
+}
+
+/// 响应的 ID 和问题区必须与查询一致 (域名不区分大小写), 否则视为伪造或不相关的响应
+fn check_reply(msg: Vec<u8>, query: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid = |m: &str| io::Error::new(io::ErrorKind::InvalidData, format!("DNS 响应无效: {}", m));
+    if msg.len() < 12 || msg[..2] != query[..2] {
+        return Err(invalid("报文头不匹配"));
+    }
+    // 查询只有一个问题: 域名 + 类型 + 类
+    let (name, rest) = query[12..].split_at(query.len() - 16);
+    let same = |q: &[u8]| q[..name.len()].eq_ignore_ascii_case(name) && q[name.len()..] == *rest;
+    if msg[4..6] != [0, 1] || !msg.get(12..query.len()).is_some_and(same) {
+        return Err(invalid("问题区与查询不一致"));
+    }
+    Ok(msg)
+}
+
+async fn read_message<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).await?;
+    let mut msg = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut msg).await?;
+    Ok(msg)
+}
+
+/// 递归查询报文: 一个问题, IN 类
+fn encode_query(id: u16, name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let mut msg = Vec::with_capacity(name.len() + 18);
+    msg.extend_from_slice(&id.to_be_bytes());
+    msg.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]); // RD, QDCOUNT = 1
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("无效的域名: {}", name)));
+        }
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+    msg.extend_from_slice(&qtype.to_be_bytes());
+    msg.extend_from_slice(&1u16.to_be_bytes());
+    Ok(msg)
+}
+
+/// 回答区的 (类型, TTL, 数据); 域名不存在时为空
+fn answers(msg: &[u8]) -> io::Result<Vec<(u16, u32, &[u8])>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("DNS 响应无效: {}", msg));
+    let u16_at = |off: usize| msg.get(off..off + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+    if msg.len() < 12 {
+        return Err(invalid("报文不完整"));
+    }
+    match msg[3] & 0x0F {
+        0 | 3 => {} // NOERROR, NXDOMAIN
+        rcode => return Err(invalid(&format!("RCODE {}", rcode))),
+    }
+    let (qdcount, ancount) = (u16_at(4).unwrap_or(0), u16_at(6).unwrap_or(0));
+    let mut off = 12;
+    for _ in 0..qdcount {
+        off = skip_name(msg, off).ok_or_else(|| invalid("问题区格式错误"))? + 4;
+    }
+    let mut records = Vec::with_capacity(ancount as usize);
+    for _ in 0..ancount {
+        off = skip_name(msg, off).ok_or_else(|| invalid("回答区格式错误"))?;
+        let (Some(rtype), Some(ttl), Some(len)) = (u16_at(off), msg.get(off + 4..off + 8), u16_at(off + 8)) else {
+            return Err(invalid("回答区格式错误"));
+        };
+        let ttl = u32::from_be_bytes([ttl[0], ttl[1], ttl[2], ttl[3]]);
+        let data = msg.get(off + 10..off + 10 + len as usize).ok_or_else(|| invalid("回答区格式错误"))?;
+        records.push((rtype, ttl, data));
+        off += 10 + len as usize;
+    }
+    Ok(records)
+}
+
+/// 解码 off 处的域名, 跟随压缩指针; 根域名为空字符串
+fn read_name(msg: &[u8], mut off: usize) -> Option<String> {
+    let mut labels: Vec<&str> = Vec::new();
+    // 限制跟随次数, 防止指针成环
+    for _ in 0..MAX_POINTERS {
+        loop {
+            let len = *msg.get(off)? as usize;
+            if len == 0 {
+                return Some(labels.join("."));
+            }
+            if len & 0xC0 == 0xC0 {
+                off = (len & 0x3F) << 8 | *msg.get(off + 1)? as usize;
+                break;
+            }
+            labels.push(std::str::from_utf8(msg.get(off + 1..off + 1 + len)?).ok()?);
+            off += 1 + len;
+        }
+    }
+    None
+}
+
+/// 跳过 off 处的域名 (可能以压缩指针结束), 返回其后的偏移
+fn skip_name(msg: &[u8], mut off: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(off)?;
+        match len {
+            0 => return Some(off + 1),
+            l if l & 0xC0 == 0xC0 => return Some(off + 2),
+            l => off += 1 + l as usize,
+        }
+    }
+}
+
+/// 解开 HTTP 分块编码的响应体; 每块数据后必须紧跟 CRLF
+fn dechunk(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        let end = body.windows(2).position(|w| w == b"\r\n")?;
+        let size = std::str::from_utf8(&body[..end]).ok()?.split(';').next()?.trim();
+        if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        let size = usize::from_str_radix(size, 16).ok()?;
+        if size == 0 {
+            return Some(out);
+        }
+        let rest = &body[end + 2..];
+        let data = rest.get(..size)?;
+        if rest.get(size..size.checked_add(2)?)? != b"\r\n" {
+            return None;
+        }
+        out.extend_from_slice(data);
+        body = &rest[size + 2..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 按查询构造响应: 复制报文头和问题区, 追加 ancount 条回答
+    fn reply(query: &[u8], ancount: u16, records: &[u8]) -> Vec<u8> {
+        let mut msg = query.to_vec();
+        msg[2] |= 0x80;
+        msg[6..8].copy_from_slice(&ancount.to_be_bytes());
+        msg.extend_from_slice(records);
+        msg
+    }
+
+    /// 一条以压缩指针 (指向问题区的域名) 开头的回答
+    fn record(rtype: u16, ttl: u32, data: &[u8]) -> Vec<u8> {
+        let mut rr = vec![0xC0, 0x0C];
+        rr.extend_from_slice(&rtype.to_be_bytes());
+        rr.extend_from_slice(&1u16.to_be_bytes());
+        rr.extend_from_slice(&ttl.to_be_bytes());
+        rr.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        rr.extend_from_slice(data);
+        rr
+    }
+
+    #[test]
+    fn parses_answers() {
+        let query = encode_query(0x1234, "a.example", TYPE_A).unwrap();
+        let records = [record(TYPE_A, 60, &[10, 0, 0, 1]), record(TYPE_A, 30, &[10, 0, 0, 2])].concat();
+        let msg = reply(&query, 2, &records);
+        let got = answers(&msg).unwrap();
+        assert_eq!(got, [(TYPE_A, 60, &[10, 0, 0, 1][..]), (TYPE_A, 30, &[10, 0, 0, 2][..])]);
+        assert_eq!(cache_ttl(&msg), Some(30));
+        assert_eq!(read_name(&msg, query.len()).as_deref(), Some("a.example"));
+    }
+
+    #[test]
+    fn reply_must_match_query() {
+        let query = encode_query(0x1234, "a.example", TYPE_A).unwrap();
+        let ok = reply(&query, 0, &[]);
+        assert!(check_reply(ok.clone(), &query).is_ok());
+        // 域名大小写不同仍然匹配
+        let upper = reply(&encode_query(0x1234, "A.Example", TYPE_A).unwrap(), 0, &[]);
+        assert!(check_reply(upper, &query).is_ok());
+
+        let mut wrong_id = ok.clone();
+        wrong_id[1] ^= 1;
+        let other_name = reply(&encode_query(0x1234, "b.example", TYPE_A).unwrap(), 0, &[]);
+        let other_type = reply(&encode_query(0x1234, "a.example", TYPE_AAAA).unwrap(), 0, &[]);
+        let mut no_question = ok[..12].to_vec();
+        no_question[5] = 0;
+        for bad in [wrong_id, other_name, other_type, no_question, ok[..ok.len() - 1].to_vec(), ok[..11].to_vec()] {
+            assert!(check_reply(bad, &query).is_err());
+        }
+    }
+
+    #[test]
+    fn random_ids_vary() {
+        let ids: std::collections::HashSet<u16> = (0..16).map(|_| random_id().unwrap()).collect();
+        assert!(ids.len() > 1);
+    }
+
+    #[test]
+    fn pointer_loops_terminate() {
+        // 指向自身, 以及两个互相指向的指针
+        let mut msg = vec![0u8; 12];
+        msg.extend_from_slice(&[0xC0, 0x0C]);
+        assert_eq!(read_name(&msg, 12), None);
+        let mut msg = vec![0u8; 12];
+        msg.extend_from_slice(&[1, b'a', 0xC0, 0x10, 1, b'b', 0xC0, 0x0C]);
+        assert_eq!(read_name(&msg, 12), None);
+        // 指针越界, 标签超出报文
+        assert_eq!(read_name(&[0xC0, 0xFF], 0), None);
+        assert_eq!(read_name(&[5, b'a', b'b'], 0), None);
+        assert_eq!(skip_name(&[5, b'a', b'b'], 0), None);
+        assert_eq!(skip_name(&[0xC0], 0), Some(2));
+    }
+
+    #[test]
+    fn truncated_records_are_rejected() {
+        let query = encode_query(1, "a.example", TYPE_A).unwrap();
+        let msg = reply(&query, 1, &record(TYPE_A, 60, &[10, 0, 0, 1]));
+        for n in 0..msg.len() {
+            assert!(answers(&msg[..n]).is_err(), "前 {} 字节", n);
+        }
+        // ancount 比实际回答多
+        assert!(answers(&reply(&query, 2, &record(TYPE_A, 60, &[10, 0, 0, 1]))).is_err());
+    }
+
+    #[test]
+    fn rdlength_overrun_is_rejected() {
+        let query = encode_query(1, "a.example", TYPE_A).unwrap();
+        let mut rr = record(TYPE_A, 60, &[10, 0, 0, 1]);
+        let len = rr.len();
+        rr[len - 6..len - 4].copy_from_slice(&5u16.to_be_bytes());
+        assert!(answers(&reply(&query, 1, &rr)).is_err());
+        rr[len - 6..len - 4].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert!(answers(&reply(&query, 1, &rr)).is_err());
+    }
+
+    #[test]
+    fn malformed_messages_do_not_panic() {
+        let query = encode_query(1, "a.example", TYPE_SRV).unwrap();
+        let valid = reply(&query, 1, &record(TYPE_SRV, 60, &[0, 1, 0, 0, 0x01, 0xBB, 0xC0, 0x0C]));
+        for i in 0..valid.len() {
+            for b in [0x00, 0x01, 0x3F, 0x40, 0xC0, 0xFF] {
+                let mut msg = valid.clone();
+                msg[i] = b;
+                if let Ok(records) = answers(&msg) {
+                    for (_, _, data) in records {
+                        let offset = data.as_ptr() as usize - msg.as_ptr() as usize;
+                        read_name(&msg, offset);
+                    }
+                }
+                read_name(&msg, i);
+                skip_name(&msg, i);
+            }
+        }
+    }
+
+    #[test]
+    fn dechunk_valid() {
+        assert_eq!(dechunk(b"5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\n\r\n").unwrap(), b"hello world");
+        assert_eq!(dechunk(b"A\r\n0123456789\r\n0\r\n").unwrap(), b"0123456789");
+        assert!(dechunk(b"0\r\n\r\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn dechunk_rejects_malformed_sizes() {
+        for bad in [
+            &b"5\r\nhel"[..],                      // 数据不足
+            b"5\r\nhello",                         // 缺少块后的 CRLF
+            b"5\r\nhelloXY0\r\n\r\n",          // 块后不是 CRLF
+            b"3\r\nhello\r\n0\r\n\r\n",      // 声明长度小于实际数据
+            b"zz\r\nhello\r\n0\r\n\r\n",     // 非十六进制
+            b"+5\r\nhello\r\n0\r\n\r\n",     // 带符号
+            b"\r\nhello\r\n",                    // 缺少长度
+            b"ffffffffffffffff\r\nx\r\n",        // 长度接近 usize::MAX
+            b"fffffffffffffffffff\r\nx\r\n",     // 长度溢出
+            b"5\r\nhello\r\n",                   // 没有结束块
+            b"5",
+            b"",
+        ] {
+            assert_eq!(dechunk(bad), None, "{:?}", String::from_utf8_lossy(bad));
+        }
+    }
+
+    #[test]
+    fn doh_body_limits() {
+        let ok = b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nabcdef";
+        assert_eq!(doh_body(ok).unwrap(), b"abc");
+        let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n";
+        assert_eq!(doh_body(chunked).unwrap(), b"abc");
+        assert!(doh_body(b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\nabc").is_err());
+        assert!(doh_body(b"HTTP/1.1 404 Not Found\r\n\r\n").is_err());
+        assert!(doh_body(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabcd").is_err());
+
+        // 读取时多取 1 字节, 正好等于上限的响应有效, 多出的部分说明响应被截断
+        let head = b"HTTP/1.1 200 OK\r\n\r\n";
+        let mut resp = head.to_vec();
+        resp.resize(MAX_RESPONSE, 0);
+        assert_eq!(doh_body(&resp).unwrap().len(), MAX_RESPONSE - head.len());
+        resp.push(0);
+        let err = doh_body(&resp).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
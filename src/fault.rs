@@ -0,0 +1,68 @@
+//! 故障注入: 仅用于测试选路逻辑, 给探测结果叠加人为延迟和丢包, 不影响真实转发。
+
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// 探测轮次计数, 与种子一起决定每次探测是否丢包, 保证同一种子下结果可复现
+static ROUND: AtomicU64 = AtomicU64::new(0);
+
+/// 开始新一轮探测, 返回轮次编号
+pub fn next_round() -> u64 {
+    ROUND.fetch_add(1, Ordering::Relaxed)
+}
+
+/// 按给定概率判定某次探测是否人为丢包
+pub fn should_drop(seed: u64, target: &str, round: u64, probe: u32, loss: f64) -> bool {
+    if loss <= 0.0 {
+        return false;
+    }
+    // 取值接近 u64::MAX 时换算结果为 1.0, 需单独处理才能保证 100% 丢包
+    if loss >= 1.0 {
+        return true;
+    }
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    (seed, target, round, probe).hash(&mut hasher);
+    let roll = splitmix64(hasher.finish()) as f64 / u64::MAX as f64;
+    roll < loss
+}
+
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_inputs_same_decision() {
+        for probe in 0..200 {
+            let first = should_drop(42, "a", 7, probe, 0.5);
+            assert_eq!(first, should_drop(42, "a", 7, probe, 0.5));
+        }
+        // 换种子后丢包序列应当不同
+        let pattern = |seed| (0..64).map(|p| should_drop(seed, "a", 7, p, 0.5)).collect::<Vec<_>>();
+        assert_eq!(pattern(42), pattern(42));
+        assert_ne!(pattern(42), pattern(43));
+    }
+
+    #[test]
+    fn loss_bounds() {
+        for round in 0..50 {
+            for probe in 0..50 {
+                assert!(!should_drop(round, "a", round, probe, 0.0));
+                assert!(!should_drop(round, "a", round, probe, -1.0));
+                assert!(should_drop(round, "a", round, probe, 1.0));
+            }
+        }
+    }
+
+    #[test]
+    fn loss_rate_roughly_matches() {
+        let dropped = (0..10_000).filter(|&p| should_drop(1, "a", 0, p, 0.3)).count();
+        assert!((2_500..3_500).contains(&dropped), "{}", dropped);
+    }
+}
@@ -0,0 +1,412 @@
+use anyhow::{Context, Result};
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+
+// 元数据位于文件末尾的标记之后
+const METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+// 搜索树与数据区之间的 16 字节分隔
+const DATA_SEPARATOR: usize = 16;
+
+// 数据区的类型编号
+const POINTER: u8 = 1;
+const STRING: u8 = 2;
+const MAP: u8 = 7;
+const ARRAY: u8 = 11;
+const BOOLEAN: u8 = 14;
+// map / array 的最大嵌套深度, 防止损坏的文件导致栈溢出
+const MAX_DEPTH: usize = 32;
+
+/// 读入内存的 MaxMind DB (GeoLite2 / GeoIP2 的 Country 或 City 库), 只用于按 IP 查询国家代码
+pub struct Db {
+    buf: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+    ipv4_start: usize,
+    data_start: usize,
+    pub database_type: String,
+    pub build_epoch: u64,
+}
+
+impl fmt::Debug for Db {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Db({}, build {})", self.database_type, self.build_epoch)
+    }
+}
+
+impl Db {
+    pub fn open(path: &Path) -> Result<Db> {
+        let buf = std::fs::read(path).with_context(|| format!("无法读取 GeoIP 数据库: {}", path.display()))?;
+        Db::parse(buf).with_context(|| format!("GeoIP 数据库格式无效: {}", path.display()))
+    }
+
+    fn parse(buf: Vec<u8>) -> Result<Db> {
+        let marker = buf
+            .windows(METADATA_MARKER.len())
+            .rposition(|w| w == METADATA_MARKER)
+            .context("没有找到元数据")?;
+        let meta = Decoder(&buf[marker + METADATA_MARKER.len()..]);
+        let field = |key: &str| meta.get(0, key).and_then(|off| meta.uint(off));
+        let node_count = field("node_count").context("缺少 node_count")? as usize;
+        let record_size = field("record_size").context("缺少 record_size")? as usize;
+        let ip_version = field("ip_version").context("缺少 ip_version")?;
+        if ![24, 28, 32].contains(&record_size) || ![4, 6].contains(&ip_version) {
+            anyhow::bail!("不支持的 record_size {} / ip_version {}", record_size, ip_version);
+        }
+        let database_type = meta.get(0, "database_type").and_then(|off| meta.string(off)).unwrap_or_default().to_string();
+        let build_epoch = field("build_epoch").unwrap_or_default();
+        let data_start = node_count.checked_mul(record_size / 4).and_then(|n| n.checked_add(DATA_SEPARATOR));
+        let Some(data_start) = data_start.filter(|&n| n <= marker) else {
+            anyhow::bail!("搜索树超出文件长度");
+        };
+
+        let mut db = Db { buf, node_count, record_size, ip_version, ipv4_start: 0, data_start, database_type, build_epoch };
+        // IPv6 库中 IPv4 地址位于 ::/96 之下, 预先走完前 96 位
+        if ip_version == 6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = db.record(node, 0).context("搜索树损坏")?;
+            }
+            db.ipv4_start = node;
+        }
+        Ok(db)
+    }
+
+    /// 查询 IP 所在国家的 ISO 代码 (大写), 没有 country 时使用 registered_country
+    pub fn country(&self, ip: IpAddr) -> Option<&str> {
+        let off = self.lookup(ip)?;
+        let data = Decoder(&self.buf[self.data_start..]);
+        ["country", "registered_country"]
+            .iter()
+            .find_map(|key| data.get(off, key).and_then(|c| data.get(c, "iso_code")).and_then(|c| data.string(c)))
+    }
+
+    /// 沿搜索树查找, 返回数据区中记录的偏移
+    fn lookup(&self, ip: IpAddr) -> Option<usize> {
+        let (bits, width, mut node) = match ip.to_canonical() {
+            IpAddr::V4(a) => (u32::from(a) as u128, 32, self.ipv4_start),
+            IpAddr::V6(_) if self.ip_version == 4 => return None,
+            IpAddr::V6(a) => (u128::from(a), 128, 0),
+        };
+        for i in (0..width).rev() {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, (bits >> i) as usize & 1)?;
+        }
+        // node_count 表示没有数据, 更大的值指向数据区; 落在分隔区内或未走到叶子说明文件损坏
+        node.checked_sub(self.node_count + DATA_SEPARATOR)
+    }
+
+    /// 节点的左 (bit 0) 或右 (bit 1) 记录
+    fn record(&self, node: usize, bit: usize) -> Option<usize> {
+        let size = self.record_size / 4;
+        let b = self.buf.get(node * size..node * size + size)?;
+        Some(match self.record_size {
+            24 => be(&b[bit * 3..bit * 3 + 3]) as usize,
+            // 28 位记录: 中间字节的高/低 4 位分别是左/右记录的最高位
+            28 if bit == 0 => (b[3] as usize & 0xF0) << 20 | be(&b[0..3]) as usize,
+            28 => (b[3] as usize & 0x0F) << 24 | be(&b[4..7]) as usize,
+            _ => be(&b[bit * 4..bit * 4 + 4]) as usize,
+        })
+    }
+}
+
+fn be(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |acc, &b| acc << 8 | b as u64)
+}
+
+/// 数据区解码, 指针相对于数据区起点; 只解出需要的字段, 其余跳过
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn bytes(&self, off: usize, n: usize) -> Option<u64> {
+        self.0.get(off..off + n).map(be)
+    }
+
+    /// 返回 (类型, 长度, 内容起点); 指针由 pointer 处理
+    fn header(&self, off: usize) -> Option<(u8, usize, usize)> {
+        let ctrl = *self.0.get(off)?;
+        let mut off = off + 1;
+        let mut kind = ctrl >> 5;
+        if kind == 0 {
+            kind = 7u8.checked_add(*self.0.get(off)?)?;
+            off += 1;
+        }
+        let (size, extra) = match ctrl & 0x1F {
+            29 => (29 + self.bytes(off, 1)? as usize, 1),
+            30 => (285 + self.bytes(off, 2)? as usize, 2),
+            31 => (65821 + self.bytes(off, 3)? as usize, 3),
+            s => (s as usize, 0),
+        };
+        Some((kind, size, off + extra))
+    }
+
+    /// off 处为指针时返回 (指向的偏移, 指针之后的偏移)
+    fn pointer(&self, off: usize) -> Option<(usize, usize)> {
+        let ctrl = *self.0.get(off)?;
+        if ctrl >> 5 != POINTER {
+            return None;
+        }
+        let n = ((ctrl >> 3) & 0x3) as usize + 1;
+        let v = (ctrl & 0x7) as usize;
+        let b = self.bytes(off + 1, n)? as usize;
+        let target = match n {
+            1 => v << 8 | b,
+            2 => (v << 16 | b) + 2048,
+            3 => (v << 24 | b) + 526336,
+            _ => b,
+        };
+        Some((target, off + 1 + n))
+    }
+
+    fn deref(&self, off: usize) -> usize {
+        self.pointer(off).map_or(off, |(target, _)| target)
+    }
+
+    /// 跳过 off 处的值, 返回其后的偏移
+    fn skip(&self, off: usize) -> Option<usize> {
+        self.skip_nested(off, 0)
+    }
+
+    fn skip_nested(&self, off: usize, depth: usize) -> Option<usize> {
+        if let Some((_, end)) = self.pointer(off) {
+            return Some(end);
+        }
+        if depth >= MAX_DEPTH {
+            return None;
+        }
+        let (kind, size, mut off) = self.header(off)?;
+        let items = match kind {
+            MAP => size * 2,
+            ARRAY => size,
+            BOOLEAN => return Some(off), // 布尔值保存在长度中
+            _ => return Some(off + size),
+        };
+        for _ in 0..items {
+            off = self.skip_nested(off, depth + 1)?;
+        }
+        Some(off)
+    }
+
+    /// off 处的 map 中 key 对应值的偏移
+    fn get(&self, off: usize, key: &str) -> Option<usize> {
+        let (kind, size, mut off) = self.header(self.deref(off))?;
+        if kind != MAP {
+            return None;
+        }
+        for _ in 0..size {
+            let matched = self.string(off)? == key;
+            off = self.skip(off)?;
+            if matched {
+                return Some(off);
+            }
+            off = self.skip(off)?;
+        }
+        None
+    }
+
+    fn string(&self, off: usize) -> Option<&'a str> {
+        let (kind, size, off) = self.header(self.deref(off))?;
+        if kind != STRING {
+            return None;
+        }
+        std::str::from_utf8(self.0.get(off..off + size)?).ok()
+    }
+
+    /// uint16 / uint32 / uint64
+    fn uint(&self, off: usize) -> Option<u64> {
+        let (kind, size, off) = self.header(self.deref(off))?;
+        if !matches!(kind, 5 | 6 | 9) || size > 8 {
+            return None;
+        }
+        self.bytes(off, size)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // 数据区编码, 与 MaxMind DB 规范一致
+    fn ctrl(kind: u8, size: usize) -> Vec<u8> {
+        assert!(size < 29);
+        if kind < 8 { vec![kind << 5 | size as u8] } else { vec![size as u8, kind - 7] }
+    }
+
+    fn string(s: &str) -> Vec<u8> {
+        [ctrl(STRING, s.len()), s.as_bytes().to_vec()].concat()
+    }
+
+    fn uint(kind: u8, v: u64, n: usize) -> Vec<u8> {
+        [ctrl(kind, n), v.to_be_bytes()[8 - n..].to_vec()].concat()
+    }
+
+    fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut out = ctrl(MAP, entries.len());
+        for (k, v) in entries {
+            out.extend(string(k));
+            out.extend_from_slice(v);
+        }
+        out
+    }
+
+    #[derive(Clone, Copy)]
+    enum Rec {
+        Empty,
+        Node(usize),
+        Data(usize),
+    }
+
+    /// 构造只含若干网段的库: (网段, 前缀长度, 国家代码); IPv6 库中的 IPv4 网段放在 ::/96 之下
+    pub(crate) fn build(record_size: usize, ip_version: u64, nets: &[(&str, u32, &str)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut nodes = vec![[Rec::Empty; 2]];
+        for &(net, prefix, code) in nets {
+            let (bits, width, prefix) = match net.parse::<IpAddr>().unwrap() {
+                IpAddr::V4(a) if ip_version == 6 => (u32::from(a) as u128, 128, prefix + 96),
+                IpAddr::V4(a) => (u32::from(a) as u128, 32, prefix),
+                IpAddr::V6(a) => (u128::from(a), 128, prefix),
+            };
+            let leaf = Rec::Data(data.len());
+            data.extend(map(&[("country", map(&[("iso_code", string(code))]))]));
+            let mut node = 0;
+            for i in 0..prefix {
+                let bit = (bits >> (width - 1 - i)) as usize & 1;
+                if i == prefix - 1 {
+                    nodes[node][bit] = leaf;
+                    break;
+                }
+                if let Rec::Node(next) = nodes[node][bit] {
+                    node = next;
+                } else {
+                    nodes.push([Rec::Empty; 2]);
+                    nodes[node][bit] = Rec::Node(nodes.len() - 1);
+                    node = nodes.len() - 1;
+                }
+            }
+        }
+        let n = nodes.len();
+        let value = |r: Rec| match r {
+            Rec::Empty => n,
+            Rec::Node(i) => i,
+            Rec::Data(off) => n + DATA_SEPARATOR + off,
+        };
+        let mut out = Vec::new();
+        for [l, r] in nodes {
+            let (l, r) = (value(l) as u32, value(r) as u32);
+            match record_size {
+                24 => {
+                    out.extend_from_slice(&l.to_be_bytes()[1..]);
+                    out.extend_from_slice(&r.to_be_bytes()[1..]);
+                }
+                28 => {
+                    out.extend_from_slice(&l.to_be_bytes()[1..]);
+                    out.push(((l >> 24) as u8 & 0x0F) << 4 | (r >> 24) as u8 & 0x0F);
+                    out.extend_from_slice(&r.to_be_bytes()[1..]);
+                }
+                _ => {
+                    out.extend_from_slice(&l.to_be_bytes());
+                    out.extend_from_slice(&r.to_be_bytes());
+                }
+            }
+        }
+        out.extend_from_slice(&[0; DATA_SEPARATOR]);
+        out.extend(data);
+        out.extend_from_slice(METADATA_MARKER);
+        out.extend(map(&[
+            ("node_count", uint(6, n as u64, 4)),
+            ("record_size", uint(5, record_size as u64, 2)),
+            ("ip_version", uint(5, ip_version, 2)),
+            ("database_type", string("Test-Country")),
+            ("build_epoch", uint(9, 1_700_000_000, 8)),
+        ]));
+        out
+    }
+
+    const NETS: &[(&str, u32, &str)] = &[("1.2.3.0", 24, "JP"), ("10.0.0.0", 8, "DE"), ("2001:db8::", 32, "US")];
+
+    fn country(db: &Db, ip: &str) -> Option<String> {
+        db.country(ip.parse().unwrap()).map(str::to_string)
+    }
+
+    #[test]
+    fn record_sizes() {
+        for record_size in [24, 28, 32] {
+            let db = Db::parse(build(record_size, 6, NETS)).unwrap();
+            assert_eq!(db.database_type, "Test-Country");
+            assert_eq!(db.build_epoch, 1_700_000_000);
+            assert_eq!(country(&db, "1.2.3.4").as_deref(), Some("JP"), "record_size {}", record_size);
+            assert_eq!(country(&db, "10.200.0.1").as_deref(), Some("DE"));
+            assert_eq!(country(&db, "2001:db8::1").as_deref(), Some("US"));
+            assert_eq!(country(&db, "1.2.4.4"), None);
+            assert_eq!(country(&db, "2001:db9::1"), None);
+        }
+    }
+
+    #[test]
+    fn ipv4_in_ipv6_tree() {
+        let db = Db::parse(build(28, 6, NETS)).unwrap();
+        // IPv4 映射地址按 IPv4 查询
+        assert_eq!(country(&db, "::ffff:1.2.3.4").as_deref(), Some("JP"));
+        assert_eq!(country(&db, "::ffff:10.1.1.1").as_deref(), Some("DE"));
+
+        let v4 = Db::parse(build(24, 4, &NETS[..2])).unwrap();
+        assert_eq!(country(&v4, "1.2.3.4").as_deref(), Some("JP"));
+        assert_eq!(country(&v4, "2001:db8::1"), None);
+    }
+
+    #[test]
+    fn truncated_file_does_not_panic() {
+        let full = build(28, 6, NETS);
+        for n in 0..full.len() {
+            if let Ok(db) = Db::parse(full[..n].to_vec()) {
+                for ip in ["1.2.3.4", "10.0.0.1", "2001:db8::1", "::1"] {
+                    country(&db, ip);
+                }
+            }
+        }
+        // 搜索树之后的数据全部丢失, 只保留元数据
+        let marker = full.windows(METADATA_MARKER.len()).rposition(|w| w == METADATA_MARKER).unwrap();
+        assert!(Db::parse(full[marker..].to_vec()).is_err());
+    }
+
+    #[test]
+    fn corrupt_data_section() {
+        // 扩展类型字节过大时 7 + n 溢出
+        assert_eq!(Decoder(&[0x00, 0xFF]).header(0), None);
+        assert_eq!(Decoder(&[0x00, 0x01]).header(0), Some((8, 0, 2)));
+        // 嵌套过深的 map
+        let mut deep = Vec::new();
+        for _ in 0..1000 {
+            deep.extend(ctrl(MAP, 1));
+            deep.extend(string("k"));
+        }
+        deep.extend(string("v"));
+        assert_eq!(Decoder(&deep).skip(0), None);
+        let mut shallow = Vec::new();
+        for _ in 0..MAX_DEPTH - 1 {
+            shallow.extend(ctrl(MAP, 1));
+            shallow.extend(string("k"));
+        }
+        shallow.extend(string("v"));
+        assert_eq!(Decoder(&shallow).skip(0), Some(shallow.len()));
+
+        // 任意改动一个字节后查询不会 panic
+        let full = build(24, 6, NETS);
+        for i in 0..full.len() {
+            for b in [0x00, 0x1F, 0x20, 0xE0, 0xFF] {
+                let mut buf = full.clone();
+                buf[i] = b;
+                if let Ok(db) = Db::parse(buf) {
+                    country(&db, "1.2.3.4");
+                    country(&db, "2001:db8::1");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::net::{self, SocketOptions};
+use crate::tls;
+
+const MAX_STATUS_LINE: usize = 1024; // 状态行最大字节数
+
+/// HTTP 健康检查: 连接目标并发送 GET, 读到状态行即返回, 调用方的计时即为首字节时间
+/// 状态码不是 2xx / 3xx 时返回错误; upstream 不为 None 时先完成 TLS 握手 (HTTPS)
+pub async fn check(
+    addr: SocketAddr,
+    opts: &SocketOptions,
+    upstream: Option<&tls::Upstream>,
+    host: &str,
+    path: &str,
+) -> io::Result<()> {
+    let tcp = net::connect(addr, opts).await?;
+    match upstream {
+        Some(u) => request(tls::connect(u, tcp).await?, host, path).await,
+        None => request(tcp, host, path).await,
+    }
+}
+
+/// TLS 探测: 完成建连和 TLS 握手即算成功
+pub async fn handshake(addr: SocketAddr, opts: &SocketOptions, upstream: &tls::Upstream) -> io::Result<()> {
+    let tcp = net::connect(addr, opts).await?;
+    tls::connect(upstream, tcp).await.map(drop)
+}
+
+async fn request<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, host: &str, path: &str) -> io::Result<()> {
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: forward-optimal\r\nConnection: close\r\n\r\n",
+        path, host
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut buf = Vec::with_capacity(256);
+    let mut chunk = [0u8; 256];
+    let end = loop {
+        if let Some(i) = buf.windows(2).position(|w| w == b"\r\n") {
+            break i;
+        }
+        if buf.len() > MAX_STATUS_LINE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "状态行过长"));
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "连接在响应前关闭"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let line = String::from_utf8_lossy(&buf[..end]);
+    let status: u16 = line
+        .strip_prefix("HTTP/")
+        .and_then(|rest| rest.split_whitespace().nth(1))
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("无效的状态行: {:.64}", line)))?;
+    if !(200..400).contains(&status) {
+        return Err(io::Error::other(format!("HTTP 状态码 {}", status)));
+    }
+    Ok(())
+}
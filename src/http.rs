@@ -0,0 +1,131 @@
+use rustls_pki_types::ServerName;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+const MAX_RESPONSE: u64 = 16 * 1024; // 读取的响应字节数上限
+
+/// 向外发送 JSON 的 http:// 或 https:// 地址 (OTLP、webhook 等); 不带路径时为 "/"
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Url {
+    url: String,
+    host: String, // 含端口
+    path: String,
+    tls: Option<(ServerName<'static>, TlsConnector)>,
+}
+
+impl fmt::Debug for Url {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Url({})", self.url)
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.url)
+    }
+}
+
+impl PartialEq for Url {
+    fn eq(&self, other: &Self) -> bool {
+        self.url == other.url
+    }
+}
+
+impl TryFrom<String> for Url {
+    type Error = String;
+
+    fn try_from(url: String) -> Result<Self, Self::Error> {
+        Url::parse(&url, "/")
+    }
+}
+
+impl Url {
+    pub fn parse(url: &str, default_path: &str) -> Result<Url, String> {
+        let (https, rest) = match (url.strip_prefix("https://"), url.strip_prefix("http://")) {
+            (Some(rest), _) => (true, rest),
+            (_, Some(rest)) => (false, rest),
+            _ => return Err(format!("应为 http:// 或 https:// 地址: {}", url)),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) if i + 1 < rest.len() => rest.split_at(i),
+            Some(i) => (&rest[..i], default_path),
+            None => (rest, default_path),
+        };
+        if authority.is_empty() || authority.contains(char::is_whitespace) || path.contains(char::is_whitespace) {
+            return Err(format!("地址格式无效: {}", url));
+        }
+        let (name, host) = match authority.rsplit_once(':') {
+            Some((h, p)) if p.parse::<u16>().is_ok() && (!h.contains(':') || h.starts_with('[')) => {
+                (h, authority.to_string())
+            }
+            _ => (authority, format!("{}:{}", authority, if https { 443 } else { 80 })),
+        };
+        let tls = if https {
+            let name = name.trim_start_matches('[').trim_end_matches(']');
+            let server_name = ServerName::try_from(name.to_string()).map_err(|_| format!("主机名无效: {}", url))?;
+            let mut roots = RootCertStore::empty();
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            let mut config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+            config.alpn_protocols = vec![b"http/1.1".to_vec()];
+            Some((server_name, TlsConnector::from(Arc::new(config))))
+        } else {
+            None
+        };
+        Ok(Url { url: url.to_string(), host, path: path.to_string(), tls })
+    }
+
+    /// 在路径后追加 suffix; 显示的地址不变 (追加的部分可能含有令牌, 不应出现在日志里)
+    pub fn join(&self, suffix: &str) -> Url {
+        Url { path: format!("{}{}", self.path.trim_end_matches('/'), suffix), ..self.clone() }
+    }
+}
+
+/// POST 一个 JSON 请求体, 超时 (含建连和握手) 或状态码不是 2xx 时返回错误; headers 附加到请求中
+pub async fn post_json(url: &Url, headers: &HashMap<String, String>, body: String, timeout: Duration) -> io::Result<()> {
+    let sent = tokio::time::timeout(timeout, async {
+        let tcp = TcpStream::connect(&url.host).await?;
+        tcp.set_nodelay(true)?;
+        match url.tls {
+            Some((ref name, ref connector)) => request(connector.connect(name.clone(), tcp).await?, url, headers, body).await,
+            None => request(tcp, url, headers, body).await,
+        }
+    });
+    sent.await.unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "超时")))
+}
+
+async fn request<S>(mut stream: S, url: &Url, headers: &HashMap<String, String>, body: String) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let extra: String = headers.iter().map(|(k, v)| format!("{}: {}\r\n", k, v)).collect();
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: forward-optimal\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\n{}Connection: close\r\n\r\n",
+        url.path,
+        url.host,
+        body.len(),
+        extra
+    );
+    stream.write_all(&[head.as_bytes(), body.as_bytes()].concat()).await?;
+    let mut resp = Vec::new();
+    if let Err(e) = (&mut stream).take(MAX_RESPONSE).read_to_end(&mut resp).await {
+        // 部分服务器发完响应后不发送 close_notify 直接断开
+        if e.kind() != io::ErrorKind::UnexpectedEof || resp.is_empty() {
+            return Err(e);
+        }
+    }
+    let status = resp.split(|&b| b == b' ').nth(1).map(String::from_utf8_lossy).unwrap_or_default();
+    if !status.starts_with('2') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("HTTP 状态码 {}", status)));
+    }
+    Ok(())
+}
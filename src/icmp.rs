@@ -0,0 +1,96 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU16, Ordering};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+use crate::net;
+
+const PAYLOAD: &[u8] = b"forward-optimal"; // 回显数据, 用于认出本进程的回复
+const ECHO_REQUEST_V4: u8 = 8;
+const ECHO_REPLY_V4: u8 = 0;
+const ECHO_REQUEST_V6: u8 = 128;
+const ECHO_REPLY_V6: u8 = 129;
+
+static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+
+/// 一个目标在一轮探测中使用的 ICMP 套接字
+/// 优先使用无需特权的 ICMP 数据报套接字 (net.ipv4.ping_group_range), 不允许时改用原始套接字 (需要 root / CAP_NET_RAW)
+pub struct Pinger {
+    socket: UdpSocket,
+    v6: bool,
+    raw: bool,
+    id: u16,
+}
+
+impl Pinger {
+    pub fn new(ip: IpAddr, bind: Option<&net::OutboundBind>, mark: Option<u32>) -> io::Result<Pinger> {
+        let v6 = ip.is_ipv6();
+        let (domain, protocol) = if v6 { (Domain::IPV6, Protocol::ICMPV6) } else { (Domain::IPV4, Protocol::ICMPV4) };
+        let (socket, raw) = match Socket::new(domain, Type::DGRAM, Some(protocol)) {
+            Ok(s) => (s, false),
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => (Socket::new(domain, Type::RAW, Some(protocol))?, true),
+            Err(e) => return Err(e),
+        };
+        socket.set_nonblocking(true)?;
+        net::bind_outbound(socket2::SockRef::from(&socket), bind, mark, ip)?;
+        // connect 后只收到来自目标的报文
+        socket.connect(&SocketAddr::new(ip, 0).into())?;
+        let socket = UdpSocket::from_std(socket.into())?;
+        let id = (std::process::id() as u16).wrapping_add(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+        Ok(Pinger { socket, v6, raw, id })
+    }
+
+    /// 发送一个回显请求并等待对应的回复, 超时由调用方控制
+    pub async fn ping(&self, seq: u16) -> io::Result<()> {
+        self.socket.send(&self.echo_request(seq)).await?;
+        let mut buf = [0u8; 1500];
+        loop {
+            let n = self.socket.recv(&mut buf).await?;
+            if self.is_reply(&buf[..n], seq) {
+                return Ok(());
+            }
+        }
+    }
+
+    fn echo_request(&self, seq: u16) -> Vec<u8> {
+        let kind = if self.v6 { ECHO_REQUEST_V6 } else { ECHO_REQUEST_V4 };
+        let mut packet = vec![kind, 0, 0, 0];
+        packet.extend_from_slice(&self.id.to_be_bytes());
+        packet.extend_from_slice(&seq.to_be_bytes());
+        packet.extend_from_slice(PAYLOAD);
+        // ICMPv6 的校验和包含伪首部, 由内核计算
+        if !self.v6 {
+            let sum = checksum(&packet);
+            packet[2..4].copy_from_slice(&sum.to_be_bytes());
+        }
+        packet
+    }
+
+    fn is_reply(&self, data: &[u8], seq: u16) -> bool {
+        // IPv4 原始套接字收到的数据带 IP 头
+        let data = match (self.raw && !self.v6, data.first()) {
+            (true, Some(b)) => data.get(((b & 0x0f) as usize) * 4..).unwrap_or_default(),
+            _ => data,
+        };
+        let reply = if self.v6 { ECHO_REPLY_V6 } else { ECHO_REPLY_V4 };
+        if data.len() < 8 || data[0] != reply || data[8..] != *PAYLOAD {
+            return false;
+        }
+        // 数据报套接字的标识由内核改写并按套接字分发, 只有原始套接字需要比对
+        let id_ok = !self.raw || data[4..6] == self.id.to_be_bytes();
+        id_ok && data[6..8] == seq.to_be_bytes()
+    }
+}
+
+/// RFC 1071 校验和
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], c.get(1).copied().unwrap_or(0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
@@ -0,0 +1,92 @@
+use serde_json::{Map, Value};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// 是否以 JSON 输出日志
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// 日志改为每行一个 JSON 对象: ts / level / msg, 以及日志附带的结构化字段 (event、target 等)
+pub fn install(logger: &mut env_logger::Builder) {
+    ENABLED.store(true, Ordering::Relaxed);
+    logger.format(|buf, record| {
+        let mut line = Map::new();
+        line.insert("ts".into(), buf.timestamp_millis().to_string().into());
+        line.insert("level".into(), record.level().as_str().into());
+        line.insert("msg".into(), record.args().to_string().into());
+        let _ = record.key_values().visit(&mut Fields(&mut line));
+        writeln!(buf, "{}", Value::Object(line))
+    });
+}
+
+/// 是否以 JSON 输出日志; 访问日志的格式与之相同
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// 连接建立与结束事件的日志级别: 文本日志中为 debug, 避免逐条连接刷屏; JSON 日志中为 info, 便于采集
+pub fn connection_level() -> log::Level {
+    if enabled() {
+        log::Level::Info
+    } else {
+        log::Level::Debug
+    }
+}
+
+// 日志附带的字段, 空值 (None) 不输出
+struct Fields<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> log::kv::VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: log::kv::Key<'kvs>, value: log::kv::Value<'kvs>) -> Result<(), log::kv::Error> {
+        let mut json = Value::Null;
+        value.visit(Json(&mut json))?;
+        if !json.is_null() {
+            self.0.insert(key.as_str().into(), json);
+        }
+        Ok(())
+    }
+}
+
+// 数值、布尔和空值保持 JSON 类型, 其余按显示格式输出为字符串
+struct Json<'a>(&'a mut Value);
+
+impl<'v> log::kv::VisitValue<'v> for Json<'_> {
+    fn visit_any(&mut self, value: log::kv::Value) -> Result<(), log::kv::Error> {
+        *self.0 = value.to_string().into();
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), log::kv::Error> {
+        *self.0 = Value::Null;
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), log::kv::Error> {
+        *self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), log::kv::Error> {
+        *self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_u128(&mut self, value: u128) -> Result<(), log::kv::Error> {
+        *self.0 = u64::try_from(value).map_or_else(|_| value.to_string().into(), Value::from);
+        Ok(())
+    }
+
+    fn visit_f64(&mut self, value: f64) -> Result<(), log::kv::Error> {
+        *self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), log::kv::Error> {
+        *self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_str(&mut self, value: &str) -> Result<(), log::kv::Error> {
+        *self.0 = value.into();
+        Ok(())
+    }
+}
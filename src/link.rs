@@ -0,0 +1,127 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::relay::{timed, OpTimeouts};
+
+// --- 压缩链路帧格式: [标志 u8][帧长度 u16][原始长度 u16][数据] ---
+const FRAME_RAW: u8 = 0;      // 数据不可压缩, 原样发送
+const FRAME_LZ4: u8 = 1;      // LZ4 块压缩
+const FRAME_HEADER: usize = 5;
+const FRAME_MAX: usize = 16 * 1024; // 单帧原始数据上限, 解压时也以此为界
+
+/// 单个方向的字节统计, 用于计算压缩率
+#[derive(Default)]
+pub struct LinkStats {
+    plain: AtomicU64, // 原始数据字节数
+    wire: AtomicU64,  // 链路上实际传输的字节数 (含帧头)
+}
+
+impl LinkStats {
+    /// 返回 (原始字节数, 链路字节数)
+    pub fn get(&self) -> (u64, u64) {
+        (self.plain.load(Ordering::Relaxed), self.wire.load(Ordering::Relaxed))
+    }
+
+    /// 链路字节数占原始字节数的百分比
+    pub fn ratio(&self) -> f64 {
+        let (plain, wire) = self.get();
+        if plain == 0 {
+            return 100.0;
+        }
+        wire as f64 * 100.0 / plain as f64
+    }
+}
+
+/// 读取明文并压缩成帧写入链路, 读到 EOF 后关闭写端
+pub async fn encode_half<R, W>(mut reader: R, mut writer: W, stats: &LinkStats, timeouts: OpTimeouts) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; FRAME_MAX];
+    let mut frame = vec![0u8; FRAME_HEADER + lz4_flex::block::get_maximum_output_size(FRAME_MAX)];
+    loop {
+        let n = timed(timeouts.read, "读取", reader.read(&mut buf)).await?;
+        if n == 0 {
+            return timed(timeouts.write, "写入", writer.shutdown()).await;
+        }
+        let len = match lz4_flex::block::compress_into(&buf[..n], &mut frame[FRAME_HEADER..]) {
+            Ok(len) if len < n => {
+                frame[0] = FRAME_LZ4;
+                len
+            }
+            // 压缩后变大则原样发送
+            _ => {
+                frame[0] = FRAME_RAW;
+                frame[FRAME_HEADER..FRAME_HEADER + n].copy_from_slice(&buf[..n]);
+                n
+            }
+        };
+        frame[1..3].copy_from_slice(&(len as u16).to_be_bytes());
+        frame[3..5].copy_from_slice(&(n as u16).to_be_bytes());
+        timed(timeouts.write, "写入", writer.write_all(&frame[..FRAME_HEADER + len])).await?;
+        stats.plain.fetch_add(n as u64, Ordering::Relaxed);
+        stats.wire.fetch_add((FRAME_HEADER + len) as u64, Ordering::Relaxed);
+    }
+}
+
+/// 从链路读取帧并解压写出明文, 链路在帧边界 EOF 后关闭写端
+pub async fn decode_half<R, W>(mut reader: R, mut writer: W, stats: &LinkStats, timeouts: OpTimeouts) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("压缩链路{}", msg));
+    let mut frame = vec![0u8; FRAME_HEADER + lz4_flex::block::get_maximum_output_size(FRAME_MAX)];
+    let mut plain = vec![0u8; FRAME_MAX];
+    loop {
+        let n = timed(timeouts.read, "读取", reader.read(&mut frame[..1])).await?;
+        if n == 0 {
+            return timed(timeouts.write, "写入", writer.shutdown()).await;
+        }
+        timed(timeouts.read, "读取", reader.read_exact(&mut frame[1..FRAME_HEADER])).await?;
+        let len = u16::from_be_bytes([frame[1], frame[2]]) as usize;
+        let raw_len = u16::from_be_bytes([frame[3], frame[4]]) as usize;
+        if raw_len == 0 || raw_len > FRAME_MAX || len > frame.len() - FRAME_HEADER {
+            return Err(invalid("帧长度无效"));
+        }
+        let body = &mut frame[FRAME_HEADER..FRAME_HEADER + len];
+        timed(timeouts.read, "读取", reader.read_exact(body)).await?;
+        let data = match frame[0] {
+            FRAME_RAW if len == raw_len => &frame[FRAME_HEADER..FRAME_HEADER + len],
+            FRAME_LZ4 => match lz4_flex::block::decompress_into(&frame[FRAME_HEADER..FRAME_HEADER + len], &mut plain[..raw_len]) {
+                Ok(size) if size == raw_len => &plain[..raw_len],
+                _ => return Err(invalid("解压失败")),
+            },
+            _ => return Err(invalid("帧类型无效")),
+        };
+        timed(timeouts.write, "写入", writer.write_all(data)).await?;
+        stats.plain.fetch_add(raw_len as u64, Ordering::Relaxed);
+        stats.wire.fetch_add((FRAME_HEADER + len) as u64, Ordering::Relaxed);
+    }
+}
+
+/// 客户端与目标一侧是压缩链路、另一侧是明文时双向转发; early 为已从客户端读到的数据
+pub async fn relay<C, S>(
+    client: &mut C,
+    server: &mut S,
+    early: &[u8],
+    client_is_link: bool,
+    stats: &(LinkStats, LinkStats),
+    timeouts: OpTimeouts,
+) -> io::Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (up, down) = stats;
+    let (cr, cw) = io::split(client);
+    let (sr, sw) = io::split(server);
+    let cr = early.chain(cr);
+    if client_is_link {
+        tokio::try_join!(decode_half(cr, sw, up, timeouts), encode_half(sr, cw, down, timeouts))?;
+    } else {
+        tokio::try_join!(encode_half(cr, sw, up, timeouts), decode_half(sr, cw, down, timeouts))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,206 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{self, Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::jsonlog;
+
+// 已打开的日志文件 (主日志和各访问日志), 收到 SIGUSR1 时全部重新打开
+static OPENED: Mutex<Vec<LogFile>> = Mutex::new(Vec::new());
+// 按配置中的路径打开的访问日志; 打开失败的记为 None, 只告警一次
+static ACCESS: Mutex<Option<HashMap<String, Option<LogFile>>>> = Mutex::new(None);
+// 内置轮转设置, 对所有日志文件生效
+static ROTATION: OnceLock<Rotation> = OnceLock::new();
+
+/// 内置轮转: 文件超过 max_bytes 或进入新的时间段 (按本地时间整点 / 零点) 时,
+/// 依次改名为 path.1 ... path.keep (数字越大越旧), 超出 keep 的删除, 然后重新创建 path
+#[derive(Clone, Copy, Debug)]
+pub struct Rotation {
+    pub max_bytes: Option<u64>,
+    pub period_secs: Option<u64>,
+    pub keep: usize,
+}
+
+/// 设置内置轮转, 须在打开日志文件之前调用
+pub fn set_rotation(rotation: Rotation) {
+    let _ = ROTATION.set(rotation);
+}
+
+/// 追加写入的日志文件; 文件被轮转 (移走) 后重新打开同一路径继续写入
+#[derive(Clone)]
+pub struct LogFile {
+    path: PathBuf, // 绝对路径, 转入后台或切换工作目录后仍能重新打开
+    out: Arc<Mutex<Output>>,
+}
+
+// 当前写入的文件, 以及轮转判断所需的大小和时间段
+struct Output {
+    file: File,
+    size: u64,
+    period: Option<i64>,
+}
+
+impl LogFile {
+    pub fn open(path: &str) -> io::Result<LogFile> {
+        let path = path::absolute(path)?;
+        let out = Arc::new(Mutex::new(Output::open(&path)?));
+        let log = LogFile { path, out };
+        OPENED.lock().unwrap().push(log.clone());
+        Ok(log)
+    }
+
+    pub fn reopen(&self) -> io::Result<()> {
+        *self.out.lock().unwrap() = Output::open(&self.path)?;
+        Ok(())
+    }
+}
+
+impl Output {
+    fn open(path: &Path) -> io::Result<Output> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let meta = file.metadata()?;
+        // 已有内容按最后修改时间归入时间段: 隔天重启后第一次写入即轮转前一天的文件
+        let since = meta.modified().ok().filter(|_| meta.len() > 0).unwrap_or_else(SystemTime::now);
+        Ok(Output { file, size: meta.len(), period: period_of(since) })
+    }
+
+    /// 写入 len 字节前是否需要轮转
+    fn due(&mut self, len: usize, rotation: &Rotation) -> bool {
+        let full = rotation.max_bytes.is_some_and(|max| self.size > 0 && self.size + len as u64 > max);
+        let period = period_of(SystemTime::now());
+        if period == self.period {
+            return full;
+        }
+        self.period = period;
+        full || self.size > 0
+    }
+}
+
+// 轮转时间段的序号: 本地时间的小时数或天数
+fn period_of(t: SystemTime) -> Option<i64> {
+    let period = ROTATION.get()?.period_secs? as i64;
+    let secs = t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64);
+    Some((secs + utc_offset(secs)).div_euclid(period))
+}
+
+// 本地时区相对 UTC 的偏移 (秒)
+#[cfg(unix)]
+fn utc_offset(secs: i64) -> i64 {
+    let t = secs as libc::time_t;
+    // SAFETY: tm 只包含整数和指针字段, 全零是有效值
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // SAFETY: t 和 tm 在调用期间有效; localtime_r 可在多线程中使用
+    if unsafe { libc::localtime_r(&t, &mut tm) }.is_null() {
+        return 0;
+    }
+    tm.tm_gmtoff as i64
+}
+
+#[cfg(not(unix))]
+fn utc_offset(_secs: i64) -> i64 {
+    0
+}
+
+/// path.1 ... 依次后移, path 改名为 path.1; keep 为 0 时直接删除 path
+fn rotate(path: &Path, keep: usize) -> io::Result<()> {
+    let numbered = |i: usize| {
+        let mut p = path.as_os_str().to_owned();
+        p.push(format!(".{}", i));
+        PathBuf::from(p)
+    };
+    if keep == 0 {
+        return std::fs::remove_file(path);
+    }
+    let _ = std::fs::remove_file(numbered(keep));
+    for i in (1..keep).rev() {
+        match std::fs::rename(numbered(i), numbered(i + 1)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    std::fs::rename(path, numbered(1))
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut out = self.out.lock().unwrap();
+        if let Some(rotation) = ROTATION.get() {
+            if out.due(buf.len(), rotation) {
+                // 写日志时无法再记录日志: 轮转失败 (如没有权限) 时继续写原文件, 写满 max_bytes 后再重试
+                match rotate(&self.path, rotation.keep).and_then(|_| Output::open(&self.path)) {
+                    Ok(fresh) => *out = fresh,
+                    Err(_) => out.size = 0,
+                }
+            }
+        }
+        let n = out.file.write(buf)?;
+        out.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.lock().unwrap().file.flush()
+    }
+}
+
+/// 向访问日志追加一条记录, 首次写入时打开文件; 格式与 --log-format 相同:
+/// json 为一个 JSON 对象, text 为按 fields 顺序排列的 key=value; 空值 (None) 不输出
+pub fn access(path: &str, fields: Vec<(&str, Value)>) {
+    let fields = fields.into_iter().filter(|(_, v)| !v.is_null());
+    let mut line = if jsonlog::enabled() {
+        Value::Object(fields.map(|(k, v)| (k.to_string(), v)).collect()).to_string()
+    } else {
+        let pairs: Vec<String> = fields.map(|(k, v)| format!("{}={}", k, logfmt(v))).collect();
+        pairs.join(" ")
+    };
+    line.push('\n');
+
+    let opened = ACCESS.lock().unwrap().get_or_insert_default().entry(path.to_string()).or_insert_with(|| {
+        LogFile::open(path).inspect_err(|e| log::error!("访问日志 {} 无法打开, 不再写入: {}", path, e)).ok()
+    })
+    .clone();
+    let Some(mut log) = opened else { return };
+    if let Err(e) = log.write_all(line.as_bytes()) {
+        log::debug!("访问日志写入失败: {}", e);
+    }
+}
+
+// 字符串含空格、引号或等号时加引号
+fn logfmt(v: Value) -> String {
+    match v {
+        Value::String(s) if s.is_empty() || s.contains([' ', '"', '=']) => Value::String(s).to_string(),
+        Value::String(s) => s,
+        v => v.to_string(),
+    }
+}
+
+/// 收到 SIGUSR1 时重新打开所有日志文件, 配合 logrotate 等移走旧文件的轮转方式
+#[cfg(unix)]
+pub fn watch_reopen() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    tokio::spawn(async move {
+        let mut usr1 = match signal(SignalKind::user_defined1()) {
+            Ok(s) => s,
+            Err(e) => {
+                log::error!("无法监听 SIGUSR1, 日志文件轮转后不会重新打开: {}", e);
+                return;
+            }
+        };
+        while usr1.recv().await.is_some() {
+            let opened = OPENED.lock().unwrap().clone();
+            for log in opened {
+                match log.reopen() {
+                    Ok(()) => log::info!(">>> 日志文件已重新打开: {}", log.path.display()),
+                    Err(e) => log::error!("日志文件 {} 重新打开失败, 继续写入原文件: {}", log.path.display(), e),
+                }
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn watch_reopen() {}
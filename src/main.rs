@@ -1,228 +1,2601 @@
+mod admin;
+mod config;
+mod control;
+#[cfg(unix)]
+mod daemon;
+mod dns;
+mod fault;
+mod geoip;
+mod health;
+mod http;
+mod icmp;
+mod jsonlog;
+mod link;
+mod logfile;
+mod metrics;
+mod net;
+mod notify;
+mod otlp;
+mod proxy;
+mod queue;
+mod reload;
+mod relay;
+mod report;
+mod score;
+mod sdnotify;
+mod selfprobe;
+mod shutdown;
+mod sni;
+#[cfg(target_os = "linux")]
+mod splice;
+mod stats;
+mod statsd;
+mod state;
+mod tls;
+mod toml;
+mod tunnel;
+mod udp;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+#[cfg(windows)]
+mod winservice;
+
 use anyhow::{Context, Result};
-use clap::Parser;
+use arc_swap::ArcSwap;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use futures::future::join_all;
-use serde::Deserialize;
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::io::{self, AsyncWriteExt};
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::RwLock;
+use tokio::sync::{Notify, RwLock};
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+
+use config::{Config, CrossFamilyPolicy, EmptyTargetsPolicy, LinkType, ProbeKind, SelectionMode, SwitchExisting, TargetConfig};
+use state::{
+    BestTarget, ConnCounters, ConnGuard, InboundGuard, InboundLimits, LimitExceeded, PoolState, Snapshot, State, TargetUsage,
+    TrafficStats,
+};
 
 #[derive(Parser, Debug)]
 #[command(name = "forward-optimal", version = "2.0.1", about = "TCP 最优路径转发")]
 struct Args {
     #[arg(short = 'c', long, default_value = "config.yaml")]
     config: String,
+
+    /// 配置文件格式: yaml / toml / json, 默认按扩展名判断 (.toml / .json, 其余为 YAML)
+    #[arg(long, value_enum, visible_alias = "format")]
+    config_format: Option<config::Format>,
+
+    /// 覆盖配置中的监听地址 bind_addr
+    #[arg(long, value_name = "ADDR")]
+    bind: Option<String>,
+
+    /// 转发目标 名称=地址 (可重复, 只写地址时以地址为名称), 替换配置中的 targets 和 pools;
+    /// 未指定 -c 时不读取配置文件, 其余配置项使用默认值
+    #[arg(long = "target", value_name = "NAME=ADDR", value_parser = parse_target)]
+    targets: Vec<(String, String)>,
+
+    /// 覆盖配置中的探测间隔 update_interval (秒); 不读取配置文件时默认 60
+    #[arg(long, value_name = "SECS")]
+    interval: Option<u64>,
+
+    /// 测试用: 启用配置中各目标的 fault_inject (人为延迟/丢包, 只影响评分)
+    #[arg(long)]
+    fault_inject: bool,
+
+    /// 故障注入随机种子, 相同种子下丢包结果可复现
+    #[arg(long, default_value_t = 0)]
+    fault_seed: u64,
+
+    /// 日志追加写入文件, 不输出到终端; 收到 SIGUSR1 时重新打开 (日志轮转)
+    #[arg(long)]
+    log_file: Option<String>,
+
+    /// 日志文件 (包括 access_log) 超过该大小 (MB) 时轮转
+    #[arg(long, value_name = "MB")]
+    log_max_size: Option<u64>,
+
+    /// 日志文件按时间轮转: hourly 或 daily (本地时间)
+    #[arg(long, value_enum)]
+    log_rotate: Option<LogRotate>,
+
+    /// 轮转后保留的旧日志文件数 (path.1 ... path.N)
+    #[arg(long, value_name = "N", default_value_t = 7)]
+    log_keep: usize,
+
+    /// 日志格式: text 或 json (每行一个 JSON 对象, 便于 Loki / ELK 采集)
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// 转入后台运行 (仅 Unix), 所有服务开始监听后前台进程退出
+    #[arg(long)]
+    daemon: bool,
+
+    /// 写入进程号的文件 (仅 Unix), 退出时删除
+    #[arg(long)]
+    pid_file: Option<String>,
+
+    /// 安装为开机自动启动的 Windows 服务, 使用当前的 -c 配置文件和 --log-file
+    #[arg(long)]
+    install_service: bool,
+
+    /// 停止并删除 Windows 服务
+    #[arg(long)]
+    uninstall_service: bool,
+
+    /// 作为 Windows 服务运行 (由 --install-service 写入服务的启动命令, 不需要手动使用)
+    #[arg(long)]
+    run_as_service: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
-struct TargetConfig {
-    name: String,
-    addr: String,
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum LogFormat {
+    Text,
+    Json,
 }
 
-#[derive(Debug, Deserialize, Clone)]
-struct Config {
-    bind_addr: String,
-    targets: Vec<TargetConfig>,
-    update_interval: u64,
-    proxy_protocol: Option<String>,
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum LogRotate {
+    Hourly,
+    Daily,
 }
 
-#[derive(Clone, Debug)]
-struct BestTarget {
-    addr: SocketAddr,
-    name: String,
-    score: u128,
+fn parse_target(s: &str) -> Result<(String, String), String> {
+    let (name, addr) = s.split_once('=').unwrap_or((s, s));
+    if name.is_empty() || addr.is_empty() {
+        return Err("应为 名称=地址 或 地址".to_string());
+    }
+    Ok((name.to_string(), addr.to_string()))
 }
 
-struct State {
-    best: Option<BestTarget>,
+impl Args {
+    /// 内置日志轮转设置; 未指定 --log-max-size / --log-rotate 时不轮转
+    fn rotation(&self) -> Option<logfile::Rotation> {
+        let period_secs = self.log_rotate.map(|r| match r {
+            LogRotate::Hourly => 3600,
+            LogRotate::Daily => 86_400,
+        });
+        if self.log_max_size.is_none() && period_secs.is_none() {
+            return None;
+        }
+        let max_bytes = self.log_max_size.map(|mb| mb.max(1) * BYTES_PER_MB);
+        Some(logfile::Rotation { max_bytes, period_secs, keep: self.log_keep })
+    }
+
+    /// 写入服务启动命令的其他参数: 配置文件格式、命令行覆盖的配置项和日志轮转
+    #[cfg(windows)]
+    fn service_args(&self) -> String {
+        let mut out = String::new();
+        if let Some(f) = self.config_format.and_then(|f| f.to_possible_value()) {
+            out += &format!(" --config-format {}", f.get_name());
+        }
+        if let Some(ref addr) = self.bind {
+            out += &format!(" --bind {}", addr);
+        }
+        for (name, addr) in &self.targets {
+            out += &format!(" --target \"{}={}\"", name, addr);
+        }
+        if let Some(secs) = self.interval {
+            out += &format!(" --interval {}", secs);
+        }
+        if let Some(mb) = self.log_max_size {
+            out += &format!(" --log-max-size {}", mb);
+        }
+        if let Some(r) = self.log_rotate {
+            out += if r == LogRotate::Hourly { " --log-rotate hourly" } else { " --log-rotate daily" };
+        }
+        out + &format!(" --log-keep {}", self.log_keep)
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// 作为反向隧道代理运行: 主动连接转发器注册隧道, 把流量转给本地后端
+    TunnelAgent {
+        /// 转发器的隧道注册地址
+        #[arg(long)]
+        server: String,
+        /// 隧道名称 (作为目标名称参与选择)
+        #[arg(long)]
+        name: String,
+        /// 认证令牌, 与转发器 tunnel.token 一致
+        #[arg(long)]
+        token: String,
+        /// 本地后端地址
+        #[arg(long)]
+        local: String,
+    },
+    /// 对配置中所有目标探测一轮, 输出按评分排序的结果后退出;
+    /// 退出码: 0 全部可用, 2 部分不可用, 3 全部不可用, 1 配置错误
+    Probe {
+        /// 以 JSON 数组输出
+        #[arg(long)]
+        json: bool,
+    },
+    /// 检查配置文件并解析所有目标的域名, 一次列出全部问题, 不启动服务; 退出码: 0 没有问题, 1 有问题
+    Check,
+    /// 通过控制套接字 (配置中的 control_socket) 查询运行中实例的当前节点、评分和连接数
+    Status {
+        /// 输出原始 JSON (与管理接口 /status 相同, 每个服务一项)
+        #[arg(long)]
+        json: bool,
+    },
 }
 
 // --- 配置参数 ---
-const PROBE_COUNT: u32 = 10;       // 每轮探测次数
-const PENALTY_MS: u128 = 300;      // 失败惩罚分 (丢包权重)
-const CONNECT_TIMEOUT: u64 = 1000; // (1000ms)1秒连接超时
+const REJECT_WRITE_TIMEOUT: u64 = 1000; // 写回拒绝提示的超时 (ms)
+const DEFAULT_INTERVAL: u64 = 60; // 不读取配置文件时的探测间隔 (秒)
+const BYTES_PER_MB: u64 = 1_000_000; // 流量配额的单位
+const LIMIT_WARN_INTERVAL: Duration = Duration::from_secs(10); // 超出入站连接数上限的告警间隔
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+fn main() -> Result<()> {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
     
     // 初始化日志
+    // probe / check / status 子命令只输出结果, 默认只显示告警
+    let oneshot = matches!(args.command, Some(Command::Probe { .. } | Command::Check | Command::Status { .. }));
     if std::env::var("RUST_LOG").is_err() {
-        std::env::set_var("RUST_LOG", "info");
+        std::env::set_var("RUST_LOG", if oneshot { "warn" } else { "info" });
+    }
+    let mut logger = env_logger::builder();
+    logger.format_target(false).format_timestamp_secs();
+    if args.log_format == LogFormat::Json {
+        jsonlog::install(&mut logger);
+    }
+    if let Some(rotation) = args.rotation() {
+        logfile::set_rotation(rotation);
+    }
+    if let Some(format) = args.config_format {
+        config::set_format(format);
+    }
+    // 只给出 --target 而没有 -c 时不读取配置文件, 错误和检查结果中以 "命令行参数" 代替文件名
+    let without_file = !args.targets.is_empty() && matches.value_source("config") != Some(ValueSource::CommandLine);
+    if without_file {
+        if args.install_service {
+            anyhow::bail!("--install-service 需要配置文件, 请用 -c 指定 (--target 等参数会一并写入服务的启动命令)");
+        }
+        args.config = "命令行参数".to_string();
+    }
+    // probe / check 不监听, 不读取配置文件时也不要求 --bind
+    let bind = match args.bind {
+        None if without_file && oneshot => Some("127.0.0.1:0".to_string()),
+        None if without_file => anyhow::bail!("不读取配置文件时需要用 --bind 指定监听地址"),
+        ref bind => bind.clone(),
+    };
+    let mut names = std::collections::HashSet::new();
+    if let Some((name, _)) = args.targets.iter().find(|(name, _)| !names.insert(name)) {
+        anyhow::bail!("--target 名称重复: {}", name);
+    }
+    config::set_overrides(config::Overrides {
+        bind_addr: bind,
+        targets: args.targets.clone(),
+        update_interval: args.interval.or(without_file.then_some(DEFAULT_INTERVAL)),
+        without_file,
+    });
+    if let Some(ref path) = args.log_file {
+        let file = logfile::LogFile::open(path).with_context(|| format!("无法打开日志文件 {}", path))?;
+        logger.target(env_logger::Target::Pipe(Box::new(file)));
     }
-    env_logger::builder()
-        .format_target(false)
-        .format_timestamp_secs()
-        .init();
+    logger.init();
 
-    let config_content = std::fs::read_to_string(&args.config)
-        .with_context(|| format!("无法读取配置文件: {}", args.config))?;
-    let config: Config = serde_yaml::from_str(&config_content)?;
+    #[cfg(not(unix))]
+    if args.daemon || args.pid_file.is_some() {
+        anyhow::bail!("--daemon / --pid-file 仅支持 Unix");
+    }
+    // fork 必须在启动 tokio 运行时 (创建线程) 之前
+    #[cfg(unix)]
+    if args.daemon {
+        daemon::detach()?;
+    }
+    let result = start(args);
+    #[cfg(unix)]
+    daemon::exited(&result);
+    result
+}
 
-    let state = Arc::new(RwLock::new(State { best: None }));
+fn start(args: Args) -> Result<()> {
+    #[cfg(unix)]
+    if let Some(ref path) = args.pid_file {
+        daemon::write_pid_file(path)?;
+    }
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+    runtime.block_on(async {
+        logfile::watch_reopen();
+        run(args).await
+    })
+}
+
+async fn run(args: Args) -> Result<()> {
+    if let Some(Command::TunnelAgent { server, name, token, local }) = args.command {
+        #[cfg(unix)]
+        daemon::expect_services(0);
+        return tunnel::run_agent(server, name, token, local).await;
+    }
+    if let Some(Command::Probe { json }) = args.command {
+        let code = probe_once(&args, json).await?;
+        std::process::exit(code);
+    }
+    if let Some(Command::Check) = args.command {
+        std::process::exit(check_config(&args.config).await);
+    }
+    if let Some(Command::Status { json }) = args.command {
+        return show_status(&args.config, json).await;
+    }
+
+    if args.install_service || args.uninstall_service || args.run_as_service {
+        #[cfg(not(windows))]
+        anyhow::bail!("--install-service / --uninstall-service / --run-as-service 仅支持 Windows");
+        #[cfg(windows)]
+        {
+            if args.install_service {
+                return winservice::install(&args.config, args.log_file.as_deref(), &args.service_args());
+            }
+            if args.uninstall_service {
+                return winservice::uninstall();
+            }
+            winservice::start()?;
+            let result = serve(args).await;
+            if let Err(ref e) = result {
+                log::error!("服务异常退出: {:#}", e);
+            }
+            winservice::stopped(result.is_err());
+            return result;
+        }
+    }
+    serve(args).await
+}
+
+/// 加载配置并运行所有服务, 直到收到退出信号
+async fn serve(args: Args) -> Result<()> {
+    let mut services = config::load(&args.config)?;
+    net::inherit_listeners();
+    sdnotify::init(services.len());
+    #[cfg(unix)]
+    daemon::expect_services(services.len());
+
+    let has_faults = services
+        .iter()
+        .flat_map(|c| c.pool_list())
+        .flat_map(|p| p.targets)
+        .any(|t| t.fault_inject.is_some());
+    if args.fault_inject {
+        log::warn!("!!! 故障注入已启用 (种子: {}), 评分结果不代表真实网络状况, 请勿用于生产环境", args.fault_seed);
+        for config in &mut services {
+            config.fault_seed = Some(args.fault_seed);
+        }
+    } else if has_faults {
+        log::warn!("配置中包含 fault_inject, 但未使用 --fault-inject 启动, 已忽略");
+    }
+
+    if services.len() > 1 {
+        let names: Vec<&str> = services.iter().map(|c| c.name.as_str()).collect();
+        log::info!("共 {} 个服务: {}", services.len(), names.join(", "));
+    }
+    // 收到退出信号后停止监听 (服务任务随之结束), 已建立的连接继续转发直到结束或超时
+    let drain = services.iter().map(|c| c.shutdown_drain_timeout).max().unwrap_or_default();
+    tokio::select! {
+        res = futures::future::try_join_all(services.into_iter().map(|config| run_service(args.config.clone(), config))) => {
+            res?;
+        }
+        _ = shutdown::signal() => {
+            log::info!(">>> 收到退出信号, 停止接受新连接");
+            shutdown::drain(Duration::from_secs(drain)).await;
+        }
+    }
+    Ok(())
+}
+
+/// probe 子命令: 所有服务的所有节点池同时探测一轮, 返回退出码
+async fn probe_once(args: &Args, json: bool) -> Result<i32> {
+    let mut services = config::load(&args.config)?;
+    if args.fault_inject {
+        for config in &mut services {
+            config.fault_seed = Some(args.fault_seed);
+        }
+    }
+    let resolved = net::ResolveCache::default();
+    let probes = services.iter().flat_map(|config| {
+        let resolved = &resolved;
+        config.pool_list().into_iter().map(move |pool| async move {
+            let ranked = perform_scoring_check(config, &pool.targets, resolved).await;
+            report::probe_rows(config, &pool, ranked)
+        })
+    });
+    let mut rows: Vec<_> = join_all(probes).await.into_iter().flatten().collect();
+    // 可用的在前, 按优先级层、再按评分排序; 不可用的保持配置顺序
+    rows.sort_by_key(|r| r.result.as_ref().map_or((1, 0, 0), |t| (0, t.priority, t.raw_score)));
+
+    if json {
+        println!("{}", serde_json::Value::from_iter(rows.iter().map(report::probe_json)));
+    } else {
+        for line in report::probe_table(&rows, services.len() > 1) {
+            println!("{}", line);
+        }
+    }
+    let available = rows.iter().filter(|r| r.result.is_some()).count();
+    Ok(match available {
+        n if n == rows.len() => 0,
+        0 => 3,
+        _ => 2,
+    })
+}
+
+/// check 子命令: 校验配置并解析目标域名 (SRV 目标查询记录), 列出所有问题, 返回退出码
+async fn check_config(path: &str) -> i32 {
+    let (services, mut problems) = config::check(path);
+    let lookups = services.iter().flat_map(|config| {
+        let label = if config.name.is_empty() { String::new() } else { format!("服务 [{}]: ", config.name) };
+        // 地址格式错误的已经列出, 不再解析
+        let targets = config.pool_list().into_iter().flat_map(|p| p.targets);
+        targets.filter(|t| t.srv || config::is_host_port(&t.addr)).map(move |t| {
+            let label = label.clone();
+            async move {
+                let found = if t.srv {
+                    dns::srv(&t.addr, &config.dns_servers).await.map(|r| r.len())
+                } else {
+                    dns::lookup(&t.addr, &config.dns_servers).await.map(|a| a.len())
+                };
+                match found {
+                    Ok(0) => Some(anyhow::anyhow!("{}[{}] 没有解析到地址: {}", label, t.name, t.addr)),
+                    Ok(_) => None,
+                    Err(e) => Some(anyhow::anyhow!("{}[{}] 域名解析失败: {}: {}", label, t.name, t.addr, e)),
+                }
+            }
+        })
+    });
+    problems.extend(join_all(lookups).await.into_iter().flatten());
+
+    if problems.is_empty() {
+        let targets: usize = services.iter().map(|c| c.pool_list().iter().map(|p| p.targets.len()).sum::<usize>()).sum();
+        println!("配置有效: {} ({} 个服务, {} 个目标)", path, services.len(), targets);
+        return 0;
+    }
+    println!("配置有 {} 个问题: {}", problems.len(), path);
+    for e in &problems {
+        println!("  - {:#}", e);
+    }
+    1
+}
+
+/// status 子命令: 从运行中的实例获取状态并输出
+async fn show_status(path: &str, json: bool) -> Result<()> {
+    let services = config::load(path)?;
+    let socket = services
+        .iter()
+        .find_map(|c| c.control_socket.clone().filter(|p| !p.is_empty()))
+        .with_context(|| format!("配置中没有设置 control_socket: {}", path))?;
+    let status = control::request(&socket, "status").await?;
+    if json {
+        println!("{}", status);
+        return Ok(());
+    }
+    for (i, service) in status.as_array().into_iter().flatten().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        for line in report::status_lines(service) {
+            println!("{}", line);
+        }
+    }
+    Ok(())
+}
+
+/// 运行一个服务: 探测、选择、监听以及管理接口等附属功能都是独立的一套
+async fn run_service(path: String, config: Config) -> Result<()> {
+    let egress_diff = config.probe_socket_options().egress_differences(&config.socket_options());
+    if !egress_diff.is_empty() {
+        log::warn!(
+            "探测与转发连接的出站配置不同 ({}), 评分可能无法反映实际转发路径; 可设置 probe_same_egress: true",
+            egress_diff.join(", ")
+        );
+    }
+
+    if let Some(ref algo) = config.socket_options().tcp_congestion {
+        net::check_congestion(algo);
+    }
+    net::check_buffer_sizes(config.so_rcvbuf, config.so_sndbuf);
+    net::check_fastopen(config.tcp_fastopen, config.tcp_fastopen_connect);
+    if let Some(mark) = config.fwmark {
+        net::check_mark(mark)?;
+    }
+
+    // 证书或私钥有误时直接退出, 不带着无法握手的监听启动
+    let acceptor = config.tls.as_ref().map(tls::acceptor).transpose()?;
+    for t in config.pool_list().iter().flat_map(|p| &p.targets).filter(|t| t.uses_tls()) {
+        tls::upstream(t).with_context(|| format!("[{}] TLS 配置无效", t.name))?;
+    }
+
+    let state = State::new(&config);
+    let published = state.published.clone();
+    let state = Arc::new(RwLock::new(state));
+    let wakeup = Arc::new(Notify::new());
+
+    // --- 配置重新加载 (SIGHUP) ---
+    let live = Arc::new(ArcSwap::from_pointee(config.clone()));
+    let pending: reload::Pending = Arc::default();
+    reload::watch_sighup(path.clone(), live.clone(), pending.clone(), wakeup.clone());
+    if config.watch_config {
+        reload::watch_files(path.clone(), live.clone(), pending.clone(), wakeup.clone());
+    }
+
+    // --- 管理接口 ---
+    if let Some(admin_addr) = config.admin_addr.clone() {
+        let state_clone = state.clone();
+        let published_clone = published.clone();
+        let wakeup_clone = wakeup.clone();
+        let live_clone = live.clone();
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve(admin_addr, state_clone, published_clone, wakeup_clone, live_clone).await {
+                log::error!("管理接口异常退出: {}", e);
+            }
+        });
+    }
+
+    // --- 本地控制套接字 ---
+    control::register(published.clone());
+    if let Some(path) = config.control_socket.clone().filter(|p| !p.is_empty()) {
+        control::start(path);
+    }
+
+    // --- 反向隧道注册服务 ---
+    if let Some(tunnel_cfg) = config.tunnel.clone() {
+        let state_clone = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = tunnel::serve(tunnel_cfg, state_clone).await {
+                log::error!("隧道注册服务异常退出: {}", e);
+            }
+        });
+    }
+
+    // --- 定时输出状态汇总 ---
+    if config.report_interval > 0 {
+        tokio::spawn(report::run(config.report_interval, published.clone(), live.clone()));
+    }
+
+    // --- StatsD 指标 ---
+    if let Some(addr) = config.statsd_addr.clone().filter(|a| !a.is_empty()) {
+        tokio::spawn(statsd::run(addr, config.statsd_prefix.clone(), config.statsd_interval, published.clone()));
+    }
+
+    // --- OTLP span 导出 ---
+    if let Some(endpoint) = config.otlp_endpoint.clone() {
+        otlp::start(endpoint, config.otlp_service_name.clone(), config.otlp_headers.clone());
+    }
+
+    // --- Prometheus 指标 ---
+    if let Some(addr) = config.metrics_addr.clone().filter(|a| !a.is_empty()) {
+        let published_clone = published.clone();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(addr, published_clone).await {
+                log::error!("指标接口异常退出: {}", e);
+            }
+        });
+    }
+
+    // --- 启动延迟: 等待网络和 DNS 就绪后再开始探测和接受连接 ---
+    if config.startup_delay > 0 {
+        log::info!("等待 {} 秒后开始探测和接受连接 (startup_delay)", config.startup_delay);
+        tokio::time::sleep(Duration::from_secs(config.startup_delay)).await;
+        log::info!("启动延迟结束");
+    }
 
     // --- 后台探测任务 ---
     let state_clone = state.clone();
-    let config_clone = config.clone();
+    let live_clone = live.clone();
     tokio::spawn(async move {
+        let mut targets_empty = false;
+        let mut round: u64 = 0;
+        let mut force_full = false;
+        let mut verifying: Option<Arc<Config>> = None; // 待验证的重新加载, 保存上一份可用配置用于回滚
+        let mut down_since: Option<Instant> = None; // 全部节点不可用的开始时间, 恢复时通知
+        let resolved = net::ResolveCache::default();
+        let mut heartbeat = sdnotify::Heartbeat::register();
         loop {
-            log::info!("--- 正在探测节点状态 ---");
-
-            if let Some(winner) = perform_scoring_check(&config_clone.targets).await {
-                let mut s = state_clone.write().await;
-                
-                // 判断是否发生了切换
-                let is_changed = match &s.best {
-                    Some(current) => current.name != winner.name,
-                    None => true,
-                };
+            heartbeat.beat();
+            // 应用重新加载的配置
+            let reloaded = pending.lock().unwrap().take();
+            if let Some(new) = reloaded {
+                let old = live_clone.load_full();
+                let restart = reload::restart_required(&old, &new);
+                if !restart.is_empty() {
+                    log::warn!("以下配置项需要重启才能生效: {}", restart.join(", "));
+                }
+                let verify = new.verify_reload;
+                let service = if new.name.is_empty() { String::new() } else { format!(" (服务 [{}])", new.name) };
+                live_clone.store(Arc::new(new));
+                state_clone.write().await.reconfigure(&live_clone.load());
+                // 连续重新加载时回滚到最后一份验证过的配置
+                verifying = if verify { verifying.or(Some(old)) } else { None };
+                force_full = true;
+                log::info!(">>> 配置已重新加载{}{}", service, if verify { ", 等待本轮探测验证" } else { "" });
+            }
+            let config_clone = live_clone.load_full();
 
-                if is_changed {
-                    log::info!(">>> 路由切换: 选定最优节点 [{}] ({})", winner.name, winner.addr);
-                } else {
-                    log::info!(">>> 保持最优: 当前最优节点 [{}] ({})", winner.name, winner.addr);
+            if let Some(since) = state_clone.read().await.paused_since {
+                log::info!("--- 探测已暂停 ({}秒), 保持当前节点 ---", since.elapsed().as_secs());
+                wait_next_round(&wakeup, config_clone.update_interval, &heartbeat).await;
+                continue;
+            }
+
+            force_full |= std::mem::take(&mut state_clone.write().await.reprobe);
+            let mut pool_configs = config_clone.pool_list();
+
+            // 自适应探测: 非完整轮只探测各池前几名, 其余节点沿用上次结果
+            let adaptive = config_clone.adaptive_probing.as_ref();
+            let full_round = adaptive.is_none_or(|a| force_full || round.is_multiple_of(a.full_every));
+            round += 1;
+            force_full = false;
+            let mut carried = Vec::new();
+            let mut prev_best = None;
+            if let Some(a) = adaptive.filter(|_| !full_round) {
+                let s = state_clone.read().await;
+                let total: usize = pool_configs.iter().map(|p| p.targets.len()).sum();
+                carried = plan_adaptive_round(&s, &mut pool_configs, a.contenders);
+                prev_best = s.select().map(|t| (t.name.clone(), t.score));
+                let probed: usize = pool_configs.iter().map(|p| p.targets.len()).sum();
+                log::info!("--- 正在探测节点状态 (自适应: {}/{} 个目标) ---", probed, total);
+            } else {
+                log::info!("--- 正在探测节点状态 ---");
+            }
+
+            let mut round_span = otlp::Span::start("probe_round", otlp::Kind::Internal);
+            let (mut results, probes) = otlp::collect_probes(join_all(
+                pool_configs.iter().map(|p| perform_scoring_check(&config_clone, &p.targets, &resolved)),
+            ))
+            .await;
+            if let Some(ref mut span) = round_span {
+                span.attr("forward.service", Some(config_clone.name.clone()).filter(|n| !n.is_empty()));
+                span.attr("forward.full_round", full_round);
+                span.events(probes);
+            }
+
+            // 已注册的反向隧道作为单独的节点池, 按心跳 RTT 评分
+            if let Some(ref tunnel_cfg) = config_clone.tunnel {
+                let tlvs = Arc::new(proxy::encode_tlvs(&config_clone.proxy_tlvs).unwrap_or_default());
+                let scored = state::score_tunnels(&state_clone.read().await.tunnels, config_clone.penalty_ms, &tlvs);
+                for t in &scored {
+                    log::info!(
+                        event = "probe_result", target = t.name.as_str(), addr:% = t.addr, available = true, score = t.score, tunnel = true;
+                        "[{}] (隧道 {}) 评分: {}", t.name, t.addr, t.score
+                    );
+                    if let Some(ref mut span) = round_span {
+                        span.event("probe_result", vec![
+                            ("target", t.name.clone().into()),
+                            ("addr", t.addr.to_string().into()),
+                            ("available", true.into()),
+                            ("score", (t.score as u64).into()),
+                            ("tunnel", true.into()),
+                        ]);
+                    }
+                }
+                pool_configs.push(config::PoolConfig {
+                    name: tunnel_cfg.pool.clone(),
+                    mode: config::SelectionMode::Best,
+                    sticky: None,
+                    targets: Vec::new(),
+                    proxy_tlvs: None,
+                    sni: Vec::new(),
+                    countries: Vec::new(),
+                });
+                results.push(scored);
+            }
+
+            let mut s = state_clone.write().await;
+
+            // 探测过程中被暂停, 丢弃本轮结果
+            if s.paused_since.is_some() {
+                continue;
+            }
+
+            // 验证重新加载的配置: 本轮没有任何可用节点则回滚, 继续使用之前的节点
+            if let Some(old) = verifying.take() {
+                if results.iter().all(|r| r.is_empty()) {
+                    log::error!("!!! 新配置没有任何可用节点, 已回滚到之前的配置");
+                    live_clone.store(old);
+                    s.reconfigure(&live_clone.load());
+                    force_full = true;
+                    continue;
+                }
+                log::info!(">>> 新配置验证通过");
+            }
+
+            // 目标列表为空 (没有配置目标, 也没有已注册的隧道)
+            if pool_configs.iter().all(|p| p.targets.is_empty()) && results.iter().all(|r| r.is_empty()) {
+                targets_empty = true;
+                match config_clone.empty_targets_policy {
+                    EmptyTargetsPolicy::FailOpen => {
+                        let last = s.select().map(|t| t.name.clone()).unwrap_or_else(|| "无".to_string());
+                        log::warn!("!!! 目标列表为空, 继续使用上次可用的节点 [{}], 等待目标恢复", last);
+                    }
+                    EmptyTargetsPolicy::FailClosed => {
+                        log::warn!("!!! 目标列表为空, 拒绝所有连接, 等待目标恢复");
+                        s.pools = Arc::default();
+                        s.hold = None;
+                        s.sticky = None;
+                        s.publish();
+                    }
+                }
+                drop(s);
+                drop(round_span);
+                wait_next_round(&wakeup, config_clone.update_interval, &heartbeat).await;
+                continue;
+            }
+            if std::mem::take(&mut targets_empty) {
+                log::info!(">>> 目标列表已恢复");
+            }
+
+            // 按上升/下降速率平滑评分
+            for (p, ranked) in pool_configs.iter().zip(results.iter_mut()) {
+                apply_score_decay(&mut s.history, &p.targets, ranked, &config_clone);
+                apply_traffic_weight(&s.traffic, ranked, config_clone.traffic_weight, config_clone.penalty_ms);
+            }
+            for (ranked, kept) in results.iter_mut().zip(carried) {
+                ranked.extend(kept);
+            }
+            // 维护中的节点照常探测, 但不参与选择
+            for ranked in results.iter_mut() {
+                ranked.retain(|t| !s.maintenance.contains(&t.name) && !s.maintenance.contains(&t.group));
+            }
+            for (p, ranked) in pool_configs.iter().zip(results.iter_mut()) {
+                apply_traffic_quota(&s.usage, &p.targets, ranked);
+            }
+            if let Some(ref mut span) = round_span {
+                span.attr("forward.available", results.iter().map(Vec::len).sum::<usize>());
+            }
+
+            if results.iter().any(|r| !r.is_empty()) {
+                let previous = s.select().map(|t| t.name.clone());
+                install_round(&mut s, &pool_configs, results, previous.as_deref(), &config_clone);
+                s.publish();
+
+                if let Some(pinned) = s.pinned.as_deref().filter(|p| s.find(p).is_none()) {
+                    log::warn!("!!! 固定节点 [{}] 当前不可用, 按正常规则选择", pinned);
+                }
+                if let Some((pool, winner)) = s.select_with_pool() {
+                    heartbeat.ready();
+                    let fields = serde_json::json!({
+                        "target": winner.name,
+                        "addr": winner.addr.to_string(),
+                        "score": winner.score as u64,
+                        "pool": pool.name,
+                        "previous": previous,
+                    });
+                    // 判断是否发生了切换
+                    let is_changed = previous.as_deref() != Some(winner.name.as_str());
+                    let pool_note = if s.pools.len() > 1 { format!(" 节点池: {}", pool.name) } else { String::new() };
+                    if let Some(ref mut span) = round_span {
+                        span.attr("forward.target", winner.name.clone());
+                        span.attr("forward.addr", winner.addr.to_string());
+                        span.attr("forward.score", winner.score as u64);
+                        span.attr("forward.pool", pool.name.clone());
+                        span.attr("forward.switched", is_changed);
+                        span.attr("forward.previous", previous.clone());
+                    }
+
+                    if is_changed {
+                        stats::inc(&stats::SWITCHES);
+                        log::info!(
+                            event = "route_switch", target = winner.name.as_str(), addr:% = winner.addr, pool = pool.name.as_str(),
+                            previous = previous.as_deref().unwrap_or_default();
+                            ">>> 路由切换: 选定最优节点 [{}] ({}){}", winner.name, winner.addr, pool_note
+                        );
+                        // 启动后的第一次选择和全部不可用后的恢复不作为切换通知
+                        if let Some(previous) = previous.as_ref().filter(|_| down_since.is_none()) {
+                            let previous_available = s.find(previous).is_some();
+                            let text = if previous_available {
+                                format!("路由切换: [{}] -> [{}] ({})", previous, winner.name, winner.addr)
+                            } else {
+                                format!("路由切换: [{}] 不可用, 切换到 [{}] ({})", previous, winner.name, winner.addr)
+                            };
+                            let mut fields = fields.clone();
+                            fields["previous_available"] = previous_available.into();
+                            notify::send(&config_clone, "route_switch", text, fields);
+                        }
+                        if let Some(ref previous) = previous {
+                            switch_existing(&s, previous, &config_clone);
+                        }
+                    } else {
+                        log::info!(">>> 保持最优: 当前最优节点 [{}] ({}){}", winner.name, winner.addr, pool_note);
+                    }
+                    if let Some(since) = down_since.take() {
+                        let text = format!("节点已恢复: 选定 [{}] ({}), 不可用持续 {}秒", winner.name, winner.addr, since.elapsed().as_secs());
+                        let mut fields = fields;
+                        fields["down_secs"] = since.elapsed().as_secs().into();
+                        notify::send(&config_clone, "recovered", text, fields);
+                    }
+                }
+
+                // 当前最优变差时立即完整探测, 不等其余节点的下一次探测
+                if let (Some(a), Some((name, prev_score))) = (adaptive, prev_best) {
+                    let degraded = s.find(&name).is_none_or(|(_, t)| t.score > prev_score + a.degrade_ms);
+                    if degraded {
+                        log::warn!("!!! 最优节点 [{}] 评分变差或不可用, 立即完整探测", name);
+                        force_full = true;
+                    }
                 }
-                
-                s.best = Some(winner);
             } else {
                 log::warn!("!!! 本轮探测没有发现任何可用节点");
+                if down_since.is_none() {
+                    down_since = Some(Instant::now());
+                    let last = s.select().map(|t| t.name.clone());
+                    let text = format!("全部节点不可用 (之前的节点: [{}])", last.as_deref().unwrap_or("无"));
+                    notify::send(&config_clone, "all_down", text, serde_json::json!({ "previous": last }));
+                }
+            }
+            drop(s);
+            drop(round_span);
+
+            if !force_full {
+                wait_next_round(&wakeup, config_clone.update_interval, &heartbeat).await;
             }
-            
-            tokio::time::sleep(Duration::from_secs(config_clone.update_interval)).await;
         }
     });
 
     // --- 监听服务 ---
-    let listener = TcpListener::bind(&config.bind_addr).await?;
-    log::info!("服务启动: {} (优选间隔: {}秒)", config.bind_addr, config.update_interval);
+    let listeners = net::listen(&config.bind_addr, config.listen_workers, config.transparent).await?;
+    if config.tcp_fastopen {
+        listeners.iter().for_each(net::listen_fastopen);
+    }
+    #[cfg(unix)]
+    daemon::listening();
+    if config.name.is_empty() {
+        log::info!("服务启动: {} (优选间隔: {}秒)", config.bind_addr, config.update_interval);
+    } else {
+        log::info!("服务 [{}] 启动: {} (优选间隔: {}秒)", config.name, config.bind_addr, config.update_interval);
+    }
+    if let (Some(path), Some(db)) = (&config.geoip_db, &config.geoip) {
+        log::info!("GeoIP 数据库: {} ({})", path, db.database_type);
+    }
+
+    // --- UDP 转发 ---
+    if let Some(udp_cfg) = config.udp.clone() {
+        let state_clone = state.clone();
+        let opts = config.socket_options();
+        tokio::spawn(async move {
+            if let Err(e) = udp::serve(udp_cfg, opts, state_clone).await {
+                log::error!("UDP 转发异常退出: {}", e);
+            }
+        });
+    }
+
+    // --- 转发自检: 经自身监听地址验证完整的转发通路 ---
+    if config.self_probe {
+        tokio::spawn(selfprobe::run(live.clone(), published.clone()));
+    }
 
+    if listeners.len() > 1 {
+        log::info!("监听套接字数: {} (SO_REUSEPORT)", listeners.len());
+    }
+    let limits = Arc::new(InboundLimits::default());
+    let loops: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| {
+            tokio::spawn(accept_loop(
+                listener,
+                live.clone(),
+                limits.clone(),
+                acceptor.clone(),
+                state.clone(),
+                published.clone(),
+            ))
+        })
+        .collect();
+    // 接受连接出错时整个服务退出, 与只有一个监听套接字时相同
+    let (res, _, rest) = futures::future::select_all(loops).await;
+    rest.iter().for_each(JoinHandle::abort);
+    res?
+}
+
+/// 一个监听套接字的接受循环; 多个循环共享入站连接数计数和超限告警的限频
+async fn accept_loop(
+    listener: TcpListener,
+    live: Arc<ArcSwap<Config>>,
+    limits: Arc<InboundLimits>,
+    acceptor: Option<TlsAcceptor>,
+    state: Arc<RwLock<State>>,
+    published: Arc<ArcSwap<Snapshot>>,
+) -> Result<()> {
     loop {
-        let (client_stream, _) = listener.accept().await?;
-        let target_info = state.read().await.best.clone();
-        
-        if let Some(target) = target_info {
-            let cfg = config.clone();
-            tokio::spawn(async move {
-                let _ = handle_forward(client_stream, target, cfg).await;
-            });
+        let (client_stream, client_addr) = listener.accept().await?;
+        let config = live.load_full();
+        // 来源地址不被 allow/deny 允许时直接关闭, 不读取任何数据;
+        // 受信任的负载均衡转来的连接在读出 PROXY 头之后按真实客户端检查 (见 serve_client)
+        let proxied = config.trusts_proxy_header(client_addr.ip());
+        if !proxied && !config.admits(client_addr.ip()) {
+            stats::inc(&stats::DENIED);
+            log::debug!("来源地址 {} 不被允许, 关闭连接", client_addr);
+            continue;
         }
+        // 超出入站连接数上限时直接关闭; 受信任的负载均衡转来的连接同样在读出 PROXY 头之后按真实客户端计单 IP 连接数
+        let per_ip = config.max_connections_per_ip.filter(|_| !proxied);
+        let inbound = match limits.admit(client_addr.ip(), config.max_connections, per_ip) {
+            Ok(g) => g,
+            Err(e) => {
+                refuse_over_limit(e, client_addr, &limits);
+                continue;
+            }
+        };
+        tokio::spawn(serve_client(
+            client_stream,
+            client_addr,
+            inbound,
+            acceptor.clone(),
+            state.clone(),
+            published.clone(),
+            config,
+        ));
     }
 }
 
-/// 执行评分探测 
-async fn perform_scoring_check(targets: &[TargetConfig]) -> Option<BestTarget> {
-    let tasks = targets.iter().map(|t| {
-        let t = t.clone();
-        async move {
-            let addr = match tokio::net::lookup_host(&t.addr).await {
-                Ok(mut addrs) => addrs.next()?,
-                Err(_) => {
-                    log::warn!("[{}] DNS解析失败", t.name);
-                    return None;
-                }
-            };
+/// 记录超出入站连接数上限而关闭的连接, 告警每 10 秒最多一次
+fn refuse_over_limit(e: LimitExceeded, client_addr: SocketAddr, limits: &InboundLimits) {
+    stats::inc(&stats::LIMITED);
+    let reason = match e {
+        LimitExceeded::Total(max) => format!("入站连接总数已达上限 {}", max),
+        LimitExceeded::PerIp(max) => format!("{} 的连接数已达上限 {}", client_addr.ip(), max),
+    };
+    if limits.warn_due(LIMIT_WARN_INTERVAL) {
+        log::warn!("!!! {}, 关闭来自 {} 的新连接", reason, client_addr);
+    } else {
+        log::debug!("{}, 关闭来自 {} 的新连接", reason, client_addr);
+    }
+}
 
-            let mut valid_rtt_sum: u128 = 0;
-            let mut success_count = 0;
-            let mut min_ms: u128 = u128::MAX;
-            let mut max_ms: u128 = 0;
-
-            for _ in 0..PROBE_COUNT {
-                let start = Instant::now();
-                let res = tokio::time::timeout(
-                    Duration::from_millis(CONNECT_TIMEOUT),
-                    TcpStream::connect(addr)
-                ).await;
-
-                if let Ok(Ok(_)) = res {
-                    let rtt = start.elapsed().as_millis();
-                    success_count += 1;
-                    valid_rtt_sum += rtt;
-                    
-                    if rtt < min_ms { min_ms = rtt; }
-                    if rtt > max_ms { max_ms = rtt; }
-                }
-                tokio::time::sleep(Duration::from_millis(10)).await;
+/// 读取入站连接的 PROXY 头和 SNI, 开启 tls 时先完成握手, 再选择节点并转发
+async fn serve_client(
+    mut client: TcpStream,
+    client_addr: SocketAddr,
+    mut inbound: InboundGuard,
+    acceptor: Option<TlsAcceptor>,
+    state: Arc<RwLock<State>>,
+    published: Arc<ArcSwap<Snapshot>>,
+    config: Arc<Config>,
+) {
+    let _active = shutdown::Active::enter();
+    let _ = client.set_nodelay(true);
+    net::set_buffer_sizes(&client, config.so_rcvbuf, config.so_sndbuf);
+    net::set_keepalive(&client, config.tcp_keepalive.as_ref());
+    if let Some(dscp) = config.dscp {
+        if let Err(e) = net::set_dscp(socket2::SockRef::from(&client), dscp, client_addr.is_ipv6()) {
+            log::debug!("设置 DSCP 失败: {}", e);
+        }
+    }
+    if config.transparent {
+        // TPROXY 重定向的连接, 本地地址即原始目的地址
+        if let Ok(dst) = client.local_addr() {
+            log::debug!("透明代理: {} 原目的地址 {}", client_addr, dst);
+        }
+    }
+    let Some(mut preamble) = read_preamble(&mut client, client_addr, &config).await else { return };
+    if config.trusts_proxy_header(client_addr.ip()) {
+        // LOCAL 头等不带地址的 PROXY 头按负载均衡本身检查
+        let real = preamble.client_addr.unwrap_or(client_addr);
+        if !config.admits(real.ip()) {
+            stats::inc(&stats::DENIED);
+            log::debug!("来源地址 {} (经 {}) 不被允许, 关闭连接", real, client_addr);
+            return;
+        }
+        if let Err(e) = inbound.rebind(real.ip(), config.max_connections_per_ip) {
+            refuse_over_limit(e, real, inbound.limits());
+            return;
+        }
+    }
+    let Some(acceptor) = acceptor else {
+        let choice = choose(&*state.read().await, &config, preamble.client_addr.map(|a| a.ip()), preamble.sni.as_deref());
+        dispatch(client, client_addr, choice, preamble, published, config).await;
+        return;
+    };
+
+    // 终止 TLS: PROXY 头之后已读出的数据属于握手, 交给 TLS 层; SNI 取自握手
+    let early = std::mem::take(&mut preamble.early_data);
+    let client = match tls::accept(&acceptor, client, early).await {
+        Ok(c) => c,
+        Err(e) => {
+            log::debug!("TLS 握手失败 ({}): {}", client_addr, e);
+            return;
+        }
+    };
+    preamble.sni = tls::server_name(&client);
+    let choice = choose(&*state.read().await, &config, preamble.client_addr.map(|a| a.ip()), preamble.sni.as_deref());
+    dispatch(client, client_addr, choice, preamble, published, config).await;
+}
+
+/// 为新连接选出的节点, 以及随之登记的连接计数、对冲备选和隧道
+struct Choice {
+    target: BestTarget,
+    guard: ConnGuard,
+    hedge: Option<Hedge>,
+    tunnel: Option<Arc<tunnel::Session>>,
+    pool: Option<String>, // 按 SNI 路由到的节点池, 重试也限定在该池内
+}
+
+/// 选择节点: 有 SNI 且匹配到节点池时只在该池内选择;
+/// 否则客户端国家匹配到节点池时优先在该池内选择, 该池没有可用节点时按默认规则选择
+fn choose(s: &State, config: &Config, client: Option<IpAddr>, sni: Option<&str>) -> Option<Choice> {
+    let mut routed = sni.and_then(|host| s.sni_pool(host));
+    if let (Some(host), Some(pool)) = (sni, routed) {
+        log::debug!("SNI {} -> 节点池 [{}]", host, pool.name);
+    }
+    let regional = match (routed, client) {
+        (None, Some(ip)) if config.geo_routing() => {
+            config.country(ip).and_then(|c| s.country_pool(c).map(|p| (ip, c, p)))
+        }
+        _ => None,
+    };
+    let selected = match (routed, regional) {
+        (Some(pool), _) => s.select_in_pool(pool, client),
+        (None, Some((ip, country, pool))) => match s.select_in_pool(pool, client) {
+            Some(selected) => {
+                log::debug!("客户端 {} ({}) -> 节点池 [{}]", ip, country, pool.name);
+                routed = Some(pool);
+                Some(selected)
             }
+            None => {
+                log::debug!("节点池 [{}] 没有可用节点, 来自 {} ({}) 的连接按默认规则选择", pool.name, ip, country);
+                s.select_with_overflow(client)
+            }
+        },
+        (None, None) => s.select_with_overflow(client),
+    };
+    let target = match selected {
+        Some((t, Some(full))) => {
+            log::warn!(
+                "[{}] 连接数已满 ({}/{}), 新连接溢出到 [{}]",
+                full.name,
+                s.conns.get(&full.name),
+                full.max_connections.unwrap_or_default(),
+                t.name
+            );
+            t.clone()
+        }
+        Some((t, None)) => t.clone(),
+        None => {
+            if let Some(full) = routed.map_or_else(|| s.select(), |p| p.select()) {
+                log::warn!("[{}] 连接数已满, 所有节点都没有余量, 拒绝连接", full.name);
+            } else if let Some(pool) = routed {
+                log::warn!("节点池 [{}] 没有可用节点, 拒绝 SNI 为 {} 的连接", pool.name, sni.unwrap_or_default());
+            }
+            return None;
+        }
+    };
+    let pool = routed.map(|p| p.name.clone());
+    let tunnel = Some(&target).filter(|t| t.via_tunnel).and_then(|t| s.tunnels.get(&t.name).cloned());
+    let guard = s.conns.acquire(&target.name);
+    let hedge = Some(&target)
+        .filter(|t| config.hedged_connect && !t.via_tunnel)
+        .and_then(|t| s.runner_up(t, pool.as_deref()))
+        .map(|t| Hedge { target: t.clone(), conns: s.conns.clone() });
+    Some(Choice { target, guard, hedge, tunnel, pool })
+}
 
-            if success_count == 0 {
-                log::error!("[{}] ({}) 评分: INF (无法连接, 100% 丢包)", t.name, addr);
-                None
-            } else {
-                let fail_count = PROBE_COUNT - success_count;
-                let final_score = (valid_rtt_sum + (fail_count as u128 * PENALTY_MS)) / PROBE_COUNT as u128;
-                let avg_ms = valid_rtt_sum / success_count as u128;
+/// 转发选出的节点, 没有选出节点或跨协议族被拒绝时拒绝连接; client 为 TCP 连接或终止后的 TLS 连接
+async fn dispatch<C>(
+    client: C,
+    client_addr: SocketAddr,
+    choice: Option<Choice>,
+    preamble: Preamble,
+    published: Arc<ArcSwap<Snapshot>>,
+    config: Arc<Config>,
+) where
+    C: AsyncRead + AsyncWrite + Unpin + Send + net::Abort + net::Plain,
+{
+    let Some(choice) = choice else {
+        reject(client, &config).await;
+        return;
+    };
+    let target = &choice.target;
+    // 按真实客户端地址判断 (有入站 PROXY 头时取自该头), 与出站 PROXY 头使用的地址一致
+    let real_client = preamble.client_addr.unwrap_or(client_addr);
+    if config.cross_family_policy == CrossFamilyPolicy::Deny && is_cross_family(real_client, target.addr) {
+        log::warn!("拒绝跨协议族转发: {} -> [{}] ({})", real_client, target.name, target.addr);
+        reject(client, &config).await;
+        return;
+    }
+    let id = stats::CONNECTIONS.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
+    // 按 connection_log_sample_rate 抽样, 出错结束的连接总是记录
+    let level = jsonlog::connection_level();
+    let sampled = stats::sample_summary(config.connection_log_sample_rate);
+    if sampled {
+        log::log!(
+            level, event = "connection_open", id = id, client:% = client_addr, target = target.name.as_str(), addr:% = target.addr;
+            "连接 #{}: {} -> [{}] ({})", id, client_addr, target.name, target.addr
+        );
+    }
+    let access_log = config.access_log.clone().filter(|p| !p.is_empty());
+    let config_name = config.name.clone();
+    let mut record = ConnRecord::default();
+    let start = Instant::now();
+    let opened = std::time::SystemTime::now();
+    let res = handle_forward(client, client_addr, choice, preamble, published, &mut record, config).await;
+    let (up, down) = record.activity.bytes();
+    let duration_ms = start.elapsed().as_millis() as u64;
+    if res.is_err() && record.close == CloseReason::Eof {
+        record.close = CloseReason::Error;
+    }
+    let (name, addr, score) = match record.target {
+        Some((ref n, a, score)) => (n.as_str(), Some(a), Some(score as u64)),
+        None => ("", None, None),
+    };
+    let addr_str = addr.map(|a| a.to_string());
+    let error = res.as_ref().err().map(|e| format!("{:#}", e));
+    let close = record.close.as_str();
+    if sampled || error.is_some() {
+        log::log!(
+            level, event = "connection_close", id = id, client:% = client_addr, target = name, addr = addr_str.as_deref(),
+            connect_ms = record.connect_ms, bytes_up = up, bytes_down = down, duration_ms = duration_ms, close = close,
+            error = error.as_deref();
+            "连接 #{} 结束 ({}): [{}] {}ms, 上行 {} 字节, 下行 {} 字节{}", id, close, name, duration_ms, up, down,
+            error.as_deref().map(|e| format!(", 错误: {}", e)).unwrap_or_default()
+        );
+    }
+    if let Some(path) = access_log {
+        logfile::access(
+            &path,
+            vec![
+                ("ts", humantime::format_rfc3339_millis(std::time::SystemTime::now()).to_string().into()),
+                ("id", id.into()),
+                ("client", client_addr.to_string().into()),
+                ("target", name.into()),
+                ("addr", addr_str.clone().into()),
+                ("connect_ms", record.connect_ms.into()),
+                ("bytes_up", up.into()),
+                ("bytes_down", down.into()),
+                ("duration_ms", duration_ms.into()),
+                ("close", close.into()),
+                ("error", error.clone().into()),
+            ],
+        );
+    }
+    // 与连接结束事件相同的抽样
+    let traced = if sampled || error.is_some() { otlp::Span::started("forward", otlp::Kind::Server, opened) } else { None };
+    if let Some(mut span) = traced {
+        span.attr("forward.service", Some(config_name).filter(|n| !n.is_empty()));
+        span.attr("client.address", client_addr.ip().to_canonical().to_string());
+        span.attr("client.port", client_addr.port());
+        span.attr("forward.target", Some(name).filter(|n| !n.is_empty()));
+        span.attr("forward.score", score);
+        span.attr("server.address", addr.map(|a| a.ip().to_string()));
+        span.attr("server.port", addr.map(|a| a.port()));
+        span.attr("forward.connect_ms", record.connect_ms);
+        span.attr("forward.bytes_up", up);
+        span.attr("forward.bytes_down", down);
+        span.attr("forward.close", close);
+        if let Some(e) = error {
+            span.fail(e);
+        }
+    }
+}
+
+/// 一条转发连接的记录: 转发过程中填写, 结束时写入连接日志和访问日志
+#[derive(Default)]
+struct ConnRecord {
+    activity: Arc<relay::Activity>, // io_uring 转发线程也要更新, 需要共享
+    target: Option<(String, SocketAddr, u128)>, // 实际连上的节点 (改连或对冲之后) 及其评分
+    connect_ms: Option<u64>,              // 连上目标 (或打开隧道流) 的耗时
+    close: CloseReason,
+}
+
+/// 连接结束的原因
+#[derive(Clone, Copy, Default, PartialEq)]
+enum CloseReason {
+    #[default]
+    Eof, // 任一方正常关闭
+    Error,
+    ConnectFailed,
+    IdleTimeout,
+    Switched,     // 路由切换后强制断开
+    DrainTimeout, // 路由切换后排空超时
+}
+
+impl CloseReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            CloseReason::Eof => "eof",
+            CloseReason::Error => "error",
+            CloseReason::ConnectFailed => "connect_failed",
+            CloseReason::IdleTimeout => "idle_timeout",
+            CloseReason::Switched => "switched",
+            CloseReason::DrainTimeout => "drain_timeout",
+        }
+    }
+}
+
+/// 拒绝连接: 配置了 reject_response 时先写回提示内容再关闭, 否则直接关闭
+async fn reject<C: AsyncWrite + Unpin>(mut client: C, config: &Config) {
+    stats::inc(&stats::REJECTED);
+    let Some(response) = config.reject_response.as_ref().map(|r| r.to_bytes()) else { return };
+    let _ = tokio::time::timeout(Duration::from_millis(REJECT_WRITE_TIMEOUT), async {
+        client.write_all(&response).await?;
+        client.shutdown().await
+    })
+    .await;
+}
+
+/// 切换滞后: 新节点领先当前节点不足 switch_threshold_ms / switch_threshold_percent (取较大者) 时保留当前节点
+fn apply_switch_threshold(s: &mut State, previous: &str, config: &Config) {
+    if config.switch_threshold_ms == 0 && config.switch_threshold_percent == 0.0 {
+        return;
+    }
+    let (Some((_, winner)), Some((_, incumbent))) = (s.natural_select(), s.find(previous)) else {
+        return;
+    };
+    // 更优先的层恢复时直接切回, 不受切换阈值限制
+    if winner.name == incumbent.name || winner.priority != incumbent.priority {
+        return;
+    }
+    let by_percent = (incumbent.score as f64 * config.switch_threshold_percent / 100.0).round() as u128;
+    let required = config.switch_threshold_ms.max(by_percent);
+    let gain = incumbent.score.saturating_sub(winner.score);
+    if gain < required {
+        log::info!(
+            ">>> 不切换: [{}] 仅优于当前节点 [{}] {}ms, 未达到切换阈值 {}ms",
+            winner.name,
+            incumbent.name,
+            gain,
+            required
+        );
+        s.sticky = Some(previous.to_string());
+    }
+}
 
-                log::info!(
-                    "[{}] ({}) 评分: {} (最低延迟: {}, 最高延迟: {}, 平均延迟: {}, 丢包: {}/{})", 
-                    t.name, 
-                    addr, 
-                    final_score, 
-                    min_ms, 
-                    max_ms, 
-                    avg_ms, 
-                    fail_count, 
-                    PROBE_COUNT
-                );
+/// 路由切换后按 switch_existing 通知旧节点上的连接
+/// 旧节点仍可用时只处理 best 模式且未开启会话保持的池, 其他模式下旧节点本来就会继续分到连接
+fn switch_existing(s: &State, previous: &str, config: &Config) {
+    if config.switch_existing == SwitchExisting::Leave {
+        return;
+    }
+    if s.find(previous).is_some_and(|(p, _)| p.mode != SelectionMode::Best || p.sticky_mode.is_some()) {
+        return;
+    }
+    let active = s.conns.get(previous);
+    if active > 0 {
+        match config.switch_existing {
+            SwitchExisting::Drain => log::info!(
+                ">>> 旧节点 [{}] 上的 {} 个连接将在 {} 秒内关闭",
+                previous,
+                active,
+                config.switch_drain_timeout
+            ),
+            _ => log::info!(">>> 强制断开旧节点 [{}] 上的 {} 个连接", previous, active),
+        }
+    }
+    s.conns.switch_away(previous);
+}
 
-                Some(BestTarget { addr, name: t.name, score: final_score })
+/// 用本轮探测结果替换各池排名, 再按切换阈值和推迟切换规则决定是否留在旧节点
+fn install_round(s: &mut State, pool_configs: &[config::PoolConfig], results: Vec<Vec<BestTarget>>, previous: Option<&str>, config: &Config) {
+    s.hold = None;
+    s.sticky = None;
+    let pools = pool_configs
+        .iter()
+        .zip(results)
+        .map(|(p, mut ranked)| {
+            ranked.sort_by_key(|t| (t.priority, t.score));
+            PoolState {
+                name: p.name.clone(),
+                mode: p.mode,
+                sticky_mode: p.sticky,
+                ranked,
+                sni: p.sni.clone(),
+                countries: p.countries.clone(),
             }
+        })
+        .collect();
+    s.pools = Arc::new(pools);
+    if let Some(previous) = previous {
+        apply_switch_threshold(s, previous, config);
+        defer_marginal_switch(s, previous, config);
+    }
+}
+
+/// 新节点只比旧节点略好且旧节点上连接较多时, 推迟切换, 避免大量连接同时迁移
+fn defer_marginal_switch(s: &mut State, previous: &str, config: &Config) {
+    if s.switch_connection_threshold == 0 {
+        return;
+    }
+    let (Some((_, winner)), Some((_, incumbent))) = (s.natural_select(), s.find(previous)) else {
+        return;
+    };
+    if winner.name == incumbent.name || winner.priority != incumbent.priority {
+        return;
+    }
+    let gain = incumbent.score.saturating_sub(winner.score);
+    let active = s.conns.get(previous);
+    if gain < config.switch_marginal_ms && active >= s.switch_connection_threshold {
+        log::info!(
+            ">>> 推迟切换: [{}] 仅优于当前节点 {}ms, [{}] 仍有 {} 个连接 (阈值: {})",
+            winner.name,
+            gain,
+            incumbent.name,
+            active,
+            s.switch_connection_threshold
+        );
+        s.hold = Some(previous.to_string());
+    }
+}
+
+/// 流量配额: 到期的计数先清零, 配额已用完的节点本轮不参与选择
+fn apply_traffic_quota(usage: &TargetUsage, targets: &[TargetConfig], ranked: &mut Vec<BestTarget>) {
+    for t in targets {
+        let Some(quota_mb) = t.traffic_quota_mb else { continue };
+        if let Some(days) = t.quota_reset_days {
+            if usage.reset_expired(&t.name, Duration::from_secs(days * 86400)) {
+                log::info!(">>> [{}] 流量配额计数已清零 (周期: {}天)", t.name, days);
+            }
+        }
+        let used = usage.bytes(&t.name);
+        if used >= quota_mb * BYTES_PER_MB {
+            log::warn!("!!! [{}] 流量配额已用完 ({}/{} MB), 不再接收新连接", t.name, used / BYTES_PER_MB, quota_mb);
+            ranked.retain(|r| r.group != t.name);
+        }
+    }
+}
+
+/// 用历史评分平滑本轮结果; 本轮不可用的目标按丢包惩罚计入历史, 恢复时从较差的分数开始
+fn apply_score_decay(
+    history: &mut HashMap<String, f64>,
+    targets: &[TargetConfig],
+    ranked: &mut [BestTarget],
+    config: &Config,
+) {
+    let (up, down) = (config.score_decay_up, config.score_decay_down);
+    for t in ranked.iter_mut() {
+        let smoothed = score::decay(history.get(&t.name).copied(), t.raw_score as f64, up, down);
+        history.insert(t.name.clone(), smoothed);
+        t.score = smoothed.round() as u128;
+        if t.score != t.raw_score {
+            log::debug!("[{}] 平滑评分: {} (原始: {})", t.name, t.score, t.raw_score);
+        }
+    }
+    for t in targets {
+        // 展开的候选按历史中出现过的 "名称 (IP)" 逐个计入
+        let names: Vec<String> = if t.expands() {
+            let prefix = format!("{} (", t.name);
+            history.keys().filter(|k| k.starts_with(&prefix)).cloned().collect()
+        } else {
+            vec![t.name.clone()]
+        };
+        for name in names {
+            if ranked.iter().any(|r| r.name == name) {
+                continue;
+            }
+            // 不可用期间历史评分只会变差
+            let prev = history.get(&name).copied();
+            let penalty = t.penalty_ms.unwrap_or(config.penalty_ms) as f64;
+            let dead = prev.map_or(penalty, |p| p.max(penalty));
+            history.insert(name, score::decay(prev, dead, up, down));
+        }
+    }
+}
+
+/// 把各池的探测目标缩减为上轮排名前 contenders 的节点, 返回其余可用节点的上轮结果
+/// 上轮没有可用节点的池照常完整探测
+fn plan_adaptive_round(s: &State, pools: &mut [config::PoolConfig], contenders: usize) -> Vec<Vec<BestTarget>> {
+    pools
+        .iter_mut()
+        .map(|p| {
+            let Some(prev) = s.pools.iter().find(|sp| sp.name == p.name).filter(|sp| !sp.ranked.is_empty()) else {
+                return Vec::new();
+            };
+            let (top, rest) = prev.ranked.split_at(contenders.min(prev.ranked.len()));
+            p.targets.retain(|t| top.iter().any(|r| r.group == t.name));
+            // 重新探测的目标的其余展开候选以本轮结果为准
+            rest.iter().filter(|r| !p.targets.iter().any(|t| t.name == r.group)).cloned().collect()
+        })
+        .collect()
+}
+
+/// 按真实流量的建连失败率追加惩罚: 探测正常但实际连接失败的节点会被降级
+fn apply_traffic_weight(traffic: &TrafficStats, ranked: &mut [BestTarget], weight: f64, penalty_ms: u128) {
+    if weight <= 0.0 {
+        return;
+    }
+    for t in ranked.iter_mut() {
+        let Some(rate) = traffic.success_rate(&t.name) else { continue };
+        let penalty = (weight * (1.0 - rate) * penalty_ms as f64).round() as u128;
+        if penalty > 0 {
+            log::info!("[{}] 真实连接成功率 {:.0}%, 评分 +{} -> {}", t.name, rate * 100.0, penalty, t.score + penalty);
+            t.score += penalty;
+        }
+    }
+}
+
+/// 等待下一轮探测, 可被管理接口提前唤醒; 等待期间按看门狗间隔报告探测循环存活
+async fn wait_next_round(wakeup: &Notify, interval: u64, heartbeat: &sdnotify::Heartbeat) {
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(interval);
+    let notified = wakeup.notified();
+    tokio::pin!(notified);
+    loop {
+        let until = heartbeat.interval().map_or(deadline, |i| deadline.min(tokio::time::Instant::now() + i));
+        tokio::select! {
+            _ = tokio::time::sleep_until(until) => {}
+            _ = &mut notified => return,
+        }
+        if until == deadline {
+            return;
+        }
+        heartbeat.beat();
+    }
+}
+
+/// 执行评分探测 
+/// 解析出的地址就是选中后实际转发的地址 (BestTarget.addr), 转发时不再重新解析
+async fn perform_scoring_check(config: &Config, targets: &[TargetConfig], resolved: &net::ResolveCache) -> Vec<BestTarget> {
+    let round = fault::next_round();
+    let tasks = targets.iter().map(|t| async move {
+        if t.srv {
+            score_srv(t, config, resolved, round).await
+        } else {
+            score_target(t.clone(), &t.name, config, resolved, round).await
         }
     });
+    join_all(tasks).await.concat()
+}
 
-    let results = join_all(tasks).await;
-    results.into_iter().flatten().min_by_key(|n| n.score)
+/// SRV 目标: 每轮重新查询, 每条记录作为独立候选 "名称 (主机:端口)" 探测,
+/// 记录的 priority 加到目标的 priority 上作为优先级层
+async fn score_srv(t: &TargetConfig, config: &Config, resolved: &net::ResolveCache, round: u64) -> Vec<BestTarget> {
+    let records = match dns::srv(&t.addr, &config.dns_servers).await {
+        Ok(records) if records.is_empty() => {
+            log::warn!("[{}] 没有查询到 SRV 记录: {}", t.name, t.addr);
+            return Vec::new();
+        }
+        Ok(records) => records,
+        Err(e) => {
+            log::warn!("[{}] SRV 记录查询失败: {}: {}", t.name, t.addr, e);
+            return Vec::new();
+        }
+    };
+    let list: Vec<String> = records.iter().map(|r| format!("{}:{} (priority {})", r.target, r.port, r.priority)).collect();
+    log::debug!("[{}] SRV 记录: {}", t.name, list.join(", "));
+    let tasks = records.into_iter().map(|r| {
+        let addr = format!("{}:{}", r.target, r.port);
+        let candidate = TargetConfig {
+            name: format!("{} ({})", t.name, addr),
+            addr,
+            srv: false,
+            priority: t.priority + r.priority as u32,
+            ..t.clone()
+        };
+        async move { score_target(candidate, &t.name, config, resolved, round).await }
+    });
+    join_all(tasks).await.concat()
 }
 
-/// 转发逻辑
-async fn handle_forward(mut client: TcpStream, target: BestTarget, config: Config) -> Result<()> {
-    let mut server = TcpStream::connect(target.addr).await?;
-    let _ = client.set_nodelay(true);
+/// 探测一个目标; group 为配置中的目标名称 (SRV 展开的候选与 t.name 不同)
+async fn score_target(
+    t: TargetConfig,
+    group: &str,
+    config: &Config,
+    resolved: &net::ResolveCache,
+    round: u64,
+) -> Vec<BestTarget> {
+    let base_opts = &config.probe_socket_options();
+    let pin = Duration::from_secs(config.resolve_pin_secs);
+    let opts = &base_opts.with_bind(t.outbound_bind.as_ref());
+    // 证书每轮重新读取, 替换证书文件后下一轮生效; HTTPS / TLS 探测与转发共用同一组 tls_* 配置
+    let upstream = match t.uses_tls().then(|| tls::upstream(&t)).transpose() {
+        Ok(upstream) => upstream.map(Arc::new),
+        Err(e) => {
+            log::error!("[{}] TLS 配置无效, 本轮跳过: {:#}", t.name, e);
+            return Vec::new();
+        }
+    };
+    let dns_start = Instant::now();
+    let (addrs, dns_ms) = match resolved.resolve(&t.addr, pin, &config.dns_servers).await {
+        Ok((addrs, dns_ms, true)) => {
+            log::debug!("[{}] 沿用已解析的地址 ({})", t.name, join_addrs(&addrs));
+            (addrs, dns_ms)
+        }
+        Ok((addrs, dns_ms, false)) => {
+            log::debug!("[{}] DNS解析耗时: {:.2}ms ({})", t.name, dns_ms, join_addrs(&addrs));
+            (addrs, dns_ms)
+        }
+        Err(e) => {
+            let dns_ms = dns_start.elapsed().as_secs_f64() * 1000.0;
+            log::warn!("[{}] DNS解析失败 (耗时: {:.2}ms): {}", t.name, dns_ms, e);
+            return Vec::new();
+        }
+    };
+
+    let fault = config.fault_seed.zip(t.fault_inject.as_ref());
+    if let Some((_, f)) = fault {
+        log::warn!("[{}] 故障注入: 延迟 +{}ms, 丢包率 {:.0}%", t.name, f.latency_ms, f.loss * 100.0);
+    }
+
+    // 默认每个协议族只使用解析结果中的第一个地址
+    let preferred = t.prefer.filter(&if t.probe_all_addresses { addrs.clone() } else { net::first_per_family(&addrs) });
+    if preferred.is_empty() {
+        let family = if t.prefer == config::FamilyPreference::Ipv4 { "IPv4" } else { "IPv6" };
+        log::warn!("[{}] 没有解析到 {} 地址 (prefer), 已解析: {}", t.name, family, join_addrs(&addrs));
+        return Vec::new();
+    }
+    // probe_all_addresses: 每个解析到的地址单独探测, 作为独立的候选节点 "名称 (IP)" 参与选择
+    if t.probe_all_addresses {
+        let probed = join_all(preferred.iter().map(|&a| probe_samples(&t, a, opts, upstream.as_deref(), config, round))).await;
+        let probed: Vec<Samples> = probed.into_iter().flatten().collect();
+        // 沿用的地址中有完全不可用的, 下一轮重新解析
+        if probed.iter().any(|s| s.rtts.is_empty()) {
+            resolved.forget(&t.addr);
+        }
+        let scored = probed.into_iter().map(|s| {
+            let name = format!("{} ({})", t.name, s.addr.ip());
+            score_candidate(&t, name, group, vec![s], dns_ms, upstream.clone(), config)
+        });
+        return join_all(scored).await.into_iter().flatten().collect();
+    }
+    // 双栈目标开启 happy_eyeballs 时两个协议族同时探测, 使用评分更低的一个, 另一个作为建连备选
+    let candidates = if config.happy_eyeballs { &preferred[..] } else { &preferred[..1] };
+    let probed = join_all(candidates.iter().map(|&a| probe_samples(&t, a, opts, upstream.as_deref(), config, round))).await;
+    let probed: Vec<Samples> = probed.into_iter().flatten().collect();
+    // 沿用的地址完全不可用时, 下一轮重新解析
+    if !probed.is_empty() && probed.iter().all(|s| s.rtts.is_empty()) {
+        resolved.forget(&t.addr);
+    }
+    let name = t.name.clone();
+    score_candidate(&t, name, group, probed, dns_ms, upstream, config).await.into_iter().collect()
+}
+
+/// 按一个候选的探测样本评分; probed 含多个地址时 (happy_eyeballs) 使用评分更低的一个, 另一个作为建连备选
+/// group 为配置中的目标名称, 用于流量配额和维护
+async fn score_candidate(
+    t: &TargetConfig,
+    name: String,
+    group: &str,
+    mut probed: Vec<Samples>,
+    dns_ms: f64,
+    upstream: Option<Arc<tls::Upstream>>,
+    config: &Config,
+) -> Option<BestTarget> {
+    let probe_count = t.probe_count.unwrap_or(config.probe_count);
+    let penalty_ms = t.penalty_ms.unwrap_or(config.penalty_ms);
+    let expanded = name != t.name;
+    if probed.is_empty() {
+        return None;
+    }
+    let family_score = |s: &Samples| {
+        let fail = probe_count - s.rtts.len() as u32;
+        let sum = score::aggregate_rtt_sum(&s.rtts, config.round_aggregation, config.trim_fraction);
+        (s.rtts.is_empty(), (sum + fail as u128 * penalty_ms) / probe_count as u128)
+    };
+    // 评分相同时保持解析顺序
+    probed.sort_by_key(family_score);
+    let fallback = probed.get(1).filter(|s| !s.rtts.is_empty()).map(|s| s.addr);
+    if probed.len() > 1 {
+        let scores: Vec<String> = probed
+            .iter()
+            .map(|s| match family_score(s) {
+                (true, _) => format!("{} 不可用", s.addr),
+                (false, score) => format!("{} 评分 {}", s.addr, score),
+            })
+            .collect();
+        log::debug!("[{}] 双栈探测: {}", name, scores.join(", "));
+    }
+    let Samples { addr, rtts: samples } = probed.swap_remove(0);
+    let success_count = samples.len() as u32;
+    let valid_rtt_sum: u128 = samples.iter().sum();
+    let min_ms = samples.iter().copied().min().unwrap_or(u128::MAX);
+    let max_ms = samples.iter().copied().max().unwrap_or(0);
+
+    let min_success = (config.min_success_ratio * probe_count as f64).ceil() as u32;
+    if success_count == 0 {
+        log::error!(
+            event = "probe_result", target = name, addr:% = addr, available = false, success = 0, probes = probe_count;
+            "[{}] ({}) 评分: INF (无法连接, 100% 丢包)", name, addr
+        );
+        otlp::probe_result(vec![
+            ("target", name.into()),
+            ("addr", addr.to_string().into()),
+            ("available", false.into()),
+            ("success", 0.into()),
+            ("probes", probe_count.into()),
+        ]);
+        None
+    } else if success_count < min_success {
+        log::error!(
+            event = "probe_result", target = name, addr:% = addr, available = false, success = success_count, probes = probe_count;
+            "[{}] ({}) 评分: INF (成功 {}/{}, 低于最低要求 {})",
+            name,
+            addr,
+            success_count,
+            probe_count,
+            min_success
+        );
+        otlp::probe_result(vec![
+            ("target", name.into()),
+            ("addr", addr.to_string().into()),
+            ("available", false.into()),
+            ("success", success_count.into()),
+            ("probes", probe_count.into()),
+        ]);
+        None
+    } else {
+        let fail_count = probe_count - success_count;
+        let scored_rtt_sum = score::aggregate_rtt_sum(&samples, config.round_aggregation, config.trim_fraction);
+        let rtt_score = (scored_rtt_sum + (fail_count as u128 * penalty_ms)) / probe_count as u128;
+        let avg_ms = valid_rtt_sum / success_count as u128;
+
+        // 按后端上报的队列深度加分, 获取失败时本轮只按延迟评分
+        let queue_depth = match t.queue_metric_url {
+            Some(ref url) => match queue::fetch(url).await {
+                Ok(depth) => Some(depth),
+                Err(e) => {
+                    log::warn!("[{}] 队列深度获取失败, 本轮只按延迟评分: {:#}", name, e);
+                    None
+                }
+            },
+            None => None,
+        };
+        let queue_score = queue_depth.map_or(0, |d| (d * config.queue_weight).round() as u128);
+        let queue_note = queue_depth.map_or(String::new(), |d| format!(", 队列: {} (+{})", d, queue_score));
+        // 抖动按成功样本 RTT 的标准差计入评分, 延迟相近时更稳定的节点优先
+        let jitter_ms = score::stddev(&samples);
+        let jitter_score = (jitter_ms * config.jitter_weight).round() as u128;
+        let jitter_note = if config.jitter_weight > 0.0 {
+            format!(", 抖动: {:.1} (+{})", jitter_ms, jitter_score)
+        } else {
+            String::new()
+        };
+        // 权重作为乘数作用于整个评分, 便于表达 "除非明显更差, 否则优先使用某个节点"
+        let final_score = ((rtt_score + jitter_score + queue_score) as f64 * t.weight).round() as u128;
+        let weight_note = if t.weight != 1.0 { format!(", 权重: {}", t.weight) } else { String::new() };
+
+        log::info!(
+            event = "probe_result", target = name, addr:% = addr, available = true, score = final_score, min_ms = min_ms,
+            max_ms = max_ms, avg_ms = avg_ms, loss = fail_count, probes = probe_count, jitter_ms = jitter_ms;
+            "[{}] ({}) 评分: {} (最低延迟: {}, 最高延迟: {}, 平均延迟: {}, 丢包: {}/{}{}{}{})", 
+            name, 
+            addr, 
+            final_score, 
+            min_ms, 
+            max_ms, 
+            avg_ms, 
+            fail_count, 
+            probe_count,
+            jitter_note,
+            queue_note,
+            weight_note
+        );
+        otlp::probe_result(vec![
+            ("target", name.clone().into()),
+            ("addr", addr.to_string().into()),
+            ("available", true.into()),
+            ("score", (final_score as u64).into()),
+            ("avg_ms", (avg_ms as u64).into()),
+            ("loss", fail_count.into()),
+            ("probes", probe_count.into()),
+            ("jitter_ms", jitter_ms.into()),
+        ]);
+
+        Some(BestTarget {
+            addr,
+            fallback,
+            // 展开的候选固定使用探测的地址, 不再重新解析
+            host: (!expanded && t.addr.parse::<SocketAddr>().is_err()).then(|| t.addr.clone()),
+            prefer: t.prefer,
+            name,
+            group: group.to_string(),
+            score: final_score,
+            raw_score: final_score,
+            rtt_score,
+            jitter_score,
+            jitter_ms,
+            queue_score,
+            priority: t.priority,
+            weight: t.weight,
+            min_ms,
+            max_ms,
+            avg_ms,
+            queue_depth,
+            dns_ms,
+            loss: fail_count,
+            max_connections: t.max_connections,
+            rate_limit_kbps: t.rate_limit_kbps,
+            bandwidth_cap_kbps: t.bandwidth_cap_kbps,
+            outbound_bind: t.outbound_bind.clone(),
+            proxy_tlvs: Arc::new(proxy::encode_tlvs(t.proxy_tlvs.as_deref().unwrap_or_default()).unwrap_or_default()),
+            via_tunnel: false,
+            forward_link: t.kind == LinkType::ForwardLink,
+            tls: upstream.filter(|_| t.tls),
+        })
+    }
+}
+
+/// 一个候选地址本轮探测的成功样本 (ms)
+struct Samples {
+    addr: SocketAddr,
+    rtts: Vec<u128>,
+}
+
+fn join_addrs(addrs: &[SocketAddr]) -> String {
+    addrs.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+}
+
+/// 对一个地址执行一轮探测; ICMP 套接字无法创建时返回 None
+async fn probe_samples(
+    t: &TargetConfig,
+    addr: SocketAddr,
+    opts: &net::SocketOptions,
+    upstream: Option<&tls::Upstream>,
+    config: &Config,
+    round: u64,
+) -> Option<Samples> {
+    let probe_count = t.probe_count.unwrap_or(config.probe_count);
+    let probe_timeout = Duration::from_millis(t.probe_timeout_ms.unwrap_or(config.probe_timeout_ms));
+    let mut rtts: Vec<u128> = Vec::with_capacity(probe_count as usize);
+
+    // ICMP 探测: 整轮共用一个套接字, 按序号匹配回复
+    let pinger = match t.probe {
+        ProbeKind::Tcp | ProbeKind::Http | ProbeKind::Https | ProbeKind::Tls => None,
+        ProbeKind::Icmp => match icmp::Pinger::new(addr.ip(), opts.bind.as_ref(), opts.mark) {
+            Ok(p) => Some(p),
+            Err(e) => {
+                log::error!("[{}] 无法创建 ICMP 套接字 (需要 root 或 net.ipv4.ping_group_range): {}", t.name, e);
+                return None;
+            }
+        },
+    };
+
+    let http_host = t.tls_server_name.clone().unwrap_or_else(|| t.addr.clone());
+    let http_path = t.probe_path.as_deref().unwrap_or("/");
+    let fault = config.fault_seed.zip(t.fault_inject.as_ref());
+
+    for i in 0..probe_count {
+        let start = Instant::now();
+        let probe = async {
+            match (&pinger, t.probe) {
+                (Some(p), _) => p.ping(i as u16).await,
+                (None, ProbeKind::Http | ProbeKind::Https) => {
+                    let https = upstream.filter(|_| t.probe == ProbeKind::Https);
+                    health::check(addr, opts, https, &http_host, http_path)
+                        .await
+                        .inspect_err(|e| log::debug!("[{}] HTTP 探测失败: {}", t.name, e))
+                }
+                (None, ProbeKind::Tls) => match upstream {
+                    Some(u) => health::handshake(addr, opts, u)
+                        .await
+                        .inspect_err(|e| log::debug!("[{}] TLS 探测失败: {}", t.name, e)),
+                    None => net::connect(addr, opts).await.map(drop),
+                },
+                (None, _) => net::connect(addr, opts).await.map(drop),
+            }
+        };
+        let res = tokio::time::timeout(probe_timeout, probe).await;
+        let dropped = fault.is_some_and(|(seed, f)| fault::should_drop(seed, &t.name, round, i, f.loss));
+
+        if let (Ok(Ok(_)), false) = (res, dropped) {
+            rtts.push(start.elapsed().as_millis() + fault.map_or(0, |(_, f)| f.latency_ms));
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    Some(Samples { addr, rtts })
+}
+
+/// 转发逻辑; peer 为入站 TCP 连接的对端地址
+async fn handle_forward<C>(
+    mut client: C,
+    peer: SocketAddr,
+    choice: Choice,
+    preamble: Preamble,
+    published: Arc<ArcSwap<Snapshot>>,
+    record: &mut ConnRecord,
+    config: Arc<Config>,
+) -> Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin + Send + net::Abort + net::Plain,
+{
+    let traffic = published.load().traffic.clone();
+    let Choice { mut target, guard: mut _guard, mut hedge, tunnel, pool } = choice;
+    let Preamble { client_addr, early_data, sni } = preamble;
+    let ConnRecord { activity, target: chosen, connect_ms, close } = record;
+    let activity = &*activity;
+    if let Some(ref sni) = sni {
+        log::debug!("[{}] 客户端 SNI: {}", target.name, sni);
+    }
+    // 透传的 TLS 连接: SNI 作为出站 PROXY 头的 AUTHORITY
+    let authority = sni.filter(|_| config.forward_sni_as_authority && config.proxy_protocol.as_deref() == Some("v2"));
+    let with_sni = |t: &mut BestTarget| {
+        if let Some(ref sni) = authority {
+            t.proxy_tlvs = Arc::new(proxy::with_authority(&t.proxy_tlvs, sni));
+        }
+    };
+
+    if target.via_tunnel {
+        with_sni(&mut target);
+        let tunnel = tunnel.ok_or_else(|| anyhow::anyhow!("隧道 [{}] 已注销", target.name))?;
+        let connect_start = Instant::now();
+        let opened = tunnel.open_stream().await;
+        traffic.record(&target.name, opened.is_ok());
+        *chosen = Some((target.name.clone(), target.addr, target.score));
+        match opened {
+            Ok(_) => *connect_ms = Some(connect_start.elapsed().as_millis() as u64),
+            Err(_) => *close = CloseReason::ConnectFailed,
+        }
+        let limits = relay::Limits {
+            kbps: config.rate_limit_kbps,
+            meter: Some(published.load().usage.meter(&target.name)),
+            ..Default::default()
+        };
+        let tracked = relay::Tracked::new(relay::Throttled::new(&mut client, limits), activity);
+        let relay = forward_via_tunnel(tracked, peer, client_addr, early_data, &target, opened?, &config);
+        let outcome = relay_or_switch(relay, activity, close, &mut _guard, &target, &config).await;
+        return outcome.unwrap_or_else(|| {
+            client.abort_on_close();
+            Ok(())
+        });
+    }
+
+    // 默认直接使用探测时解析并评分的地址; resolve_per_connection 时先确认域名仍解析到该地址
+    if config.resolve_per_connection {
+        let hedge_target = hedge.as_mut().map(|h| &mut h.target);
+        let servers = &config.dns_servers;
+        futures::future::join(refresh_addr(&mut target, servers), async {
+            if let Some(t) = hedge_target {
+                refresh_addr(t, servers).await;
+            }
+        })
+        .await;
+    }
+    let mut opts = config.socket_options();
+    if config.transparent {
+        opts.spoof_source = client_addr.map(|a| a.ip().to_canonical());
+    }
+    let delay = Duration::from_millis(config.hedge_delay_ms);
+    let race_delay = Duration::from_millis(config.happy_eyeballs_delay_ms);
+    let connect_start = Instant::now();
+    let (mut connected, hedge_won, hedge_tried) =
+        hedged_connect(&target, hedge.as_ref().map(|h| &h.target), delay, race_delay, &opts, &traffic).await;
+    let mut tried = vec![target.name.clone()];
+    tried.extend(hedge.as_ref().filter(|_| hedge_tried).map(|h| h.target.name.clone()));
+    if let (true, Some(h)) = (hedge_won, hedge) {
+        // 连接数计到实际使用的节点上
+        _guard = h.conns.acquire(&h.target.name);
+        target = h.target;
+    }
+
+    // 建连失败时按排名依次改连其他可用节点
+    for _ in 0..config.connect_retries {
+        let Err(ref e) = connected else { break };
+        let snapshot = published.load();
+        let skip: Vec<&str> = tried.iter().map(String::as_str).collect();
+        let Some(next) = snapshot.fallback(pool.as_deref(), &skip) else { break };
+        log::warn!("[{}] 连接失败 ({}), 改连 [{}]", target.name, e, next.name);
+        _guard = snapshot.conns.acquire(&next.name);
+        target = next.clone();
+        if config.resolve_per_connection {
+            refresh_addr(&mut target, &config.dns_servers).await;
+        }
+        tried.push(target.name.clone());
+        let next_opts = opts.with_bind(target.outbound_bind.as_ref());
+        connected = net::connect_racing(target.addr, target.fallback, race_delay, &next_opts).await;
+        traffic.record(&target.name, connected.is_ok());
+    }
+    if let Err(ref e) = connected {
+        if tried.len() > 1 {
+            log::warn!("已尝试 {} 个节点, 全部连接失败: {}", tried.len(), e);
+        }
+    }
+    *chosen = Some((target.name.clone(), target.addr, target.score));
+    match connected {
+        Ok(_) => *connect_ms = Some(connect_start.elapsed().as_millis() as u64),
+        Err(_) => *close = CloseReason::ConnectFailed,
+    }
+    with_sni(&mut target);
+    let mut server = connected?;
     let _ = server.set_nodelay(true);
+    net::set_keepalive(&server, config.tcp_keepalive.as_ref());
+
+    // PROXY 头不经过压缩, 对端转发器可照常用 accept_proxy_protocol 读取
+    if let Some(header) = outbound_proxy_header(&config, client_addr, &target) {
+        server.write_all(&header).await?;
+    }
+    // PROXY 头在 TLS 握手之前以明文发送
+    let usage = published.load().usage.clone();
+    let limits = relay::Limits {
+        kbps: target.rate_limit_kbps.or(config.rate_limit_kbps),
+        cap: target.bandwidth_cap_kbps.map(|kbps| usage.cap(&target.name, kbps)),
+        meter: Some(usage.meter(&target.group)),
+    };
+    let direct = kernel_relay_eligible(&config, &target, &limits);
+    let outcome = match (target.tls.clone(), client.plain().filter(|_| direct)) {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        (None, Some(plain)) if config.io_uring => {
+            selfprobe::forwarded(Some(peer), &target.name);
+            let relay = relay_io_uring(plain, server, &early_data, limits, activity, &target, &config);
+            relay_or_switch(relay, activity, close, &mut _guard, &target, &config).await
+        }
+        #[cfg(target_os = "linux")]
+        (None, Some(plain)) => {
+            selfprobe::forwarded(Some(peer), &target.name);
+            let relay = relay_zero_copy(plain, server, &early_data, limits, activity, &target, &config);
+            relay_or_switch(relay, activity, close, &mut _guard, &target, &config).await
+        }
+        (None, _) => {
+            let tracked = relay::Tracked::new(relay::Throttled::new(&mut client, limits), activity);
+            selfprobe::forwarded(Some(peer), &target.name);
+            let relay = relay_streams(tracked, server, &early_data, &target, &config);
+            relay_or_switch(relay, activity, close, &mut _guard, &target, &config).await
+        }
+        (Some(upstream), _) => {
+            let tracked = relay::Tracked::new(relay::Throttled::new(&mut client, limits), activity);
+            let server = tls::connect(&upstream, server)
+                .await
+                .inspect_err(|e| log::warn!("[{}] TLS 握手失败: {}", target.name, e))?;
+            selfprobe::forwarded(Some(peer), &target.name);
+            let relay = relay_streams(tracked, server, &early_data, &target, &config);
+            relay_or_switch(relay, activity, close, &mut _guard, &target, &config).await
+        }
+    };
+    // 强制断开: 目标连接已随转发结束关闭, 客户端连接以 RST 关闭
+    outcome.unwrap_or_else(|| {
+        client.abort_on_close();
+        Ok(())
+    })
+}
 
-    if let Some(ref proto) = config.proxy_protocol {
-        if proto == "v2" {
-            if let Ok(src_addr) = client.peer_addr() {
-                let header = build_proxy_v2_header(src_addr, target.addr);
-                server.write_all(&header).await?;
+/// 转发直到连接结束; 双向空闲超过 idle_timeout 时关闭, 路由从该节点切走时按 switch_existing 处理
+/// 需要强制断开时返回 None
+async fn relay_or_switch(
+    relay: impl Future<Output = Result<()>>,
+    activity: &relay::Activity,
+    close: &mut CloseReason,
+    guard: &mut ConnGuard,
+    target: &BestTarget,
+    config: &Config,
+) -> Option<Result<()>> {
+    let idle = async {
+        match config.idle_timeout {
+            0 => std::future::pending().await,
+            secs => activity.idle(Duration::from_secs(secs)).await,
+        }
+    };
+    let switched = async {
+        match config.switch_existing {
+            SwitchExisting::Leave => std::future::pending().await,
+            _ => guard.switched_away().await,
+        }
+    };
+    tokio::pin!(relay);
+    tokio::select! {
+        res = &mut relay => return Some(res),
+        _ = idle => {
+            log::info!("[{}] 连接空闲超过 {}秒, 已断开", target.name, config.idle_timeout);
+            *close = CloseReason::IdleTimeout;
+            return Some(Ok(()));
+        }
+        _ = switched => {}
+    }
+    match config.switch_existing {
+        SwitchExisting::Drain => {
+            let drain = Duration::from_secs(config.switch_drain_timeout);
+            match tokio::time::timeout(drain, relay).await {
+                Ok(res) => Some(res),
+                Err(_) => {
+                    log::debug!("[{}] 路由已切换, 旧连接排空超时, 已关闭", target.name);
+                    *close = CloseReason::DrainTimeout;
+                    Some(Ok(()))
+                }
             }
         }
+        _ => {
+            log::debug!("[{}] 路由已切换, 强制断开旧连接", target.name);
+            *close = CloseReason::Switched;
+            None
+        }
     }
+}
+
+/// 可以绕过 tokio 的读写直接在两个套接字之间搬运数据: 开启 zero_copy 或 io_uring, 两侧都是明文 TCP,
+/// 且不需要链路编解码、镜像、单次读写超时、写合并或限速
+fn kernel_relay_eligible(config: &Config, target: &BestTarget, limits: &relay::Limits) -> bool {
+    cfg!(target_os = "linux")
+        && (config.zero_copy || config.io_uring)
+        && target.tls.is_none()
+        && (config.listen_type == LinkType::ForwardLink) == target.forward_link
+        && config.mirror_addr.as_deref().is_none_or(str::is_empty)
+        && !config.op_timeouts().is_set()
+        && config.write_coalesce().is_none()
+        && limits.kbps.is_none()
+        && limits.cap.is_none()
+}
 
-    io::copy_bidirectional(&mut client, &mut server).await?;
+/// 零拷贝转发 (splice); 无法创建管道时改用普通转发
+#[cfg(target_os = "linux")]
+async fn relay_zero_copy(
+    client: &mut TcpStream,
+    mut server: TcpStream,
+    early_data: &[u8],
+    limits: relay::Limits,
+    activity: &relay::Activity,
+    target: &BestTarget,
+    config: &Config,
+) -> Result<()> {
+    let pipes = match splice::Pipe::pair(config.relay_buffer_size) {
+        Ok(p) => p,
+        Err(e) => {
+            log::debug!("[{}] 无法创建管道, 改用普通转发: {}", target.name, e);
+            let tracked = relay::Tracked::new(relay::Throttled::new(client, limits), activity);
+            return relay_streams(tracked, server, early_data, target, config).await;
+        }
+    };
+    if !early_data.is_empty() {
+        server.write_all(early_data).await?;
+    }
+    let (up, down) = splice::relay(client, &server, pipes, activity, limits.meter.as_deref()).await?;
+    stats::add_bytes(up, down);
     Ok(())
 }
 
-/// PROXY Protocol V2 构造器
-fn build_proxy_v2_header(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
-    let mut header = Vec::with_capacity(32);
-    header.extend_from_slice(b"\x0D\x0A\x0D\x0A\x00\x0D\x0A\x51\x55\x49\x54\x0A");
-    header.push(0x21); 
-    match (src, dst) {
-        (SocketAddr::V4(s), SocketAddr::V4(d)) => {
-            header.push(0x11);
-            header.extend_from_slice(&12u16.to_be_bytes());
-            header.extend_from_slice(&s.ip().octets());
-            header.extend_from_slice(&d.ip().octets());
-            header.extend_from_slice(&s.port().to_be_bytes());
-            header.extend_from_slice(&d.port().to_be_bytes());
-        }
-        (SocketAddr::V6(s), SocketAddr::V6(d)) => {
-            header.push(0x21);
-            header.extend_from_slice(&36u16.to_be_bytes());
-            header.extend_from_slice(&s.ip().octets());
-            header.extend_from_slice(&d.ip().octets());
-            header.extend_from_slice(&s.port().to_be_bytes());
-            header.extend_from_slice(&d.port().to_be_bytes());
+/// io_uring 转发; 转发线程无法启动时改用普通转发
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+async fn relay_io_uring(
+    client: &mut TcpStream,
+    mut server: TcpStream,
+    early_data: &[u8],
+    limits: relay::Limits,
+    activity: &Arc<relay::Activity>,
+    target: &BestTarget,
+    config: &Config,
+) -> Result<()> {
+    let driver = match uring::driver() {
+        Ok(d) => d,
+        Err(e) => {
+            uring::warn_fallback(&target.name, &e);
+            let tracked = relay::Tracked::new(relay::Throttled::new(client, limits), activity);
+            return relay_streams(tracked, server, early_data, target, config).await;
         }
-        _ => {
-            header.push(0x00);
-            header.extend_from_slice(&0u16.to_be_bytes());
+    };
+    if !early_data.is_empty() {
+        server.write_all(early_data).await?;
+    }
+    let (buffer, meter) = (config.relay_buffer_size, limits.meter.clone());
+    let (up, down) = uring::relay(&driver, client, &server, buffer, activity.clone(), meter).await?;
+    stats::add_bytes(up, down);
+    Ok(())
+}
+
+/// 在客户端与目标连接之间双向转发; early_data 为已从客户端读出的数据
+async fn relay_streams<C, S>(mut client: C, mut server: S, early_data: &[u8], target: &BestTarget, config: &Config) -> Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin + Send,
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    // 只有一侧是压缩链路时才需要编解码; 两侧都是时帧原样透传
+    let client_is_link = config.listen_type == LinkType::ForwardLink;
+    if client_is_link != target.forward_link {
+        let stats = Default::default();
+        let res = link::relay(&mut client, &mut server, early_data, client_is_link, &stats, config.op_timeouts()).await;
+        let (up, down) = &stats;
+        // 出错的连接总是记录, 正常结束的按 connection_log_sample_rate 抽样
+        if res.is_err() || stats::sample_summary(config.connection_log_sample_rate) {
+            log::info!(
+                "[{}] 压缩链路: 上行 {} -> {} 字节 ({:.1}%), 下行 {} -> {} 字节 ({:.1}%)",
+                target.name,
+                up.get().0,
+                up.get().1,
+                up.ratio(),
+                down.get().0,
+                down.get().1,
+                down.ratio()
+            );
+        }
+        stats::add_bytes(up.get().0, down.get().0);
+        res?;
+        return Ok(());
+    }
+
+    // 先建好镜像再写出早到数据, 镜像流从客户端的第一个字节开始
+    let mirror = config.mirror_addr.clone().filter(|a| !a.is_empty());
+    let (tap, back_tap) = match mirror {
+        Some(addr) if config.mirror_both_directions => {
+            let (up, down) = relay::MirrorTap::connect_pair(addr);
+            (Some(up), Some(down))
+        }
+        addr => (addr.map(relay::MirrorTap::connect), None),
+    };
+    if !early_data.is_empty() {
+        if let Some(ref tap) = tap {
+            tap.feed(early_data);
+        }
+        server.write_all(early_data).await?;
+    }
+
+    let timeouts = config.op_timeouts();
+    let coalesce = config.write_coalesce();
+    let buffer = config.relay_buffer_size;
+    if tap.is_none() && !timeouts.is_set() && coalesce.is_none() {
+        let (up, down) = match buffer {
+            Some(n) => io::copy_bidirectional_with_sizes(&mut client, &mut server, n, n).await?,
+            None => io::copy_bidirectional(&mut client, &mut server).await?,
+        };
+        stats::add_bytes(up, down);
+        return Ok(());
+    }
+
+    // 镜像、单次读写超时或写合并需要逐块处理, 使用自定义拷贝
+    let (cr, cw) = io::split(client);
+    let (sr, sw) = io::split(server);
+    let res = tokio::try_join!(
+        relay::copy_half(cr, sw, tap.as_ref(), timeouts, coalesce, buffer),
+        relay::copy_half(sr, cw, back_tap.as_ref(), timeouts, coalesce, buffer),
+    );
+    let dropped: u64 = tap.iter().chain(&back_tap).map(relay::MirrorTap::dropped).sum();
+    if dropped > 0 {
+        log::warn!("[{}] 镜像跟不上, 本连接丢弃 {} 个数据块", target.name, dropped);
+    }
+    if let Err(ref e) = res {
+        if e.kind() == io::ErrorKind::TimedOut {
+            log::info!("[{}] 连接{}, 已断开", target.name, e);
+        }
+    }
+    let (up, down) = res?;
+    stats::add_bytes(up, down);
+    Ok(())
+}
+
+/// 选择节点前从入站连接读取的内容
+struct Preamble {
+    client_addr: Option<SocketAddr>, // 真实客户端地址, 有入站 PROXY 头时取自该头
+    early_data: Vec<u8>,             // 已读出、需要照常转发的数据
+    sni: Option<String>,
+}
+
+/// 剥离下游负载均衡发来的 PROXY 头, 并在需要时读取 ClientHello 中的 SNI; PROXY 头无效时返回 None
+async fn read_preamble(client: &mut TcpStream, peer: SocketAddr, config: &Config) -> Option<Preamble> {
+    let mut client_addr = Some(peer);
+    let mut early_data = Vec::new();
+    if config.trusts_proxy_header(peer.ip()) {
+        match proxy::read_header(client, config.proxy_header_max_size).await {
+            Ok((info, rest)) => {
+                client_addr = info.src.or(client_addr);
+                early_data = rest;
+            }
+            Err(e) => {
+                log::warn!("入站 PROXY 头无效 ({}): {}", client_addr.map(|a| a.to_string()).unwrap_or_default(), e);
+                return None;
+            }
+        }
+    }
+
+    let authority = config.forward_sni_as_authority && config.proxy_protocol.as_deref() == Some("v2");
+    let mut sni = None;
+    // 终止 TLS 时 SNI 在握手后取得
+    if (authority || config.sni_routing()) && config.listen_type == LinkType::Tcp && config.tls.is_none() {
+        sni = sni::peek(client, &mut early_data).await;
+    }
+    Some(Preamble { client_addr, early_data, sni })
+}
+
+/// 对冲建连的备选节点
+struct Hedge {
+    target: BestTarget,
+    conns: Arc<ConnCounters>,
+}
+
+// 建连前重新解析域名的超时, 超时或失败时使用探测时的地址
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// 重新解析节点的域名: 探测时的地址仍在解析结果中则照常使用,
+/// 否则改用新解析到的地址 (按 prefer 筛选, 不再使用竞速备选), 下一轮探测再按新地址评分
+async fn refresh_addr(t: &mut BestTarget, servers: &[dns::Server]) {
+    let Some(ref host) = t.host else { return };
+    let resolved: Vec<SocketAddr> = match tokio::time::timeout(RESOLVE_TIMEOUT, dns::lookup(host, servers)).await {
+        Ok(Ok(addrs)) => addrs,
+        Ok(Err(e)) => {
+            log::debug!("[{}] 重新解析 {} 失败, 使用探测时的地址: {}", t.name, host, e);
+            return;
+        }
+        Err(_) => {
+            log::debug!("[{}] 重新解析 {} 超时, 使用探测时的地址", t.name, host);
+            return;
+        }
+    };
+    if resolved.contains(&t.addr) {
+        t.fallback = t.fallback.filter(|a| resolved.contains(a));
+        return;
+    }
+    let Some(&addr) = t.prefer.filter(&resolved).first() else { return };
+    log::info!("[{}] {} 的解析结果已变化, 改用 {} (探测时: {})", t.name, host, addr, t.addr);
+    t.addr = addr;
+    t.fallback = None;
+}
+
+/// 先连接 primary, delay 内没有结果时同时连接 hedge, 使用先连上的一个并取消另一个
+/// 返回连接结果、是否由 hedge 连上以及是否尝试过 hedge; 被取消的连接尚未发送任何数据 (PROXY 头在连上后才写)
+async fn hedged_connect(
+    primary: &BestTarget,
+    hedge: Option<&BestTarget>,
+    delay: Duration,
+    race_delay: Duration,
+    opts: &net::SocketOptions,
+    traffic: &TrafficStats,
+) -> (io::Result<TcpStream>, bool, bool) {
+    let primary_opts = opts.with_bind(primary.outbound_bind.as_ref());
+    let first = net::connect_racing(primary.addr, primary.fallback, race_delay, &primary_opts);
+    tokio::pin!(first);
+    let Some(alt) = hedge else {
+        let res = first.await;
+        traffic.record(&primary.name, res.is_ok());
+        return (res, false, false);
+    };
+    tokio::select! {
+        res = &mut first => {
+            traffic.record(&primary.name, res.is_ok());
+            return (res, false, false);
+        }
+        _ = tokio::time::sleep(delay) => {}
+    }
+
+    log::info!("[{}] {}ms 内未连上, 同时连接次优节点 [{}]", primary.name, delay.as_millis(), alt.name);
+    let alt_opts = opts.with_bind(alt.outbound_bind.as_ref());
+    let second = net::connect_racing(alt.addr, alt.fallback, race_delay, &alt_opts);
+    tokio::pin!(second);
+    let (mut first_err, mut second_err) = (None, None);
+    loop {
+        tokio::select! {
+            res = &mut first, if first_err.is_none() => {
+                traffic.record(&primary.name, res.is_ok());
+                match res {
+                    Ok(s) => {
+                        log::info!("对冲建连: [{}] 先连上", primary.name);
+                        return (Ok(s), false, true);
+                    }
+                    Err(e) => first_err = Some(e),
+                }
+            }
+            res = &mut second, if second_err.is_none() => {
+                traffic.record(&alt.name, res.is_ok());
+                match res {
+                    Ok(s) => {
+                        log::info!("对冲建连: [{}] 先连上", alt.name);
+                        return (Ok(s), true, true);
+                    }
+                    Err(e) => second_err = Some(e),
+                }
+            }
         }
+        if second_err.is_some() {
+            if let Some(e) = first_err.take() {
+                log::warn!("对冲建连: [{}] 和 [{}] 都连接失败", primary.name, alt.name);
+                return (Err(e), false, true);
+            }
+        }
+    }
+}
+
+/// 经反向隧道转发: PROXY 头和已读数据作为流的首批数据发出
+async fn forward_via_tunnel<C>(
+    client: C,
+    peer: SocketAddr,
+    client_addr: Option<SocketAddr>,
+    early_data: Vec<u8>,
+    target: &BestTarget,
+    stream: tunnel::TunnelStream,
+    config: &Config,
+) -> Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    if let Some(header) = outbound_proxy_header(config, client_addr, target) {
+        stream.send(&header).await?;
+    }
+    selfprobe::forwarded(Some(peer), &target.name);
+    if !early_data.is_empty() {
+        stream.send(&early_data).await?;
+    }
+    let (up, down) = tunnel::relay(client, stream).await?;
+    stats::add_bytes(up, down);
+    Ok(())
+}
+
+/// 按配置构造发往目标的 PROXY 头
+fn outbound_proxy_header(config: &Config, client_addr: Option<SocketAddr>, target: &BestTarget) -> Option<Vec<u8>> {
+    let version = config.proxy_protocol.as_deref().filter(|v| matches!(*v, "v1" | "v2"))?;
+    let src_addr = client_addr?;
+    let (src, dst) = match config.cross_family_policy {
+        CrossFamilyPolicy::Map => map_to_same_family(src_addr, target.addr),
+        _ => (src_addr, target.addr),
+    };
+    // v1 是文本格式, 不支持 TLV
+    match version {
+        "v1" => Some(proxy::build_proxy_v1_header(src, dst)),
+        _ => Some(proxy::build_proxy_v2_header(src, dst, &target.proxy_tlvs)),
+    }
+}
+
+/// 判断两个地址是否属于不同协议族 (v4-mapped 地址视为 IPv4)
+fn is_cross_family(a: SocketAddr, b: SocketAddr) -> bool {
+    a.ip().to_canonical().is_ipv4() != b.ip().to_canonical().is_ipv4()
+}
+
+/// 将一对地址统一到同一协议族: 都能表示为 IPv4 时用 IPv4, 否则把 IPv4 一侧转成 v4-mapped IPv6
+fn map_to_same_family(src: SocketAddr, dst: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let canonical = |a: SocketAddr| SocketAddr::new(a.ip().to_canonical(), a.port());
+    let (src, dst) = (canonical(src), canonical(dst));
+    if src.is_ipv4() && dst.is_ipv4() {
+        return (src, dst);
     }
-    header
+    let to_v6 = |a: SocketAddr| match a.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), a.port()),
+        IpAddr::V6(_) => a,
+    };
+    (to_v6(src), to_v6(dst))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    // 两个目标指向同一个本地监听, 只靠注入的延迟 / 丢包区分
+    async fn scored_round(config: &Config, pool_configs: &[config::PoolConfig]) -> Vec<Vec<BestTarget>> {
+        let resolved = net::ResolveCache::default();
+        let mut results = Vec::new();
+        for p in pool_configs {
+            results.push(perform_scoring_check(config, &p.targets, &resolved).await);
+        }
+        results
+    }
+
+    // 经 config::load 读取, 与启动时的默认值和校验一致
+    fn load_yaml(name: &str, yaml: &str) -> Config {
+        let path = std::env::temp_dir().join(format!("forward-optimal-test-{}-{}.yaml", name, std::process::id()));
+        std::fs::write(&path, yaml).unwrap();
+        let mut services = config::load(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).ok();
+        services.remove(0)
+    }
+
+    fn load_config(name: &str, port: u16, latency_a: u128, latency_b: u128, loss_a: f64) -> Config {
+        let yaml = format!(
+            "bind_addr: 127.0.0.1:0
+update_interval: 1
+probe_count: 4
+probe_timeout_ms: 500
+switch_connection_threshold: 1
+switch_marginal_ms: 50
+targets:
+  - name: a
+    addr: 127.0.0.1:{port}
+    fault_inject: {{ latency_ms: {latency_a}, loss: {loss_a} }}
+  - name: b
+    addr: 127.0.0.1:{port}
+    fault_inject: {{ latency_ms: {latency_b} }}
+"
+        );
+        // 与 --fault-inject 启动时相同
+        let mut config = load_yaml(name, &yaml);
+        config.fault_seed = Some(7);
+        config
+    }
+
+    async fn listener() -> u16 {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = listener.accept().await {
+                drop(conn);
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn injected_latency_and_loss_drive_failover() {
+        let port = listener().await;
+        let config = load_config("failover", port, 0, 80, 0.0);
+        let pool_configs = config.pool_list();
+        let mut s = State::new(&config);
+        install_round(&mut s, &pool_configs, scored_round(&config, &pool_configs).await, None, &config);
+        assert_eq!(s.select().unwrap().name, "a");
+
+        // a 全部丢包后不再可用, 切换到 b
+        let config = load_config("failover", port, 0, 80, 1.0);
+        let pool_configs = config.pool_list();
+        let results = scored_round(&config, &pool_configs).await;
+        assert!(results[0].iter().all(|t| t.name != "a"));
+        install_round(&mut s, &pool_configs, results, Some("a"), &config);
+        assert_eq!(s.select().unwrap().name, "b");
+    }
+
+    #[tokio::test]
+    async fn marginal_gain_keeps_busy_incumbent() {
+        let port = listener().await;
+        let config = load_config("marginal", port, 0, 100, 0.0);
+        let pool_configs = config.pool_list();
+        let mut s = State::new(&config);
+        install_round(&mut s, &pool_configs, scored_round(&config, &pool_configs).await, None, &config);
+        assert_eq!(s.select().unwrap().name, "a");
+
+        // b 只领先约 30ms (小于 switch_marginal_ms), a 上有连接时留在 a
+        let config = load_config("marginal", port, 130, 100, 0.0);
+        let pool_configs = config.pool_list();
+        let guard = s.conns.acquire("a");
+        install_round(&mut s, &pool_configs, scored_round(&config, &pool_configs).await, Some("a"), &config);
+        assert_eq!(s.select().unwrap().name, "a");
+        assert_eq!(s.hold.as_deref(), Some("a"));
+
+        // 没有连接时直接切换
+        drop(guard);
+        install_round(&mut s, &pool_configs, scored_round(&config, &pool_configs).await, Some("a"), &config);
+        assert_eq!(s.select().unwrap().name, "b");
+    }
+
+    #[tokio::test]
+    async fn forwarding_uses_probed_address() {
+        // 监听 localhost 解析出的第一个地址, 探测选中的地址就是转发连接的地址
+        let first = tokio::net::lookup_host("localhost:0").await.unwrap().next().unwrap();
+        let listener = tokio::net::TcpListener::bind(first).await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = listener.accept().await {
+                tx.send(conn.local_addr().unwrap()).ok();
+            }
+        });
+        let yaml = format!(
+            "bind_addr: 127.0.0.1:0
+update_interval: 1
+probe_count: 2
+resolve_pin_secs: 60
+targets:
+  - name: a
+    addr: localhost:{}
+",
+            listen_addr.port()
+        );
+        let config = load_yaml("pinned", &yaml);
+        let resolved = net::ResolveCache::default();
+        let probed = perform_scoring_check(&config, &config.targets, &resolved).await;
+        assert_eq!(probed.len(), 1);
+        assert_eq!(probed[0].addr, listen_addr);
+
+        // 窗口内沿用地址, 本轮不计 DNS 耗时
+        let pinned = perform_scoring_check(&config, &config.targets, &resolved).await;
+        assert_eq!(pinned[0].addr, listen_addr);
+        assert_eq!(pinned[0].dns_ms, 0.0);
+
+        while rx.try_recv().is_ok() {}
+        let delay = Duration::from_millis(config.hedge_delay_ms);
+        let race_delay = Duration::from_millis(config.happy_eyeballs_delay_ms);
+        let (conn, _, _) =
+            hedged_connect(&pinned[0], None, delay, race_delay, &config.socket_options(), &TrafficStats::default()).await;
+        assert_eq!(conn.unwrap().peer_addr().unwrap(), listen_addr);
+        assert_eq!(rx.recv().await, Some(listen_addr));
+    }
+
+    #[tokio::test]
+    async fn mirror_splits_directions_and_includes_early_data() {
+        let sink = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let yaml = format!(
+            "bind_addr: 127.0.0.1:0
+update_interval: 1
+mirror_addr: \"{}\"
+mirror_both_directions: true
+targets: [{{ name: a, addr: \"127.0.0.1:{}\" }}]
+",
+            sink.local_addr().unwrap(),
+            listener().await
+        );
+        let config = load_yaml("mirror", &yaml);
+        let target = perform_scoring_check(&config, &config.targets, &net::ResolveCache::default()).await.remove(0);
+        let (client, mut client_peer) = tokio::io::duplex(1024);
+        let (server, mut server_peer) = tokio::io::duplex(1024);
+        let relay = relay_streams(client, server, b"early ", &target, &config);
+        let peers = async {
+            client_peer.write_all(b"request").await.unwrap();
+            client_peer.shutdown().await.unwrap();
+            let mut got = Vec::new();
+            server_peer.read_to_end(&mut got).await.unwrap();
+            assert_eq!(got, b"early request");
+            server_peer.write_all(b"response").await.unwrap();
+            server_peer.shutdown().await.unwrap();
+            got.clear();
+            client_peer.read_to_end(&mut got).await.unwrap();
+            assert_eq!(got, b"response");
+        };
+        let (res, ()) = tokio::join!(relay, peers);
+        res.unwrap();
+
+        // 先接受的是上行镜像, 早到数据在最前面; 下行单独一条连接
+        let mut mirrored = Vec::new();
+        for _ in 0..2 {
+            let (mut conn, _) = sink.accept().await.unwrap();
+            let mut got = Vec::new();
+            conn.read_to_end(&mut got).await.unwrap();
+            mirrored.push(got);
+        }
+        assert_eq!(mirrored, [&b"early request"[..], b"response"]);
+    }
+
+    // 在本地监听上运行接受循环; 没有探测过的目标不可选, 通过来源检查的连接收到 reject_response
+    async fn serve(config: Config) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(RwLock::new(State::new(&config)));
+        let live = Arc::new(ArcSwap::from_pointee(config));
+        tokio::spawn(accept_loop(listener, live, Arc::default(), None, state, Arc::default()));
+        addr
+    }
+
+    // 以 PROXY v1 头声明来源地址并读完回应; 被来源检查关闭时回应为空
+    async fn send_proxied(addr: SocketAddr, src: &str) -> Vec<u8> {
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        conn.write_all(&proxy::build_proxy_v1_header(src.parse().unwrap(), addr)).await.unwrap();
+        let mut reply = Vec::new();
+        let read = conn.read_to_end(&mut reply);
+        tokio::time::timeout(Duration::from_secs(5), read).await.unwrap().unwrap();
+        reply
+    }
+
+    #[tokio::test]
+    async fn acl_applies_to_proxied_client() {
+        let yaml = "bind_addr: 127.0.0.1:0
+update_interval: 1
+accept_proxy_protocol: true
+proxy_protocol_trusted: [127.0.0.1]
+deny: [198.51.100.0/24]
+reject_response: { body: rejected }
+targets: [{ name: a, addr: \"127.0.0.1:1\" }]
+";
+        let addr = serve(load_yaml("proxied-acl", yaml)).await;
+        assert!(send_proxied(addr, "198.51.100.7:4000").await.is_empty());
+        assert_eq!(send_proxied(addr, "203.0.113.9:4000").await, b"rejected");
+
+        // 负载均衡本身在 allow 之外也不影响它转来的连接
+        let allowed = yaml.replace("deny: [198.51.100.0/24]", "allow: [203.0.113.0/24]");
+        let addr = serve(load_yaml("proxied-allow", &allowed)).await;
+        assert_eq!(send_proxied(addr, "203.0.113.9:4000").await, b"rejected");
+        assert!(send_proxied(addr, "198.51.100.7:4000").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn countries_apply_to_proxied_client() {
+        let db = std::env::temp_dir().join(format!("forward-optimal-test-countries-{}.mmdb", std::process::id()));
+        std::fs::write(&db, geoip::tests::build(24, 4, &[("198.51.100.0", 24, "KP"), ("203.0.113.0", 24, "JP")])).unwrap();
+        let yaml = format!(
+            "bind_addr: 127.0.0.1:0
+update_interval: 1
+accept_proxy_protocol: true
+proxy_protocol_trusted: [127.0.0.1]
+geoip_db: {}
+deny_countries: [KP]
+reject_response: {{ body: rejected }}
+targets: [{{ name: a, addr: \"127.0.0.1:1\" }}]
+",
+            db.display()
+        );
+        let config = load_yaml("proxied-countries", &yaml);
+        std::fs::remove_file(&db).ok();
+        let addr = serve(config).await;
+        assert!(send_proxied(addr, "198.51.100.7:4000").await.is_empty());
+        assert_eq!(send_proxied(addr, "203.0.113.9:4000").await, b"rejected");
+    }
+
+    #[test]
+    fn per_ip_limit_follows_proxied_client() {
+        let limits = Arc::new(InboundLimits::default());
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let lb = ip("127.0.0.1");
+        // 负载均衡本身不计单 IP 上限, 读出 PROXY 头后改记到真实客户端
+        let mut first = limits.admit(lb, None, None).ok().unwrap();
+        let mut second = limits.admit(lb, None, None).ok().unwrap();
+        assert!(first.rebind(ip("203.0.113.9"), Some(1)).is_ok());
+        assert!(matches!(second.rebind(ip("203.0.113.9"), Some(1)), Err(LimitExceeded::PerIp(1))));
+        assert!(second.rebind(ip("203.0.113.10"), Some(1)).is_ok());
+        // 释放后真实客户端的名额归还, 负载均衡的登记已转走
+        drop(first);
+        assert!(limits.admit(ip("203.0.113.9"), None, Some(1)).is_ok());
+        assert!(limits.admit(lb, None, Some(1)).is_ok());
+    }
+}
\ No newline at end of file
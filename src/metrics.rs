@@ -0,0 +1,126 @@
+use anyhow::Result;
+use arc_swap::ArcSwap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::relay;
+use crate::state::{BestTarget, Snapshot};
+use crate::stats;
+
+// 请求头最大长度, 超出直接断开
+const MAX_REQUEST_SIZE: usize = 8192;
+const PREFIX: &str = "forward_optimal_";
+
+// 节点指标: 名称, 说明, 取值
+type Gauge<'a> = (&'static str, &'static str, &'a dyn Fn(&BestTarget) -> String);
+
+/// Prometheus 指标接口, 只响应 GET /metrics
+pub async fn serve(addr: String, published: Arc<ArcSwap<Snapshot>>) -> Result<()> {
+    let listener = TcpListener::bind(&addr).await?;
+    log::info!("Prometheus 指标接口启动: {}", addr);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let published = published.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(stream, &published).await {
+                log::debug!("指标接口请求处理失败: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_request(mut stream: TcpStream, published: &ArcSwap<Snapshot>) -> Result<()> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 || buf.len() + n > MAX_REQUEST_SIZE {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let head = String::from_utf8_lossy(&buf);
+    let mut parts = head.split_whitespace();
+    let (status, body) = match (parts.next().unwrap_or(""), parts.next().unwrap_or("")) {
+        ("GET", "/metrics") => ("200 OK", render(&published.load())),
+        ("GET", _) => ("404 Not Found", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+    };
+    let resp = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(resp.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// 全局计数器和各节点本轮探测结果, 节点按 pool / target 标签区分
+fn render(s: &Snapshot) -> String {
+    let mut out = String::new();
+    let counters: [(&str, &str, &AtomicU64); 8] = [
+        ("connections_total", "已转发的连接数", &stats::CONNECTIONS),
+        ("rejected_total", "被拒绝的连接数", &stats::REJECTED),
+        ("limited_total", "超出入站连接数上限被关闭的连接数", &stats::LIMITED),
+        ("denied_total", "来源地址不被允许而关闭的连接数", &stats::DENIED),
+        ("bytes_up_total", "客户端 -> 目标字节数", &stats::BYTES_UP),
+        ("bytes_down_total", "目标 -> 客户端字节数", &stats::BYTES_DOWN),
+        ("switches_total", "路由切换次数", &stats::SWITCHES),
+        ("mirror_drops_total", "流量镜像丢弃的数据块数", &relay::MIRROR_DROPS),
+    ];
+    for (name, help, counter) in counters {
+        header(&mut out, name, "counter", help);
+        let _ = writeln!(out, "{}{} {}", PREFIX, name, counter.load(Ordering::Relaxed));
+    }
+
+    let selected = s.select().map(|t| t.name.as_str());
+    let targets: Vec<(&str, &BestTarget)> =
+        s.pools.iter().flat_map(|p| p.ranked.iter().map(move |t| (p.name.as_str(), t))).collect();
+    let gauges: [Gauge; 11] = [
+        ("target_score", "用于选择的评分 (平滑后)", &|t| t.score.to_string()),
+        ("target_raw_score", "本轮探测的原始评分", &|t| t.raw_score.to_string()),
+        ("target_rtt_min_ms", "本轮最低延迟 (ms)", &|t| t.min_ms.to_string()),
+        ("target_rtt_max_ms", "本轮最高延迟 (ms)", &|t| t.max_ms.to_string()),
+        ("target_rtt_avg_ms", "本轮平均延迟 (ms)", &|t| t.avg_ms.to_string()),
+        ("target_rtt_stddev_ms", "本轮延迟标准差 (ms)", &|t| format!("{:.3}", t.jitter_ms)),
+        ("target_loss", "本轮丢包次数", &|t| t.loss.to_string()),
+        ("target_dns_ms", "本轮 DNS 解析耗时 (ms)", &|t| format!("{:.3}", t.dns_ms)),
+        ("target_active_connections", "活跃转发连接数", &|t| s.conns.get(&t.name).to_string()),
+        ("target_traffic_bytes", "配额计数周期内的累计转发字节数 (双向合计)", &|t| s.usage.bytes(&t.group).to_string()),
+        ("target_selected", "是否为当前最优节点", &|t| u8::from(selected == Some(t.name.as_str())).to_string()),
+    ];
+    for (name, help, value) in gauges {
+        header(&mut out, name, "gauge", help);
+        for (pool, t) in &targets {
+            let _ = writeln!(
+                out,
+                "{}{}{{pool=\"{}\",target=\"{}\"}} {}",
+                PREFIX,
+                name,
+                escape(pool),
+                escape(&t.name),
+                value(t)
+            );
+        }
+    }
+    header(&mut out, "available_targets", "gauge", "可用节点数");
+    let _ = writeln!(out, "{}available_targets {}", PREFIX, targets.len());
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {}{} {}", PREFIX, name, help);
+    let _ = writeln!(out, "# TYPE {}{} {}", PREFIX, name, kind);
+}
+
+/// 标签值中的反斜杠、双引号和换行需要转义
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
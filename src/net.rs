@@ -0,0 +1,740 @@
+use serde::Deserialize;
+use socket2::TcpKeepalive;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+use crate::config::Keepalive;
+use crate::dns;
+
+/// 出站连接的套接字参数
+#[derive(Debug, Clone, Default)]
+pub struct SocketOptions {
+    pub tcp_congestion: Option<String>,
+    pub local_ports: Option<PortRange>,
+    pub recv_buffer: Option<u32>,
+    pub send_buffer: Option<u32>,
+    pub bind: Option<OutboundBind>,
+    pub fastopen: bool, // TCP_FASTOPEN_CONNECT, 仅 Linux
+    pub spoof_source: Option<IpAddr>, // 透明代理: 以客户端地址为源地址 (IP_TRANSPARENT), 协议族与目标不同时不使用
+    pub mark: Option<u32>,            // SO_MARK, 供策略路由匹配, 仅 Linux
+    pub dscp: Option<Dscp>,
+}
+
+impl SocketOptions {
+    /// 改用目标的出站绑定
+    pub fn with_bind(&self, bind: Option<&OutboundBind>) -> SocketOptions {
+        SocketOptions { bind: bind.cloned(), ..self.clone() }
+    }
+
+    /// 与另一组参数相比, 会影响出站路径 (源地址/端口、策略路由) 的不同项
+    pub fn egress_differences(&self, other: &SocketOptions) -> Vec<&'static str> {
+        let mut diff = Vec::new();
+        if self.local_ports != other.local_ports {
+            diff.push("local_port_range");
+        }
+        if self.dscp != other.dscp {
+            diff.push("dscp");
+        }
+        diff
+    }
+}
+
+/// 可以改为以 RST 关闭的连接, 用于强制断开客户端
+pub trait Abort {
+    /// 设置 SO_LINGER 为 0, 之后关闭连接时直接发送 RST
+    fn abort_on_close(&self);
+}
+
+impl Abort for TcpStream {
+    fn abort_on_close(&self) {
+        let _ = socket2::SockRef::from(self).set_linger(Some(Duration::ZERO));
+    }
+}
+
+/// 入站连接底层的明文 TCP 连接, 用于零拷贝转发; TLS 终止后的连接为 None
+pub trait Plain {
+    fn plain(&mut self) -> Option<&mut TcpStream>;
+}
+
+impl Plain for TcpStream {
+    fn plain(&mut self) -> Option<&mut TcpStream> {
+        Some(self)
+    }
+}
+
+// 监听队列长度, 与 TcpListener::bind 相同
+const LISTEN_BACKLOG: u32 = 1024;
+
+/// 打开监听: sockets 为 1 时与 TcpListener::bind 相同;
+/// 大于 1 时打开多个设置了 SO_REUSEPORT 的套接字, 由内核在它们之间分配新连接;
+/// transparent 时设置 IP_TRANSPARENT, 接受 TPROXY 重定向的、目的地址不是本机的连接
+pub async fn listen(addr: &str, sockets: usize, transparent: bool) -> io::Result<Vec<TcpListener>> {
+    let inherited = take_inherited(addr).await?;
+    if !inherited.is_empty() {
+        return inherited
+            .into_iter()
+            .map(|l| {
+                let local = l.local_addr()?;
+                log::info!("使用 systemd 传入的监听套接字: {}", local);
+                if transparent {
+                    set_transparent(socket2::SockRef::from(&l), local.is_ipv4())?;
+                }
+                TcpListener::from_std(l)
+            })
+            .collect();
+    }
+    if sockets <= 1 && !transparent {
+        return Ok(vec![TcpListener::bind(addr).await?]);
+    }
+    let addr = tokio::net::lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "没有解析到地址"))?;
+    (0..sockets.max(1))
+        .map(|_| {
+            let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+            socket.set_reuseaddr(true)?;
+            #[cfg(unix)]
+            if sockets > 1 {
+                socket.set_reuseport(true)?;
+            }
+            if transparent {
+                set_transparent(socket2::SockRef::from(&socket), addr.is_ipv4())?;
+            }
+            socket.bind(addr)?;
+            socket.listen(LISTEN_BACKLOG)
+        })
+        .collect()
+}
+
+// systemd 传入的第一个描述符 (SD_LISTEN_FDS_START)
+#[cfg(target_os = "linux")]
+const LISTEN_FDS_START: i32 = 3;
+
+// systemd socket activation 传入、尚未被服务使用的监听套接字
+static INHERITED: Mutex<Vec<std::net::TcpListener>> = Mutex::new(Vec::new());
+
+/// 接管 systemd socket activation 传入的监听套接字 (LISTEN_PID / LISTEN_FDS), 启动时调用一次;
+/// 之后 listen 遇到相同监听地址时直接使用, 不再绑定。读取后清除环境变量, 不传给子进程
+#[cfg(target_os = "linux")]
+pub fn inherit_listeners() {
+    let var = |key: &str| std::env::var(key).ok().and_then(|v| v.parse::<u32>().ok());
+    let (pid, fds) = (var("LISTEN_PID"), var("LISTEN_FDS"));
+    for key in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(key);
+    }
+    let (Some(pid), Some(fds)) = (pid, fds) else { return };
+    if pid != std::process::id() {
+        return;
+    }
+    let mut inherited = INHERITED.lock().unwrap();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + fds as i32 {
+        use std::os::fd::{BorrowedFd, FromRawFd};
+        // SAFETY: LISTEN_PID 与本进程一致时, 3 起的 LISTEN_FDS 个描述符由 systemd 传给本进程
+        let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+        let sock = socket2::SockRef::from(&borrowed);
+        if sock.r#type().ok() != Some(socket2::Type::STREAM) || !sock.is_listener().unwrap_or(false) {
+            log::warn!("systemd 传入的描述符 {} 不是 TCP 监听套接字, 已忽略", fd);
+            continue;
+        }
+        if let Err(e) = sock.set_nonblocking(true).and_then(|_| sock.set_cloexec(true)) {
+            log::warn!("systemd 传入的描述符 {} 无法使用: {}", fd, e);
+            continue;
+        }
+        // SAFETY: 描述符归本进程所有, 只在这里接管一次
+        inherited.push(unsafe { std::net::TcpListener::from_raw_fd(fd) });
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn inherit_listeners() {}
+
+/// 取出监听地址与 addr 相同的传入套接字; 通配地址 (0.0.0.0 / [::]) 端口相同即视为相同
+async fn take_inherited(addr: &str) -> io::Result<Vec<std::net::TcpListener>> {
+    if INHERITED.lock().unwrap().is_empty() {
+        return Ok(Vec::new());
+    }
+    let wanted: Vec<SocketAddr> = tokio::net::lookup_host(addr).await?.collect();
+    let same = |a: SocketAddr| {
+        wanted.iter().any(|w| *w == a || (w.port() == a.port() && w.ip().is_unspecified() && a.ip().is_unspecified()))
+    };
+    let mut inherited = INHERITED.lock().unwrap();
+    let (taken, rest) = std::mem::take(&mut *inherited).into_iter().partition(|l| l.local_addr().is_ok_and(same));
+    *inherited = rest;
+    Ok(taken)
+}
+
+// 解析时间, 候选地址
+type Resolved = (Instant, Vec<SocketAddr>);
+
+/// 固定时间窗口内沿用的 DNS 解析结果, 按目标地址索引
+#[derive(Default)]
+pub struct ResolveCache(Mutex<HashMap<String, Resolved>>);
+
+impl ResolveCache {
+    /// 窗口内沿用上次的地址, 否则重新解析 (servers 为空时使用系统解析); 返回 (候选地址, 解析耗时 ms, 是否沿用)
+    /// 沿用时本轮没有解析, 耗时为 0; 候选地址按解析顺序排列并去重, 至少有一个
+    pub async fn resolve(
+        &self,
+        host: &str,
+        window: Duration,
+        servers: &[dns::Server],
+    ) -> io::Result<(Vec<SocketAddr>, f64, bool)> {
+        if let Some((at, addrs)) = self.0.lock().unwrap().get(host) {
+            if at.elapsed() < window {
+                return Ok((addrs.clone(), 0.0, true));
+            }
+        }
+        let start = Instant::now();
+        let mut addrs: Vec<SocketAddr> = Vec::new();
+        for addr in dns::lookup(host, servers).await? {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+        let dns_ms = start.elapsed().as_secs_f64() * 1000.0;
+        if addrs.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "没有解析到地址"));
+        }
+        if !window.is_zero() {
+            self.0.lock().unwrap().insert(host.to_string(), (Instant::now(), addrs.clone()));
+        }
+        Ok((addrs, dns_ms, false))
+    }
+
+    /// 丢弃沿用的地址, 下次重新解析
+    pub fn forget(&self, host: &str) {
+        self.0.lock().unwrap().remove(host);
+    }
+}
+
+/// 每个协议族只保留第一个地址, 保持原顺序
+pub fn first_per_family(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let mut first: Vec<SocketAddr> = Vec::with_capacity(2);
+    for &addr in addrs {
+        if !first.iter().any(|a| a.is_ipv4() == addr.is_ipv4()) {
+            first.push(addr);
+        }
+    }
+    first
+}
+
+/// 本地端口范围, 配置写法 "40000-40100"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let (a, b) = s.split_once('-').unwrap_or((&s, &s));
+        let parse = |p: &str| p.trim().parse::<u16>().map_err(|_| format!("无效的端口范围: {}", s));
+        let (start, end) = (parse(a)?, parse(b)?);
+        if start == 0 || start > end {
+            return Err(format!("无效的端口范围: {}", s));
+        }
+        Ok(PortRange { start, end })
+    }
+}
+
+impl PortRange {
+    fn len(&self) -> usize {
+        (self.end - self.start) as usize + 1
+    }
+}
+
+/// 地址段, 配置写法 "10.0.0.0/8"、"2001:db8::/32", 不带前缀长度时为单个地址
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("无效的地址段: {}", s);
+        let (ip, prefix) = s.trim().split_once('/').map_or((s.trim(), None), |(a, p)| (a, Some(p)));
+        let addr: IpAddr = ip.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse::<u8>().ok().filter(|&p| p <= max).ok_or_else(invalid)?,
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl Cidr {
+    /// 地址是否在该段内; 双栈监听收到的 IPv4 映射地址按 IPv4 比较
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let masked = |bits: u128, width: u32| match self.prefix {
+            0 => 0,
+            p => bits >> (width - p as u32),
+        };
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => masked(u32::from(a) as u128, 32) == masked(u32::from(b) as u128, 32),
+            (IpAddr::V6(a), IpAddr::V6(b)) => masked(u128::from(a), 128) == masked(u128::from(b), 128),
+            _ => false,
+        }
+    }
+}
+
+/// 出站连接的源地址或网卡, 配置写法 "192.0.2.10" 或网卡名 "eth1" (SO_BINDTODEVICE, 仅 Linux)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum OutboundBind {
+    Addr(IpAddr),
+    Device(String),
+}
+
+// 网卡名最大长度 (IFNAMSIZ - 1)
+const IFNAME_MAX: usize = 15;
+
+impl TryFrom<String> for OutboundBind {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        if let Ok(ip) = s.trim().parse() {
+            return Ok(OutboundBind::Addr(ip));
+        }
+        if s.is_empty() || s.len() > IFNAME_MAX || s.contains(|c: char| c.is_whitespace() || c == '/' || c == ':') {
+            return Err(format!("无效的 outbound_bind: {} (应为本机地址或网卡名)", s));
+        }
+        if cfg!(not(target_os = "linux")) {
+            return Err(format!("按网卡绑定 (outbound_bind: {}) 只支持 Linux", s));
+        }
+        Ok(OutboundBind::Device(s))
+    }
+}
+
+impl std::fmt::Display for OutboundBind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutboundBind::Addr(ip) => write!(f, "{}", ip),
+            OutboundBind::Device(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// DSCP 标记, 配置写法为 0 ~ 63 的数字或名称: EF、AF11 ~ AF43、CS0 ~ CS7、BE、LE
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "DscpSpec")]
+pub struct Dscp(pub u8);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DscpSpec {
+    Code(u64),
+    Name(String),
+}
+
+impl TryFrom<DscpSpec> for Dscp {
+    type Error = String;
+
+    fn try_from(spec: DscpSpec) -> Result<Self, Self::Error> {
+        let name = match spec {
+            DscpSpec::Code(n) if n <= 63 => return Ok(Dscp(n as u8)),
+            DscpSpec::Code(n) => return Err(format!("dscp 必须在 0 到 63 之间, 当前: {}", n)),
+            DscpSpec::Name(s) => s.trim().to_ascii_uppercase(),
+        };
+        let digit = |c: u8| (c as char).to_digit(10).map(|d| d as u8);
+        let code = match name.as_bytes() {
+            b"EF" => Some(46),
+            b"BE" | b"DF" => Some(0),
+            b"LE" => Some(1),
+            [b'C', b'S', n] => digit(*n).filter(|&n| n <= 7).map(|n| n * 8),
+            // AFxy: 类别 x (1 ~ 4), 丢弃优先级 y (1 ~ 3)
+            [b'A', b'F', x, y] => match (digit(*x), digit(*y)) {
+                (Some(x @ 1..=4), Some(y @ 1..=3)) => Some(x * 8 + y * 2),
+                _ => None,
+            },
+            _ => name.parse().ok().filter(|&n| n <= 63),
+        };
+        code.map(Dscp).ok_or_else(|| format!("无效的 dscp: {} (应为 0 ~ 63 或 EF、AF41、CS1 等名称)", name))
+    }
+}
+
+/// 设置发出报文的 DSCP (TOS / Traffic Class 的高 6 位); IPv6 套接字上的 IPv4 映射连接同时设置 IP_TOS
+pub fn set_dscp(socket: socket2::SockRef<'_>, dscp: Dscp, v6: bool) -> io::Result<()> {
+    let tos = u32::from(dscp.0) << 2;
+    if !v6 {
+        return socket.set_tos_v4(tos);
+    }
+    let _ = socket.set_tos_v4(tos);
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd"))]
+    socket.set_tclass_v6(tos)?;
+    Ok(())
+}
+
+/// outbound_bind 指定的源地址; 与目标协议族不同时返回错误
+fn source_ip(bind: Option<&OutboundBind>, target: IpAddr) -> io::Result<Option<IpAddr>> {
+    match bind {
+        Some(&OutboundBind::Addr(ip)) if ip.is_ipv4() != target.is_ipv4() => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("outbound_bind {} 与目标 {} 的协议族不同", ip, target),
+        )),
+        Some(&OutboundBind::Addr(ip)) => Ok(Some(ip)),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_transparent(socket: socket2::SockRef<'_>, v4: bool) -> io::Result<()> {
+    let res = if v4 { socket.set_ip_transparent_v4(true) } else { socket.set_ip_transparent_v6(true) };
+    res.map_err(|e| io::Error::new(e.kind(), format!("无法设置 IP_TRANSPARENT (需要 CAP_NET_ADMIN): {}", e)))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_transparent(_socket: socket2::SockRef<'_>, _v4: bool) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// 出站连接的源地址: 透明代理时为客户端地址, 否则为 outbound_bind 中的地址
+fn local_source(opts: &SocketOptions, target: IpAddr) -> io::Result<Option<IpAddr>> {
+    match opts.spoof_source.filter(|ip| ip.is_ipv4() == target.is_ipv4()) {
+        Some(ip) => Ok(Some(ip)),
+        None => source_ip(opts.bind.as_ref(), target),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_mark(socket: &socket2::SockRef<'_>, mark: u32) -> io::Result<()> {
+    socket
+        .set_mark(mark)
+        .map_err(|e| io::Error::new(e.kind(), format!("无法设置 fwmark {} (需要 CAP_NET_ADMIN): {}", mark, e)))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_mark(_socket: &socket2::SockRef<'_>, _mark: u32) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// 启动时确认有权限设置 fwmark, 否则出站流量会绕过策略路由
+pub fn check_mark(mark: u32) -> io::Result<()> {
+    let socket = TcpSocket::new_v4()?;
+    set_mark(&socket2::SockRef::from(&socket), mark)
+}
+
+#[cfg(target_os = "linux")]
+fn bind_device(socket: socket2::SockRef<'_>, name: &str) -> io::Result<()> {
+    socket
+        .bind_device(Some(name.as_bytes()))
+        .map_err(|e| io::Error::new(e.kind(), format!("无法绑定网卡 {}: {}", name, e)))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_socket: socket2::SockRef<'_>, _name: &str) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// 在 connect 之前设置 fwmark, 并按 outbound_bind 绑定源地址 (端口由系统分配) 或网卡, 用于 UDP / ICMP 等非 TCP 出站套接字
+pub fn bind_outbound(
+    socket: socket2::SockRef<'_>,
+    bind: Option<&OutboundBind>,
+    mark: Option<u32>,
+    target: IpAddr,
+) -> io::Result<()> {
+    if let Some(mark) = mark {
+        set_mark(&socket, mark)?;
+    }
+    if let Some(OutboundBind::Device(name)) = bind {
+        bind_device(socket, name)?;
+    } else if let Some(ip) = source_ip(bind, target)? {
+        socket.bind(&SocketAddr::new(ip, 0).into())?;
+    }
+    Ok(())
+}
+
+// 端口轮转起点, 避免每次都从范围开头重试
+static NEXT_PORT: AtomicUsize = AtomicUsize::new(0);
+
+// 内核拒绝拥塞算法时只告警一次, 之后降为 debug
+static CONGESTION_WARNED: AtomicBool = AtomicBool::new(false);
+// 同上, TCP Fast Open
+static FASTOPEN_WARNED: AtomicBool = AtomicBool::new(false);
+
+// 监听端 TFO 队列长度: 尚未完成握手却已携带数据的连接数上限
+#[cfg(target_os = "linux")]
+const FASTOPEN_QUEUE: libc::c_int = 256;
+
+/// 按参数建立出站 TCP 连接
+pub async fn connect(addr: SocketAddr, opts: &SocketOptions) -> io::Result<TcpStream> {
+    match opts.local_ports {
+        Some(range) => connect_from_range(addr, opts, range).await,
+        None => {
+            let socket = new_socket(addr, opts)?;
+            if let Some(ip) = local_source(opts, addr.ip())? {
+                socket.bind(SocketAddr::new(ip, 0))?;
+            }
+            socket.connect(addr).await
+        }
+    }
+}
+
+fn new_socket(addr: SocketAddr, opts: &SocketOptions) -> io::Result<TcpSocket> {
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    apply_options(&socket, opts);
+    if let Some(OutboundBind::Device(ref name)) = opts.bind {
+        bind_device(socket2::SockRef::from(&socket), name)?;
+    }
+    if let Some(mark) = opts.mark {
+        set_mark(&socket2::SockRef::from(&socket), mark)?;
+    }
+    // 在 connect 之前设置, SYN 也带上标记
+    if let Some(dscp) = opts.dscp {
+        set_dscp(socket2::SockRef::from(&socket), dscp, addr.is_ipv6())?;
+    }
+    // 源地址不属于本机, 需要 IP_TRANSPARENT 才能绑定
+    if opts.spoof_source.is_some_and(|ip| ip.is_ipv4() == addr.is_ipv4()) {
+        set_transparent(socket2::SockRef::from(&socket), addr.is_ipv4())?;
+    }
+    Ok(socket)
+}
+
+/// Happy Eyeballs (RFC 8305) 建连: 先连接 primary, delay 内没有连上或已失败时开始连接 fallback, 先连上的胜出
+/// 两者都失败时返回 primary 的错误
+pub async fn connect_racing(
+    primary: SocketAddr,
+    fallback: Option<SocketAddr>,
+    delay: Duration,
+    opts: &SocketOptions,
+) -> io::Result<TcpStream> {
+    let first = connect(primary, opts);
+    let Some(fallback) = fallback else { return first.await };
+    tokio::pin!(first);
+    let first_err = tokio::select! {
+        res = &mut first => match res {
+            Ok(stream) => return Ok(stream),
+            Err(e) => Some(e),
+        },
+        _ = tokio::time::sleep(delay) => None,
+    };
+    log::debug!("{} 未连上, 开始连接 {}", primary, fallback);
+    let second = connect(fallback, opts);
+    if let Some(e) = first_err {
+        return second.await.map_err(|_| e);
+    }
+    tokio::pin!(second);
+    tokio::select! {
+        res = &mut first => match res {
+            Ok(stream) => Ok(stream),
+            Err(e) => second.await.map_err(|_| e),
+        },
+        res = &mut second => match res {
+            Ok(stream) => Ok(stream),
+            Err(_) => first.await,
+        },
+    }
+}
+
+/// 在端口范围内逐个尝试绑定本地端口, 端口被占用时换下一个
+async fn connect_from_range(addr: SocketAddr, opts: &SocketOptions, range: PortRange) -> io::Result<TcpStream> {
+    let unspecified = if addr.is_ipv4() {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    };
+    let source = local_source(opts, addr.ip())?.unwrap_or(unspecified);
+    let offset = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+    for i in 0..range.len() {
+        let port = range.start + ((offset + i) % range.len()) as u16;
+        let socket = new_socket(addr, opts)?;
+        socket.set_reuseaddr(true)?;
+        match socket.bind(SocketAddr::new(source, port)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e),
+        }
+        match socket.connect(addr).await {
+            // 四元组冲突时内核返回 EADDRNOTAVAIL, 同样换下一个端口
+            Err(e) if matches!(e.kind(), io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable) => continue,
+            res => return res,
+        }
+    }
+    log::warn!("本地端口范围 {}-{} 已耗尽, 无法连接 {}", range.start, range.end, addr);
+    Err(io::Error::new(io::ErrorKind::AddrInUse, "本地端口范围已耗尽"))
+}
+
+fn apply_options(socket: &TcpSocket, opts: &SocketOptions) {
+    if let Some(ref algo) = opts.tcp_congestion {
+        set_congestion(socket, algo);
+    }
+    // 在 connect 之前设置, 窗口缩放按设置后的缓冲区协商
+    if let Some(n) = opts.recv_buffer {
+        let _ = socket.set_recv_buffer_size(n);
+    }
+    if let Some(n) = opts.send_buffer {
+        let _ = socket.set_send_buffer_size(n);
+    }
+    if opts.fastopen {
+        if let Err(e) = set_fastopen(socket, FastOpen::Connect) {
+            warn_fastopen(e);
+        }
+    }
+}
+
+enum FastOpen {
+    Listen,
+    Connect,
+}
+
+/// 设置 TCP Fast Open; 内核不支持时返回错误, 连接照常使用普通握手
+#[cfg(target_os = "linux")]
+fn set_fastopen(socket: &impl std::os::fd::AsRawFd, mode: FastOpen) -> io::Result<()> {
+    let (opt, value) = match mode {
+        FastOpen::Listen => (libc::TCP_FASTOPEN, FASTOPEN_QUEUE),
+        FastOpen::Connect => (libc::TCP_FASTOPEN_CONNECT, 1),
+    };
+    let len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: value 在调用期间有效, 长度与类型一致
+    let rc = unsafe { libc::setsockopt(socket.as_raw_fd(), libc::IPPROTO_TCP, opt, (&value as *const libc::c_int).cast(), len) };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_fastopen<S>(_socket: &S, _mode: FastOpen) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "当前系统不支持"))
+}
+
+fn warn_fastopen(e: io::Error) {
+    if !FASTOPEN_WARNED.swap(true, Ordering::Relaxed) {
+        log::warn!("无法开启 TCP Fast Open, 使用普通握手: {}", e);
+    } else {
+        log::debug!("无法开启 TCP Fast Open, 使用普通握手: {}", e);
+    }
+}
+
+/// 在监听套接字上开启 TCP Fast Open, 携带 cookie 的客户端可以在 SYN 中发送首包数据
+pub fn listen_fastopen(listener: &TcpListener) {
+    if let Err(e) = set_fastopen(listener, FastOpen::Listen) {
+        warn_fastopen(e);
+    }
+}
+
+/// 启动时检查内核的 net.ipv4.tcp_fastopen 是否开启了所需的一端 (1: 客户端, 2: 服务端)
+pub fn check_fastopen(listen: bool, connect: bool) {
+    if !listen && !connect {
+        return;
+    }
+    if cfg!(not(target_os = "linux")) {
+        log::warn!("tcp_fastopen 仅支持 Linux, 当前配置将被忽略");
+        return;
+    }
+    let Ok(value) = std::fs::read_to_string("/proc/sys/net/ipv4/tcp_fastopen") else { return };
+    let flags = value.trim().parse::<u32>().unwrap_or(0);
+    for (enabled, bit, side) in [(connect, 1, "客户端"), (listen, 2, "服务端")] {
+        if enabled && flags & bit == 0 {
+            log::warn!("net.ipv4.tcp_fastopen = {}, 未开启{} TFO, 将使用普通握手", flags, side);
+        }
+    }
+}
+
+/// 设置已接受的入站连接的收发缓冲区大小
+pub fn set_buffer_sizes(stream: &TcpStream, recv: Option<u32>, send: Option<u32>) {
+    let sock = socket2::SockRef::from(stream);
+    if let Some(n) = recv {
+        let _ = sock.set_recv_buffer_size(n as usize);
+    }
+    if let Some(n) = send {
+        let _ = sock.set_send_buffer_size(n as usize);
+    }
+}
+
+/// 开启 TCP keepalive; 次数设置在部分平台上不支持, 使用系统默认
+pub fn set_keepalive(stream: &TcpStream, keepalive: Option<&Keepalive>) {
+    let Some(k) = keepalive else { return };
+    let params = TcpKeepalive::new()
+        .with_time(Duration::from_secs(k.idle))
+        .with_interval(Duration::from_secs(k.interval));
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd", windows))]
+    let params = params.with_retries(k.count);
+    if let Err(e) = socket2::SockRef::from(stream).set_tcp_keepalive(&params) {
+        log::debug!("设置 TCP keepalive 失败: {}", e);
+    }
+}
+
+/// 启动时检查缓冲区大小是否超出内核上限 (仅 Linux), 超出时内核按上限设置
+pub fn check_buffer_sizes(recv: Option<u32>, send: Option<u32>) {
+    let checks = [("so_rcvbuf", recv, "rmem_max"), ("so_sndbuf", send, "wmem_max")];
+    for (key, size, sysctl) in checks {
+        let Some(size) = size else { continue };
+        let path = format!("/proc/sys/net/core/{}", sysctl);
+        let Ok(max) = std::fs::read_to_string(&path).map(|s| s.trim().parse::<u64>().unwrap_or(u64::MAX)) else { continue };
+        if u64::from(size) > max {
+            log::warn!("{} {} 超过内核上限 net.core.{} ({}), 实际按上限设置", key, size, sysctl, max);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_congestion(socket: &TcpSocket, algo: &str) {
+    if let Err(e) = socket2::SockRef::from(socket).set_tcp_congestion(algo.as_bytes()) {
+        if !CONGESTION_WARNED.swap(true, Ordering::Relaxed) {
+            log::warn!("内核拒绝拥塞控制算法 [{}]: {}", algo, e);
+        } else {
+            log::debug!("内核拒绝拥塞控制算法 [{}]: {}", algo, e);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_congestion(_socket: &TcpSocket, algo: &str) {
+    if !CONGESTION_WARNED.swap(true, Ordering::Relaxed) {
+        log::warn!("当前系统不支持设置拥塞控制算法 [{}], 已忽略", algo);
+    }
+}
+
+/// 启动时检查拥塞控制算法是否可用 (仅 Linux)
+pub fn check_congestion(algo: &str) {
+    if cfg!(not(target_os = "linux")) {
+        log::warn!("tcp_congestion 仅支持 Linux, 当前配置 [{}] 将被忽略", algo);
+        return;
+    }
+    match std::fs::read_to_string("/proc/sys/net/ipv4/tcp_available_congestion_control") {
+        Ok(list) if list.split_whitespace().any(|a| a == algo) => {
+            log::info!("TCP 拥塞控制算法: {}", algo);
+        }
+        Ok(list) => {
+            log::warn!("拥塞控制算法 [{}] 不在可用列表中 ({}), 内核可能拒绝设置", algo, list.trim());
+        }
+        Err(e) => {
+            log::warn!("无法读取可用拥塞控制算法列表: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn pinned_resolve_reports_no_dns_time() {
+        let cache = ResolveCache::default();
+        let window = Duration::from_secs(60);
+        let (addrs, _, cached) = cache.resolve("127.0.0.1:80", window, &[]).await.unwrap();
+        assert_eq!(addrs, ["127.0.0.1:80".parse::<SocketAddr>().unwrap()]);
+        assert!(!cached);
+        assert_eq!(cache.resolve("127.0.0.1:80", window, &[]).await.unwrap(), (addrs.clone(), 0.0, true));
+
+        cache.forget("127.0.0.1:80");
+        assert!(!cache.resolve("127.0.0.1:80", window, &[]).await.unwrap().2);
+        // 窗口为 0 时不沿用
+        assert!(!cache.resolve("127.0.0.2:80", Duration::ZERO, &[]).await.unwrap().2);
+        assert!(!cache.resolve("127.0.0.2:80", Duration::ZERO, &[]).await.unwrap().2);
+    }
+}
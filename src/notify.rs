@@ -0,0 +1,97 @@
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use crate::config::{ChatId, Config, Telegram};
+use crate::http;
+
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(5); // 一次发送 (含建连和握手) 的超时
+const RETRY_DELAY: Duration = Duration::from_secs(2); // 第一次重试前的等待, 之后依次加倍
+const TELEGRAM_API: &str = "https://api.telegram.org";
+
+/// 通知事件名称, 也是消息模板的键
+pub const EVENTS: [&str; 3] = ["route_switch", "all_down", "recovered"];
+
+/// 向配置的 webhook / Telegram / Slack 发送一条通知, 在后台发送, 失败后按 retries 重试, 不阻塞探测循环
+/// 事件字段为 {"event", "service", "time", "text", ...fields}, text 为可直接展示的说明;
+/// webhook 收到全部字段, Telegram / Slack 收到按模板生成的消息 (没有模板时为 text)
+pub fn send(config: &Config, event: &'static str, text: String, fields: Value) {
+    let mut payload = Map::new();
+    payload.insert("event".into(), event.into());
+    payload.insert("service".into(), Some(config.name.as_str()).filter(|n| !n.is_empty()).into());
+    payload.insert("time".into(), humantime::format_rfc3339_seconds(SystemTime::now()).to_string().into());
+    payload.insert("text".into(), text.into());
+    if let Value::Object(fields) = fields {
+        payload.extend(fields);
+    }
+
+    if let Some(ref hook) = config.webhook {
+        let body = Value::Object(payload.clone()).to_string();
+        deliver("webhook", event, hook.url.clone(), hook.headers.clone(), body, hook.retries);
+    }
+    if let Some(ref tg) = config.telegram {
+        telegram(tg, event, &payload);
+    }
+    if let Some(ref slack) = config.slack {
+        let body = json!({ "text": render(slack.templates.get(event), &payload) }).to_string();
+        deliver("Slack", event, slack.webhook_url.clone(), HashMap::new(), body, slack.retries);
+    }
+}
+
+fn telegram(tg: &Telegram, event: &'static str, payload: &Map<String, Value>) {
+    let base = match tg.api_url.clone().map_or_else(|| http::Url::parse(TELEGRAM_API, "/"), Ok) {
+        Ok(base) => base,
+        Err(e) => {
+            log::warn!("Telegram 地址无效: {}", e);
+            return;
+        }
+    };
+    let chat_id = match tg.chat_id {
+        ChatId::Id(id) => Value::from(id),
+        ChatId::Name(ref name) => Value::from(name.as_str()),
+    };
+    let message = render(tg.templates.get(event), payload);
+    let body = json!({ "chat_id": chat_id, "text": message, "disable_web_page_preview": true }).to_string();
+    let url = base.join(&format!("/bot{}/sendMessage", tg.bot_token));
+    deliver("Telegram", event, url, HashMap::new(), body, tg.retries);
+}
+
+/// 按模板生成消息: {字段名} 替换为事件字段的值, 如 "{previous} -> {target}"; 不存在或为空的字段替换为空
+fn render(template: Option<&String>, payload: &Map<String, Value>) -> String {
+    let Some(template) = template else {
+        return payload["text"].as_str().unwrap_or_default().to_string();
+    };
+    let mut out = String::new();
+    let mut rest = template.as_str();
+    while let Some((open, close)) = rest.find('{').and_then(|o| Some((o, o + rest[o..].find('}')?))) {
+        out.push_str(&rest[..open]);
+        let key = &rest[open + 1..close];
+        match payload.get(key) {
+            Some(Value::String(s)) => out.push_str(s),
+            Some(Value::Null) | None => {}
+            Some(v) => out.push_str(&v.to_string()),
+        }
+        rest = &rest[close + 1..];
+    }
+    out + rest
+}
+
+fn deliver(channel: &'static str, event: &'static str, url: http::Url, headers: HashMap<String, String>, body: String, retries: u32) {
+    tokio::spawn(async move {
+        let mut delay = RETRY_DELAY;
+        for attempt in 0..=retries {
+            match http::post_json(&url, &headers, body.clone(), ATTEMPT_TIMEOUT).await {
+                Ok(()) => {
+                    log::debug!("{} 通知已发送: {}", channel, event);
+                    return;
+                }
+                Err(e) if attempt < retries => {
+                    log::debug!("{} 通知发送失败 ({}), {}秒后重试: {}", channel, event, delay.as_secs(), e);
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => log::warn!("{} 通知发送失败 ({}), 已重试 {} 次: {}", channel, event, retries, e),
+            }
+        }
+    });
+}
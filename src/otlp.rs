@@ -0,0 +1,253 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+use crate::http;
+
+const MAX_QUEUE: usize = 4096; // 等待发送的 span 上限, 超出时丢弃
+const MAX_BATCH: usize = 512; // 每个请求最多包含的 span 数
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5); // 未攒满一批时的发送间隔
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10); // 一次发送 (含建连和握手) 的超时
+
+// 发送队列, 第一个配置了 otlp_endpoint 的服务启动导出后设置
+static QUEUE: OnceLock<mpsc::Sender<(SpanData, SystemTime)>> = OnceLock::new();
+// 队列已满而丢弃的 span 数
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+tokio::task_local! {
+    // 本轮探测中各节点的探测结果, 作为探测轮 span 的事件
+    static PROBE_EVENTS: RefCell<Vec<Event>>;
+}
+
+/// OTLP/HTTP 接收地址, 如 "http://127.0.0.1:4318" (路径默认 /v1/traces) 或 "https://otel.example.com/v1/traces"
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Endpoint(http::Url);
+
+impl TryFrom<String> for Endpoint {
+    type Error = String;
+
+    fn try_from(url: String) -> Result<Self, Self::Error> {
+        http::Url::parse(&url, "/v1/traces").map(Endpoint)
+    }
+}
+
+/// 启动 span 导出, 整个进程只有一个导出任务: 多个服务都配置时以第一个为准
+/// resource 中的 service.name 为 service_name, headers 附加到每个请求 (如认证头)
+pub fn start(endpoint: Endpoint, service_name: String, headers: HashMap<String, String>) {
+    let (tx, rx) = mpsc::channel(MAX_QUEUE);
+    if QUEUE.set(tx).is_err() {
+        return;
+    }
+    log::info!("OTLP span 导出: {} (service.name: {})", endpoint.0, service_name);
+    tokio::spawn(export(rx, endpoint, service_name, headers));
+}
+
+#[derive(Clone, Copy)]
+pub enum Kind {
+    Internal = 1,
+    Server = 2,
+}
+
+/// 一个 span, 结束 (离开作用域) 时放入发送队列
+pub struct Span(SpanData);
+
+struct SpanData {
+    name: &'static str,
+    kind: Kind,
+    trace_id: u128,
+    span_id: u64,
+    start: SystemTime,
+    attrs: Vec<(&'static str, Value)>,
+    events: Vec<Event>,
+    error: Option<String>,
+}
+
+/// span 中带时间的事件
+pub struct Event {
+    name: &'static str,
+    time: SystemTime,
+    attrs: Vec<(&'static str, Value)>,
+}
+
+impl Span {
+    /// 开始一个新的 trace 的根 span; 未开启导出时返回 None
+    pub fn start(name: &'static str, kind: Kind) -> Option<Span> {
+        Span::started(name, kind, SystemTime::now())
+    }
+
+    /// 同 start, 开始时间为 at (事后才决定是否导出时使用)
+    pub fn started(name: &'static str, kind: Kind, at: SystemTime) -> Option<Span> {
+        QUEUE.get()?;
+        let trace_id = (random_id() as u128) << 64 | random_id() as u128;
+        Some(Span(SpanData {
+            name,
+            kind,
+            trace_id,
+            span_id: random_id(),
+            start: at,
+            attrs: Vec::new(),
+            events: Vec::new(),
+            error: None,
+        }))
+    }
+
+    /// 设置属性, 空值 (None) 不输出
+    pub fn attr(&mut self, key: &'static str, value: impl Into<Value>) {
+        let value = value.into();
+        if !value.is_null() {
+            self.0.attrs.push((key, value));
+        }
+    }
+
+    pub fn event(&mut self, name: &'static str, attrs: Vec<(&'static str, Value)>) {
+        self.0.events.push(Event { name, time: SystemTime::now(), attrs });
+    }
+
+    pub fn events(&mut self, events: Vec<Event>) {
+        self.0.events.extend(events);
+    }
+
+    /// 标记为出错
+    pub fn fail(&mut self, message: String) {
+        self.0.error = Some(message);
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let Some(queue) = QUEUE.get() else { return };
+        let data = SpanData {
+            attrs: std::mem::take(&mut self.0.attrs),
+            events: std::mem::take(&mut self.0.events),
+            error: self.0.error.take(),
+            ..self.0
+        };
+        if queue.try_send((data, SystemTime::now())).is_err() {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// 运行一轮探测, 收集其中各节点报告的 probe_result 事件
+pub async fn collect_probes<T>(probing: impl Future<Output = T>) -> (T, Vec<Event>) {
+    PROBE_EVENTS
+        .scope(RefCell::new(Vec::new()), async {
+            let out = probing.await;
+            (out, PROBE_EVENTS.with(|e| e.take()))
+        })
+        .await
+}
+
+/// 记录一个节点的探测结果, 只在 collect_probes 中有效
+pub fn probe_result(attrs: Vec<(&'static str, Value)>) {
+    if QUEUE.get().is_none() {
+        return;
+    }
+    let event = Event { name: "probe_result", time: SystemTime::now(), attrs };
+    let _ = PROBE_EVENTS.try_with(|e| e.borrow_mut().push(event));
+}
+
+fn random_id() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+fn nanos(t: SystemTime) -> String {
+    t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64).to_string()
+}
+
+// OTLP JSON 编码的属性列表; 整数按规范编码为字符串
+fn attributes(attrs: &[(&str, Value)]) -> Value {
+    let any = |v: &Value| match v {
+        Value::Bool(b) => json!({ "boolValue": b }),
+        Value::Number(n) if n.is_f64() => json!({ "doubleValue": n }),
+        Value::Number(n) => json!({ "intValue": n.to_string() }),
+        Value::String(s) => json!({ "stringValue": s }),
+        v => json!({ "stringValue": v.to_string() }),
+    };
+    attrs.iter().map(|(k, v)| json!({ "key": k, "value": any(v) })).collect()
+}
+
+impl SpanData {
+    fn to_json(&self, end: SystemTime) -> Value {
+        let mut span = json!({
+            "traceId": format!("{:032x}", self.trace_id),
+            "spanId": format!("{:016x}", self.span_id),
+            "name": self.name,
+            "kind": self.kind as u8,
+            "startTimeUnixNano": nanos(self.start),
+            "endTimeUnixNano": nanos(end),
+            "attributes": attributes(&self.attrs),
+        });
+        if !self.events.is_empty() {
+            let events = self.events.iter().map(|e| {
+                json!({ "timeUnixNano": nanos(e.time), "name": e.name, "attributes": attributes(&e.attrs) })
+            });
+            span["events"] = events.collect();
+        }
+        if let Some(ref message) = self.error {
+            span["status"] = json!({ "code": 2, "message": message });
+        }
+        span
+    }
+}
+
+/// 按批发送: 攒满 MAX_BATCH 个或距上次发送超过 EXPORT_INTERVAL 时发送一次, 发送失败的一批直接丢弃
+async fn export(mut rx: mpsc::Receiver<(SpanData, SystemTime)>, endpoint: Endpoint, service_name: String, headers: HashMap<String, String>) {
+    let resource = json!({ "attributes": attributes(&[
+        ("service.name", service_name.into()),
+        ("process.pid", std::process::id().into()),
+    ]) });
+    let mut batch = Vec::new();
+    let mut failing = false;
+    loop {
+        let deadline = tokio::time::sleep(EXPORT_INTERVAL);
+        tokio::pin!(deadline);
+        while batch.len() < MAX_BATCH {
+            tokio::select! {
+                span = rx.recv() => match span {
+                    Some(ended) => batch.push(ended),
+                    None => return,
+                },
+                _ = &mut deadline => break,
+            }
+        }
+        let dropped = DROPPED.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            log::warn!("OTLP 发送队列已满, 丢弃了 {} 个 span", dropped);
+        }
+        if batch.is_empty() {
+            continue;
+        }
+        let spans: Vec<Value> = batch.drain(..).map(|(span, end)| span.to_json(end)).collect();
+        let count = spans.len();
+        let body = json!({ "resourceSpans": [{
+            "resource": resource,
+            "scopeSpans": [{ "scope": { "name": "forward-optimal" }, "spans": spans }],
+        }] });
+        match http::post_json(&endpoint.0, &headers, body.to_string(), EXPORT_TIMEOUT).await {
+            Ok(()) => {
+                if std::mem::take(&mut failing) {
+                    log::info!("OTLP 发送已恢复");
+                }
+                log::debug!("OTLP 已发送 {} 个 span", count);
+            }
+            // 接收端不可用时每次失败都会重试, 只在第一次失败时告警
+            Err(e) => {
+                if !std::mem::replace(&mut failing, true) {
+                    log::warn!("OTLP 发送失败, 丢弃 {} 个 span: {}", count, e);
+                } else {
+                    log::debug!("OTLP 发送失败, 丢弃 {} 个 span: {}", count, e);
+                }
+            }
+        }
+    }
+}
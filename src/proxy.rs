@@ -0,0 +1,637 @@
+use anyhow::Result;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+use crate::config::TlvConfig;
+
+const V2_SIGNATURE: &[u8; 12] = b"\x0D\x0A\x0D\x0A\x00\x0D\x0A\x51\x55\x49\x54\x0A";
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107; // 规范规定 v1 头最长 107 字节 (含 CRLF)
+const V2_FIXED_LEN: usize = 16;
+
+/// 入站 PROXY 头大小的硬上限, 配置值也不能超过它
+pub const MAX_HEADER_CEILING: usize = V2_FIXED_LEN + u16::MAX as usize;
+// 读取入站 PROXY 头的超时 (ms)
+const HEADER_READ_TIMEOUT: u64 = 3000;
+
+/// 入站 PROXY 头携带的地址; LOCAL / UNKNOWN 时为 None
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyInfo {
+    pub src: Option<SocketAddr>,
+    pub dst: Option<SocketAddr>,
+}
+
+/// 增量解析结果
+#[derive(Debug, PartialEq, Eq)]
+pub enum Parsed {
+    /// 数据不足, 需要继续读取
+    Incomplete,
+    /// 解析完成, 第二个值为头部占用的字节数
+    Complete(ProxyInfo, usize),
+}
+
+/// 从客户端读取并剥离 PROXY 头, 返回解析结果和头部之后已读到的多余数据
+pub async fn read_header(stream: &mut TcpStream, max_size: usize) -> Result<(ProxyInfo, Vec<u8>)> {
+    let max_size = max_size.min(MAX_HEADER_CEILING);
+    let read = async {
+        // 缓冲区按上限一次性分配, 不依据客户端声明的长度扩容
+        let mut buf = vec![0u8; max_size];
+        let mut filled = 0;
+        loop {
+            match parse(&buf[..filled], max_size)? {
+                Parsed::Complete(info, used) => return Ok((info, buf[used..filled].to_vec())),
+                Parsed::Incomplete if filled == max_size => anyhow::bail!("PROXY 头超过长度上限 {}", max_size),
+                Parsed::Incomplete => {}
+            }
+            let n = stream.read(&mut buf[filled..]).await?;
+            if n == 0 {
+                anyhow::bail!("PROXY 头不完整, 连接已关闭");
+            }
+            filled += n;
+        }
+    };
+    tokio::time::timeout(Duration::from_millis(HEADER_READ_TIMEOUT), read)
+        .await
+        .map_err(|_| anyhow::anyhow!("读取 PROXY 头超时"))?
+}
+
+/// 解析 PROXY v1/v2 头; 任何格式错误都返回 Err, 不会 panic
+pub fn parse(buf: &[u8], max_size: usize) -> Result<Parsed> {
+    let max_size = max_size.min(MAX_HEADER_CEILING);
+    if buf.is_empty() {
+        return Ok(Parsed::Incomplete);
+    }
+    if buf[0] == V2_SIGNATURE[0] {
+        parse_v2(buf, max_size)
+    } else if buf[0] == V1_PREFIX[0] {
+        parse_v1(buf, max_size)
+    } else {
+        anyhow::bail!("无效的 PROXY 头签名")
+    }
+}
+
+fn parse_v2(buf: &[u8], max_size: usize) -> Result<Parsed> {
+    let sig_len = buf.len().min(V2_SIGNATURE.len());
+    if buf[..sig_len] != V2_SIGNATURE[..sig_len] {
+        anyhow::bail!("无效的 PROXY v2 签名");
+    }
+    if buf.len() < V2_FIXED_LEN {
+        return Ok(Parsed::Incomplete);
+    }
+
+    let ver_cmd = buf[12];
+    if ver_cmd >> 4 != 2 {
+        anyhow::bail!("不支持的 PROXY 版本: {}", ver_cmd >> 4);
+    }
+    let fam = buf[13];
+    let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    let total = V2_FIXED_LEN + len;
+    if total > max_size {
+        anyhow::bail!("PROXY v2 声明长度 {} 超过上限 {}", total, max_size);
+    }
+    if buf.len() < total {
+        return Ok(Parsed::Incomplete);
+    }
+    let body = &buf[V2_FIXED_LEN..total];
+
+    // 地址块长度
+    let addr_len = match fam {
+        0x00 => 0,
+        0x11 | 0x12 => 12,
+        0x21 | 0x22 => 36,
+        0x31 | 0x32 => 216,
+        _ => anyhow::bail!("未知的 PROXY v2 地址族: {:#04x}", fam),
+    };
+    if body.len() < addr_len {
+        anyhow::bail!("PROXY v2 地址块长度不足: {} < {}", body.len(), addr_len);
+    }
+    validate_tlvs(&body[addr_len..])?;
+
+    let info = match ver_cmd & 0x0F {
+        // LOCAL: 健康检查等, 不携带客户端地址
+        0x00 => ProxyInfo { src: None, dst: None },
+        0x01 => match fam {
+            0x11 | 0x12 => {
+                let ip = |o: usize| IpAddr::V4(Ipv4Addr::new(body[o], body[o + 1], body[o + 2], body[o + 3]));
+                let port = |o: usize| u16::from_be_bytes([body[o], body[o + 1]]);
+                ProxyInfo {
+                    src: Some(SocketAddr::new(ip(0), port(8))),
+                    dst: Some(SocketAddr::new(ip(4), port(10))),
+                }
+            }
+            0x21 | 0x22 => {
+                let ip = |o: usize| {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(&body[o..o + 16]);
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                };
+                let port = |o: usize| u16::from_be_bytes([body[o], body[o + 1]]);
+                ProxyInfo {
+                    src: Some(SocketAddr::new(ip(0), port(32))),
+                    dst: Some(SocketAddr::new(ip(16), port(34))),
+                }
+            }
+            _ => ProxyInfo { src: None, dst: None },
+        },
+        cmd => anyhow::bail!("未知的 PROXY v2 命令: {}", cmd),
+    };
+    Ok(Parsed::Complete(info, total))
+}
+
+/// 校验 TLV 区域: 每个 TLV 的声明长度都必须落在实际数据范围内
+fn validate_tlvs(mut tlvs: &[u8]) -> Result<()> {
+    while !tlvs.is_empty() {
+        if tlvs.len() < 3 {
+            anyhow::bail!("PROXY v2 TLV 头不完整");
+        }
+        let len = u16::from_be_bytes([tlvs[1], tlvs[2]]) as usize;
+        if tlvs.len() < 3 + len {
+            anyhow::bail!("PROXY v2 TLV 长度越界: 类型 {:#04x}, 长度 {}", tlvs[0], len);
+        }
+        tlvs = &tlvs[3 + len..];
+    }
+    Ok(())
+}
+
+fn parse_v1(buf: &[u8], max_size: usize) -> Result<Parsed> {
+    let prefix_len = buf.len().min(V1_PREFIX.len());
+    if buf[..prefix_len] != V1_PREFIX[..prefix_len] {
+        anyhow::bail!("无效的 PROXY v1 签名");
+    }
+    let limit = V1_MAX_LEN.min(max_size);
+    let end = match buf.windows(2).take(limit.saturating_sub(1)).position(|w| w == b"\r\n") {
+        Some(pos) => pos,
+        None if buf.len() >= limit => anyhow::bail!("PROXY v1 头超过 {} 字节", limit),
+        None => return Ok(Parsed::Incomplete),
+    };
+
+    let line = std::str::from_utf8(&buf[V1_PREFIX.len().min(end)..end])
+        .map_err(|_| anyhow::anyhow!("PROXY v1 头包含非法字符"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    let info = match parts.as_slice() {
+        ["UNKNOWN", ..] => ProxyInfo { src: None, dst: None },
+        [proto @ ("TCP4" | "TCP6"), src_ip, dst_ip, src_port, dst_port] => {
+            let v6 = *proto == "TCP6";
+            let ip = |s: &str| -> Result<IpAddr> {
+                let ip: IpAddr = s.parse().map_err(|_| anyhow::anyhow!("PROXY v1 地址无效: {}", s))?;
+                if ip.is_ipv6() != v6 {
+                    anyhow::bail!("PROXY v1 地址与协议不符: {} {}", proto, s);
+                }
+                Ok(ip)
+            };
+            let port = |s: &str| -> Result<u16> {
+                if s.is_empty() || (s.len() > 1 && s.starts_with('0')) {
+                    anyhow::bail!("PROXY v1 端口无效: {}", s);
+                }
+                s.parse().map_err(|_| anyhow::anyhow!("PROXY v1 端口无效: {}", s))
+            };
+            ProxyInfo {
+                src: Some(SocketAddr::new(ip(src_ip)?, port(src_port)?)),
+                dst: Some(SocketAddr::new(ip(dst_ip)?, port(dst_port)?)),
+            }
+        }
+        _ => anyhow::bail!("PROXY v1 头格式错误"),
+    };
+    Ok(Parsed::Complete(info, end + 2))
+}
+
+// 出站 TLV 类型 (规范 2.2.x)
+const PP2_TYPE_AUTHORITY: u8 = 0x02;
+const PP2_TYPE_CRC32C: u8 = 0x03;
+const PP2_TYPE_UNIQUE_ID: u8 = 0x05;
+const PP2_TYPE_SSL: u8 = 0x20;
+const PP2_UNIQUE_ID_MAX: usize = 128;
+// TLV 区域上限: 总长度字段为 u16, 需给 IPv6 地址块留出 36 字节
+const TLV_AREA_MAX: usize = u16::MAX as usize - 36;
+
+/// 校验并编码 TLV 模板, 结果直接追加在地址块之后
+pub fn encode_tlvs(tlvs: &[TlvConfig]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    for tlv in tlvs {
+        let value = match (&tlv.value, &tlv.hex) {
+            (Some(v), None) => v.as_bytes().to_vec(),
+            (None, Some(h)) => decode_hex(h).ok_or_else(|| anyhow::anyhow!("TLV {:#04x} 的 hex 无效: {}", tlv.kind, h))?,
+            _ => anyhow::bail!("TLV {:#04x} 必须且只能设置 value 或 hex 之一", tlv.kind),
+        };
+        match tlv.kind {
+            0x00 => anyhow::bail!("TLV 类型 0x00 未定义"),
+            // 需要按连接计算或有固定结构, 不能用静态模板表达
+            PP2_TYPE_CRC32C | PP2_TYPE_SSL => anyhow::bail!("TLV 类型 {:#04x} 不支持静态配置", tlv.kind),
+            PP2_TYPE_UNIQUE_ID if value.len() > PP2_UNIQUE_ID_MAX => {
+                anyhow::bail!("UNIQUE_ID 最长 {} 字节, 当前: {}", PP2_UNIQUE_ID_MAX, value.len())
+            }
+            _ => {}
+        }
+        if value.len() > u16::MAX as usize {
+            anyhow::bail!("TLV {:#04x} 的值过长: {} 字节", tlv.kind, value.len());
+        }
+        out.push(tlv.kind);
+        out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        out.extend_from_slice(&value);
+    }
+    if out.len() > TLV_AREA_MAX {
+        anyhow::bail!("TLV 总长度 {} 超过上限 {}", out.len(), TLV_AREA_MAX);
+    }
+    Ok(out)
+}
+
+/// 用客户端的 SNI 替换 TLV 模板中的 AUTHORITY; SNI 为空或超出 TLV 区域上限时保持模板不变
+pub fn with_authority(tlvs: &[u8], authority: &str) -> Vec<u8> {
+    if authority.is_empty() {
+        return tlvs.to_vec();
+    }
+    let mut out = Vec::with_capacity(tlvs.len() + 3 + authority.len());
+    out.push(PP2_TYPE_AUTHORITY);
+    out.extend_from_slice(&(authority.len() as u16).to_be_bytes());
+    out.extend_from_slice(authority.as_bytes());
+    let mut rest = tlvs;
+    while rest.len() >= 3 {
+        let len = 3 + u16::from_be_bytes([rest[1], rest[2]]) as usize;
+        let Some(tlv) = rest.get(..len) else { break };
+        if tlv[0] != PP2_TYPE_AUTHORITY {
+            out.extend_from_slice(tlv);
+        }
+        rest = &rest[len..];
+    }
+    if out.len() > TLV_AREA_MAX {
+        return tlvs.to_vec();
+    }
+    out
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim();
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// PROXY Protocol V1 文本头; 两端协议族不同时发送 UNKNOWN
+pub fn build_proxy_v1_header(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let proto = match (src, dst) {
+        (SocketAddr::V4(_), SocketAddr::V4(_)) => "TCP4",
+        (SocketAddr::V6(_), SocketAddr::V6(_)) => "TCP6",
+        _ => return b"PROXY UNKNOWN\r\n".to_vec(),
+    };
+    format!("PROXY {} {} {} {} {}\r\n", proto, src.ip(), dst.ip(), src.port(), dst.port()).into_bytes()
+}
+
+/// PROXY v2 LOCAL 头: 连接由代理自身发起 (健康检查等), 不携带地址
+pub fn build_proxy_v2_local_header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(V2_FIXED_LEN);
+    header.extend_from_slice(V2_SIGNATURE);
+    header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+    header
+}
+
+/// PROXY Protocol V2 构造器, tlvs 为 encode_tlvs 编码后的 TLV 区域
+pub fn build_proxy_v2_header(src: SocketAddr, dst: SocketAddr, tlvs: &[u8]) -> Vec<u8> {
+    let mut header = Vec::with_capacity(V2_FIXED_LEN + 36 + tlvs.len());
+    header.extend_from_slice(V2_SIGNATURE);
+    header.push(0x21);
+    let len = |addr_len: usize| ((addr_len + tlvs.len()) as u16).to_be_bytes();
+    match (src, dst) {
+        (SocketAddr::V4(s), SocketAddr::V4(d)) => {
+            header.push(0x11);
+            header.extend_from_slice(&len(12));
+            header.extend_from_slice(&s.ip().octets());
+            header.extend_from_slice(&d.ip().octets());
+            header.extend_from_slice(&s.port().to_be_bytes());
+            header.extend_from_slice(&d.port().to_be_bytes());
+        }
+        (SocketAddr::V6(s), SocketAddr::V6(d)) => {
+            header.push(0x21);
+            header.extend_from_slice(&len(36));
+            header.extend_from_slice(&s.ip().octets());
+            header.extend_from_slice(&d.ip().octets());
+            header.extend_from_slice(&s.port().to_be_bytes());
+            header.extend_from_slice(&d.port().to_be_bytes());
+        }
+        _ => {
+            header.push(0x00);
+            header.extend_from_slice(&len(0));
+        }
+    }
+    header.extend_from_slice(tlvs);
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: usize = 4096;
+
+    fn v4(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    /// 手工拼一个 v2 头: 签名 + 版本/命令 + 地址族 + 声明长度 + body
+    fn v2(ver_cmd: u8, fam: u8, declared: u16, body: &[u8]) -> Vec<u8> {
+        let mut h = V2_SIGNATURE.to_vec();
+        h.extend_from_slice(&[ver_cmd, fam]);
+        h.extend_from_slice(&declared.to_be_bytes());
+        h.extend_from_slice(body);
+        h
+    }
+
+    fn complete(buf: &[u8]) -> (ProxyInfo, usize) {
+        match parse(buf, MAX).unwrap() {
+            Parsed::Complete(info, used) => (info, used),
+            Parsed::Incomplete => panic!("头不完整: {:?}", buf),
+        }
+    }
+
+    #[test]
+    fn v1_round_trip() {
+        for (src, dst) in [(v4("10.0.0.1:5000"), v4("10.0.0.2:443")), (v4("[2001:db8::1]:1"), v4("[::1]:65535"))] {
+            let mut buf = build_proxy_v1_header(src, dst);
+            let len = buf.len();
+            buf.extend_from_slice(b"payload");
+            assert_eq!(complete(&buf), (ProxyInfo { src: Some(src), dst: Some(dst) }, len));
+        }
+        let unknown = build_proxy_v1_header(v4("10.0.0.1:1"), v4("[::1]:2"));
+        assert_eq!(complete(&unknown), (ProxyInfo { src: None, dst: None }, unknown.len()));
+    }
+
+    #[test]
+    fn v2_round_trip() {
+        for (src, dst) in [(v4("10.0.0.1:5000"), v4("10.0.0.2:443")), (v4("[2001:db8::1]:1"), v4("[::1]:65535"))] {
+            let mut buf = build_proxy_v2_header(src, dst, &[0x01, 0x00, 0x02, b'h', b'2']);
+            let len = buf.len();
+            buf.extend_from_slice(b"payload");
+            assert_eq!(complete(&buf), (ProxyInfo { src: Some(src), dst: Some(dst) }, len));
+        }
+        let local = build_proxy_v2_local_header();
+        assert_eq!(complete(&local), (ProxyInfo { src: None, dst: None }, local.len()));
+    }
+
+    #[test]
+    fn truncated_headers_are_incomplete() {
+        let v1 = build_proxy_v1_header(v4("[2001:db8::1]:1"), v4("[::1]:2"));
+        let v2 = build_proxy_v2_header(v4("10.0.0.1:1"), v4("10.0.0.2:2"), &[0x01, 0x00, 0x01, b'x']);
+        for full in [v1, v2] {
+            for n in 0..full.len() {
+                assert_eq!(parse(&full[..n], MAX).unwrap(), Parsed::Incomplete, "前 {} 字节", n);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_header_then_eof_is_rejected() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let full = build_proxy_v2_header(v4("10.0.0.1:1"), v4("10.0.0.2:2"), &[]);
+        for n in [1, 12, 15, full.len() - 1] {
+            let part = full[..n].to_vec();
+            let client = tokio::spawn(async move {
+                use tokio::io::AsyncWriteExt;
+                let mut s = TcpStream::connect(addr).await.unwrap();
+                s.write_all(&part).await.unwrap();
+            });
+            let (mut stream, _) = listener.accept().await.unwrap();
+            client.await.unwrap();
+            assert!(read_header(&mut stream, MAX).await.is_err(), "前 {} 字节", n);
+        }
+    }
+
+    #[test]
+    fn declared_length_over_max_size() {
+        // 只收到固定头部就应拒绝, 不等待声明的数据
+        assert!(parse(&v2(0x21, 0x11, 5000, &[]), MAX).is_err());
+        assert!(parse(&v2(0x21, 0x11, u16::MAX, &[]), MAX_HEADER_CEILING + 1).is_ok());
+        assert!(parse(&v2(0x21, 0x11, 100, &[]), 64).is_err());
+        let long = [b"PROXY TCP4 ".as_slice(), &[b'1'; 200]].concat();
+        assert!(parse(&long, MAX).is_err());
+        assert!(parse(&long[..60], 40).is_err());
+    }
+
+    #[test]
+    fn bad_signature() {
+        assert!(parse(b"GET / HTTP/1.1\r\n", MAX).is_err());
+        assert!(parse(b"PROXZ TCP4", MAX).is_err());
+        let mut sig = build_proxy_v2_local_header();
+        sig[5] ^= 0xFF;
+        assert!(parse(&sig, MAX).is_err());
+        assert!(parse(&sig[..6], MAX).is_err());
+    }
+
+    #[test]
+    fn unknown_version_command_family() {
+        assert!(parse(&v2(0x11, 0x11, 12, &[0; 12]), MAX).is_err());
+        assert!(parse(&v2(0x31, 0x11, 12, &[0; 12]), MAX).is_err());
+        assert!(parse(&v2(0x22, 0x11, 12, &[0; 12]), MAX).is_err());
+        assert!(parse(&v2(0x21, 0x41, 12, &[0; 12]), MAX).is_err());
+        assert!(parse(&v2(0x21, 0x13, 12, &[0; 12]), MAX).is_err());
+        assert!(parse(b"PROXY TCP5 1.2.3.4 1.2.3.4 1 2\r\n", MAX).is_err());
+    }
+
+    #[test]
+    fn short_address_block() {
+        assert!(parse(&v2(0x21, 0x11, 4, &[0; 4]), MAX).is_err());
+        assert!(parse(&v2(0x21, 0x21, 12, &[0; 12]), MAX).is_err());
+        assert!(parse(&v2(0x21, 0x31, 100, &[0; 100]), MAX).is_err());
+    }
+
+    #[test]
+    fn tlv_overruns_buffer() {
+        let overrun = [[0u8; 12].as_slice(), &[0x01, 0x00, 0x10, b'a']].concat();
+        assert!(parse(&v2(0x21, 0x11, overrun.len() as u16, &overrun), MAX).is_err());
+        let cut = [[0u8; 12].as_slice(), &[0x01, 0x00]].concat();
+        assert!(parse(&v2(0x21, 0x11, cut.len() as u16, &cut), MAX).is_err());
+        let exact = [[0u8; 12].as_slice(), &[0x01, 0x00, 0x01, b'a']].concat();
+        assert!(parse(&v2(0x21, 0x11, exact.len() as u16, &exact), MAX).is_ok());
+    }
+
+    #[test]
+    fn malformed_v1_lines() {
+        for line in [
+            "PROXY TCP4 1.2.3.4 5.6.7.8 0123 80\r\n",
+            "PROXY TCP4 1.2.3.4 5.6.7.8 70000 80\r\n",
+            "PROXY TCP4 1.2.3.4 5.6.7.8 1  80\r\n",
+            "PROXY TCP4 ::1 5.6.7.8 1 80\r\n",
+            "PROXY TCP6 1.2.3.4 ::1 1 80\r\n",
+            "PROXY TCP4 1.2.3.4 5.6.7.8 1\r\n",
+            "PROXY TCP4 1.2.3.4 5.6.7.8 1 2 3\r\n",
+            "PROXY \r\n",
+            "PROXY\r\n",
+        ] {
+            assert!(parse(line.as_bytes(), MAX).is_err(), "{:?}", line);
+        }
+        assert!(parse(b"PROXY TCP4 1.2.3.4 5.6.7.\xff 1 2\r\n", MAX).is_err());
+    }
+
+    /// 逐字节篡改合法头部以及随机字节: 只要求不 panic, 且完成时报告的长度不超过输入
+    #[test]
+    fn fuzz_does_not_panic() {
+        let valid = [
+            build_proxy_v1_header(v4("10.0.0.1:5000"), v4("10.0.0.2:443")),
+            build_proxy_v2_header(v4("10.0.0.1:5000"), v4("10.0.0.2:443"), &[0x01, 0x00, 0x02, b'h', b'2']),
+            build_proxy_v2_header(v4("[2001:db8::1]:1"), v4("[::1]:2"), &[]),
+        ];
+        let check = |buf: &[u8], max: usize| {
+            if let Ok(Parsed::Complete(_, used)) = parse(buf, max) {
+                assert!(used <= buf.len() && used <= max.min(MAX_HEADER_CEILING));
+            }
+        };
+        for header in &valid {
+            for i in 0..header.len() {
+                for b in 0..=255u8 {
+                    let mut buf = header.clone();
+                    buf[i] = b;
+                    check(&buf, MAX);
+                    check(&buf[..i + 1], 32);
+                }
+            }
+        }
+        let mut seed = 0x2545_F491_4F6C_DD1Du64;
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        for _ in 0..20_000 {
+            let len = (next() % 300) as usize;
+            let mut buf: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+            // 多数用例以合法签名开头, 以便进入解析的深处
+            match next() % 3 {
+                0 if len >= 16 => buf[..12].copy_from_slice(V2_SIGNATURE),
+                1 if len >= 6 => buf[..6].copy_from_slice(V1_PREFIX),
+                _ => {}
+            }
+            check(&buf, (next() % 5000) as usize);
+        }
+    }
+
+    fn tlv(kind: u8, value: Option<&str>, hex: Option<&str>) -> TlvConfig {
+        TlvConfig { kind, value: value.map(str::to_string), hex: hex.map(str::to_string) }
+    }
+
+    #[test]
+    fn encode_tlvs_layout() {
+        let out = encode_tlvs(&[tlv(0x02, Some("a.example"), None), tlv(0xE0, None, Some(" DEADbeef "))]).unwrap();
+        let mut expected = vec![0x02, 0x00, 0x09];
+        expected.extend_from_slice(b"a.example");
+        expected.extend_from_slice(&[0xE0, 0x00, 0x04, 0xDE, 0xAD, 0xBE, 0xEF]);
+        assert_eq!(out, expected);
+        assert!(encode_tlvs(&[]).unwrap().is_empty());
+        // 空值合法, 只有类型和长度
+        assert_eq!(encode_tlvs(&[tlv(0xE1, Some(""), None)]).unwrap(), [0xE1, 0x00, 0x00]);
+        let max_id = "x".repeat(PP2_UNIQUE_ID_MAX);
+        assert_eq!(encode_tlvs(&[tlv(0x05, Some(&max_id), None)]).unwrap().len(), 3 + PP2_UNIQUE_ID_MAX);
+    }
+
+    #[test]
+    fn encode_tlvs_rejects_invalid() {
+        let long = "x".repeat(u16::MAX as usize + 1);
+        let id = "x".repeat(PP2_UNIQUE_ID_MAX + 1);
+        for bad in [
+            tlv(0xE0, Some("a"), Some("00")),
+            tlv(0xE0, None, None),
+            tlv(0xE0, None, Some("abc")),
+            tlv(0xE0, None, Some("zz")),
+            tlv(0x00, Some("a"), None),
+            tlv(PP2_TYPE_CRC32C, None, Some("00000000")),
+            tlv(PP2_TYPE_SSL, None, Some("00")),
+            tlv(PP2_TYPE_UNIQUE_ID, Some(&id), None),
+            tlv(0xE0, Some(&long), None),
+        ] {
+            assert!(encode_tlvs(std::slice::from_ref(&bad)).is_err(), "{:?}", bad);
+        }
+        // 单个 TLV 合法, 但总长度超过 TLV 区域上限
+        let half = "x".repeat(TLV_AREA_MAX / 2);
+        assert!(encode_tlvs(&[tlv(0xE0, Some(&half), None), tlv(0xE1, Some(&half), None)]).is_err());
+        let fits = "x".repeat(TLV_AREA_MAX - 3);
+        assert_eq!(encode_tlvs(&[tlv(0xE0, Some(&fits), None)]).unwrap().len(), TLV_AREA_MAX);
+    }
+
+    #[test]
+    fn v2_length_field_covers_tlvs() {
+        let tlvs = encode_tlvs(&[tlv(0x02, Some("host"), None)]).unwrap();
+        let cases = [
+            (v4("10.0.0.1:1"), v4("10.0.0.2:2"), 0x11, 12),
+            (v4("[2001:db8::1]:1"), v4("[::1]:2"), 0x21, 36),
+            (v4("10.0.0.1:1"), v4("[::1]:2"), 0x00, 0),
+        ];
+        for (src, dst, fam, addr_len) in cases {
+            let header = build_proxy_v2_header(src, dst, &tlvs);
+            assert_eq!(header[13], fam);
+            assert_eq!(u16::from_be_bytes([header[14], header[15]]) as usize, addr_len + tlvs.len());
+            assert_eq!(header.len(), V2_FIXED_LEN + addr_len + tlvs.len());
+            assert!(header.ends_with(&tlvs));
+            let without = build_proxy_v2_header(src, dst, &[]);
+            assert_eq!(u16::from_be_bytes([without[14], without[15]]) as usize, addr_len);
+        }
+    }
+
+    #[test]
+    fn v2_without_tlvs_matches_plain_header() {
+        // 引入 TLV 之前的固定输出
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0x00, 0x0C, 10, 0, 0, 1, 10, 0, 0, 2, 0x13, 0x88, 0x01, 0xBB]);
+        assert_eq!(build_proxy_v2_header(v4("10.0.0.1:5000"), v4("10.0.0.2:443"), &[]), expected);
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x21, 0x00, 0x24]);
+        expected.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        expected.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        expected.extend_from_slice(&[0x00, 0x01, 0x00, 0x02]);
+        assert_eq!(build_proxy_v2_header(v4("[2001:db8::1]:1"), v4("[::1]:2"), &[]), expected);
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x00, 0x00, 0x00]);
+        assert_eq!(build_proxy_v2_header(v4("10.0.0.1:1"), v4("[::1]:2"), &[]), expected);
+    }
+
+    // 依次拆出 TLV 区域中的 (类型, 值)
+    fn split_tlvs(mut tlvs: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut out = Vec::new();
+        while !tlvs.is_empty() {
+            let len = u16::from_be_bytes([tlvs[1], tlvs[2]]) as usize;
+            out.push((tlvs[0], tlvs[3..3 + len].to_vec()));
+            tlvs = &tlvs[3 + len..];
+        }
+        out
+    }
+
+    #[test]
+    fn authority_replaces_template() {
+        let template = encode_tlvs(&[
+            tlv(0xE0, None, Some("0102")),
+            tlv(PP2_TYPE_AUTHORITY, Some("template.example"), None),
+            tlv(PP2_TYPE_UNIQUE_ID, Some("id-1"), None),
+        ])
+        .unwrap();
+        let out = with_authority(&template, "sni.example");
+        assert_eq!(
+            split_tlvs(&out),
+            [
+                (PP2_TYPE_AUTHORITY, b"sni.example".to_vec()),
+                (0xE0, vec![0x01, 0x02]),
+                (PP2_TYPE_UNIQUE_ID, b"id-1".to_vec()),
+            ]
+        );
+        // 模板中没有 AUTHORITY 时追加一个
+        assert_eq!(split_tlvs(&with_authority(&[], "sni.example")), [(PP2_TYPE_AUTHORITY, b"sni.example".to_vec())]);
+
+        let header = build_proxy_v2_header(v4("10.0.0.1:1"), v4("10.0.0.2:2"), &out);
+        assert_eq!(u16::from_be_bytes([header[14], header[15]]) as usize, 12 + out.len());
+        let (_, used) = complete(&header);
+        assert_eq!(used, header.len());
+    }
+
+    #[test]
+    fn empty_authority_keeps_template() {
+        let template = encode_tlvs(&[tlv(PP2_TYPE_AUTHORITY, Some("template.example"), None)]).unwrap();
+        assert_eq!(with_authority(&template, ""), template);
+        assert!(with_authority(&[], "").is_empty());
+    }
+
+    #[test]
+    fn oversized_authority_keeps_template() {
+        let template = encode_tlvs(&[tlv(0xE0, Some(&"x".repeat(TLV_AREA_MAX - 10)), None)]).unwrap();
+        assert_eq!(with_authority(&template, "sni.example"), template);
+    }
+}
@@ -0,0 +1,67 @@
+use anyhow::{Context, Result};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const FETCH_TIMEOUT: u64 = 1000; // 获取队列深度的总超时 (ms)
+const MAX_RESPONSE: usize = 64 * 1024; // 响应最大字节数
+
+/// 解析后的 http:// 地址
+pub struct MetricUrl<'a> {
+    host: &'a str, // 可能含端口
+    path: &'a str,
+}
+
+/// 只支持 http://host[:port]/path, 不带端口时使用 80
+pub fn parse_url(url: &str) -> Result<MetricUrl<'_>> {
+    let rest = url.strip_prefix("http://").ok_or_else(|| anyhow::anyhow!("只支持 http:// 地址"))?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    if host.is_empty() || host.contains(char::is_whitespace) || path.contains(char::is_whitespace) {
+        anyhow::bail!("地址格式无效");
+    }
+    Ok(MetricUrl { host, path })
+}
+
+/// 获取后端上报的队列深度; 响应体应为一个非负数字
+pub async fn fetch(url: &str) -> Result<f64> {
+    let url = parse_url(url)?;
+    tokio::time::timeout(Duration::from_millis(FETCH_TIMEOUT), get(&url))
+        .await
+        .map_err(|_| anyhow::anyhow!("请求超时"))?
+}
+
+async fn get(url: &MetricUrl<'_>) -> Result<f64> {
+    let addr = if url.host.rsplit_once(':').is_some_and(|(_, p)| p.parse::<u16>().is_ok()) {
+        url.host.to_string()
+    } else {
+        format!("{}:80", url.host)
+    };
+    let mut stream = TcpStream::connect(&addr).await.context("连接失败")?;
+    // HTTP/1.0 请求, 响应以连接关闭结束, 不需要处理分块编码
+    let request = format!("GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n", url.path, url.host);
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut resp = Vec::new();
+    (&mut stream).take(MAX_RESPONSE as u64 + 1).read_to_end(&mut resp).await?;
+    if resp.len() > MAX_RESPONSE {
+        anyhow::bail!("响应超过 {} 字节", MAX_RESPONSE);
+    }
+    let text = String::from_utf8_lossy(&resp);
+    let (head, body) = text.split_once("\r\n\r\n").ok_or_else(|| anyhow::anyhow!("响应不完整"))?;
+    let status = head.split_whitespace().nth(1).unwrap_or("");
+    if status != "200" {
+        anyhow::bail!("HTTP 状态码 {}", status);
+    }
+    parse_depth(body)
+}
+
+fn parse_depth(body: &str) -> Result<f64> {
+    let depth: f64 = body.trim().parse().map_err(|_| anyhow::anyhow!("响应不是数字: {:.32}", body.trim()))?;
+    if !(depth >= 0.0 && depth.is_finite()) {
+        anyhow::bail!("队列深度无效: {}", depth);
+    }
+    Ok(depth)
+}
@@ -0,0 +1,410 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+// --- 镜像参数 ---
+const MIRROR_QUEUE: usize = 256;          // 镜像队列长度 (按数据块计), 满了直接丢弃
+const MIRROR_CONNECT_TIMEOUT: u64 = 1000; // 镜像连接超时 (ms)
+const RELAY_BUFFER: usize = 16 * 1024;    // 默认转发缓冲区大小
+
+/// 全局镜像丢弃块数
+pub static MIRROR_DROPS: AtomicU64 = AtomicU64::new(0);
+
+/// 流量镜像: 尽力而为地把数据复制到旁路地址, 永远不阻塞主转发
+pub struct MirrorTap {
+    tx: mpsc::Sender<Vec<u8>>,
+    dropped: AtomicU64,
+}
+
+impl MirrorTap {
+    fn new() -> (Self, mpsc::Receiver<Vec<u8>>) {
+        let (tx, rx) = mpsc::channel::<Vec<u8>>(MIRROR_QUEUE);
+        (MirrorTap { tx, dropped: AtomicU64::new(0) }, rx)
+    }
+
+    /// 只镜像客户端->目标方向: 一条镜像连接
+    pub fn connect(addr: String) -> Self {
+        let (tap, rx) = Self::new();
+        tokio::spawn(async move {
+            if let Some(sink) = open_sink(&addr).await {
+                drain(sink, rx, &addr).await;
+            }
+        });
+        tap
+    }
+
+    /// 双向镜像: 上行、下行各用一条镜像连接, 不混在同一条流里;
+    /// 上行连接建立之后才连接下行, 镜像端按接受顺序区分 (先上行后下行)
+    pub fn connect_pair(addr: String) -> (Self, Self) {
+        let ((up, up_rx), (down, down_rx)) = (Self::new(), Self::new());
+        tokio::spawn(async move {
+            let Some(up_sink) = open_sink(&addr).await else { return };
+            let down_sink = open_sink(&addr).await;
+            let down = async {
+                if let Some(sink) = down_sink {
+                    drain(sink, down_rx, &addr).await;
+                }
+            };
+            tokio::join!(drain(up_sink, up_rx, &addr), down);
+        });
+        (up, down)
+    }
+
+    /// 投递一份数据副本, 队列满或镜像不可用时丢弃并计数
+    pub fn feed(&self, data: &[u8]) {
+        if self.tx.try_send(data.to_vec()).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            MIRROR_DROPS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// 连接镜像地址, 超时或失败时返回 None
+async fn open_sink(addr: &str) -> Option<TcpStream> {
+    match tokio::time::timeout(Duration::from_millis(MIRROR_CONNECT_TIMEOUT), TcpStream::connect(addr)).await {
+        Ok(Ok(s)) => Some(s),
+        _ => {
+            log::debug!("镜像地址连接失败: {}", addr);
+            None
+        }
+    }
+}
+
+/// 把队列中的数据写往镜像连接, 连接中断后丢弃其余数据 (feed 随之计入丢弃)
+async fn drain(mut sink: TcpStream, mut rx: mpsc::Receiver<Vec<u8>>, addr: &str) {
+    while let Some(buf) = rx.recv().await {
+        if sink.write_all(&buf).await.is_err() {
+            log::debug!("镜像连接中断: {}", addr);
+            return;
+        }
+    }
+}
+
+/// 单次读/写操作的超时, None 表示不限制
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OpTimeouts {
+    pub read: Option<Duration>,
+    pub write: Option<Duration>,
+}
+
+impl OpTimeouts {
+    pub fn is_set(&self) -> bool {
+        self.read.is_some() || self.write.is_some()
+    }
+}
+
+/// 限时执行单次 IO 操作, 超时返回 TimedOut
+pub async fn timed<T>(limit: Option<Duration>, what: &str, op: impl std::future::Future<Output = io::Result<T>>) -> io::Result<T> {
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, op)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("{}超时 ({}ms)", what, limit.as_millis())))?,
+        None => op.await,
+    }
+}
+
+/// 单向拷贝, 读到 EOF 后关闭写端, 可选把数据旁路给镜像; 单次读写超过限制时返回错误
+/// coalesce 不为空时, 在该时间窗口内把连续的小块数据合并后再写出; buffer 为拷贝缓冲区大小
+pub async fn copy_half<R, W>(
+    mut reader: R,
+    mut writer: W,
+    mirror: Option<&MirrorTap>,
+    timeouts: OpTimeouts,
+    coalesce: Option<Duration>,
+    buffer: Option<usize>,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; buffer.unwrap_or(RELAY_BUFFER)];
+    let mut total: u64 = 0;
+    loop {
+        let mut n = timed(timeouts.read, "读取", reader.read(&mut buf)).await?;
+        let mut eof = n == 0;
+        if let (Some(window), false) = (coalesce, eof) {
+            (n, eof) = read_more(&mut reader, &mut buf, n, window).await?;
+        }
+        if n > 0 {
+            timed(timeouts.write, "写入", writer.write_all(&buf[..n])).await?;
+            if let Some(m) = mirror {
+                m.feed(&buf[..n]);
+            }
+            total += n as u64;
+        }
+        if eof {
+            timed(timeouts.write, "写入", writer.shutdown()).await?;
+            return Ok(total);
+        }
+    }
+}
+
+/// 从首块数据开始计时, 窗口内继续读取直到缓冲区满; 返回 (已读字节数, 是否读到 EOF)
+async fn read_more<R>(reader: &mut R, buf: &mut [u8], mut filled: usize, window: Duration) -> io::Result<(usize, bool)>
+where
+    R: AsyncRead + Unpin,
+{
+    let deadline = tokio::time::Instant::now() + window;
+    while filled < buf.len() {
+        match tokio::time::timeout_at(deadline, reader.read(&mut buf[filled..])).await {
+            Ok(Ok(0)) => return Ok((filled, true)),
+            Ok(Ok(n)) => filled += n,
+            Ok(Err(e)) => return Err(e),
+            Err(_) => break,
+        }
+    }
+    Ok((filled, false))
+}
+
+/// 连接最近一次收发数据的时间, 用于空闲超时; 同时累计两个方向的字节数, 用于连接结束时的日志
+pub struct Activity {
+    base: Instant,
+    last_ms: AtomicU64, // 相对 base 的毫秒数
+    up: AtomicU64,      // 客户端 -> 目标
+    down: AtomicU64,    // 目标 -> 客户端
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Activity { base: Instant::now(), last_ms: AtomicU64::new(0), up: AtomicU64::new(0), down: AtomicU64::new(0) }
+    }
+}
+
+impl Activity {
+    pub fn touch(&self) {
+        self.last_ms.store(self.base.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// 记录一次收发, upstream 为客户端 -> 目标方向
+    pub fn transfer(&self, upstream: bool, n: usize) {
+        self.touch();
+        let counter = if upstream { &self.up } else { &self.down };
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// 已转发的 (客户端 -> 目标, 目标 -> 客户端) 字节数
+    pub fn bytes(&self) -> (u64, u64) {
+        (self.up.load(Ordering::Relaxed), self.down.load(Ordering::Relaxed))
+    }
+
+    /// 等到连续 idle 时间没有收发数据
+    pub async fn idle(&self, idle: Duration) {
+        loop {
+            let deadline = self.base + Duration::from_millis(self.last_ms.load(Ordering::Relaxed)) + idle;
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+    }
+}
+
+/// 客户端连接的包装: 每次读到或写出数据时记录到 Activity
+/// 两个方向的数据都经过客户端连接, 只包装这一侧即可覆盖双向
+pub struct Tracked<'a, S> {
+    inner: S,
+    activity: &'a Activity,
+}
+
+impl<'a, S> Tracked<'a, S> {
+    pub fn new(inner: S, activity: &'a Activity) -> Self {
+        Tracked { inner, activity }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tracked<'_, S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if matches!(res, Poll::Ready(Ok(()))) && buf.filled().len() > before {
+            self.activity.transfer(true, buf.filled().len() - before);
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tracked<'_, S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            if n > 0 {
+                self.activity.transfer(false, n);
+            }
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// 令牌桶: 允许透支, 余额为负时等到补足再继续, 平均速率不超过 rate
+pub struct Bucket {
+    rate: f64,     // 字节/秒
+    capacity: f64, // 空闲时最多积攒的字节数
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    /// kbps 为速率上限 (千比特/秒)
+    pub fn new(kbps: u64) -> Self {
+        let rate = (kbps * 1000 / 8) as f64;
+        Bucket { rate, capacity: rate / 10.0, tokens: 0.0, last: Instant::now() }
+    }
+
+    /// 补充令牌; 余额为负时返回补足所需的等待时间
+    fn wait(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.capacity);
+        self.last = now;
+        (self.tokens < 0.0).then(|| Duration::from_secs_f64(-self.tokens / self.rate))
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.tokens -= n as f64;
+    }
+}
+
+/// 同一目标所有连接共享的带宽上限, 每个方向一个令牌桶
+pub struct SharedCap {
+    pub up: Mutex<Bucket>,
+    pub down: Mutex<Bucket>,
+}
+
+impl SharedCap {
+    pub fn new(kbps: u64) -> Self {
+        SharedCap { up: Mutex::new(Bucket::new(kbps)), down: Mutex::new(Bucket::new(kbps)) }
+    }
+}
+
+/// 限速与计量参数: 本连接的速率上限、目标共享的带宽上限、目标的累计流量计数
+#[derive(Default)]
+pub struct Limits {
+    pub kbps: Option<u64>,
+    pub cap: Option<Arc<SharedCap>>,
+    pub meter: Option<Arc<AtomicU64>>,
+}
+
+/// 一个方向的限速: 本连接和共享的令牌桶都不透支时才继续
+struct Lane {
+    own: Option<Bucket>,
+    shared: Option<Arc<SharedCap>>,
+    up: bool,
+    sleep: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl Lane {
+    fn shared(&self) -> Option<&Mutex<Bucket>> {
+        self.shared.as_deref().map(|c| if self.up { &c.up } else { &c.down })
+    }
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            let own = self.own.as_mut().and_then(Bucket::wait);
+            let shared = self.shared().and_then(|b| b.lock().unwrap().wait());
+            let Some(wait) = own.max(shared) else {
+                self.sleep = None;
+                return Poll::Ready(());
+            };
+            let deadline = tokio::time::Instant::now() + wait;
+            match self.sleep {
+                Some(ref mut sleep) => sleep.as_mut().reset(deadline),
+                None => self.sleep = Some(Box::pin(tokio::time::sleep_until(deadline))),
+            }
+            if self.sleep.as_mut().unwrap().as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    fn consume(&mut self, n: usize) {
+        if let Some(ref mut b) = self.own {
+            b.consume(n);
+        }
+        if let Some(b) = self.shared() {
+            b.lock().unwrap().consume(n);
+        }
+    }
+
+    fn active(&self) -> bool {
+        self.own.is_some() || self.shared.is_some()
+    }
+}
+
+/// 客户端连接的限速包装: 读 (客户端 -> 目标) 和写 (目标 -> 客户端) 分别限速, 双向字节数计入 meter
+/// 未设置任何限制时直接透传
+pub struct Throttled<S> {
+    inner: S,
+    up: Lane,
+    down: Lane,
+    meter: Option<Arc<AtomicU64>>,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, limits: Limits) -> Self {
+        let lane = |up| Lane { own: limits.kbps.map(Bucket::new), shared: limits.cap.clone(), up, sleep: None };
+        Throttled { inner, up: lane(true), down: lane(false), meter: limits.meter }
+    }
+
+    fn record(&self, n: usize) {
+        if let Some(ref m) = self.meter {
+            m.fetch_add(n as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.up.active() {
+            std::task::ready!(this.up.poll_ready(cx));
+        }
+        let before = buf.filled().len();
+        let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            let n = buf.filled().len() - before;
+            this.up.consume(n);
+            this.record(n);
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.down.active() {
+            std::task::ready!(this.down.poll_ready(cx));
+        }
+        let res = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            this.down.consume(n);
+            this.record(n);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,154 @@
+use arc_swap::ArcSwap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::Notify;
+
+use crate::config::{self, Config};
+
+const WATCH_INTERVAL: u64 = 2; // 检查配置文件变化的间隔 (秒)
+
+/// 已读取、等待探测任务应用的新配置
+pub type Pending = Arc<Mutex<Option<Config>>>;
+
+/// 重新读取配置文件中同名服务的配置; 故障注入开关只由命令行决定, 沿用当前值
+pub fn load(path: &str, current: &Config) -> anyhow::Result<(Config, Vec<PathBuf>)> {
+    let (services, files) = config::load_with_files(path)?;
+    let mut config = services
+        .into_iter()
+        .find(|s| s.name == current.name)
+        .ok_or_else(|| anyhow::anyhow!("服务 [{}] 已不在配置中, 增删服务需要重启", current.name))?;
+    config.fault_seed = current.fault_seed;
+    Ok((config, files))
+}
+
+/// 读取新配置交给探测任务应用, 返回读取过的文件; 失败时保持当前配置
+fn submit(path: &str, live: &ArcSwap<Config>, pending: &Pending, wakeup: &Notify) -> Option<Vec<PathBuf>> {
+    match load(path, &live.load()) {
+        Ok((config, files)) => {
+            *pending.lock().unwrap() = Some(config);
+            wakeup.notify_one();
+            Some(files)
+        }
+        Err(e) => {
+            log::error!("!!! 配置重新加载失败, 保持当前配置: {:#}", e);
+            None
+        }
+    }
+}
+
+/// 新旧配置中需要重启才能生效的项
+pub fn restart_required(old: &Config, new: &Config) -> Vec<&'static str> {
+    let mut keys = Vec::new();
+    if old.bind_addr != new.bind_addr {
+        keys.push("bind_addr");
+    }
+    if old.listen_workers != new.listen_workers {
+        keys.push("listen_workers");
+    }
+    if old.tcp_fastopen != new.tcp_fastopen {
+        keys.push("tcp_fastopen");
+    }
+    if old.transparent != new.transparent {
+        keys.push("transparent");
+    }
+    if old.admin_addr != new.admin_addr {
+        keys.push("admin_addr");
+    }
+    if old.metrics_addr != new.metrics_addr {
+        keys.push("metrics_addr");
+    }
+    if old.control_socket != new.control_socket {
+        keys.push("control_socket");
+    }
+    if old.tunnel != new.tunnel {
+        keys.push("tunnel");
+    }
+    if old.udp != new.udp {
+        keys.push("udp");
+    }
+    if old.tls != new.tls {
+        keys.push("tls");
+    }
+    if old.report_interval != new.report_interval {
+        keys.push("report_interval");
+    }
+    if old.self_probe != new.self_probe {
+        keys.push("self_probe");
+    }
+    if old.watch_config != new.watch_config {
+        keys.push("watch_config");
+    }
+    if old.shutdown_drain_timeout != new.shutdown_drain_timeout {
+        keys.push("shutdown_drain_timeout");
+    }
+    if (&old.statsd_addr, &old.statsd_prefix, old.statsd_interval)
+        != (&new.statsd_addr, &new.statsd_prefix, new.statsd_interval)
+    {
+        keys.push("statsd_*");
+    }
+    if (&old.otlp_endpoint, &old.otlp_service_name, &old.otlp_headers)
+        != (&new.otlp_endpoint, &new.otlp_service_name, &new.otlp_headers)
+    {
+        keys.push("otlp_*");
+    }
+    keys
+}
+
+/// 收到 SIGHUP 时重新读取配置, 读取或校验失败则保持当前配置
+#[cfg(unix)]
+pub fn watch_sighup(path: String, live: Arc<ArcSwap<Config>>, pending: Pending, wakeup: Arc<Notify>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    tokio::spawn(async move {
+        let mut hup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                log::error!("无法监听 SIGHUP, 配置重新加载不可用: {}", e);
+                return;
+            }
+        };
+        while hup.recv().await.is_some() {
+            log::info!(">>> 收到 SIGHUP, 重新加载配置: {}", path);
+            submit(&path, &live, &pending, &wakeup);
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn watch_sighup(_path: String, _live: Arc<ArcSwap<Config>>, _pending: Pending, _wakeup: Arc<Notify>) {
+    log::debug!("当前平台不支持 SIGHUP, 配置重新加载不可用");
+}
+
+/// 定时检查配置文件 (含 include 的子文件) 的修改时间和大小, 变化后重新加载
+/// 连续两次检查结果一致才加载, 避免读到写了一半的文件
+pub fn watch_files(path: String, live: Arc<ArcSwap<Config>>, pending: Pending, wakeup: Arc<Notify>) {
+    tokio::spawn(async move {
+        log::info!("监视配置文件变化: {} (间隔: {}秒)", path, WATCH_INTERVAL);
+        let mut files = config::load_with_files(&path).map(|(_, f)| f).unwrap_or_else(|_| vec![path.clone().into()]);
+        let mut loaded = fingerprint(&files);
+        let mut seen = loaded.clone();
+        loop {
+            tokio::time::sleep(Duration::from_secs(WATCH_INTERVAL)).await;
+            let current = fingerprint(&files);
+            if current == loaded || current != seen {
+                seen = current;
+                continue;
+            }
+            log::info!(">>> 检测到配置文件变化, 重新加载配置: {}", path);
+            if let Some(new_files) = submit(&path, &live, &pending, &wakeup) {
+                files = new_files;
+            }
+            // 加载失败也记下当前状态, 等文件再次修改后重试
+            loaded = fingerprint(&files);
+            seen = loaded.clone();
+        }
+    });
+}
+
+fn fingerprint(files: &[PathBuf]) -> Vec<Option<(SystemTime, u64)>> {
+    files
+        .iter()
+        .map(|f| std::fs::metadata(f).ok().and_then(|m| Some((m.modified().ok()?, m.len()))))
+        .collect()
+}
@@ -0,0 +1,253 @@
+use arc_swap::ArcSwap;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::{Config, PoolConfig, TargetConfig};
+use crate::state::{BestTarget, Snapshot};
+
+const HEADERS: [&str; 7] = ["节点池", "节点", "地址", "评分", "丢包", "连接数", "状态"];
+const STATUS_HEADERS: [&str; 7] = ["节点池", "节点", "地址", "评分", "原始评分", "连接数", "状态"];
+const PROBE_HEADERS: [&str; 9] = ["节点池", "节点", "地址", "评分", "最低", "平均", "最高", "丢包", "状态"];
+
+/// 按固定间隔输出所有节点的状态汇总表, 与探测间隔无关
+pub async fn run(interval: u64, published: Arc<ArcSwap<Snapshot>>, config: Arc<ArcSwap<Config>>) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+    ticker.tick().await; // 第一次立即触发, 跳过 (此时还没有探测结果)
+    loop {
+        ticker.tick().await;
+        let rows = collect_rows(&published.load(), &config.load().pool_list());
+        log::info!("--- 节点状态汇总 ---");
+        for line in render(&HEADERS, &rows) {
+            log::info!("{}", line);
+        }
+    }
+}
+
+/// probe_all_addresses / srv 的目标每个可用的候选一行, 没有可用候选时一行
+fn expand<'a>(s: &'a Snapshot, p: &'a PoolConfig, t: &'a TargetConfig) -> Vec<(&'a str, &'a str, &'a str)> {
+    let candidates: Vec<_> = s
+        .pools
+        .iter()
+        .filter(|sp| sp.name == p.name)
+        .flat_map(|sp| &sp.ranked)
+        .filter(|r| t.expands() && r.group == t.name)
+        .map(|r| (p.name.as_str(), r.name.as_str(), ""))
+        .collect();
+    if candidates.is_empty() {
+        vec![(p.name.as_str(), t.name.as_str(), t.addr.as_str())]
+    } else {
+        candidates
+    }
+}
+
+/// 每个配置的目标一行; 隧道等运行时才出现的节点池附在最后
+fn collect_rows(s: &Snapshot, pools: &[PoolConfig]) -> Vec<Vec<String>> {
+    let selected = s.select().map(|t| t.name.as_str());
+    let names = pools
+        .iter()
+        .flat_map(|p| p.targets.iter().flat_map(move |t| expand(s, p, t)))
+        .chain(
+            s.pools
+                .iter()
+                .filter(|p| !pools.iter().any(|c| c.name == p.name))
+                .flat_map(|p| p.ranked.iter().map(move |t| (p.name.as_str(), t.name.as_str(), ""))),
+        );
+
+    names
+        .map(|(pool, name, addr)| match s.find(name) {
+            Some((_, t)) => vec![
+                pool.to_string(),
+                name.to_string(),
+                t.addr.to_string(),
+                t.score.to_string(),
+                t.loss.to_string(),
+                s.conns.get(name).to_string(),
+                if selected == Some(name) { "* 使用中" } else { "可用" }.to_string(),
+            ],
+            None => vec![
+                pool.to_string(),
+                name.to_string(),
+                addr.to_string(),
+                "-".to_string(),
+                "-".to_string(),
+                s.conns.get(name).to_string(),
+                "不可用".to_string(),
+            ],
+        })
+        .collect()
+}
+
+/// 按显示宽度对齐各列
+fn render(headers: &[&str], rows: &[Vec<String>]) -> Vec<String> {
+    let mut widths: Vec<usize> = headers.iter().map(|h| display_width(h)).collect();
+    for row in rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(display_width(cell));
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let padded: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(c, w)| format!("{}{}", c, " ".repeat(w - display_width(c))))
+            .collect();
+        padded.join("  ").trim_end().to_string()
+    };
+    std::iter::once(line(headers.to_vec()))
+        .chain(rows.iter().map(|r| line(r.iter().map(String::as_str).collect())))
+        .collect()
+}
+
+/// 终端显示宽度: 中日韩等全角字符按 2 计
+fn display_width(s: &str) -> usize {
+    s.chars().map(|c| if c >= '\u{1100}' { 2 } else { 1 }).sum()
+}
+
+/// probe 子命令中一个候选的探测结果; result 为 None 表示本轮不可用
+pub struct ProbeRow {
+    pub service: String,
+    pub pool: String,
+    pub name: String,
+    pub addr: String,
+    pub probes: u32,
+    pub result: Option<BestTarget>,
+    pub best: bool, // 所在节点池本轮的最优节点
+}
+
+/// 一个节点池的探测结果展开为行: 每个可用的候选一行, 没有可用候选的目标一行
+pub fn probe_rows(config: &Config, pool: &PoolConfig, mut ranked: Vec<BestTarget>) -> Vec<ProbeRow> {
+    ranked.sort_by_key(|t| (t.priority, t.raw_score));
+    let best = ranked.first().map(|t| t.name.clone());
+    pool.targets
+        .iter()
+        .flat_map(|t| {
+            let probes = t.probe_count.unwrap_or(config.probe_count);
+            let mut rows: Vec<ProbeRow> = ranked
+                .iter()
+                .filter(|r| r.group == t.name)
+                .map(|r| ProbeRow {
+                    service: config.name.clone(),
+                    pool: pool.name.clone(),
+                    name: r.name.clone(),
+                    addr: r.addr.to_string(),
+                    probes,
+                    result: Some(r.clone()),
+                    best: best.as_ref() == Some(&r.name),
+                })
+                .collect();
+            if rows.is_empty() {
+                rows.push(ProbeRow {
+                    service: config.name.clone(),
+                    pool: pool.name.clone(),
+                    name: t.name.clone(),
+                    addr: t.addr.clone(),
+                    probes,
+                    result: None,
+                    best: false,
+                });
+            }
+            rows
+        })
+        .collect()
+}
+
+/// probe 子命令的表格; 多个服务时第一列为服务名称
+pub fn probe_table(rows: &[ProbeRow], with_service: bool) -> Vec<String> {
+    let headers: Vec<&str> = with_service.then_some("服务").into_iter().chain(PROBE_HEADERS).collect();
+    let cells: Vec<Vec<String>> = rows
+        .iter()
+        .map(|r| {
+            let mut row: Vec<String> = with_service.then(|| r.service.clone()).into_iter().collect();
+            row.extend([r.pool.clone(), r.name.clone(), r.addr.clone()]);
+            match r.result {
+                Some(ref t) => row.extend([
+                    t.raw_score.to_string(),
+                    format!("{}ms", t.min_ms),
+                    format!("{}ms", t.avg_ms),
+                    format!("{}ms", t.max_ms),
+                    format!("{}/{}", t.loss, r.probes),
+                    if r.best { "* 最优" } else { "可用" }.to_string(),
+                ]),
+                None => row.extend(["-", "-", "-", "-", "-", "不可用"].map(String::from)),
+            }
+            row
+        })
+        .collect();
+    render(&headers, &cells)
+}
+
+/// probe 子命令 --json 输出的一行
+pub fn probe_json(r: &ProbeRow) -> Value {
+    let mut row = json!({
+        "service": Some(r.service.as_str()).filter(|n| !n.is_empty()),
+        "pool": r.pool,
+        "target": r.name,
+        "addr": r.addr,
+        "probes": r.probes,
+        "available": r.result.is_some(),
+        "best": r.best,
+    });
+    if let Some(ref t) = r.result {
+        row["score"] = u64::try_from(t.raw_score).map_or(Value::Null, Value::from);
+        row["min_ms"] = (t.min_ms as u64).into();
+        row["avg_ms"] = (t.avg_ms as u64).into();
+        row["max_ms"] = (t.max_ms as u64).into();
+        row["jitter_ms"] = t.jitter_ms.into();
+        row["loss"] = t.loss.into();
+    }
+    row
+}
+
+/// status 子命令: 一个服务的状态 (管理接口 /status 的 JSON) 转为文本
+pub fn status_lines(s: &Value) -> Vec<String> {
+    let mut lines = Vec::new();
+    if let Some(name) = s["service"].as_str() {
+        lines.push(format!("服务 [{}]", name));
+    }
+    let best = &s["best"];
+    lines.push(match best["name"].as_str() {
+        Some(name) => format!("当前节点: {} ({}), 评分 {}", name, best["addr"].as_str().unwrap_or_default(), best["score"]),
+        None => "当前节点: 无可用节点".to_string(),
+    });
+    if s["probing_paused"] == true {
+        lines.push(format!("探测已暂停 {} 秒", s["paused_secs"]));
+    }
+    if let Some(name) = s["pinned"].as_str() {
+        lines.push(format!("手动固定: {}", name));
+    }
+    let maintenance: Vec<&str> = s["maintenance"].as_array().into_iter().flatten().filter_map(Value::as_str).collect();
+    if !maintenance.is_empty() {
+        lines.push(format!("维护中: {}", maintenance.join(", ")));
+    }
+
+    let pools = s["pools"].as_array().into_iter().flatten();
+    let rows: Vec<Vec<String>> = pools
+        .flat_map(|p| p["targets"].as_array().into_iter().flatten().map(move |t| (p, t)))
+        .map(|(p, t)| {
+            let name = t["name"].as_str().unwrap_or_default();
+            let conns = match t["max_connections"].as_u64() {
+                Some(max) => format!("{}/{}", t["active_connections"], max),
+                None => t["active_connections"].to_string(),
+            };
+            let state = if best["name"] == t["name"] {
+                "* 使用中"
+            } else if maintenance.contains(&name) {
+                "维护中"
+            } else {
+                "可用"
+            };
+            vec![
+                p["name"].as_str().unwrap_or_default().to_string(),
+                name.to_string(),
+                t["addr"].as_str().unwrap_or_default().to_string(),
+                t["score"].to_string(),
+                t["raw_score"].to_string(),
+                conns,
+                state.to_string(),
+            ]
+        })
+        .collect();
+    lines.extend(render(&STATUS_HEADERS, &rows));
+    lines
+}
@@ -0,0 +1,82 @@
+use serde::Deserialize;
+
+/// 单轮探测样本的聚合方式
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RoundAggregation {
+    /// 算术平均
+    #[default]
+    Mean,
+    /// 截尾平均: 去掉最高和最低各 trim_fraction 比例的样本后再平均
+    TrimmedMean,
+    /// 中位数
+    Median,
+    /// 第 95 / 99 百分位 (最近秩), 按尾部延迟选择
+    P95,
+    P99,
+}
+
+/// 聚合成功样本的 RTT, 返回用于评分的 RTT 总和 (与成功次数同量纲, 便于叠加丢包惩罚)
+pub fn aggregate_rtt_sum(samples: &[u128], mode: RoundAggregation, trim_fraction: f64) -> u128 {
+    match mode {
+        RoundAggregation::Mean => samples.iter().sum(),
+        RoundAggregation::TrimmedMean => {
+            if samples.is_empty() {
+                return 0;
+            }
+            let mut sorted = samples.to_vec();
+            sorted.sort_unstable();
+            // 样本太少时减少截尾数量, 至少保留一个样本
+            let k = ((sorted.len() as f64 * trim_fraction) as usize).min((sorted.len() - 1) / 2);
+            let kept = &sorted[k..sorted.len() - k];
+            let mean = kept.iter().sum::<u128>() / kept.len() as u128;
+            mean * samples.len() as u128
+        }
+        RoundAggregation::Median => {
+            let mut sorted = samples.to_vec();
+            sorted.sort_unstable();
+            let n = sorted.len();
+            let median = match n {
+                0 => 0,
+                _ if n % 2 == 1 => sorted[n / 2],
+                _ => (sorted[n / 2 - 1] + sorted[n / 2]) / 2,
+            };
+            median * n as u128
+        }
+        RoundAggregation::P95 => percentile(samples, 0.95) * samples.len() as u128,
+        RoundAggregation::P99 => percentile(samples, 0.99) * samples.len() as u128,
+    }
+}
+
+/// 最近秩百分位: 排序后第 ceil(p * n) 个样本
+fn percentile(samples: &[u128], p: f64) -> u128 {
+    if samples.is_empty() {
+        return 0;
+    }
+    let mut sorted = samples.to_vec();
+    sorted.sort_unstable();
+    let rank = ((p * sorted.len() as f64).ceil() as usize).clamp(1, sorted.len());
+    sorted[rank - 1]
+}
+
+/// 成功样本 RTT 的标准差 (抖动), 少于两个样本时为 0
+pub fn stddev(samples: &[u128]) -> f64 {
+    if samples.len() < 2 {
+        return 0.0;
+    }
+    let n = samples.len() as f64;
+    let mean = samples.iter().sum::<u128>() as f64 / n;
+    let var = samples.iter().map(|&s| (s as f64 - mean).powi(2)).sum::<f64>() / n;
+    var.sqrt()
+}
+
+/// 非对称指数平滑: 评分变差时按 up 速率跟随, 变好时按 down 速率跟随 (速率 1 表示不平滑)
+pub fn decay(prev: Option<f64>, raw: f64, up: f64, down: f64) -> f64 {
+    match prev {
+        Some(prev) => {
+            let rate = if raw > prev { up } else { down };
+            prev + rate * (raw - prev)
+        }
+        None => raw,
+    }
+}
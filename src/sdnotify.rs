@@ -0,0 +1,102 @@
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+// 由 systemd 启动 (Type=notify) 时的通知地址和看门狗超时
+struct Notifier {
+    socket: String, // NOTIFY_SOCKET, @ 开头为抽象命名空间
+    watchdog: Option<Duration>,
+}
+
+// 各服务的探测循环: 全部就绪后发送 READY=1, 全部报告存活后发送一次 WATCHDOG=1
+struct Loops {
+    total: usize,
+    ready: usize,
+    alive: Vec<bool>,
+}
+
+static NOTIFIER: OnceLock<Notifier> = OnceLock::new();
+static LOOPS: Mutex<Loops> = Mutex::new(Loops { total: 0, ready: 0, alive: Vec::new() });
+
+/// 读取 systemd 传入的 NOTIFY_SOCKET / WATCHDOG_USEC, 启动时调用一次; services 为服务数
+/// 读取后清除环境变量, 不传给子进程
+pub fn init(services: usize) {
+    let var = |key: &str| std::env::var(key).ok().filter(|v| !v.is_empty());
+    let socket = var("NOTIFY_SOCKET");
+    let usec = var("WATCHDOG_USEC").and_then(|v| v.parse::<u64>().ok()).filter(|&u| u > 0);
+    let pid = var("WATCHDOG_PID").and_then(|v| v.parse::<u32>().ok());
+    for key in ["NOTIFY_SOCKET", "WATCHDOG_USEC", "WATCHDOG_PID"] {
+        std::env::remove_var(key);
+    }
+    let Some(socket) = socket.filter(|_| cfg!(target_os = "linux")) else { return };
+    let watchdog = usec.filter(|_| pid.is_none_or(|p| p == std::process::id())).map(Duration::from_micros);
+    if let Some(w) = watchdog {
+        log::info!("systemd 看门狗已启用: 超时 {}ms", w.as_millis());
+    }
+    LOOPS.lock().unwrap().total = services;
+    let _ = NOTIFIER.set(Notifier { socket, watchdog });
+}
+
+/// 一个服务的探测循环在 systemd 中的状态
+pub struct Heartbeat {
+    slot: usize,
+    ready: bool,
+}
+
+impl Heartbeat {
+    pub fn register() -> Heartbeat {
+        let mut loops = LOOPS.lock().unwrap();
+        loops.alive.push(false);
+        Heartbeat { slot: loops.alive.len() - 1, ready: false }
+    }
+
+    /// 本服务已选出最优节点; 所有服务都就绪后通知 systemd 启动完成
+    pub fn ready(&mut self) {
+        let Some(n) = NOTIFIER.get() else { return };
+        if std::mem::replace(&mut self.ready, true) {
+            return;
+        }
+        let mut loops = LOOPS.lock().unwrap();
+        loops.ready += 1;
+        if loops.ready == loops.total {
+            drop(loops);
+            n.send("READY=1");
+            log::info!("已通知 systemd 服务就绪");
+        }
+    }
+
+    /// 探测循环仍在运行; 所有服务的探测循环都报告过后喂一次看门狗
+    pub fn beat(&self) {
+        let Some(n) = NOTIFIER.get().filter(|n| n.watchdog.is_some()) else { return };
+        let mut loops = LOOPS.lock().unwrap();
+        loops.alive[self.slot] = true;
+        if loops.alive.len() == loops.total && loops.alive.iter().all(|a| *a) {
+            loops.alive.iter_mut().for_each(|a| *a = false);
+            drop(loops);
+            n.send("WATCHDOG=1");
+        }
+    }
+
+    /// 等待下一轮期间报告存活的间隔: 看门狗超时的一半
+    pub fn interval(&self) -> Option<Duration> {
+        NOTIFIER.get()?.watchdog.map(|w| w / 2)
+    }
+}
+
+impl Notifier {
+    #[cfg(target_os = "linux")]
+    fn send(&self, msg: &str) {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+        let sent = UnixDatagram::unbound().and_then(|sock| match self.socket.strip_prefix('@') {
+            Some(name) => sock.send_to_addr(msg.as_bytes(), &SocketAddr::from_abstract_name(name)?),
+            None => sock.send_to(msg.as_bytes(), &self.socket),
+        });
+        if let Err(e) = sent {
+            log::warn!("systemd 通知 {} 发送失败: {}", msg, e);
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn send(&self, _msg: &str) {}
+}
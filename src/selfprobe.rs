@@ -0,0 +1,153 @@
+use arc_swap::ArcSwap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+
+use crate::config::Config;
+use crate::proxy;
+use crate::state::Snapshot;
+
+const CONNECT_TIMEOUT: u64 = 1000; // 连接本机监听地址的超时 (ms)
+const FORWARD_TIMEOUT: u64 = 5000; // 等待转发器连上目标的超时 (ms)
+const GRACE: u64 = 500; // 连上目标后继续观察的时间 (ms), 期间被关闭视为目标拒绝了连接
+
+/// 一次自检的结果
+#[derive(Clone)]
+pub struct Outcome {
+    pub at: Instant,
+    pub target: Option<String>,
+    pub result: Result<u128, String>, // 成功时为往返耗时 (ms)
+}
+
+// 各服务最近一次自检结果, 供管理接口读取
+static LAST: LazyLock<Mutex<HashMap<String, Outcome>>> = LazyLock::new(Default::default);
+// 正在进行的自检连接: 转发器按客户端地址认出它并回报转发结果
+static ARMED: AtomicUsize = AtomicUsize::new(0);
+static WAITING: LazyLock<Mutex<HashMap<SocketAddr, oneshot::Sender<String>>>> = LazyLock::new(Default::default);
+
+/// 服务最近一次自检结果
+pub fn last(service: &str) -> Option<Outcome> {
+    LAST.lock().unwrap().get(service).cloned()
+}
+
+/// 转发任务连上目标并写完 PROXY 头后调用; 只有自检连接会被认领
+pub fn forwarded(peer: Option<SocketAddr>, target: &str) {
+    if ARMED.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let Some(peer) = peer else { return };
+    if let Some(tx) = WAITING.lock().unwrap().remove(&peer) {
+        let _ = tx.send(target.to_string());
+    }
+}
+
+/// 按 self_probe_interval 连接自身的监听地址, 验证经转发器到当前最优节点的完整转发
+pub async fn run(live: Arc<ArcSwap<Config>>, published: Arc<ArcSwap<Snapshot>>) {
+    log::info!("转发自检已开启 (间隔: {}秒)", live.load().self_probe_interval);
+    loop {
+        tokio::time::sleep(Duration::from_secs(live.load().self_probe_interval)).await;
+        // 没有可用节点时转发器本来就会拒绝连接, 不算数据通路故障
+        if published.load().select().is_none() {
+            log::debug!("自检跳过: 当前没有可用节点");
+            continue;
+        }
+
+        let config = live.load_full();
+        let failed_before = LAST.lock().unwrap().get(&config.name).is_some_and(|o| o.result.is_err());
+        let (target, result) = match check(&config).await {
+            Ok((target, ms)) => {
+                if failed_before {
+                    log::info!(">>> 转发自检恢复: 经 [{}] 往返 {}ms", target, ms);
+                } else {
+                    log::debug!("转发自检通过: 经 [{}] 往返 {}ms", target, ms);
+                }
+                (Some(target), Ok(ms))
+            }
+            Err((target, e)) => {
+                log::warn!("!!! 转发自检失败: {}", e);
+                (target, Err(e))
+            }
+        };
+        LAST.lock().unwrap().insert(config.name.clone(), Outcome { at: Instant::now(), target, result });
+    }
+}
+
+/// 一次自检: 连上监听地址 -> 转发器回报已连上目标 -> 目标没有立即关闭连接
+async fn check(config: &Config) -> Result<(String, u128), (Option<String>, String)> {
+    let addr = local_addr(&config.bind_addr).await.map_err(|e| (None, e))?;
+    let start = Instant::now();
+    let mut stream = tokio::time::timeout(Duration::from_millis(CONNECT_TIMEOUT), TcpStream::connect(addr))
+        .await
+        .map_err(|_| (None, format!("连接监听地址 {} 超时", addr)))?
+        .map_err(|e| (None, format!("无法连接监听地址 {}: {}", addr, e)))?;
+
+    let (tx, mut rx) = oneshot::channel();
+    let local = stream.local_addr().map_err(|e| (None, e.to_string()))?;
+    WAITING.lock().unwrap().insert(local, tx);
+    ARMED.fetch_add(1, Ordering::Relaxed);
+    let result = verify(config, &mut stream, local, &mut rx, start).await;
+    ARMED.fetch_sub(1, Ordering::Relaxed);
+    WAITING.lock().unwrap().remove(&local);
+    result
+}
+
+async fn verify(
+    config: &Config,
+    stream: &mut TcpStream,
+    local: SocketAddr,
+    rx: &mut oneshot::Receiver<String>,
+    start: Instant,
+) -> Result<(String, u128), (Option<String>, String)> {
+    // 转发器会解析本连接的 PROXY 头时发送 LOCAL 头, 与负载均衡的健康检查相同
+    if config.trusts_proxy_header(local.ip()) {
+        stream.write_all(&proxy::build_proxy_v2_local_header()).await.map_err(|e| (None, e.to_string()))?;
+    }
+
+    let mut buf = [0u8; 256];
+    let target = tokio::select! {
+        reported = &mut *rx => reported.map_err(|_| (None, "转发器没有回报转发结果".to_string()))?,
+        read = stream.read(&mut buf) => {
+            return Err((None, match read {
+                Ok(n) if n > 0 && is_reject(config, &buf[..n]) => "转发器拒绝了连接".to_string(),
+                Ok(0) | Err(_) => "转发器未能连上目标, 连接已关闭".to_string(),
+                Ok(_) => "转发器在连上目标前返回了数据".to_string(),
+            }));
+        }
+        _ = tokio::time::sleep(Duration::from_millis(FORWARD_TIMEOUT)) => {
+            return Err((None, format!("转发器 {}ms 内没有连上目标", FORWARD_TIMEOUT)));
+        }
+    };
+    let rtt = start.elapsed().as_millis();
+
+    // 目标收到 PROXY 头后立即断开, 多半是头部格式有误
+    match tokio::time::timeout(Duration::from_millis(GRACE), stream.read(&mut buf)).await {
+        Ok(Ok(0)) | Ok(Err(_)) => Err((Some(target.clone()), format!("[{}] 连上后立即关闭了连接", target))),
+        _ => Ok((target, rtt)),
+    }
+}
+
+/// 转发器拒绝连接时写回的内容
+fn is_reject(config: &Config, data: &[u8]) -> bool {
+    config.reject_response.as_ref().is_some_and(|r| r.to_bytes().starts_with(data))
+}
+
+/// 监听地址为 0.0.0.0 / :: 时改用对应的回环地址
+async fn local_addr(bind_addr: &str) -> Result<SocketAddr, String> {
+    let mut addr = tokio::net::lookup_host(bind_addr)
+        .await
+        .ok()
+        .and_then(|mut a| a.next())
+        .ok_or_else(|| format!("无法解析监听地址 {}", bind_addr))?;
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        });
+    }
+    Ok(addr)
+}
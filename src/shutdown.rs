@@ -0,0 +1,80 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+// 正在处理的入站连接数 (所有服务合计), 退出前等待其归零
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// 登记一条入站连接, 释放时自动减一
+pub struct Active(());
+
+impl Active {
+    pub fn enter() -> Self {
+        ACTIVE.fetch_add(1, Ordering::Relaxed);
+        Active(())
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        ACTIVE.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// 等待退出信号: SIGTERM 或 Ctrl-C (SIGINT)
+#[cfg(unix)]
+pub async fn signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut term) => {
+            tokio::select! {
+                _ = term.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        }
+        Err(e) => {
+            log::error!("无法监听 SIGTERM, 只响应 Ctrl-C: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+/// 以 Windows 服务运行时还响应服务管理器的停止请求
+#[cfg(windows)]
+pub async fn signal() {
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = crate::winservice::stop_requested() => {}
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+pub async fn signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// 已停止接受新连接后, 最多等待 timeout 让已有连接结束; 期间再次收到退出信号时立即返回
+pub async fn drain(timeout: Duration) {
+    let active = ACTIVE.load(Ordering::Relaxed);
+    if active == 0 {
+        return;
+    }
+    log::info!(">>> 等待 {} 个连接结束 (最长 {}秒)", active, timeout.as_secs());
+    let start = Instant::now();
+    let wait = async {
+        while ACTIVE.load(Ordering::Relaxed) > 0 && start.elapsed() < timeout {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    };
+    tokio::select! {
+        _ = wait => {}
+        _ = signal() => {
+            log::warn!("!!! 再次收到退出信号, 立即退出");
+            return;
+        }
+    }
+    match ACTIVE.load(Ordering::Relaxed) {
+        0 => log::info!(">>> 所有连接已结束"),
+        n => log::warn!("!!! 等待超时, 断开剩余的 {} 个连接", n),
+    }
+}
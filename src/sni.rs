@@ -0,0 +1,235 @@
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+const PEEK_TIMEOUT: u64 = 1000; // 等待 ClientHello 的超时 (ms)
+const RECORD_HEADER: usize = 5;
+const RECORD_MAX: usize = RECORD_HEADER + 16 * 1024; // TLS 单条记录上限
+const HOST_NAME_MAX: usize = 255;
+
+/// 从客户端读取第一条 TLS 记录并取出 ClientHello 中的 SNI, 读到的数据追加到 early 中照常转发
+/// 不是 TLS、没有 SNI 或超时都返回 None, 不影响转发
+pub async fn peek(client: &mut TcpStream, early: &mut Vec<u8>) -> Option<String> {
+    let read = async {
+        let mut chunk = [0u8; 4096];
+        loop {
+            match parse(early) {
+                Parsed::Incomplete => {}
+                Parsed::Done(sni) => return sni,
+            }
+            let n = client.read(&mut chunk).await.ok()?;
+            if n == 0 {
+                return None;
+            }
+            early.extend_from_slice(&chunk[..n]);
+        }
+    };
+    tokio::time::timeout(Duration::from_millis(PEEK_TIMEOUT), read).await.ok().flatten()
+}
+
+enum Parsed {
+    Incomplete,
+    Done(Option<String>),
+}
+
+/// 解析客户端首条 TLS 记录; ClientHello 跨多条记录时不处理
+fn parse(buf: &[u8]) -> Parsed {
+    if buf.is_empty() {
+        return Parsed::Incomplete;
+    }
+    // 0x16: Handshake 记录
+    if buf[0] != 0x16 {
+        return Parsed::Done(None);
+    }
+    if buf.len() < RECORD_HEADER {
+        return Parsed::Incomplete;
+    }
+    let len = RECORD_HEADER + u16::from_be_bytes([buf[3], buf[4]]) as usize;
+    if len > RECORD_MAX {
+        return Parsed::Done(None);
+    }
+    match buf.get(RECORD_HEADER..len) {
+        Some(record) => Parsed::Done(client_hello_sni(record)),
+        None => Parsed::Incomplete,
+    }
+}
+
+/// 依次跳过 ClientHello 的固定字段, 在扩展中查找 server_name (0x0000) 的 host_name
+fn client_hello_sni(msg: &[u8]) -> Option<String> {
+    let mut r = Reader(msg);
+    // 0x01: ClientHello
+    if r.u8()? != 0x01 {
+        return None;
+    }
+    let body_len = r.u24()?;
+    let mut r = Reader(r.take(body_len)?);
+    r.take(2 + 32)?; // 版本, random
+    let n = r.u8()? as usize;
+    r.take(n)?; // session_id
+    let n = r.u16()?;
+    r.take(n)?; // cipher_suites
+    let n = r.u8()? as usize;
+    r.take(n)?; // compression_methods
+    let n = r.u16()?;
+    let mut exts = Reader(r.take(n)?);
+    while !exts.0.is_empty() {
+        let kind = exts.u16()?;
+        let n = exts.u16()?;
+        let data = exts.take(n)?;
+        if kind != 0x0000 {
+            continue;
+        }
+        let mut list = Reader(data);
+        let n = list.u16()?;
+        let mut list = Reader(list.take(n)?);
+        while !list.0.is_empty() {
+            let name_type = list.u8()?;
+            let n = list.u16()?;
+            let name = list.take(n)?;
+            // 0x00: host_name
+            if name_type == 0x00 && !name.is_empty() && name.len() <= HOST_NAME_MAX {
+                return std::str::from_utf8(name).ok().filter(|s| s.is_ascii()).map(str::to_string);
+            }
+        }
+        return None;
+    }
+    None
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<usize> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3).map(|b| (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::io::AsyncWriteExt;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::{ClientConfig, ClientConnection, RootCertStore};
+
+    // rustls 客户端发出的第一条记录
+    fn real_client_hello(host: &str) -> Vec<u8> {
+        let config = ClientConfig::builder().with_root_certificates(RootCertStore::empty()).with_no_client_auth();
+        let mut conn = ClientConnection::new(Arc::new(config), ServerName::try_from(host.to_string()).unwrap()).unwrap();
+        let mut out = Vec::new();
+        conn.write_tls(&mut out).unwrap();
+        out
+    }
+
+    fn ext(kind: u16, data: &[u8]) -> Vec<u8> {
+        [&kind.to_be_bytes()[..], &(data.len() as u16).to_be_bytes(), data].concat()
+    }
+
+    fn server_name(host: &[u8]) -> Vec<u8> {
+        let entry = [&[0x00][..], &(host.len() as u16).to_be_bytes(), host].concat();
+        ext(0x0000, &[&(entry.len() as u16).to_be_bytes()[..], &entry].concat())
+    }
+
+    // 手工构造的 ClientHello 记录, 扩展区原样放入 exts
+    fn client_hello(exts: &[u8]) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend([0u8; 32]); // random
+        body.extend([0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]); // session_id, cipher_suites, compression_methods
+        body.extend((exts.len() as u16).to_be_bytes());
+        body.extend(exts);
+        let msg = [&[0x01][..], &(body.len() as u32).to_be_bytes()[1..], &body].concat();
+        [&[0x16, 0x03, 0x01][..], &(msg.len() as u16).to_be_bytes(), &msg].concat()
+    }
+
+    fn sni(buf: &[u8]) -> Option<String> {
+        match parse(buf) {
+            Parsed::Done(sni) => sni,
+            Parsed::Incomplete => panic!("记录不完整"),
+        }
+    }
+
+    #[test]
+    fn real_client_hello_sni() {
+        let hello = real_client_hello("www.example.com");
+        assert_eq!(sni(&hello).as_deref(), Some("www.example.com"));
+    }
+
+    #[test]
+    fn incomplete_record_waits_for_more() {
+        let hello = real_client_hello("split.example.com");
+        for cut in [0, 1, RECORD_HEADER - 1, RECORD_HEADER, RECORD_HEADER + 1, hello.len() / 2, hello.len() - 1] {
+            assert!(matches!(parse(&hello[..cut]), Parsed::Incomplete), "{}", cut);
+        }
+    }
+
+    #[tokio::test]
+    async fn peek_reassembles_split_reads() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let hello = real_client_hello("split.example.com");
+        let (head, tail) = hello.split_at(hello.len() / 2);
+        client.write_all(head).await.unwrap();
+        let writer = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            client.write_all(tail).await.unwrap();
+        };
+        let mut early = Vec::new();
+        let (got, ()) = tokio::join!(peek(&mut server, &mut early), writer);
+        assert_eq!(got.as_deref(), Some("split.example.com"));
+        assert_eq!(early, hello);
+    }
+
+    #[test]
+    fn not_a_handshake_record() {
+        assert_eq!(sni(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"), None);
+        assert_eq!(sni(&[0x17, 0x03, 0x03, 0x00, 0x01, 0x00]), None);
+        // 记录长度超过 TLS 上限时不等待, 直接放弃
+        assert_eq!(sni(&[0x16, 0x03, 0x01, 0xff, 0xff]), None);
+    }
+
+    #[test]
+    fn bad_extension_lengths() {
+        assert_eq!(sni(&client_hello(&server_name(b"ok.example.com"))).as_deref(), Some("ok.example.com"));
+        // 扩展长度超出扩展区
+        let mut oversized = server_name(b"ok.example.com");
+        oversized[2..4].copy_from_slice(&0xffffu16.to_be_bytes());
+        assert_eq!(sni(&client_hello(&oversized)), None);
+        // 扩展区被截断
+        let full = server_name(b"ok.example.com");
+        assert_eq!(sni(&client_hello(&full[..full.len() - 3])), None);
+        // server_name 列表长度超出扩展数据
+        let mut list = server_name(b"ok.example.com");
+        list[4..6].copy_from_slice(&0x00ffu16.to_be_bytes());
+        assert_eq!(sni(&client_hello(&list)), None);
+        // 超长或空的 host_name
+        assert_eq!(sni(&client_hello(&server_name(&[b'a'; HOST_NAME_MAX + 1]))), None);
+        assert_eq!(sni(&client_hello(&server_name(b""))), None);
+    }
+
+    #[test]
+    fn server_name_position() {
+        let groups = ext(0x000a, &[0x00, 0x02, 0x00, 0x1d]);
+        let alpn = ext(0x0010, &[0x00, 0x03, 0x02, b'h', b'2']);
+        assert_eq!(sni(&client_hello(&[groups.clone(), alpn.clone()].concat())), None);
+        let later = [groups, alpn, server_name(b"late.example.com")].concat();
+        assert_eq!(sni(&client_hello(&later)).as_deref(), Some("late.example.com"));
+    }
+}
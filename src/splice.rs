@@ -0,0 +1,105 @@
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{self, Interest};
+use tokio::net::TcpStream;
+
+use crate::relay::Activity;
+
+const DEFAULT_CAPACITY: usize = 64 * 1024; // 管道默认容量
+
+/// 一个方向使用的管道: 套接字 -> 管道 -> 套接字, 数据不经过用户态
+pub struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+    capacity: usize, // 单次 splice 的最大字节数
+}
+
+impl Pipe {
+    /// 两个方向各一个管道; capacity 为期望的管道容量, 超出 /proc/sys/fs/pipe-max-size 时保持默认
+    pub fn pair(capacity: Option<usize>) -> io::Result<(Pipe, Pipe)> {
+        Ok((Pipe::new(capacity)?, Pipe::new(capacity)?))
+    }
+
+    fn new(capacity: Option<usize>) -> io::Result<Pipe> {
+        let mut fds = [0; 2];
+        // SAFETY: fds 为两个元素的数组, 成功时内核写入两个新的描述符
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: 描述符刚由 pipe2 创建, 只归这里所有
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        let mut pipe = Pipe { read, write, capacity: DEFAULT_CAPACITY };
+        if let Some(size) = capacity {
+            // 内核按页向上取整, 返回实际容量
+            // SAFETY: F_SETPIPE_SZ 只读取整数参数
+            match unsafe { libc::fcntl(pipe.write.as_raw_fd(), libc::F_SETPIPE_SZ, size as libc::c_int) } {
+                n if n > 0 => pipe.capacity = n as usize,
+                _ => log::debug!("无法把管道容量设置为 {}: {}", size, io::Error::last_os_error()),
+            }
+        }
+        Ok(pipe)
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+    // SAFETY: 偏移量为空指针, 两端都是有效的描述符
+    let n = unsafe { libc::splice(from, std::ptr::null_mut(), to, std::ptr::null_mut(), len, flags) };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+/// 双向零拷贝转发, 返回 (客户端 -> 目标, 目标 -> 客户端) 字节数
+/// 一侧读到 EOF 后关闭另一侧的写方向, 两个方向都结束后返回, 与 copy_bidirectional 相同
+pub async fn relay(
+    client: &TcpStream,
+    server: &TcpStream,
+    (up, down): (Pipe, Pipe),
+    activity: &Activity,
+    meter: Option<&AtomicU64>,
+) -> io::Result<(u64, u64)> {
+    tokio::try_join!(pump(client, server, &up, true, activity, meter), pump(server, client, &down, false, activity, meter))
+}
+
+async fn pump(
+    src: &TcpStream,
+    dst: &TcpStream,
+    pipe: &Pipe,
+    upstream: bool,
+    activity: &Activity,
+    meter: Option<&AtomicU64>,
+) -> io::Result<u64> {
+    let mut total = 0;
+    loop {
+        // 每次都先排空管道再读, 读不到数据只可能是套接字暂时没有数据
+        src.readable().await?;
+        let n = match src.try_io(Interest::READABLE, || splice(src.as_raw_fd(), pipe.write.as_raw_fd(), pipe.capacity)) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        };
+        activity.touch();
+        let mut pending = n;
+        while pending > 0 {
+            dst.writable().await?;
+            match dst.try_io(Interest::WRITABLE, || splice(pipe.read.as_raw_fd(), dst.as_raw_fd(), pending)) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(m) => pending -= m,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        activity.transfer(upstream, n);
+        if let Some(m) = meter {
+            m.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        total += n as u64;
+    }
+    socket2::SockRef::from(dst).shutdown(Shutdown::Write)?;
+    Ok(total)
+}
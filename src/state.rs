@@ -0,0 +1,709 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::hash::{BuildHasher, DefaultHasher, Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use arc_swap::ArcSwap;
+use tokio::sync::watch;
+
+use crate::config::{Config, FamilyPreference, PoolPolicy, SelectionMode, StickyMode};
+use crate::net;
+use crate::relay;
+use crate::tls;
+use crate::tunnel;
+
+#[derive(Clone, Debug)]
+pub struct BestTarget {
+    pub addr: SocketAddr,
+    pub fallback: Option<SocketAddr>, // happy_eyeballs: 另一协议族的可用地址, 建连时作为竞速备选
+    pub host: Option<String>, // 目标配置中的域名 (含端口), resolve_per_connection 时建连前重新解析; IP 地址和隧道为 None
+    pub prefer: FamilyPreference,
+    pub name: String,
+    pub group: String, // 配置中的目标名称, probe_all_addresses / srv 展开的候选共用; 流量配额和维护按它计
+    pub score: u128,     // 用于选择的评分 (平滑后)
+    pub raw_score: u128, // 本轮探测的原始评分
+    pub rtt_score: u128, // 原始评分中的延迟部分
+    pub jitter_score: u128, // 原始评分中的抖动部分
+    pub jitter_ms: f64,  // 本轮成功样本 RTT 的标准差
+    pub queue_score: u128, // 原始评分中的队列深度部分
+    pub priority: u32,   // 优先级层, 数值越小越优先
+    pub weight: f64,     // 原始评分已乘上的权重
+    pub min_ms: u128,    // 本轮最低延迟
+    pub max_ms: u128,    // 本轮最高延迟
+    pub avg_ms: u128,    // 本轮平均延迟
+    pub queue_depth: Option<f64>, // 后端上报的队列深度, 未配置或获取失败时为 None
+    pub dns_ms: f64,     // 本轮 DNS 解析耗时
+    pub loss: u32,       // 本轮丢包次数 (隧道为丢失的心跳数)
+    pub max_connections: Option<usize>, // 连接数上限, 达到后新连接溢出到下一个节点
+    pub rate_limit_kbps: Option<u64>,   // 覆盖服务的每连接限速
+    pub bandwidth_cap_kbps: Option<u64>, // 所有连接合计的带宽上限
+    pub outbound_bind: Option<net::OutboundBind>, // 出站连接的源地址或网卡
+    pub proxy_tlvs: Arc<Vec<u8>>,       // 编码后的出站 PROXY v2 TLV
+    pub via_tunnel: bool, // 经反向隧道转发, addr 为隧道对端地址
+    pub forward_link: bool, // 目标是另一个转发器的压缩链路入口
+    pub tls: Option<Arc<tls::Upstream>>, // 转发时向目标发起 TLS
+}
+
+/// 单个节点池的探测结果
+#[derive(Clone)]
+pub struct PoolState {
+    pub name: String,
+    pub mode: SelectionMode,
+    pub sticky_mode: Option<StickyMode>,
+    pub ranked: Vec<BestTarget>, // 按优先级层、再按评分从低到高排序的可用节点
+    pub sni: Vec<String>,        // 按 SNI 路由到本池的主机名, 非空时不参与默认选择
+    pub countries: Vec<String>,  // 优先路由到本池的客户端国家代码, 非空时不参与默认选择
+}
+
+// 加权模式的权重 = 1 / (评分 + WEIGHT_BASE_MS), 避免评分接近 0 时个别节点权重过大
+const WEIGHT_BASE_MS: f64 = 10.0;
+
+impl PoolState {
+    /// 按池内选择模式选出节点; 加权和最少连接模式下为排名第一的节点, 新连接的实际分配见 State::spread
+    pub fn select(&self) -> Option<&BestTarget> {
+        match self.mode {
+            SelectionMode::Best | SelectionMode::Weighted | SelectionMode::LeastConn => self.ranked.first(),
+        }
+    }
+
+    /// 是否只接收按 SNI 或客户端国家路由过来的连接
+    pub fn routed(&self) -> bool {
+        !self.sni.is_empty() || !self.countries.is_empty()
+    }
+
+    /// SNI 与本池主机名的匹配程度: 2 为完全匹配, 1 为通配符匹配, 0 为不匹配
+    fn sni_match(&self, host: &str) -> u8 {
+        let host = host.to_ascii_lowercase();
+        let rank = |pattern: &String| {
+            let pattern = pattern.to_ascii_lowercase();
+            match pattern.strip_prefix("*.") {
+                // 通配符只匹配一级子域名
+                Some(suffix) => {
+                    let label = host.strip_suffix(suffix).and_then(|h| h.strip_suffix('.'));
+                    u8::from(label.is_some_and(|l| !l.is_empty() && !l.contains('.')))
+                }
+                None => 2 * u8::from(pattern == host),
+            }
+        };
+        self.sni.iter().map(rank).max().unwrap_or(0)
+    }
+
+    /// 在最优先的优先级层中满足 eligible 的节点里按评分加权随机选出一个
+    pub fn weighted(&self, eligible: impl Fn(&BestTarget) -> bool) -> Option<&BestTarget> {
+        let tier = self.ranked.first().map(|t| t.priority);
+        let candidates: Vec<(&BestTarget, f64)> = self
+            .ranked
+            .iter()
+            .filter(|t| Some(t.priority) == tier && eligible(t))
+            .map(|t| (t, 1.0 / (t.score as f64 + WEIGHT_BASE_MS)))
+            .collect();
+        let total: f64 = candidates.iter().map(|(_, w)| w).sum();
+        let mut roll = random_unit() * total;
+        for (t, w) in &candidates {
+            if roll < *w {
+                return Some(t);
+            }
+            roll -= w;
+        }
+        candidates.last().map(|(t, _)| *t)
+    }
+
+    /// 在最优先的优先级层中满足 eligible 的节点里选出活跃连接数最少的, 相同时取评分低的
+    pub fn least_conn(&self, conns: &ConnCounters, eligible: impl Fn(&BestTarget) -> bool) -> Option<&BestTarget> {
+        let tier = self.ranked.first().map(|t| t.priority);
+        self.ranked
+            .iter()
+            .filter(|t| Some(t.priority) == tier && eligible(t))
+            .min_by_key(|t| (conns.get(&t.name), t.score))
+    }
+
+    /// 会话保持: 在最优先的优先级层中满足 eligible 的节点里按客户端 IP 一致性哈希 (rendezvous hashing)
+    /// 节点增减只影响分配到该节点的客户端, 其余客户端保持原节点
+    pub fn hashed(&self, client: IpAddr, eligible: impl Fn(&BestTarget) -> bool) -> Option<&BestTarget> {
+        let tier = self.ranked.first().map(|t| t.priority);
+        self.ranked.iter().filter(|t| Some(t.priority) == tier && eligible(t)).max_by_key(|t| {
+            // DefaultHasher::new 的密钥固定, 重启后同一客户端仍落在同一节点
+            let mut h = DefaultHasher::new();
+            (client, &t.name).hash(&mut h);
+            h.finish()
+        })
+    }
+}
+
+/// [0, 1) 内的随机数, 只用于分配连接, 不要求密码学强度
+fn random_unit() -> f64 {
+    static SEQ: AtomicU64 = AtomicU64::new(0);
+    let x = std::collections::hash_map::RandomState::new().hash_one(SEQ.fetch_add(1, Ordering::Relaxed));
+    (x >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// 已注册隧道按心跳 RTT 评分, 丢失的心跳按丢包惩罚计分
+pub fn score_tunnels(
+    tunnels: &HashMap<String, Arc<tunnel::Session>>,
+    penalty_ms: u128,
+    proxy_tlvs: &Arc<Vec<u8>>,
+) -> Vec<BestTarget> {
+    tunnels
+        .values()
+        .filter(|t| !t.is_closed())
+        .map(|t| BestTarget {
+            addr: t.peer,
+            fallback: None,
+            host: None,
+            prefer: FamilyPreference::Auto,
+            name: t.name.clone(),
+            group: t.name.clone(),
+            score: t.rtt_ms() + t.missed_pings() as u128 * penalty_ms,
+            raw_score: t.rtt_ms() + t.missed_pings() as u128 * penalty_ms,
+            rtt_score: t.rtt_ms() + t.missed_pings() as u128 * penalty_ms,
+            jitter_score: 0,
+            jitter_ms: 0.0,
+            queue_score: 0,
+            priority: 0,
+            weight: 1.0,
+            min_ms: t.rtt_ms(),
+            max_ms: t.rtt_ms(),
+            avg_ms: t.rtt_ms(),
+            queue_depth: None,
+            dns_ms: 0.0,
+            loss: t.missed_pings(),
+            max_connections: None,
+            rate_limit_kbps: None,
+            bandwidth_cap_kbps: None,
+            outbound_bind: None,
+            proxy_tlvs: proxy_tlvs.clone(),
+            via_tunnel: true,
+            forward_link: false,
+            tls: None,
+        })
+        .collect()
+}
+
+/// 各目标的活跃转发连接数
+#[derive(Default)]
+pub struct ConnCounters(Mutex<HashMap<String, Arc<Counter>>>);
+
+#[derive(Default)]
+struct Counter {
+    active: AtomicUsize,
+    switched: watch::Sender<u64>, // 每次路由从该节点切走时加一
+}
+
+impl ConnCounters {
+    /// 登记一条连接, 返回的守卫释放时自动减一
+    pub fn acquire(&self, name: &str) -> ConnGuard {
+        let counter = self.0.lock().unwrap().entry(name.to_string()).or_default().clone();
+        counter.active.fetch_add(1, Ordering::Relaxed);
+        let switched = counter.switched.subscribe();
+        ConnGuard { counter, switched }
+    }
+
+    pub fn get(&self, name: &str) -> usize {
+        self.0.lock().unwrap().get(name).map_or(0, |c| c.active.load(Ordering::Relaxed))
+    }
+
+    /// 通知该节点上已有的连接: 路由已切换到其他节点
+    pub fn switch_away(&self, name: &str) {
+        if let Some(c) = self.0.lock().unwrap().get(name) {
+            c.switched.send_modify(|n| *n += 1);
+        }
+    }
+}
+
+pub struct ConnGuard {
+    counter: Arc<Counter>,
+    switched: watch::Receiver<u64>,
+}
+
+impl ConnGuard {
+    /// 等到路由从本连接的节点切走; 登记之前发生的切换不算
+    pub async fn switched_away(&mut self) {
+        if self.switched.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// 入站连接数: 全局及按客户端 IP 统计, 接受连接时检查上限
+#[derive(Default)]
+pub struct InboundLimits {
+    total: AtomicUsize,
+    per_ip: Mutex<HashMap<IpAddr, usize>>,
+    warned: Mutex<Option<Instant>>, // 上次输出超限告警的时间
+}
+
+/// 超出的入站连接数上限
+pub enum LimitExceeded {
+    Total(usize),
+    PerIp(usize),
+}
+
+impl InboundLimits {
+    /// 未超出上限时登记一条入站连接, 返回的守卫释放时自动减一
+    pub fn admit(
+        self: &Arc<Self>,
+        ip: IpAddr,
+        max_total: Option<usize>,
+        max_per_ip: Option<usize>,
+    ) -> Result<InboundGuard, LimitExceeded> {
+        let mut per_ip = self.per_ip.lock().unwrap();
+        if let Some(max) = max_total.filter(|&max| self.total.load(Ordering::Relaxed) >= max) {
+            return Err(LimitExceeded::Total(max));
+        }
+        let count = per_ip.entry(ip).or_default();
+        if let Some(max) = max_per_ip.filter(|&max| *count >= max) {
+            return Err(LimitExceeded::PerIp(max));
+        }
+        *count += 1;
+        self.total.fetch_add(1, Ordering::Relaxed);
+        Ok(InboundGuard { limits: self.clone(), ip })
+    }
+
+    /// 距上次超限告警已超过 interval 时返回 true 并记下本次时间
+    pub fn warn_due(&self, interval: Duration) -> bool {
+        let mut warned = self.warned.lock().unwrap();
+        let due = warned.is_none_or(|at| at.elapsed() >= interval);
+        if due {
+            *warned = Some(Instant::now());
+        }
+        due
+    }
+}
+
+pub struct InboundGuard {
+    limits: Arc<InboundLimits>,
+    ip: IpAddr,
+}
+
+impl InboundGuard {
+    /// 本连接登记所在的计数
+    pub fn limits(&self) -> &InboundLimits {
+        &self.limits
+    }
+
+    /// 把本连接改记到另一个客户端 IP (受信任 PROXY 头中的真实客户端); 该 IP 已达上限时返回错误, 原登记不变
+    pub fn rebind(&mut self, ip: IpAddr, max_per_ip: Option<usize>) -> Result<(), LimitExceeded> {
+        if ip == self.ip {
+            return Ok(());
+        }
+        let mut per_ip = self.limits.per_ip.lock().unwrap();
+        let count = per_ip.entry(ip).or_default();
+        if let Some(max) = max_per_ip.filter(|&max| *count >= max) {
+            if *count == 0 {
+                per_ip.remove(&ip);
+            }
+            return Err(LimitExceeded::PerIp(max));
+        }
+        *count += 1;
+        if let Some(old) = per_ip.get_mut(&self.ip) {
+            *old -= 1;
+            if *old == 0 {
+                per_ip.remove(&self.ip);
+            }
+        }
+        self.ip = ip;
+        Ok(())
+    }
+}
+
+impl Drop for InboundGuard {
+    fn drop(&mut self) {
+        let mut per_ip = self.limits.per_ip.lock().unwrap();
+        if let Some(count) = per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                per_ip.remove(&self.ip);
+            }
+        }
+        self.limits.total.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// 各目标经本服务转发的累计流量 (双向合计) 及所有连接共享的带宽上限
+#[derive(Default)]
+pub struct TargetUsage {
+    meters: Mutex<HashMap<String, (Instant, Arc<AtomicU64>)>>, // (计数开始时间, 字节数)
+    caps: Mutex<HashMap<(String, u64), Arc<relay::SharedCap>>>, // 按 (目标, 速率) 索引, 速率变更后使用新的令牌桶
+}
+
+impl TargetUsage {
+    /// 目标的流量计数器, 转发任务直接累加
+    pub fn meter(&self, name: &str) -> Arc<AtomicU64> {
+        let mut meters = self.meters.lock().unwrap();
+        meters.entry(name.to_string()).or_insert_with(|| (Instant::now(), Arc::default())).1.clone()
+    }
+
+    /// 目标共享的带宽上限
+    pub fn cap(&self, name: &str, kbps: u64) -> Arc<relay::SharedCap> {
+        let mut caps = self.caps.lock().unwrap();
+        caps.entry((name.to_string(), kbps)).or_insert_with(|| Arc::new(relay::SharedCap::new(kbps))).clone()
+    }
+
+    /// 计数开始以来的字节数
+    pub fn bytes(&self, name: &str) -> u64 {
+        self.meters.lock().unwrap().get(name).map_or(0, |(_, m)| m.load(Ordering::Relaxed))
+    }
+
+    /// 计数已超过 period 时清零并重新开始, 返回是否清零
+    pub fn reset_expired(&self, name: &str, period: Duration) -> bool {
+        match self.meters.lock().unwrap().get_mut(name) {
+            Some((since, m)) if since.elapsed() >= period => {
+                *since = Instant::now();
+                m.store(0, Ordering::Relaxed);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// 立即清零
+    pub fn reset(&self, name: &str) {
+        if let Some((since, m)) = self.meters.lock().unwrap().get_mut(name) {
+            *since = Instant::now();
+            m.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+// 真实流量成功率统计窗口: 最多保留最近的若干次结果, 且只统计窗口时长内的
+const TRAFFIC_WINDOW_SIZE: usize = 100;
+const TRAFFIC_WINDOW: Duration = Duration::from_secs(300);
+// 样本少于该数量时不计算成功率, 避免偶发失败导致降级
+const TRAFFIC_MIN_SAMPLES: usize = 5;
+
+/// 各目标真实转发连接的建连结果
+#[derive(Default)]
+pub struct TrafficStats(Mutex<HashMap<String, VecDeque<(Instant, bool)>>>);
+
+impl TrafficStats {
+    /// 记录一次建连结果
+    pub fn record(&self, name: &str, ok: bool) {
+        let mut map = self.0.lock().unwrap();
+        let window = map.entry(name.to_string()).or_default();
+        if window.len() >= TRAFFIC_WINDOW_SIZE {
+            window.pop_front();
+        }
+        window.push_back((Instant::now(), ok));
+    }
+
+    /// 窗口内的建连成功率; 样本不足时返回 None
+    pub fn success_rate(&self, name: &str) -> Option<f64> {
+        let mut map = self.0.lock().unwrap();
+        let window = map.get_mut(name)?;
+        // 过期结果丢弃, 被降级后不再有流量的目标也能逐渐恢复
+        while window.front().is_some_and(|(at, _)| at.elapsed() > TRAFFIC_WINDOW) {
+            window.pop_front();
+        }
+        if window.len() < TRAFFIC_MIN_SAMPLES {
+            return None;
+        }
+        let ok = window.iter().filter(|(_, ok)| *ok).count();
+        Some(ok as f64 / window.len() as f64)
+    }
+}
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        self.counter.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct State {
+    pub service: String, // 服务名称, 单服务配置时为空
+    pub pools: Arc<Vec<PoolState>>, // 每轮整体替换, 快照直接共享
+    pub pool_policy: PoolPolicy,
+    pub paused_since: Option<Instant>, // 探测暂停时间, None 表示正常探测
+    pub pinned: Option<String>,       // 手动固定的节点, 可用时总是选择该节点
+    pub maintenance: BTreeSet<String>, // 维护中的节点, 不参与选择
+    pub reprobe: bool,                // 管理接口要求立即完整探测一轮
+    pub tunnels: HashMap<String, Arc<tunnel::Session>>, // 已注册的反向隧道, 按目标名称索引
+    pub history: HashMap<String, f64>,                   // 各目标的平滑评分历史
+    pub conns: Arc<ConnCounters>,
+    pub traffic: Arc<TrafficStats>, // 转发任务直接写入, 不经过 State 的锁
+    pub usage: Arc<TargetUsage>,    // 同上
+    pub hold: Option<String>,               // 推迟切换期间继续使用的旧节点
+    pub switch_connection_threshold: usize, // 旧节点连接数降到该值以下才切换, 0 表示不推迟
+    pub sticky: Option<String>,             // 新节点领先不足 switch_threshold 时保留的当前节点
+    pub published: Arc<ArcSwap<Snapshot>>,  // 供状态查询读取的只读快照
+}
+
+impl State {
+    pub fn new(config: &Config) -> Self {
+        let mut state = State {
+            service: String::new(),
+            pools: Arc::default(),
+            pool_policy: PoolPolicy::default(),
+            paused_since: None,
+            pinned: None,
+            maintenance: BTreeSet::new(),
+            reprobe: false,
+            tunnels: HashMap::new(),
+            history: HashMap::new(),
+            conns: Arc::default(),
+            traffic: Arc::default(),
+            usage: Arc::default(),
+            hold: None,
+            switch_connection_threshold: 0,
+            sticky: None,
+            published: Arc::default(),
+        };
+        state.reconfigure(config);
+        state
+    }
+
+    /// 应用配置中与选择相关的参数 (启动及重新加载时)
+    pub fn reconfigure(&mut self, config: &Config) {
+        self.service = config.name.clone();
+        self.pool_policy = config.pool_policy;
+        self.switch_connection_threshold = config.switch_connection_threshold;
+        self.publish();
+    }
+
+    /// 先按池间策略选池, 再在池内选出节点
+    pub fn select(&self) -> Option<&BestTarget> {
+        self.select_with_pool().map(|(_, t)| t)
+    }
+
+    /// 同 select, 额外返回选中的池
+    pub fn select_with_pool(&self) -> Option<(&PoolState, &BestTarget)> {
+        select_in(
+            &self.pools,
+            self.pool_policy,
+            self.pinned.as_deref(),
+            self.hold.as_deref(),
+            self.sticky.as_deref(),
+            self.switch_connection_threshold,
+            &self.conns,
+        )
+    }
+
+    /// 选出未达到连接数上限的节点: 选中节点已满时按排名顺序溢出到下一个有余量的节点
+    /// 返回的第二个值为溢出前被跳过的已满节点; client 为真实客户端 IP, 用于会话保持
+    pub fn select_with_overflow(&self, client: Option<IpAddr>) -> Option<(&BestTarget, Option<&BestTarget>)> {
+        let (pool, best) = self.select_with_pool()?;
+        // 加权、最少连接模式及会话保持的池按各自规则分配, 手动固定、推迟切换中或被保留的节点除外
+        let fixed = [self.pinned.as_deref(), self.hold.as_deref(), self.sticky.as_deref()].contains(&Some(best.name.as_str()));
+        if !fixed {
+            if let Some(t) = self.spread(pool, client) {
+                return Some((t, None));
+            }
+        }
+        if !self.saturated(best) {
+            return Some((best, None));
+        }
+        let mut candidates: Vec<&BestTarget> = self.pools.iter().filter(|p| !p.routed()).flat_map(|p| &p.ranked).collect();
+        if self.pool_policy == PoolPolicy::Best {
+            candidates.sort_by_key(|t| (t.priority, t.score));
+        }
+        candidates.into_iter().find(|t| !self.saturated(t)).map(|t| (t, Some(best)))
+    }
+
+    /// 按池的会话保持、加权或最少连接模式在未满的节点中分配; 都未启用时返回 None
+    fn spread<'a>(&self, pool: &'a PoolState, client: Option<IpAddr>) -> Option<&'a BestTarget> {
+        match (pool.sticky_mode, client) {
+            (Some(StickyMode::ClientIp), Some(ip)) => pool.hashed(ip, |t| !self.saturated(t)),
+            _ => match pool.mode {
+                SelectionMode::Best => None,
+                SelectionMode::Weighted => pool.weighted(|t| !self.saturated(t)),
+                SelectionMode::LeastConn => pool.least_conn(&self.conns, |t| !self.saturated(t)),
+            },
+        }
+    }
+
+    /// 与 SNI 匹配的节点池, 完全匹配优先于通配符
+    pub fn sni_pool(&self, host: &str) -> Option<&PoolState> {
+        self.pools.iter().filter(|p| p.sni_match(host) > 0).max_by_key(|p| p.sni_match(host))
+    }
+
+    /// 客户端国家代码对应的节点池
+    pub fn country_pool(&self, country: &str) -> Option<&PoolState> {
+        self.pools.iter().find(|p| p.countries.iter().any(|c| c.eq_ignore_ascii_case(country)))
+    }
+
+    /// 只在指定池内选择, 溢出规则与 select_with_overflow 相同
+    pub fn select_in_pool<'a>(
+        &'a self,
+        pool: &'a PoolState,
+        client: Option<IpAddr>,
+    ) -> Option<(&'a BestTarget, Option<&'a BestTarget>)> {
+        let best = pool.select()?;
+        if let Some(t) = self.spread(pool, client) {
+            return Some((t, None));
+        }
+        if !self.saturated(best) {
+            return Some((best, None));
+        }
+        pool.ranked.iter().find(|t| !self.saturated(t)).map(|t| (t, Some(best)))
+    }
+
+    /// 对冲建连的备选节点: 按与溢出相同的顺序排在 chosen 之后、未满且不经隧道的第一个节点
+    /// pool 为按 SNI 路由到的节点池, 此时只在该池内选择
+    pub fn runner_up(&self, chosen: &BestTarget, pool: Option<&str>) -> Option<&BestTarget> {
+        next_direct(&self.pools, self.pool_policy, &self.conns, pool, &[chosen.name.as_str()])
+    }
+
+    /// 节点是否已达到连接数上限
+    pub fn saturated(&self, t: &BestTarget) -> bool {
+        t.max_connections.is_some_and(|max| self.conns.get(&t.name) >= max)
+    }
+
+    /// 不考虑推迟切换时的选择结果
+    pub fn natural_select(&self) -> Option<(&PoolState, &BestTarget)> {
+        natural_select_in(&self.pools, self.pool_policy)
+    }
+
+    /// 按名称查找可用节点
+    pub fn find(&self, name: &str) -> Option<(&PoolState, &BestTarget)> {
+        find_in(&self.pools, name)
+    }
+
+    /// 把节点置为维护状态并立即从可用节点中移除; 结束维护后下一轮探测重新加入
+    pub fn set_maintenance(&mut self, name: &str, on: bool) {
+        if !on {
+            self.maintenance.remove(name);
+            return;
+        }
+        self.maintenance.insert(name.to_string());
+        if self.hold.as_deref() == Some(name) {
+            self.hold = None;
+        }
+        if self.sticky.as_deref() == Some(name) {
+            self.sticky = None;
+        }
+        let pools = self
+            .pools
+            .iter()
+            .map(|p| PoolState { ranked: p.ranked.iter().filter(|t| t.name != name && t.group != name).cloned().collect(), ..p.clone() })
+            .collect();
+        self.pools = Arc::new(pools);
+    }
+
+    /// 当前状态的只读快照, 节点列表和计数器直接共享, 不做复制
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            service: self.service.clone(),
+            pools: self.pools.clone(),
+            pool_policy: self.pool_policy,
+            paused_since: self.paused_since,
+            pinned: self.pinned.clone(),
+            maintenance: self.maintenance.clone(),
+            conns: self.conns.clone(),
+            traffic: self.traffic.clone(),
+            usage: self.usage.clone(),
+            hold: self.hold.clone(),
+            switch_connection_threshold: self.switch_connection_threshold,
+            sticky: self.sticky.clone(),
+        }
+    }
+
+    /// 发布快照; 修改了节点列表、推迟切换或暂停状态后调用
+    pub fn publish(&self) {
+        self.published.store(Arc::new(self.snapshot()));
+    }
+}
+
+/// State 的只读快照, 状态查询从这里读取, 不与转发和探测争用锁
+#[derive(Default)]
+pub struct Snapshot {
+    pub service: String,
+    pub pools: Arc<Vec<PoolState>>,
+    pub pool_policy: PoolPolicy,
+    pub paused_since: Option<Instant>,
+    pub pinned: Option<String>,
+    pub maintenance: BTreeSet<String>,
+    pub conns: Arc<ConnCounters>,
+    pub traffic: Arc<TrafficStats>,
+    pub usage: Arc<TargetUsage>,
+    pub hold: Option<String>,
+    pub switch_connection_threshold: usize,
+    pub sticky: Option<String>,
+}
+
+impl Snapshot {
+    /// 与 State::select 相同的选择结果 (连接数按实时计数)
+    pub fn select(&self) -> Option<&BestTarget> {
+        select_in(
+            &self.pools,
+            self.pool_policy,
+            self.pinned.as_deref(),
+            self.hold.as_deref(),
+            self.sticky.as_deref(),
+            self.switch_connection_threshold,
+            &self.conns,
+        )
+        .map(|(_, t)| t)
+    }
+
+    pub fn find(&self, name: &str) -> Option<(&PoolState, &BestTarget)> {
+        find_in(&self.pools, name)
+    }
+
+    /// 建连失败后改连的节点: 与 State::runner_up 顺序相同, 跳过已尝试过的节点
+    pub fn fallback(&self, pool: Option<&str>, tried: &[&str]) -> Option<&BestTarget> {
+        next_direct(&self.pools, self.pool_policy, &self.conns, pool, tried)
+    }
+}
+
+/// 按溢出顺序排列的可用节点中, 第一个不在 skip 中、未满且不经隧道的节点
+/// 指定 pool 时只看该池, 否则只看参与默认选择的池
+fn next_direct<'a>(
+    pools: &'a [PoolState],
+    policy: PoolPolicy,
+    conns: &ConnCounters,
+    pool: Option<&str>,
+    skip: &[&str],
+) -> Option<&'a BestTarget> {
+    let mut candidates: Vec<&BestTarget> = pools
+        .iter()
+        .filter(|p| pool.map_or(!p.routed(), |name| p.name == name))
+        .flat_map(|p| &p.ranked)
+        .collect();
+    if policy == PoolPolicy::Best {
+        candidates.sort_by_key(|t| (t.priority, t.score));
+    }
+    candidates.into_iter().find(|t| {
+        !skip.contains(&t.name.as_str()) && !t.via_tunnel && t.max_connections.is_none_or(|max| conns.get(&t.name) < max)
+    })
+}
+
+fn select_in<'a>(
+    pools: &'a [PoolState],
+    policy: PoolPolicy,
+    pinned: Option<&str>,
+    hold: Option<&str>,
+    sticky: Option<&str>,
+    threshold: usize,
+    conns: &ConnCounters,
+) -> Option<(&'a PoolState, &'a BestTarget)> {
+    // 手动固定的节点不可用时按正常规则选择
+    if let Some(found) = pinned.and_then(|name| find_in(pools, name)) {
+        return Some(found);
+    }
+    // 推迟切换中: 旧节点仍可用且连接数未降到阈值以下时继续使用
+    if let Some(hold) = hold {
+        if conns.get(hold) >= threshold {
+            if let Some(found) = find_in(pools, hold) {
+                return Some(found);
+            }
+        }
+    }
+    // 新节点领先不足切换阈值时保留当前节点
+    if let Some(found) = sticky.and_then(|name| find_in(pools, name)) {
+        return Some(found);
+    }
+    natural_select_in(pools, policy)
+}
+
+fn natural_select_in(pools: &[PoolState], policy: PoolPolicy) -> Option<(&PoolState, &BestTarget)> {
+    let mut candidates = pools.iter().filter(|p| !p.routed()).filter_map(|p| p.select().map(|t| (p, t)));
+    match policy {
+        PoolPolicy::Failover => candidates.next(),
+        PoolPolicy::Best => candidates.min_by_key(|(_, t)| (t.priority, t.score)),
+    }
+}
+
+fn find_in<'a>(pools: &'a [PoolState], name: &str) -> Option<(&'a PoolState, &'a BestTarget)> {
+    pools.iter().find_map(|p| p.ranked.iter().find(|t| t.name == name).map(|t| (p, t)))
+}
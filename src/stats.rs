@@ -0,0 +1,28 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// 全局转发计数, 供各指标输出方式读取
+pub static CONNECTIONS: AtomicU64 = AtomicU64::new(0); // 已转发的连接数
+pub static REJECTED: AtomicU64 = AtomicU64::new(0); // 被拒绝的连接数
+pub static LIMITED: AtomicU64 = AtomicU64::new(0); // 超出入站连接数上限被关闭的连接数
+pub static DENIED: AtomicU64 = AtomicU64::new(0); // 来源地址不在 allow/deny 允许范围内被关闭的连接数
+pub static BYTES_UP: AtomicU64 = AtomicU64::new(0); // 客户端 -> 目标字节数
+pub static BYTES_DOWN: AtomicU64 = AtomicU64::new(0); // 目标 -> 客户端字节数
+pub static SWITCHES: AtomicU64 = AtomicU64::new(0); // 路由切换次数
+
+// 连接摘要日志的抽样序号
+static SUMMARY_SEQ: AtomicU64 = AtomicU64::new(0);
+
+pub fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// 记录一条连接结束时两个方向的字节数
+pub fn add_bytes(up: u64, down: u64) {
+    BYTES_UP.fetch_add(up, Ordering::Relaxed);
+    BYTES_DOWN.fetch_add(down, Ordering::Relaxed);
+}
+
+/// 正常结束的连接摘要按 1/rate 抽样输出, rate <= 1 时全部输出
+pub fn sample_summary(rate: u64) -> bool {
+    rate <= 1 || SUMMARY_SEQ.fetch_add(1, Ordering::Relaxed).is_multiple_of(rate)
+}
@@ -0,0 +1,108 @@
+use arc_swap::ArcSwap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+use crate::relay;
+use crate::state::Snapshot;
+use crate::stats;
+
+// 单个 UDP 包的最大长度, 避免在常见 MTU 下分片
+const MAX_PACKET: usize = 1432;
+
+/// 按 flush 间隔向 StatsD 发送指标: 计数器发送增量, 评分等发送当前值
+pub async fn run(addr: String, prefix: String, interval: u64, published: Arc<ArcSwap<Snapshot>>) {
+    log::info!("StatsD 指标输出: {} (间隔: {}秒)", addr, interval);
+    let counters: [(&str, &AtomicU64); 8] = [
+        ("connections", &stats::CONNECTIONS),
+        ("rejected", &stats::REJECTED),
+        ("limited", &stats::LIMITED),
+        ("denied", &stats::DENIED),
+        ("bytes_up", &stats::BYTES_UP),
+        ("bytes_down", &stats::BYTES_DOWN),
+        ("switches", &stats::SWITCHES),
+        ("mirror_drops", &relay::MIRROR_DROPS),
+    ];
+    let mut last = [0u64; 8];
+    let mut socket = None;
+    let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+
+        let mut lines = Vec::new();
+        for ((name, counter), last) in counters.iter().zip(last.iter_mut()) {
+            let now = counter.load(Ordering::Relaxed);
+            lines.push(format!("{}.{}:{}|c", prefix, name, now - *last));
+            *last = now;
+        }
+        target_metrics(&prefix, &published.load(), &mut lines);
+
+        // 地址解析失败时下一轮重试
+        if socket.is_none() {
+            socket = connect(&addr).await;
+        }
+        if let Some(ref s) = socket {
+            for packet in pack(&lines) {
+                if let Err(e) = s.send(packet.as_bytes()).await {
+                    log::debug!("StatsD 发送失败: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// 各节点的评分、原始评分、DNS 耗时和活跃连接数
+fn target_metrics(prefix: &str, s: &Snapshot, lines: &mut Vec<String>) {
+    let selected = s.select().map(|t| t.name.clone());
+    for t in s.pools.iter().flat_map(|p| &p.ranked) {
+        let key = format!("{}.target.{}", prefix, sanitize(&t.name));
+        lines.push(format!("{}.score:{}|g", key, t.score));
+        lines.push(format!("{}.raw_score:{}|g", key, t.raw_score));
+        lines.push(format!("{}.loss:{}|g", key, t.loss));
+        lines.push(format!("{}.dns:{:.3}|ms", key, t.dns_ms));
+        lines.push(format!("{}.active_connections:{}|g", key, s.conns.get(&t.name)));
+        lines.push(format!("{}.selected:{}|g", key, u8::from(selected.as_deref() == Some(t.name.as_str()))));
+    }
+}
+
+async fn connect(addr: &str) -> Option<UdpSocket> {
+    let target: SocketAddr = match tokio::net::lookup_host(addr).await.map(|mut a| a.next()) {
+        Ok(Some(a)) => a,
+        _ => {
+            log::warn!("StatsD 地址解析失败: {}", addr);
+            return None;
+        }
+    };
+    let local = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(local).await.ok()?;
+    socket.connect(target).await.ok()?;
+    Some(socket)
+}
+
+/// StatsD 名称中只保留字母、数字、下划线和短横线
+fn sanitize(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect()
+}
+
+/// 按换行拼接成不超过 MAX_PACKET 的包
+fn pack(lines: &[String]) -> Vec<String> {
+    let mut packets = Vec::new();
+    let mut current = String::new();
+    for line in lines {
+        if !current.is_empty() && current.len() + 1 + line.len() > MAX_PACKET {
+            packets.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(line);
+    }
+    if !current.is_empty() {
+        packets.push(current);
+    }
+    packets
+}
@@ -0,0 +1,198 @@
+use anyhow::{Context, Result};
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+use tokio_rustls::rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::{client, TlsAcceptor, TlsConnector};
+
+use crate::config::{TargetConfig, TlsConfig};
+use crate::net;
+
+const HANDSHAKE_TIMEOUT: u64 = 10; // TLS 握手超时 (秒)
+
+/// 终止 TLS 后的客户端连接
+pub type ClientTls = TlsStream<Prefixed<TcpStream>>;
+
+/// 读取证书链和私钥 (PEM), 启动时调用, 文件有误直接报错
+pub fn acceptor(config: &TlsConfig) -> Result<TlsAcceptor> {
+    let server = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(read_certs(&config.cert)?, read_key(&config.key)?)
+        .context("证书或私钥无效")?;
+    Ok(TlsAcceptor::from(Arc::new(server)))
+}
+
+/// 完成 TLS 握手; early 为读取入站 PROXY 头时多读出的握手数据
+pub async fn accept(acceptor: &TlsAcceptor, tcp: TcpStream, early: Vec<u8>) -> io::Result<ClientTls> {
+    let stream = Prefixed { prefix: early, pos: 0, inner: tcp };
+    tokio::time::timeout(Duration::from_secs(HANDSHAKE_TIMEOUT), acceptor.accept(stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "握手超时"))?
+}
+
+/// 客户端在握手中发送的 SNI
+pub fn server_name(stream: &ClientTls) -> Option<String> {
+    stream.get_ref().1.server_name().map(str::to_string)
+}
+
+impl net::Abort for ClientTls {
+    fn abort_on_close(&self) {
+        self.get_ref().0.inner.abort_on_close();
+    }
+}
+
+impl net::Plain for ClientTls {
+    fn plain(&mut self) -> Option<&mut TcpStream> {
+        None
+    }
+}
+
+/// 向目标发起 TLS 所需的配置, 每轮探测按目标配置重新读取证书
+pub struct Upstream {
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl fmt::Debug for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Upstream").field("server_name", &self.server_name).finish_non_exhaustive()
+    }
+}
+
+/// 按目标的 tls_* 配置构造; 未设置 tls_ca 时使用内置的公共根证书, tls_verify: false 时不校验证书
+pub fn upstream(t: &TargetConfig) -> Result<Upstream> {
+    let builder = if t.tls_verify {
+        let mut roots = RootCertStore::empty();
+        match t.tls_ca {
+            Some(ref ca) => {
+                for cert in read_certs(ca)? {
+                    roots.add(cert).with_context(|| format!("无效的 CA 证书: {}", ca))?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        ClientConfig::builder().with_root_certificates(roots)
+    } else {
+        let provider = Arc::new(crypto::ring::default_provider());
+        ClientConfig::builder().dangerous().with_custom_certificate_verifier(Arc::new(NoVerify(provider)))
+    };
+    let config = match (&t.tls_cert, &t.tls_key) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(read_certs(cert)?, read_key(key)?)
+            .context("客户端证书或私钥无效")?,
+        _ => builder.with_no_client_auth(),
+    };
+    let host = t.tls_server_name.clone().unwrap_or_else(|| host_of(&t.addr).to_string());
+    let server_name = ServerName::try_from(host.clone()).map_err(|_| anyhow::anyhow!("无效的 TLS 主机名: {}", host))?;
+    Ok(Upstream { connector: TlsConnector::from(Arc::new(config)), server_name })
+}
+
+/// 在已连上 (并已写完 PROXY 头) 的连接上完成 TLS 握手
+pub async fn connect(upstream: &Upstream, tcp: TcpStream) -> io::Result<client::TlsStream<TcpStream>> {
+    let handshake = upstream.connector.connect(upstream.server_name.clone(), tcp);
+    tokio::time::timeout(Duration::from_secs(HANDSHAKE_TIMEOUT), handshake)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "握手超时"))?
+}
+
+/// 不校验证书链和主机名, 只校验握手签名
+#[derive(Debug)]
+struct NoVerify(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerify {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// addr 中的主机部分, IPv6 去掉方括号
+fn host_of(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn read_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("无法读取证书: {}", path))?;
+    if certs.is_empty() {
+        anyhow::bail!("证书文件中没有证书: {}", path);
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).with_context(|| format!("无法读取私钥: {}", path))
+}
+
+/// 先读出已缓存的数据, 再读底层连接; 写入直接交给底层连接
+pub struct Prefixed<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Prefixed<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if self.pos < self.prefix.len() {
+            let n = buf.remaining().min(self.prefix.len() - self.pos);
+            buf.put_slice(&self.prefix[self.pos..self.pos + n]);
+            self.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Prefixed<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
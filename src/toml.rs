@@ -0,0 +1,485 @@
+use serde_yaml::{Mapping, Number, Value};
+use std::collections::HashSet;
+
+/// 解析 TOML 文档, 转换为与 YAML 配置相同的结构; 支持 TOML 1.0 中除日期时间以外的全部类型,
+/// 数组和内联表可以跨行书写
+pub fn parse(text: &str) -> Result<Mapping, String> {
+    let mut p = Parser { chars: text.chars().collect(), pos: 0, line: 1 };
+    p.document().map_err(|e| format!("第 {} 行: {}", p.line, e))
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize, // 当前行号, 用于错误提示
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        s.chars().enumerate().all(|(i, c)| self.chars.get(self.pos + i) == Some(&c))
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.bump();
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        match self.peek() {
+            _ if self.eat(c) => Ok(()),
+            Some(found) => Err(format!("应为 '{}', 实际为 '{}'", c, found)),
+            None => Err(format!("应为 '{}', 文件已结束", c)),
+        }
+    }
+
+    fn skip_ws(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.bump();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.bump();
+            }
+        }
+    }
+
+    // 空白、注释和换行
+    fn skip_blank(&mut self) {
+        loop {
+            self.skip_ws();
+            self.skip_comment();
+            if !self.eat('\n') && !self.eat('\r') {
+                return;
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<(), String> {
+        self.skip_ws();
+        self.skip_comment();
+        self.eat('\r');
+        match self.peek() {
+            None => Ok(()),
+            _ if self.eat('\n') => Ok(()),
+            Some(c) => Err(format!("行尾有多余的内容: '{}'", c)),
+        }
+    }
+
+    fn document(&mut self) -> Result<Mapping, String> {
+        let mut root = Mapping::new();
+        let mut current: Vec<String> = Vec::new();
+        let mut defined: HashSet<Vec<String>> = HashSet::new(); // 用 [表头] 定义过的表, 不能重复定义
+        loop {
+            self.skip_blank();
+            if self.peek().is_none() {
+                return Ok(root);
+            }
+            if self.eat('[') {
+                let array = self.eat('[');
+                self.skip_ws();
+                let path = self.key()?;
+                self.expect(']')?;
+                if array {
+                    self.expect(']')?;
+                    // 数组的新元素: 其下的子表可以重新定义
+                    defined.retain(|d| !d.starts_with(&path));
+                    push_table(&mut root, &path)?;
+                } else {
+                    if !defined.insert(path.clone()) {
+                        return Err(format!("表重复定义: [{}]", path.join(".")));
+                    }
+                    table(&mut root, &path)?;
+                }
+                current = path;
+            } else {
+                let (key, value) = self.keyval()?;
+                insert(table(&mut root, &current)?, &key, value)?;
+            }
+            self.end_of_line()?;
+        }
+    }
+
+    fn keyval(&mut self) -> Result<(Vec<String>, Value), String> {
+        let key = self.key()?;
+        self.expect('=')?;
+        self.skip_ws();
+        Ok((key, self.value()?))
+    }
+
+    // 键, 点号分隔的各段可以是裸键或字符串; 读完后跳过其后的空白
+    fn key(&mut self) -> Result<Vec<String>, String> {
+        let mut path = Vec::new();
+        loop {
+            let part = match self.peek() {
+                Some('"') => {
+                    self.bump();
+                    self.basic_string(false)?
+                }
+                Some('\'') => {
+                    self.bump();
+                    self.literal_string(false)?
+                }
+                _ => {
+                    let start = self.pos;
+                    while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                        self.bump();
+                    }
+                    if self.pos == start {
+                        return Err(match self.peek() {
+                            Some(c) => format!("缺少键名, 实际为 '{}'", c),
+                            None => "缺少键名".to_string(),
+                        });
+                    }
+                    self.chars[start..self.pos].iter().collect()
+                }
+            };
+            path.push(part);
+            self.skip_ws();
+            if !self.eat('.') {
+                return Ok(path);
+            }
+            self.skip_ws();
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        if self.starts_with("\"\"\"") {
+            self.pos += 3;
+            return self.basic_string(true).map(Value::String);
+        }
+        if self.starts_with("'''") {
+            self.pos += 3;
+            return self.literal_string(true).map(Value::String);
+        }
+        match self.peek() {
+            Some('"') => {
+                self.bump();
+                self.basic_string(false).map(Value::String)
+            }
+            Some('\'') => {
+                self.bump();
+                self.literal_string(false).map(Value::String)
+            }
+            Some('[') => self.array(),
+            Some('{') => self.inline_table(),
+            _ => self.scalar(),
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.bump();
+        let mut items = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.eat(']') {
+                return Ok(Value::Sequence(items));
+            }
+            items.push(self.value()?);
+            self.skip_blank();
+            if !self.eat(',') {
+                self.expect(']')?;
+                return Ok(Value::Sequence(items));
+            }
+        }
+    }
+
+    fn inline_table(&mut self) -> Result<Value, String> {
+        self.bump();
+        let mut map = Mapping::new();
+        loop {
+            self.skip_blank();
+            if self.eat('}') {
+                return Ok(Value::Mapping(map));
+            }
+            let (key, value) = self.keyval()?;
+            insert(&mut map, &key, value)?;
+            self.skip_blank();
+            if !self.eat(',') {
+                self.expect('}')?;
+                return Ok(Value::Mapping(map));
+            }
+        }
+    }
+
+    // 左引号已读取; 多行字符串紧跟引号的换行不计入内容
+    fn basic_string(&mut self, multiline: bool) -> Result<String, String> {
+        if multiline {
+            self.eat('\r');
+            self.eat('\n');
+        }
+        let mut out = String::new();
+        loop {
+            if multiline && self.starts_with("\"\"\"") {
+                self.pos += 3;
+                // 结尾的引号最多可以再多两个, 属于内容
+                for _ in 0..2 {
+                    if self.eat('"') {
+                        out.push('"');
+                    }
+                }
+                return Ok(out);
+            }
+            match self.string_char(multiline)? {
+                '"' if !multiline => return Ok(out),
+                '\\' => match self.bump() {
+                    Some('b') => out.push('\u{8}'),
+                    Some('t') => out.push('\t'),
+                    Some('n') => out.push('\n'),
+                    Some('f') => out.push('\u{c}'),
+                    Some('r') => out.push('\r'),
+                    Some('"') => out.push('"'),
+                    Some('\\') => out.push('\\'),
+                    Some('u') => out.push(self.unicode(4)?),
+                    Some('U') => out.push(self.unicode(8)?),
+                    // 行尾的反斜杠: 去掉换行及下一行开头的空白
+                    Some(' ' | '\t' | '\r' | '\n') if multiline => {
+                        while matches!(self.peek(), Some(' ' | '\t' | '\r' | '\n')) {
+                            self.bump();
+                        }
+                    }
+                    Some(c) => return Err(format!("无效的转义字符: \\{}", c)),
+                    None => return Err("字符串没有结束".to_string()),
+                },
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn literal_string(&mut self, multiline: bool) -> Result<String, String> {
+        if multiline {
+            self.eat('\r');
+            self.eat('\n');
+        }
+        let mut out = String::new();
+        loop {
+            if multiline && self.starts_with("'''") {
+                self.pos += 3;
+                for _ in 0..2 {
+                    if self.eat('\'') {
+                        out.push('\'');
+                    }
+                }
+                return Ok(out);
+            }
+            match self.string_char(multiline)? {
+                '\'' if !multiline => return Ok(out),
+                c => out.push(c),
+            }
+        }
+    }
+
+    // 字符串中的下一个字符; 单行字符串遇到换行即没有结束 (不读取换行, 错误行号为字符串所在行)
+    fn string_char(&mut self, multiline: bool) -> Result<char, String> {
+        match self.peek() {
+            Some('\n') if !multiline => Err("字符串没有结束".to_string()),
+            None => Err("字符串没有结束".to_string()),
+            Some(_) => Ok(self.bump().unwrap_or_default()),
+        }
+    }
+
+    fn unicode(&mut self, len: usize) -> Result<char, String> {
+        let hex: String = (0..len).filter_map(|_| self.bump()).collect();
+        u32::from_str_radix(&hex, 16)
+            .ok()
+            .filter(|_| hex.len() == len)
+            .and_then(char::from_u32)
+            .ok_or_else(|| format!("无效的 Unicode 转义: {}", hex))
+    }
+
+    // 布尔值、整数 (可带 _ 分隔, 0x / 0o / 0b 前缀) 或浮点数 (含 inf / nan)
+    fn scalar(&mut self) -> Result<Value, String> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '_' | '.' | ':')) {
+            self.bump();
+        }
+        let token: String = self.chars[start..self.pos].iter().collect();
+        let invalid = || format!("无效的值: {}", token);
+        match token.as_str() {
+            "" => {
+                return Err(match self.peek() {
+                    Some(c) if !matches!(c, '\r' | '\n' | '#') => format!("无效的值: '{}'", c),
+                    _ => "缺少值".to_string(),
+                })
+            }
+            "true" => return Ok(Value::Bool(true)),
+            "false" => return Ok(Value::Bool(false)),
+            "inf" | "+inf" => return Ok(Value::Number(f64::INFINITY.into())),
+            "-inf" => return Ok(Value::Number(f64::NEG_INFINITY.into())),
+            "nan" | "+nan" | "-nan" => return Ok(Value::Number(f64::NAN.into())),
+            _ => {}
+        }
+        let date = token.len() > 4 && token[..4].bytes().all(|b| b.is_ascii_digit()) && token[4..].starts_with('-');
+        if date || token.contains(':') {
+            return Err(format!("不支持日期时间: {}", token));
+        }
+        if token.starts_with('_') || token.ends_with('_') || token.contains("__") {
+            return Err(invalid());
+        }
+        let digits = token.replace('_', "");
+        let radix = [("0x", 16), ("0o", 8), ("0b", 2)].into_iter().find(|(p, _)| digits.starts_with(p));
+        let number = match radix {
+            Some((prefix, radix)) => i64::from_str_radix(&digits[prefix.len()..], radix).map(Number::from).ok(),
+            None if digits.contains(['.', 'e', 'E']) => digits.parse::<f64>().map(Number::from).ok(),
+            None => digits.parse::<i64>().map(Number::from).ok(),
+        };
+        number.map(Value::Number).ok_or_else(invalid)
+    }
+}
+
+// 按路径找到 (不存在时创建) 表; 经过表数组时进入其最后一个元素
+fn table<'a>(mut map: &'a mut Mapping, path: &[String]) -> Result<&'a mut Mapping, String> {
+    for key in path {
+        let entry = map.entry(Value::String(key.clone())).or_insert_with(|| Value::Mapping(Mapping::new()));
+        map = match entry {
+            Value::Mapping(m) => m,
+            Value::Sequence(list) => match list.last_mut() {
+                Some(Value::Mapping(m)) => m,
+                _ => return Err(format!("{} 不是表", key)),
+            },
+            _ => return Err(format!("{} 不是表", key)),
+        };
+    }
+    Ok(map)
+}
+
+// [[路径]]: 在表数组末尾添加一个空表
+fn push_table(root: &mut Mapping, path: &[String]) -> Result<(), String> {
+    let (last, parent) = path.split_last().ok_or("缺少表名")?;
+    let entry = table(root, parent)?.entry(Value::String(last.clone())).or_insert_with(|| Value::Sequence(Vec::new()));
+    match entry {
+        Value::Sequence(list) => {
+            list.push(Value::Mapping(Mapping::new()));
+            Ok(())
+        }
+        _ => Err(format!("{} 不是表数组", path.join("."))),
+    }
+}
+
+// 点号分隔的键逐级创建子表
+fn insert(map: &mut Mapping, key: &[String], value: Value) -> Result<(), String> {
+    let (last, parent) = key.split_last().ok_or("缺少键名")?;
+    let map = table(map, parent)?;
+    let last = Value::String(last.clone());
+    if map.contains_key(&last) {
+        return Err(format!("键重复: {}", key.join(".")));
+    }
+    map.insert(last, value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn same_as_yaml(toml: &str, yaml: &str) {
+        let expected: Mapping = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(parse(toml).unwrap(), expected, "{}", toml);
+    }
+
+    fn error(toml: &str) -> String {
+        parse(toml).unwrap_err()
+    }
+
+    #[test]
+    fn tables() {
+        same_as_yaml(
+            "top = 1\n[a]\nx = 1\n[a.b]\ny = 2\n[c . \"d.e\"]\nz = 3\n",
+            "{top: 1, a: {x: 1, b: {y: 2}}, c: {d.e: {z: 3}}}",
+        );
+        // 点号键与之后定义的子表合并
+        same_as_yaml("a.b = 1\na.c = 2\n[d]\ne.f = 3\n", "{a: {b: 1, c: 2}, d: {e: {f: 3}}}");
+    }
+
+    #[test]
+    fn arrays_of_tables() {
+        same_as_yaml(
+            "[[targets]]\nname = \"a\"\n[targets.tls]\nsni = \"x\"\n[[targets]]\nname = \"b\"\n[targets.tls]\nsni = \"y\"\n",
+            "{targets: [{name: a, tls: {sni: x}}, {name: b, tls: {sni: y}}]}",
+        );
+        same_as_yaml("[[p.t]]\nn = 1\n[[p.t]]\n", "{p: {t: [{n: 1}, {}]}}");
+    }
+
+    #[test]
+    fn inline_tables_and_arrays() {
+        same_as_yaml(
+            "t = { name = \"a\", addr.host = 'h', list = [1, 2,], empty = {} }\n",
+            "{t: {name: a, addr: {host: h}, list: [1, 2], empty: {}}}",
+        );
+        same_as_yaml(
+            "a = [\n  { n = 1 },  # 注释\n  { n = 2 },\n]\nb = [[1, 2], [\"x\"]]\n",
+            "{a: [{n: 1}, {n: 2}], b: [[1, 2], [x]]}",
+        );
+    }
+
+    #[test]
+    fn strings() {
+        let doc = parse(concat!(
+            "basic = \"tab\\t quote\\\" back\\\\ \\u00e9 \\U0001F600\"\n",
+            "literal = 'C:\\path\\n'\n",
+            "multi = \"\"\"\nline1\n  line2 \\\n    joined\"\"\"\n",
+            "multi_literal = '''\nraw \\n ''quoted'''''\n",
+            "\"quoted key\" = \"\"\n",
+        ))
+        .unwrap();
+        let get = |k: &str| doc.get(k).and_then(Value::as_str).unwrap().to_string();
+        assert_eq!(get("basic"), "tab\t quote\" back\\ \u{e9} \u{1F600}");
+        assert_eq!(get("literal"), "C:\\path\\n");
+        assert_eq!(get("multi"), "line1\n  line2 joined");
+        assert_eq!(get("multi_literal"), "raw \\n ''quoted''");
+        assert_eq!(get("quoted key"), "");
+    }
+
+    #[test]
+    fn numbers_and_bools() {
+        same_as_yaml(
+            "a = 42\nb = -17\nc = +5\nd = 1_000\ne = 0xff\nf = 0o17\ng = 0b101\nh = 3.5\ni = -0.25\nj = 1e3\nk = true\nl = false\n",
+            "{a: 42, b: -17, c: 5, d: 1000, e: 255, f: 15, g: 5, h: 3.5, i: -0.25, j: 1000.0, k: true, l: false}",
+        );
+        let doc = parse("a = inf\nb = -inf\nc = nan\n").unwrap();
+        assert_eq!(doc.get("a").and_then(Value::as_f64), Some(f64::INFINITY));
+        assert_eq!(doc.get("b").and_then(Value::as_f64), Some(f64::NEG_INFINITY));
+        assert!(doc.get("c").and_then(Value::as_f64).unwrap().is_nan());
+        for bad in ["a = 1__0", "a = _1", "a = 1_", "a = 0xzz", "a = yes", "a = 1979-05-27", "a = 07:32:00"] {
+            assert!(parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn duplicate_keys_are_rejected() {
+        assert_eq!(error("a = 1\nb = 2\na = 3\n"), "第 3 行: 键重复: a");
+        assert_eq!(error("x.y = 1\nx.y = 2\n"), "第 2 行: 键重复: x.y");
+        assert_eq!(error("[t]\n[u]\n[t]\n"), "第 3 行: 表重复定义: [t]");
+        assert_eq!(error("t = { a = 1, a = 2 }\n"), "第 1 行: 键重复: a");
+        assert_eq!(error("a = 1\n[a]\n"), "第 2 行: a 不是表");
+        assert_eq!(error("a = 1\n[[a]]\n"), "第 2 行: a 不是表数组");
+    }
+
+    #[test]
+    fn error_line_numbers() {
+        assert_eq!(error("a = 1\n\n# 注释\nb = \"open\n"), "第 4 行: 字符串没有结束");
+        assert_eq!(error("a = 1\nb = 2 c\n"), "第 2 行: 行尾有多余的内容: 'c'");
+        assert_eq!(error("a = [\n1,\n2\n"), "第 4 行: 应为 ']', 文件已结束");
+        assert_eq!(error("a = \"\\q\"\n"), "第 1 行: 无效的转义字符: \\q");
+        assert_eq!(error("\n\n= 1\n"), "第 3 行: 缺少键名, 实际为 '='");
+        assert_eq!(error("a =\n"), "第 1 行: 缺少值");
+    }
+}
@@ -0,0 +1,599 @@
+//! 反向隧道: NAT 后的后端主动连到转发器并注册, 客户端连接经由隧道多路复用转发给后端。
+//!
+//! 握手: 代理端发送 `HELLO <name> <token>\n`, 服务端回复 `OK\n` 或 `ERR <原因>\n`。
+//! 之后双方收发帧: [类型 u8][流 ID u32][长度 u16][数据], 均为大端序。
+//! 每条流的数据帧受接收窗口限制: 接收方写出数据后用 WINDOW 帧 (数据为归还的帧数 u32) 归还窗口,
+//! 一条流读得慢只会停住这条流的发送方, 不影响同一隧道上的其他流和心跳。
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch, RwLock, Semaphore};
+
+use crate::config::TunnelConfig;
+use crate::state::State;
+
+// 帧类型
+const FRAME_OPEN: u8 = 1;
+const FRAME_DATA: u8 = 2;
+const FRAME_FIN: u8 = 3;
+const FRAME_RST: u8 = 4;
+const FRAME_PING: u8 = 5;
+const FRAME_PONG: u8 = 6;
+const FRAME_WINDOW: u8 = 7;
+
+// --- 隧道参数 ---
+const MAX_FRAME_PAYLOAD: usize = 16 * 1024; // 单帧最大数据量
+const SESSION_QUEUE: usize = 256;           // 隧道发送队列长度 (帧)
+const STREAM_WINDOW: u32 = 64;              // 单条流的接收窗口 (数据帧), 对端超出窗口发送时重置该流
+const WINDOW_UPDATE: u32 = STREAM_WINDOW / 2; // 写出这么多数据帧后归还窗口
+const PING_INTERVAL: u64 = 5;               // 心跳间隔 (秒)
+const PING_MAX_MISSED: u32 = 3;             // 连续丢失心跳次数上限, 超过则断开
+const HANDSHAKE_TIMEOUT: u64 = 5000;        // 握手超时 (ms)
+const MAX_HELLO_LEN: usize = 512;           // 握手行最大长度
+const AGENT_RECONNECT: u64 = 5;             // 代理端断线重连间隔 (秒)
+
+struct Frame {
+    kind: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn new(kind: u8, stream: u32, payload: Vec<u8>) -> Self {
+        Frame { kind, stream, payload }
+    }
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(r: &mut R) -> io::Result<Frame> {
+    let mut head = [0u8; 7];
+    r.read_exact(&mut head).await?;
+    let stream = u32::from_be_bytes([head[1], head[2], head[3], head[4]]);
+    let len = u16::from_be_bytes([head[5], head[6]]) as usize;
+    if len > MAX_FRAME_PAYLOAD {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "隧道帧长度超出上限"));
+    }
+    let mut payload = vec![0u8; len];
+    r.read_exact(&mut payload).await?;
+    Ok(Frame { kind: head[0], stream, payload })
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(w: &mut W, f: &Frame) -> io::Result<()> {
+    let mut head = [0u8; 7];
+    head[0] = f.kind;
+    head[1..5].copy_from_slice(&f.stream.to_be_bytes());
+    head[5..7].copy_from_slice(&(f.payload.len() as u16).to_be_bytes());
+    w.write_all(&head).await?;
+    w.write_all(&f.payload).await
+}
+
+/// 流接收到的消息, None 表示对端已关闭写方向
+type StreamMsg = Option<Vec<u8>>;
+
+/// 一条流在会话中的登记: 接收队列, 以及对端归还的发送窗口
+struct Slot {
+    tx: mpsc::Sender<StreamMsg>,
+    window: Arc<Semaphore>,
+}
+
+/// 一条已建立的隧道会话, 两端共用
+pub struct Session {
+    pub name: String,
+    pub peer: SocketAddr,
+    tx: mpsc::Sender<Frame>,
+    streams: Mutex<HashMap<u32, Slot>>,
+    next_id: AtomicU32,
+    started: Instant,
+    rtt_us: AtomicU64,
+    missed_pings: AtomicU32,
+    closed: watch::Sender<bool>,
+}
+
+impl Session {
+    /// 最近一次心跳 RTT (ms)
+    pub fn rtt_ms(&self) -> u128 {
+        (self.rtt_us.load(Ordering::Relaxed) / 1000) as u128
+    }
+
+    /// 当前连续丢失的心跳数
+    pub fn missed_pings(&self) -> u32 {
+        self.missed_pings.load(Ordering::Relaxed)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        *self.closed.borrow()
+    }
+
+    fn close(&self) {
+        if !self.closed.send_replace(true) {
+            // 丢弃所有流的发送端并关闭发送窗口, 各流随之出错结束
+            self.streams.lock().unwrap().drain().for_each(|(_, slot)| slot.window.close());
+        }
+    }
+
+    /// 等待会话关闭
+    fn wait_closed(&self) -> impl std::future::Future<Output = ()> {
+        let mut rx = self.closed.subscribe();
+        async move {
+            let _ = rx.wait_for(|closed| *closed).await;
+        }
+    }
+
+    /// 服务端: 打开一条新流
+    pub async fn open_stream(self: &Arc<Self>) -> io::Result<TunnelStream> {
+        if self.is_closed() {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "隧道已断开"));
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let stream = self.register_stream(id);
+        self.send(Frame::new(FRAME_OPEN, id, Vec::new())).await?;
+        Ok(stream)
+    }
+
+    fn register_stream(self: &Arc<Self>, id: u32) -> TunnelStream {
+        // 多留一格给 FIN, 守窗口的对端不会把队列填满
+        let (tx, rx) = mpsc::channel(STREAM_WINDOW as usize + 1);
+        let window = Arc::new(Semaphore::new(STREAM_WINDOW as usize));
+        self.streams.lock().unwrap().insert(id, Slot { tx, window: window.clone() });
+        TunnelStream { id, session: self.clone(), rx, window }
+    }
+
+    fn stream_sender(&self, id: u32) -> Option<mpsc::Sender<StreamMsg>> {
+        self.streams.lock().unwrap().get(&id).map(|slot| slot.tx.clone())
+    }
+
+    fn stream_window(&self, id: u32) -> Option<Arc<Semaphore>> {
+        self.streams.lock().unwrap().get(&id).map(|slot| slot.window.clone())
+    }
+
+    fn remove_stream(&self, id: u32) {
+        if let Some(slot) = self.streams.lock().unwrap().remove(&id) {
+            slot.window.close();
+        }
+    }
+
+    async fn send(&self, frame: Frame) -> io::Result<()> {
+        self.tx
+            .send(frame)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "隧道已断开"))
+    }
+}
+
+/// 隧道中的一条流
+pub struct TunnelStream {
+    id: u32,
+    session: Arc<Session>,
+    rx: mpsc::Receiver<StreamMsg>,
+    window: Arc<Semaphore>, // 发送窗口, 每个数据帧占用一格
+}
+
+impl TunnelStream {
+    /// 发送数据, 超过单帧上限时自动拆分
+    pub async fn send(&self, data: &[u8]) -> io::Result<()> {
+        for chunk in data.chunks(MAX_FRAME_PAYLOAD) {
+            send_data(&self.session, &self.window, self.id, chunk.to_vec()).await?;
+        }
+        Ok(())
+    }
+}
+
+/// 发出一个数据帧; 发送窗口用完时等待对端归还, 流被重置或隧道断开时返回错误
+async fn send_data(session: &Session, window: &Semaphore, id: u32, data: Vec<u8>) -> io::Result<()> {
+    let permit = window.acquire().await.map_err(|_| io::Error::new(io::ErrorKind::ConnectionReset, "隧道流已重置"))?;
+    permit.forget();
+    session.send(Frame::new(FRAME_DATA, id, data)).await
+}
+
+impl Drop for TunnelStream {
+    fn drop(&mut self) {
+        self.session.remove_stream(self.id);
+    }
+}
+
+/// 在 TCP 连接与隧道流之间双向转发, 返回 (上行字节, 下行字节)
+pub async fn relay<C: AsyncRead + AsyncWrite>(tcp: C, mut stream: TunnelStream) -> io::Result<(u64, u64)> {
+    let (mut r, mut w) = io::split(tcp);
+    let (id, session, window) = (stream.id, stream.session.clone(), stream.window.clone());
+
+    let up = async {
+        let mut buf = vec![0u8; MAX_FRAME_PAYLOAD];
+        let mut total = 0u64;
+        loop {
+            let n = r.read(&mut buf).await?;
+            if n == 0 {
+                session.send(Frame::new(FRAME_FIN, id, Vec::new())).await?;
+                return Ok::<u64, io::Error>(total);
+            }
+            send_data(&session, &window, id, buf[..n].to_vec()).await?;
+            total += n as u64;
+        }
+    };
+    let down = async {
+        let (mut total, mut unacked) = (0u64, 0u32);
+        loop {
+            match stream.rx.recv().await {
+                Some(Some(data)) => {
+                    w.write_all(&data).await?;
+                    total += data.len() as u64;
+                    unacked += 1;
+                    if unacked >= WINDOW_UPDATE {
+                        session.send(Frame::new(FRAME_WINDOW, id, unacked.to_be_bytes().to_vec())).await?;
+                        unacked = 0;
+                    }
+                }
+                Some(None) => break,
+                None => return Err(io::Error::new(io::ErrorKind::ConnectionReset, "隧道流已重置")),
+            }
+        }
+        w.shutdown().await?;
+        Ok::<u64, io::Error>(total)
+    };
+
+    let res = tokio::try_join!(up, down);
+    // 无论成功与否都通知对端释放该流
+    let _ = session.send(Frame::new(FRAME_RST, id, Vec::new())).await;
+    res
+}
+
+/// 启动会话的收发任务; on_open 为 Some 时作为代理端处理服务端发起的新流
+fn start_session<S>(
+    socket: S,
+    name: String,
+    peer: SocketAddr,
+    on_open: Option<Arc<dyn Fn(TunnelStream) + Send + Sync>>,
+) -> Arc<Session>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, mut writer) = io::split(socket);
+    let (tx, mut rx) = mpsc::channel::<Frame>(SESSION_QUEUE);
+    let session = Arc::new(Session {
+        name,
+        peer,
+        tx,
+        streams: Mutex::new(HashMap::new()),
+        next_id: AtomicU32::new(1),
+        started: Instant::now(),
+        rtt_us: AtomicU64::new(0),
+        missed_pings: AtomicU32::new(0),
+        closed: watch::Sender::new(false),
+    });
+
+    // 发送任务
+    let s = session.clone();
+    tokio::spawn(async move {
+        let closed = s.wait_closed();
+        tokio::pin!(closed);
+        loop {
+            tokio::select! {
+                frame = rx.recv() => match frame {
+                    Some(f) => {
+                        if write_frame(&mut writer, &f).await.is_err() {
+                            break;
+                        }
+                    }
+                    None => break,
+                },
+                _ = &mut closed => break,
+            }
+        }
+        s.close();
+    });
+
+    // 接收任务
+    let s = session.clone();
+    tokio::spawn(async move {
+        let closed = s.wait_closed();
+        tokio::pin!(closed);
+        loop {
+            let frame = tokio::select! {
+                f = read_frame(&mut reader) => match f {
+                    Ok(f) => f,
+                    Err(_) => break,
+                },
+                _ = &mut closed => break,
+            };
+            match frame.kind {
+                FRAME_DATA | FRAME_FIN => {
+                    let msg = if frame.kind == FRAME_DATA { Some(frame.payload) } else { None };
+                    // 不等待: 一条流读得慢不能停住整条隧道; 队列满说明对端没有遵守窗口, 重置这条流
+                    match s.stream_sender(frame.stream).map(|sender| sender.try_send(msg)) {
+                        Some(Err(mpsc::error::TrySendError::Full(_))) => {
+                            log::debug!("隧道 [{}] 流 {} 超出接收窗口, 重置", s.name, frame.stream);
+                            s.remove_stream(frame.stream);
+                            let _ = s.send(Frame::new(FRAME_RST, frame.stream, Vec::new())).await;
+                        }
+                        Some(Err(mpsc::error::TrySendError::Closed(_))) => s.remove_stream(frame.stream),
+                        _ => {}
+                    }
+                }
+                FRAME_WINDOW => {
+                    let n = <[u8; 4]>::try_from(frame.payload.as_slice()).map(u32::from_be_bytes);
+                    if let (Ok(n), Some(window)) = (n, s.stream_window(frame.stream)) {
+                        // 归还的不会多于已发出的, 多出的部分忽略
+                        let room = (STREAM_WINDOW as usize).saturating_sub(window.available_permits());
+                        window.add_permits((n as usize).min(room));
+                    }
+                }
+                FRAME_RST => s.remove_stream(frame.stream),
+                FRAME_OPEN => match on_open {
+                    Some(ref handler) => handler(s.register_stream(frame.stream)),
+                    None => {
+                        let _ = s.send(Frame::new(FRAME_RST, frame.stream, Vec::new())).await;
+                    }
+                },
+                FRAME_PING => {
+                    let _ = s.send(Frame::new(FRAME_PONG, 0, frame.payload)).await;
+                }
+                FRAME_PONG => {
+                    if let Ok(sent) = <[u8; 8]>::try_from(frame.payload.as_slice()) {
+                        let sent = u64::from_be_bytes(sent);
+                        let now = s.started.elapsed().as_micros() as u64;
+                        s.rtt_us.store(now.saturating_sub(sent), Ordering::Relaxed);
+                        s.missed_pings.store(0, Ordering::Relaxed);
+                    }
+                }
+                _ => break,
+            }
+        }
+        s.close();
+    });
+
+    session
+}
+
+/// 服务端: 接受后端注册的隧道, 并登记到 State
+pub async fn serve(cfg: TunnelConfig, state: Arc<RwLock<State>>) -> Result<()> {
+    let listener = TcpListener::bind(&cfg.bind_addr).await?;
+    log::info!("隧道注册服务启动: {}", cfg.bind_addr);
+    let cfg = Arc::new(cfg);
+
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        let (cfg, state) = (cfg.clone(), state.clone());
+        tokio::spawn(async move {
+            if let Err(e) = handle_registration(socket, peer, cfg, state).await {
+                log::warn!("隧道注册失败 ({}): {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_registration(
+    mut socket: TcpStream,
+    peer: SocketAddr,
+    cfg: Arc<TunnelConfig>,
+    state: Arc<RwLock<State>>,
+) -> Result<()> {
+    let hello = tokio::time::timeout(Duration::from_millis(HANDSHAKE_TIMEOUT), read_line(&mut socket))
+        .await
+        .map_err(|_| anyhow::anyhow!("握手超时"))??;
+    let parts: Vec<&str> = hello.split_whitespace().collect();
+    let name = match parts.as_slice() {
+        ["HELLO", name, token] if constant_time_eq(token.as_bytes(), cfg.token.as_bytes()) => name.to_string(),
+        ["HELLO", ..] => {
+            let _ = socket.write_all(b"ERR auth\n").await;
+            anyhow::bail!("认证失败");
+        }
+        _ => anyhow::bail!("握手格式错误"),
+    };
+    socket.write_all(b"OK\n").await?;
+    let _ = socket.set_nodelay(true);
+
+    let session = start_session(socket, name.clone(), peer, None);
+    if let Some(old) = state.write().await.tunnels.insert(name.clone(), session.clone()) {
+        log::warn!("隧道 [{}] 重复注册, 断开旧连接 ({})", name, old.peer);
+        old.close();
+    }
+    log::info!(">>> 隧道已注册: [{}] ({})", name, peer);
+
+    // 心跳: 携带发送时刻, 对端原样返回用于计算 RTT
+    let mut ticker = tokio::time::interval(Duration::from_secs(PING_INTERVAL));
+    let closed = session.wait_closed();
+    tokio::pin!(closed);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = &mut closed => break,
+        }
+        if session.missed_pings.fetch_add(1, Ordering::Relaxed) >= PING_MAX_MISSED {
+            log::warn!("隧道 [{}] 心跳超时", name);
+            session.close();
+            break;
+        }
+        let now = session.started.elapsed().as_micros() as u64;
+        if session.send(Frame::new(FRAME_PING, 0, now.to_be_bytes().to_vec())).await.is_err() {
+            break;
+        }
+    }
+
+    let mut s = state.write().await;
+    if s.tunnels.get(&name).is_some_and(|t| Arc::ptr_eq(t, &session)) {
+        s.tunnels.remove(&name);
+        log::warn!("!!! 隧道已断开: [{}] ({})", name, peer);
+    }
+    Ok(())
+}
+
+/// 代理端: 连接转发器注册隧道, 把服务端打开的流转发到本地后端, 断线自动重连
+pub async fn run_agent(server: String, name: String, token: String, local: String) -> Result<()> {
+    let local = Arc::new(local);
+    loop {
+        match connect_agent(&server, &name, &token).await {
+            Ok((socket, peer)) => {
+                log::info!(">>> 隧道已连接: {} (名称: {}, 本地后端: {})", server, name, local);
+                let local = local.clone();
+                let on_open: Arc<dyn Fn(TunnelStream) + Send + Sync> = Arc::new(move |stream| {
+                    let local = local.clone();
+                    tokio::spawn(async move {
+                        match TcpStream::connect(local.as_str()).await {
+                            Ok(tcp) => {
+                                let _ = tcp.set_nodelay(true);
+                                let _ = relay(tcp, stream).await;
+                            }
+                            Err(e) => log::warn!("连接本地后端失败 ({}): {}", local, e),
+                        }
+                    });
+                });
+                let session = start_session(socket, name.clone(), peer, Some(on_open));
+                session.wait_closed().await;
+                log::warn!("!!! 隧道断开, {}秒后重连", AGENT_RECONNECT);
+            }
+            Err(e) => log::warn!("隧道连接失败 ({}): {}, {}秒后重试", server, e, AGENT_RECONNECT),
+        }
+        tokio::time::sleep(Duration::from_secs(AGENT_RECONNECT)).await;
+    }
+}
+
+async fn connect_agent(server: &str, name: &str, token: &str) -> Result<(TcpStream, SocketAddr)> {
+    let mut socket = TcpStream::connect(server).await?;
+    let peer = socket.peer_addr()?;
+    socket.write_all(format!("HELLO {} {}\n", name, token).as_bytes()).await?;
+    let reply = tokio::time::timeout(Duration::from_millis(HANDSHAKE_TIMEOUT), read_line(&mut socket))
+        .await
+        .map_err(|_| anyhow::anyhow!("握手超时"))??;
+    if reply != "OK" {
+        anyhow::bail!("服务端拒绝: {}", reply);
+    }
+    let _ = socket.set_nodelay(true);
+    Ok((socket, peer))
+}
+
+/// 逐字节读取一行 (不含换行), 避免多读到后续帧数据
+async fn read_line(socket: &mut TcpStream) -> Result<String> {
+    let mut line = Vec::new();
+    loop {
+        let b = socket.read_u8().await?;
+        if b == b'\n' {
+            return Ok(String::from_utf8_lossy(&line).trim().to_string());
+        }
+        if line.len() >= MAX_HELLO_LEN {
+            anyhow::bail!("握手行过长");
+        }
+        line.push(b);
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 两端会话接在一条内存管道上; 代理端把服务端打开的流交给测试
+    fn session_pair() -> (Arc<Session>, mpsc::UnboundedReceiver<TunnelStream>) {
+        let (server, agent) = io::duplex(64 * 1024);
+        let peer: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let on_open: Arc<dyn Fn(TunnelStream) + Send + Sync> = Arc::new(move |stream| {
+            tx.send(stream).ok();
+        });
+        start_session(agent, "t".into(), peer, Some(on_open));
+        (start_session(server, "t".into(), peer, None), rx)
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_is_rejected() {
+        let mut head = vec![FRAME_DATA, 0, 0, 0, 1];
+        head.extend_from_slice(&(MAX_FRAME_PAYLOAD as u16 + 1).to_be_bytes());
+        head.resize(head.len() + MAX_FRAME_PAYLOAD + 1, 0);
+        let err = read_frame(&mut head.as_slice()).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut encoded = Vec::new();
+        write_frame(&mut encoded, &Frame::new(FRAME_DATA, 7, vec![1; MAX_FRAME_PAYLOAD])).await.unwrap();
+        let frame = read_frame(&mut encoded.as_slice()).await.unwrap();
+        assert_eq!((frame.kind, frame.stream, frame.payload.len()), (FRAME_DATA, 7, MAX_FRAME_PAYLOAD));
+    }
+
+    #[tokio::test]
+    async fn wrong_token_gets_err_auth() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config: crate::config::Config =
+            serde_yaml::from_str("bind_addr: 127.0.0.1:0\nupdate_interval: 1\ntargets: []\n").unwrap();
+        let state = Arc::new(RwLock::new(State::new(&config)));
+        let cfg = Arc::new(TunnelConfig { bind_addr: addr.to_string(), token: "secret".into(), pool: "tunnel".into() });
+        let server = tokio::spawn(async move {
+            let (socket, peer) = listener.accept().await.unwrap();
+            handle_registration(socket, peer, cfg, state.clone()).await.map(|_| state)
+        });
+        let err = connect_agent(&addr.to_string(), "a", "guess").await.err().unwrap();
+        assert_eq!(err.to_string(), "服务端拒绝: ERR auth");
+        assert_eq!(server.await.unwrap().err().unwrap().to_string(), "认证失败");
+    }
+
+    #[tokio::test]
+    async fn open_data_fin_round_trip() {
+        let (session, mut opened) = session_pair();
+        let stream = session.open_stream().await.unwrap();
+        let remote = opened.recv().await.unwrap();
+        let (client, mut client_peer) = io::duplex(1024);
+        let (backend, mut backend_peer) = io::duplex(1024);
+        let server_side = tokio::spawn(relay(client, stream));
+        let agent_side = tokio::spawn(relay(backend, remote));
+
+        // 上行多于一个窗口的数据帧, 验证窗口归还
+        let request = vec![7u8; MAX_FRAME_PAYLOAD * (STREAM_WINDOW as usize + 8)];
+        let writer = async {
+            client_peer.write_all(&request).await.unwrap();
+            client_peer.shutdown().await.unwrap();
+        };
+        let mut got = Vec::new();
+        let (_, read) = tokio::join!(writer, backend_peer.read_to_end(&mut got));
+        read.unwrap();
+        assert_eq!(got, request);
+        backend_peer.write_all(b"pong").await.unwrap();
+        backend_peer.shutdown().await.unwrap();
+        got.clear();
+        client_peer.read_to_end(&mut got).await.unwrap();
+        assert_eq!(got, b"pong");
+        assert_eq!(server_side.await.unwrap().unwrap(), (request.len() as u64, 4));
+        assert_eq!(agent_side.await.unwrap().unwrap(), (4, request.len() as u64));
+    }
+
+    #[tokio::test]
+    async fn slow_stream_does_not_stall_the_session() {
+        let (session, mut opened) = session_pair();
+        let slow = session.open_stream().await.unwrap();
+        let slow_remote = opened.recv().await.unwrap();
+        // 对端不读: 发送方用完窗口后停住, 不会把对端队列灌满
+        let flood = tokio::spawn(async move {
+            for _ in 0..STREAM_WINDOW + 1 {
+                slow.send(b"x").await.unwrap();
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!flood.is_finished());
+
+        // 同一隧道上的其他流和心跳照常收发
+        let wait = Duration::from_secs(5);
+        let fast = session.open_stream().await.unwrap();
+        let mut fast_remote = opened.recv().await.unwrap();
+        fast.send(b"hello").await.unwrap();
+        let received = tokio::time::timeout(wait, fast_remote.rx.recv()).await.unwrap();
+        assert_eq!(received, Some(Some(b"hello".to_vec())));
+        assert_eq!(slow_remote.rx.len(), STREAM_WINDOW as usize);
+        session.missed_pings.store(2, Ordering::Relaxed);
+        session.send(Frame::new(FRAME_PING, 0, 0u64.to_be_bytes().to_vec())).await.unwrap();
+        let pong = async {
+            while session.missed_pings() != 0 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        tokio::time::timeout(wait, pong).await.unwrap();
+
+        // 归还窗口后剩下的一帧发出
+        let update = Frame::new(FRAME_WINDOW, slow_remote.id, 1u32.to_be_bytes().to_vec());
+        slow_remote.session.send(update).await.unwrap();
+        tokio::time::timeout(wait, flood).await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+use anyhow::Result;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::RwLock;
+
+use crate::config::UdpConfig;
+use crate::net;
+use crate::state::{BestTarget, ConnGuard, State};
+use crate::stats;
+
+const MAX_DATAGRAM: usize = 65535;
+const MAX_SESSIONS: usize = 65536; // 会话数上限, 防止伪造源地址耗尽内存
+
+/// 一个客户端源地址对应的会话, 整个会话固定转发到创建时选中的节点
+struct Session {
+    upstream: Arc<UdpSocket>,
+    last_active: Arc<Mutex<Instant>>,
+}
+
+type Sessions = Arc<Mutex<HashMap<SocketAddr, Session>>>;
+
+/// UDP 转发: 按客户端源地址维护会话, 新会话使用当前最优节点, 空闲超时后释放
+/// opts 中的 fwmark 和 dscp 用于发往目标的套接字, dscp 同时用于发回客户端的报文
+pub async fn serve(config: UdpConfig, opts: net::SocketOptions, state: Arc<RwLock<State>>) -> Result<()> {
+    let socket = Arc::new(UdpSocket::bind(&config.bind_addr).await?);
+    if let Some(dscp) = opts.dscp {
+        net::set_dscp(socket2::SockRef::from(&*socket), dscp, socket.local_addr()?.is_ipv6())?;
+    }
+    log::info!("UDP 转发启动: {} (会话空闲超时: {}秒)", config.bind_addr, config.idle_timeout);
+    let idle = Duration::from_secs(config.idle_timeout);
+    let sessions: Sessions = Arc::default();
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+
+    loop {
+        let (n, client) = match socket.recv_from(&mut buf).await {
+            Ok(r) => r,
+            Err(e) => {
+                // ICMP 端口不可达等错误只影响单个包
+                log::debug!("UDP 接收失败: {}", e);
+                continue;
+            }
+        };
+
+        let existing = sessions.lock().unwrap().get(&client).map(|s| {
+            *s.last_active.lock().unwrap() = Instant::now();
+            s.upstream.clone()
+        });
+        let upstream = match existing {
+            Some(u) => u,
+            None => match open_session(&socket, client, &sessions, &state, idle, &opts).await {
+                Some(u) => u,
+                None => continue,
+            },
+        };
+        if upstream.send(&buf[..n]).await.is_ok() {
+            stats::add_bytes(n as u64, 0);
+        }
+    }
+}
+
+/// 为新的客户端选择节点并建立会话, 没有可用节点时丢弃该包
+async fn open_session(
+    socket: &Arc<UdpSocket>,
+    client: SocketAddr,
+    sessions: &Sessions,
+    state: &Arc<RwLock<State>>,
+    idle: Duration,
+    opts: &net::SocketOptions,
+) -> Option<Arc<UdpSocket>> {
+    if sessions.lock().unwrap().len() >= MAX_SESSIONS {
+        log::warn!("UDP 会话数已达上限 {}, 丢弃来自 {} 的包", MAX_SESSIONS, client);
+        return None;
+    }
+    let (target, guard) = {
+        let s = state.read().await;
+        // 反向隧道只承载 TCP
+        let target = s.select_with_overflow(Some(client.ip())).map(|(t, _)| t).filter(|t| !t.via_tunnel).cloned();
+        let Some(target) = target else {
+            stats::inc(&stats::REJECTED);
+            log::debug!("UDP 没有可用节点, 丢弃来自 {} 的包", client);
+            return None;
+        };
+        let guard = s.conns.acquire(&target.name);
+        (target, guard)
+    };
+
+    let upstream = match outbound_socket(&target, opts) {
+        Ok(u) => u,
+        Err(e) => {
+            log::warn!("[{}] UDP 会话创建失败: {}", target.name, e);
+            return None;
+        }
+    };
+    if let Err(e) = upstream.connect(target.addr).await {
+        log::warn!("[{}] UDP 会话创建失败: {}", target.name, e);
+        return None;
+    }
+    let upstream = Arc::new(upstream);
+    let last_active = Arc::new(Mutex::new(Instant::now()));
+    sessions
+        .lock()
+        .unwrap()
+        .insert(client, Session { upstream: upstream.clone(), last_active: last_active.clone() });
+    stats::inc(&stats::CONNECTIONS);
+    log::debug!("UDP 新会话: {} -> [{}] ({})", client, target.name, target.addr);
+
+    tokio::spawn(relay_replies(socket.clone(), upstream.clone(), client, sessions.clone(), last_active, idle, guard));
+    Some(upstream)
+}
+
+/// 发往目标的套接字, 按目标的 outbound_bind 绑定源地址或网卡
+fn outbound_socket(target: &BestTarget, opts: &net::SocketOptions) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(target.addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_nonblocking(true)?;
+    net::bind_outbound(socket2::SockRef::from(&socket), target.outbound_bind.as_ref(), opts.mark, target.addr.ip())?;
+    if let Some(dscp) = opts.dscp {
+        net::set_dscp(socket2::SockRef::from(&socket), dscp, target.addr.is_ipv6())?;
+    }
+    UdpSocket::from_std(socket.into())
+}
+
+/// 把目标的回包发回客户端, 双向都空闲超过 idle 后结束会话
+async fn relay_replies(
+    socket: Arc<UdpSocket>,
+    upstream: Arc<UdpSocket>,
+    client: SocketAddr,
+    sessions: Sessions,
+    last_active: Arc<Mutex<Instant>>,
+    idle: Duration,
+    _guard: ConnGuard,
+) {
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let remaining = idle.saturating_sub(last_active.lock().unwrap().elapsed());
+        if remaining.is_zero() {
+            break;
+        }
+        match tokio::time::timeout(remaining, upstream.recv(&mut buf)).await {
+            Ok(Ok(n)) => {
+                *last_active.lock().unwrap() = Instant::now();
+                if socket.send_to(&buf[..n], client).await.is_ok() {
+                    stats::add_bytes(0, n as u64);
+                }
+            }
+            // 目标端口不可达等错误不结束会话, 等待空闲超时
+            Ok(Err(e)) => log::debug!("UDP 会话 {} 接收失败: {}", client, e),
+            Err(_) => {}
+        }
+    }
+    sessions.lock().unwrap().remove(&client);
+    log::debug!("UDP 会话结束: {}", client);
+}
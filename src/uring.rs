@@ -0,0 +1,617 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::io;
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+
+use crate::relay::Activity;
+
+// 内核 ABI (include/uapi/linux/io_uring.h), libc 只提供系统调用号
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+const IORING_FEAT_SINGLE_MMAP: u32 = 1 << 0;
+const IORING_FEAT_NODROP: u32 = 1 << 1;
+const IORING_FEAT_FAST_POLL: u32 = 1 << 5;
+const IORING_ENTER_GETEVENTS: u32 = 1 << 0;
+const IORING_OP_ASYNC_CANCEL: u8 = 14;
+const IORING_OP_READ: u8 = 22;
+const IORING_OP_SEND: u8 = 26;
+const IORING_OP_RECV: u8 = 27;
+
+const ENTRIES: u32 = 1024; // 提交队列长度; 完成队列为其两倍, 溢出时由内核暂存 (FEAT_NODROP)
+const DEFAULT_BUFFER: usize = 64 * 1024;
+const WAKE: u64 = u64::MAX; // 读唤醒 eventfd 的请求
+const CANCEL: u64 = 1 << 63; // 取消请求的 user_data 标记
+
+static FALLBACK_WARNED: AtomicBool = AtomicBool::new(false);
+static DRIVERS: OnceLock<Result<Vec<Arc<Driver>>, String>> = OnceLock::new();
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct SqOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqOffsets,
+    cq_off: CqOffsets,
+}
+
+#[repr(C)]
+#[derive(Clone, Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32, // msg_flags 等, 随 opcode 而定
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// 共享映射的一段内存, 析构时解除映射
+struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mmap {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> io::Result<Mmap> {
+        // SAFETY: 映射 io_uring 描述符在 offset 处的区域, 长度来自内核返回的参数
+        let ptr = unsafe {
+            libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED | libc::MAP_POPULATE, fd, offset)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mmap { ptr, len })
+    }
+
+    /// 映射内 offset 处的指针; offset 来自内核, 总在映射范围内
+    fn at<T>(&self, offset: u32) -> *mut T {
+        // SAFETY: 内核给出的偏移量不超过映射长度
+        unsafe { self.ptr.cast::<u8>().add(offset as usize).cast() }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // SAFETY: ptr/len 来自成功的 mmap
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+// SAFETY: 映射只通过 Ring 访问, Ring 的使用者持有 &mut
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+/// 长期存在的 io_uring, 只由所属的转发线程读写
+struct Ring {
+    fd: OwnedFd,
+    rings: Mmap,
+    sqes: Mmap,
+    params: Params,
+    queued: u32, // 已放入提交队列、尚未交给内核的请求数
+}
+
+impl Ring {
+    fn new() -> io::Result<Ring> {
+        let mut params = Params::default();
+        // SAFETY: params 为内核期望的 io_uring_params 布局, 成功时返回新的描述符
+        let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, ENTRIES, &mut params as *mut Params) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: 描述符刚由 io_uring_setup 创建, 只归这里所有
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+        // 需要 5.7 及以上: 两个队列共用一次映射, 完成队列满时不丢事件, 套接字暂无数据时由内核等待就绪而不是返回 EAGAIN
+        let required = IORING_FEAT_SINGLE_MMAP | IORING_FEAT_NODROP | IORING_FEAT_FAST_POLL;
+        if params.features & required != required {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "内核版本过旧 (需要 5.7 及以上)"));
+        }
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len = params.cq_off.cqes as usize + params.cq_entries as usize * size_of::<Cqe>();
+        let rings = Mmap::new(fd.as_raw_fd(), sq_len.max(cq_len), IORING_OFF_SQ_RING)?;
+        let sqes = Mmap::new(fd.as_raw_fd(), params.sq_entries as usize * size_of::<Sqe>(), IORING_OFF_SQES)?;
+        Ok(Ring { fd, rings, sqes, params, queued: 0 })
+    }
+
+    fn counter(&self, offset: u32) -> &AtomicU32 {
+        // SAFETY: 内核导出的队列头尾为对齐的 u32, 与内核并发访问须用原子操作
+        unsafe { AtomicU32::from_ptr(self.rings.at(offset)) }
+    }
+
+    /// 放入提交队列, 下次 enter 时与同一轮产生的其他请求一起提交; 队列满时先提交已有的请求
+    fn push(&mut self, sqe: Sqe) -> io::Result<()> {
+        let SqOffsets { head, tail: tail_off, array, .. } = self.params.sq_off;
+        let tail = self.counter(tail_off).load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.counter(head).load(Ordering::Acquire)) == self.params.sq_entries {
+            self.enter(0)?;
+        }
+        let index = tail & (self.params.sq_entries - 1);
+        // SAFETY: index 在 sq_entries 范围内, 队列未满时该槽位已被内核消费
+        unsafe {
+            self.sqes.at::<Sqe>(0).add(index as usize).write(sqe);
+            self.rings.at::<u32>(array).add(index as usize).write(index);
+        }
+        self.counter(tail_off).store(tail.wrapping_add(1), Ordering::Release);
+        self.queued += 1;
+        Ok(())
+    }
+
+    /// 一次系统调用提交全部排队的请求, 并等待至少 wait 个完成事件
+    fn enter(&mut self, wait: u32) -> io::Result<()> {
+        let flags = if wait > 0 { IORING_ENTER_GETEVENTS } else { 0 };
+        loop {
+            // SAFETY: 不传信号掩码
+            let n = unsafe {
+                libc::syscall(libc::SYS_io_uring_enter, self.fd.as_raw_fd(), self.queued, wait, flags, std::ptr::null::<u8>(), 0)
+            };
+            if n >= 0 {
+                self.queued -= (n as u32).min(self.queued);
+                return Ok(());
+            }
+            match io::Error::last_os_error() {
+                e if e.kind() == io::ErrorKind::Interrupted => continue,
+                // 完成队列溢出尚未回写, 先处理已有的完成事件
+                e if e.raw_os_error() == Some(libc::EBUSY) => return Ok(()),
+                e => return Err(e),
+            }
+        }
+    }
+
+    /// 取出一个完成事件
+    fn complete(&self) -> Option<(u64, i32)> {
+        let off = &self.params.cq_off;
+        let head = self.counter(off.head).load(Ordering::Relaxed);
+        if head == self.counter(off.tail).load(Ordering::Acquire) {
+            return None;
+        }
+        let index = head & (self.params.cq_entries - 1);
+        // SAFETY: head 与 tail 之间的槽位已由内核写好
+        let cqe = unsafe { self.rings.at::<Cqe>(off.cqes).add(index as usize).read() };
+        self.counter(off.head).store(head.wrapping_add(1), Ordering::Release);
+        Some((cqe.user_data, cqe.res))
+    }
+}
+
+/// 一个方向的状态: 在途请求不会多于一个, 缓冲区在请求完成前不能释放
+struct Half {
+    buf: Vec<u8>,
+    pending: usize, // 已读入缓冲区、尚未写出的字节 (从 sent 开始)
+    sent: usize,
+    in_flight: bool,
+    done: bool,
+    total: u64,
+}
+
+/// 交给转发线程的一条连接; 描述符是复制出来的, 请求全部完成后随 Job 关闭
+struct Job {
+    id: u64,
+    fds: [OwnedFd; 2], // 客户端, 目标
+    halves: [Half; 2], // 0: 客户端 -> 目标, 1: 目标 -> 客户端
+    activity: Arc<Activity>,
+    meter: Option<Arc<AtomicU64>>,
+    result: Option<oneshot::Sender<io::Result<(u64, u64)>>>,
+    error: Option<io::Error>,
+    cancelled: bool,
+}
+
+impl Job {
+    fn recv(&mut self, slot: usize, dir: usize) -> Sqe {
+        let half = &mut self.halves[dir];
+        half.in_flight = true;
+        let (addr, len) = (half.buf.as_mut_ptr() as u64, half.buf.len().min(u32::MAX as usize) as u32);
+        let fd = self.fds[dir].as_raw_fd();
+        Sqe { opcode: IORING_OP_RECV, fd, addr, len, user_data: user_data(slot, dir), ..Default::default() }
+    }
+
+    fn send(&mut self, slot: usize, dir: usize) -> Sqe {
+        let half = &mut self.halves[dir];
+        half.in_flight = true;
+        let data = &half.buf[half.sent..half.sent + half.pending];
+        let (addr, len) = (data.as_ptr() as u64, data.len() as u32);
+        let (fd, op_flags) = (self.fds[1 - dir].as_raw_fd(), libc::MSG_NOSIGNAL as u32);
+        Sqe { opcode: IORING_OP_SEND, fd, addr, len, op_flags, user_data: user_data(slot, dir), ..Default::default() }
+    }
+
+    /// 停止转发: 取消在途请求, 完成后由 finished 回收
+    fn stop(&mut self, slot: usize, ring: &mut Ring) -> io::Result<()> {
+        for dir in (0..2).filter(|&d| self.halves[d].in_flight) {
+            let target = user_data(slot, dir);
+            ring.push(Sqe { opcode: IORING_OP_ASYNC_CANCEL, addr: target, user_data: CANCEL | target, ..Default::default() })?;
+        }
+        Ok(())
+    }
+
+    fn finished(&self) -> bool {
+        let idle = self.halves.iter().all(|h| !h.in_flight);
+        idle && (self.cancelled || self.error.is_some() || self.halves.iter().all(|h| h.done))
+    }
+
+    /// 处理一个方向的读写完成事件, 返回需要提交的下一个请求
+    fn complete(&mut self, slot: usize, dir: usize, res: i32) -> Option<Sqe> {
+        self.halves[dir].in_flight = false;
+        if self.cancelled || self.error.is_some() {
+            return None;
+        }
+        let reading = self.halves[dir].pending == 0;
+        // 有 FAST_POLL 时内核自行等待就绪, 这里只是兜底
+        if res == -libc::EAGAIN || res == -libc::EINTR {
+            return Some(if reading { self.recv(slot, dir) } else { self.send(slot, dir) });
+        }
+        if res < 0 {
+            self.error = Some(io::Error::from_raw_os_error(-res));
+            return None;
+        }
+        let n = res as usize;
+        let half = &mut self.halves[dir];
+        if reading {
+            if n == 0 {
+                // 一侧读到 EOF 后关闭另一侧的写方向
+                half.done = true;
+                // SAFETY: 描述符归 Job 所有
+                if unsafe { libc::shutdown(self.fds[1 - dir].as_raw_fd(), libc::SHUT_WR) } < 0 {
+                    self.error = Some(io::Error::last_os_error());
+                }
+                return None;
+            }
+            self.activity.touch();
+            (half.pending, half.sent) = (n, 0);
+            return Some(self.send(slot, dir));
+        }
+        // 写完成, 未写完的部分继续写
+        if n == 0 {
+            self.error = Some(io::ErrorKind::WriteZero.into());
+            return None;
+        }
+        half.sent += n;
+        half.pending -= n;
+        if half.pending > 0 {
+            return Some(self.send(slot, dir));
+        }
+        let sent = half.sent;
+        half.total += sent as u64;
+        self.activity.transfer(dir == 0, sent);
+        if let Some(ref m) = self.meter {
+            m.fetch_add(sent as u64, Ordering::Relaxed);
+        }
+        Some(self.recv(slot, dir))
+    }
+}
+
+fn user_data(slot: usize, dir: usize) -> u64 {
+    (slot as u64) << 1 | dir as u64
+}
+
+enum Msg {
+    Start(Box<Job>),
+    Cancel(u64),
+}
+
+#[derive(Default)]
+struct Mailbox {
+    msgs: Vec<Msg>,
+    dead: bool, // 转发线程已因错误退出, 不再接收新连接
+}
+
+/// 一个转发线程: tokio 任务把连接放进信箱并通过 eventfd 唤醒它
+pub struct Driver {
+    mailbox: Mutex<Mailbox>,
+    wake: OwnedFd,
+    woken: AtomicBool, // 已写过 eventfd 且线程尚未取走信箱, 期间不必再写
+}
+
+impl Driver {
+    fn post(&self, msg: Msg) -> bool {
+        let mut mailbox = self.mailbox.lock().unwrap();
+        if mailbox.dead {
+            return false;
+        }
+        mailbox.msgs.push(msg);
+        drop(mailbox);
+        if !self.woken.swap(true, Ordering::AcqRel) {
+            // SAFETY: 向 eventfd 写入 8 字节计数
+            unsafe { libc::eventfd_write(self.wake.as_raw_fd(), 1) };
+        }
+        true
+    }
+
+    fn run(&self, mut ring: Ring) {
+        let mut jobs: Vec<Option<Box<Job>>> = Vec::new();
+        let mut counter = Box::new(0u64);
+        let Err(err) = self.serve(&mut ring, &mut jobs, &mut counter);
+        log::error!("io_uring 转发线程出错退出, 之后的连接改用普通转发: {}", err);
+        let stranded = {
+            let mut mailbox = self.mailbox.lock().unwrap();
+            mailbox.dead = true;
+            std::mem::take(&mut mailbox.msgs)
+        };
+        drop(stranded); // 尚未开始的连接: 丢弃 Job 后其 relay 返回错误
+        // 仍有在途请求的缓冲区可能还会被内核写入, 不能释放
+        for job in jobs.into_iter().flatten() {
+            let Job { halves, result, fds, .. } = *job;
+            halves.into_iter().for_each(|h| std::mem::forget(h.buf));
+            drop((result, fds));
+        }
+        std::mem::forget(counter);
+    }
+
+    fn serve(&self, ring: &mut Ring, jobs: &mut Vec<Option<Box<Job>>>, counter: &mut u64) -> io::Result<Infallible> {
+        let mut slots = HashMap::new(); // 连接 id -> jobs 下标
+        let mut free = Vec::new();
+        let wake = Sqe { opcode: IORING_OP_READ, fd: self.wake.as_raw_fd(), addr: counter as *mut u64 as u64, len: 8, user_data: WAKE, ..Default::default() };
+        ring.push(wake.clone())?;
+        loop {
+            // 上一轮处理完成事件时产生的请求在这里一次提交
+            ring.enter(1)?;
+            while let Some((user_data, res)) = ring.complete() {
+                let (slot, next) = match user_data {
+                    WAKE => {
+                        // 先清除标记再取信箱, 之后投递的消息会再次唤醒
+                        self.woken.store(false, Ordering::Release);
+                        let msgs = std::mem::take(&mut self.mailbox.lock().unwrap().msgs);
+                        for msg in msgs {
+                            match msg {
+                                Msg::Start(mut job) => {
+                                    let slot = free.pop().unwrap_or_else(|| {
+                                        jobs.push(None);
+                                        jobs.len() - 1
+                                    });
+                                    for dir in 0..2 {
+                                        ring.push(job.recv(slot, dir))?;
+                                    }
+                                    slots.insert(job.id, slot);
+                                    jobs[slot] = Some(job);
+                                }
+                                Msg::Cancel(id) => {
+                                    let Some(&slot) = slots.get(&id) else { continue };
+                                    let job = jobs[slot].as_mut().expect("slot 对应的连接存在");
+                                    job.cancelled = true;
+                                    job.stop(slot, ring)?;
+                                }
+                            }
+                        }
+                        ring.push(wake.clone())?;
+                        continue;
+                    }
+                    u if u & CANCEL != 0 => continue,
+                    u => {
+                        let (slot, dir) = ((u >> 1) as usize, (u & 1) as usize);
+                        let job = jobs[slot].as_mut().expect("完成事件对应的连接存在");
+                        let next = job.complete(slot, dir, res);
+                        if job.error.is_some() {
+                            job.stop(slot, ring)?;
+                        }
+                        (slot, next)
+                    }
+                };
+                if let Some(sqe) = next {
+                    ring.push(sqe)?;
+                }
+                if jobs[slot].as_ref().is_some_and(|j| j.finished()) {
+                    let mut job = jobs[slot].take().expect("连接存在");
+                    slots.remove(&job.id);
+                    free.push(slot);
+                    let res = match job.error.take() {
+                        Some(e) => Err(e),
+                        None => Ok((job.halves[0].total, job.halves[1].total)),
+                    };
+                    if let Some(tx) = job.result.take() {
+                        let _ = tx.send(res);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// 启动转发线程 (每个 CPU 核一个, 与 tokio 工作线程数相同); 只在第一次使用时启动
+pub fn driver() -> io::Result<Arc<Driver>> {
+    let drivers = DRIVERS.get_or_init(|| {
+        let n = std::thread::available_parallelism().map_or(1, |n| n.get());
+        (0..n).map(|i| spawn(i).map_err(|e| e.to_string())).collect()
+    });
+    let drivers = drivers.as_ref().map_err(|e| io::Error::other(e.clone()))?;
+    let start = NEXT.fetch_add(1, Ordering::Relaxed);
+    (0..drivers.len())
+        .map(|i| &drivers[(start + i) % drivers.len()])
+        .find(|d| !d.mailbox.lock().unwrap().dead)
+        .cloned()
+        .ok_or_else(|| io::Error::other("io_uring 转发线程均已退出"))
+}
+
+fn spawn(index: usize) -> io::Result<Arc<Driver>> {
+    let ring = Ring::new()?;
+    // SAFETY: 成功时返回新的描述符
+    let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: 描述符刚由 eventfd 创建, 只归这里所有
+    let wake = unsafe { OwnedFd::from_raw_fd(fd) };
+    let driver = Arc::new(Driver { mailbox: Default::default(), wake, woken: AtomicBool::new(false) });
+    let worker = driver.clone();
+    std::thread::Builder::new().name(format!("io-uring-{}", index)).spawn(move || worker.run(ring))?;
+    Ok(driver)
+}
+
+/// 无法使用 io_uring 时只在第一次告警, 之后的连接记为 debug 日志
+pub fn warn_fallback(target: &str, e: &io::Error) {
+    if !FALLBACK_WARNED.swap(true, Ordering::Relaxed) {
+        log::warn!("[{}] 无法使用 io_uring, 改用普通转发: {}", target, e);
+    } else {
+        log::debug!("[{}] 无法使用 io_uring, 改用普通转发: {}", target, e);
+    }
+}
+
+/// future 被丢弃 (空闲超时、路由切换) 时通知转发线程取消这条连接
+struct CancelOnDrop<'a> {
+    driver: &'a Driver,
+    id: u64,
+    armed: bool,
+}
+
+impl Drop for CancelOnDrop<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            self.driver.post(Msg::Cancel(self.id));
+        }
+    }
+}
+
+/// 交给转发线程双向转发, 返回 (客户端 -> 目标, 目标 -> 客户端) 字节数
+/// 语义与 splice::relay 相同: 一侧读到 EOF 后关闭另一侧的写方向, 两个方向都结束后返回
+pub async fn relay(
+    driver: &Driver,
+    client: &TcpStream,
+    server: &TcpStream,
+    buffer: Option<usize>,
+    activity: Arc<Activity>,
+    meter: Option<Arc<AtomicU64>>,
+) -> io::Result<(u64, u64)> {
+    let size = buffer.unwrap_or(DEFAULT_BUFFER);
+    let half = || Half { buf: vec![0; size], pending: 0, sent: 0, in_flight: false, done: false, total: 0 };
+    let fds = [client.as_fd().try_clone_to_owned()?, server.as_fd().try_clone_to_owned()?];
+    let (tx, rx) = oneshot::channel();
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let job = Job { id, fds, halves: [half(), half()], activity, meter, result: Some(tx), error: None, cancelled: false };
+    if !driver.post(Msg::Start(Box::new(job))) {
+        return Err(io::Error::other("io_uring 转发线程已退出"));
+    }
+    let mut guard = CancelOnDrop { driver, id, armed: true };
+    let res = rx.await.unwrap_or_else(|_| Err(io::Error::other("io_uring 转发线程已退出")));
+    guard.armed = false;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// 一对已连接的回环 TCP 连接
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connect = TcpStream::connect(listener.local_addr().unwrap());
+        let (a, b) = tokio::join!(connect, listener.accept());
+        (a.unwrap(), b.unwrap().0)
+    }
+
+    /// 经 relay 转发 up / down 两段数据, 检查两端收到的内容和返回的字节数
+    async fn round_trip(up: Vec<u8>, down: Vec<u8>, buffer: Option<usize>) {
+        // 客户端 <-> (client, server) <-> 目标
+        let (mut outer_client, client) = pair().await;
+        let (server, mut outer_server) = pair().await;
+        let activity = Arc::new(Activity::default());
+        let meter = Arc::new(AtomicU64::new(0));
+        let driver = driver().unwrap();
+        let relay = relay(&driver, &client, &server, buffer, activity.clone(), Some(meter.clone()));
+        let peers = async {
+            let (mut oc_r, mut oc_w) = outer_client.split();
+            let (mut os_r, mut os_w) = outer_server.split();
+            let (mut got_up, mut got_down) = (Vec::new(), Vec::new());
+            tokio::join!(
+                async {
+                    oc_w.write_all(&up).await.unwrap();
+                    oc_w.shutdown().await.unwrap();
+                },
+                async {
+                    os_w.write_all(&down).await.unwrap();
+                    os_w.shutdown().await.unwrap();
+                },
+                async { os_r.read_to_end(&mut got_up).await.unwrap() },
+                async { oc_r.read_to_end(&mut got_down).await.unwrap() },
+            );
+            (got_up, got_down)
+        };
+        let (res, (got_up, got_down)) = tokio::join!(relay, peers);
+        assert_eq!(res.unwrap(), (up.len() as u64, down.len() as u64));
+        assert_eq!(got_up, up);
+        assert_eq!(got_down, down);
+        assert_eq!(activity.bytes(), (up.len() as u64, down.len() as u64));
+        assert_eq!(meter.load(Ordering::Relaxed), (up.len() + down.len()) as u64);
+    }
+
+    #[tokio::test]
+    async fn relays_both_directions() {
+        round_trip((0..300_000u32).map(|i| i as u8).collect(), vec![7u8; 100_000], Some(4096)).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn many_connections_share_the_rings() {
+        let tasks = (0..64u32).map(|i| tokio::spawn(round_trip(vec![i as u8; 50_000 + i as usize], vec![!i as u8; 20_000], None)));
+        for res in futures::future::join_all(tasks).await {
+            res.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn dropping_cancels_and_releases_sockets() {
+        let (mut outer_client, client) = pair().await;
+        let (server, mut outer_server) = pair().await;
+        let driver = driver().unwrap();
+        // 两侧都没有数据, 两个读请求都在途; 超时丢弃 future 后转发线程取消请求并关闭复制的描述符
+        let relay = relay(&driver, &client, &server, None, Arc::new(Activity::default()), None);
+        assert!(tokio::time::timeout(std::time::Duration::from_millis(50), relay).await.is_err());
+        drop((client, server));
+        let mut buf = [0; 1];
+        let eof = async {
+            assert_eq!(outer_client.read(&mut buf).await.unwrap(), 0);
+            assert_eq!(outer_server.read(&mut buf).await.unwrap(), 0);
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(2), eof).await.expect("套接字应已关闭");
+    }
+}
@@ -0,0 +1,211 @@
+use anyhow::{Context, Result};
+use std::ffi::c_void;
+use std::io;
+use std::path::{self, PathBuf};
+use std::ptr::{null, null_mut};
+use std::sync::{mpsc, Mutex, OnceLock};
+use tokio::sync::Notify;
+use windows_sys::core::PWSTR;
+use windows_sys::Win32::Foundation::{ERROR_CALL_NOT_IMPLEMENTED, ERROR_SERVICE_SPECIFIC_ERROR, NO_ERROR};
+use windows_sys::Win32::Security::SC_HANDLE;
+use windows_sys::Win32::System::Services::{
+    ChangeServiceConfig2W, CloseServiceHandle, ControlService, CreateServiceW, DeleteService, OpenSCManagerW, OpenServiceW,
+    RegisterServiceCtrlHandlerExW, SetServiceStatus, StartServiceCtrlDispatcherW, SC_ACTION, SC_ACTION_RESTART,
+    SC_MANAGER_CONNECT, SC_MANAGER_CREATE_SERVICE, SERVICE_ACCEPT_SHUTDOWN, SERVICE_ACCEPT_STOP, SERVICE_ALL_ACCESS,
+    SERVICE_AUTO_START, SERVICE_CONFIG_FAILURE_ACTIONS, SERVICE_CONTROL_INTERROGATE, SERVICE_CONTROL_SHUTDOWN,
+    SERVICE_CONTROL_STOP, SERVICE_ERROR_NORMAL, SERVICE_FAILURE_ACTIONSW, SERVICE_QUERY_STATUS, SERVICE_RUNNING,
+    SERVICE_STATUS, SERVICE_STATUS_CURRENT_STATE, SERVICE_STATUS_HANDLE, SERVICE_STOP, SERVICE_STOPPED,
+    SERVICE_STOP_PENDING, SERVICE_TABLE_ENTRYW, SERVICE_WIN32_OWN_PROCESS,
+};
+
+const SERVICE_NAME: &str = "forward-optimal";
+const DELETE: u32 = 0x0001_0000; // 标准访问权限, 删除服务时需要
+const STOP_WAIT_HINT: u32 = 30_000; // 停止过程中提示服务管理器的等待时间 (ms)
+const RESTART_DELAY: u32 = 10_000; // 异常退出后自动重启的延迟 (ms)
+const FAILURE_RESET: u32 = 86_400; // 失败计数清零的时间 (秒)
+
+// 服务管理器分配的状态句柄, 只在以服务运行时存在
+static STATUS: OnceLock<SERVICE_STATUS_HANDLE> = OnceLock::new();
+// 服务入口注册完成后通知 start
+static STARTED: Mutex<Option<mpsc::Sender<io::Result<()>>>> = Mutex::new(None);
+// 收到停止或关机请求
+static STOP: Notify = Notify::const_new();
+
+/// 服务管理器句柄, 离开作用域时关闭
+struct Handle(SC_HANDLE);
+
+impl Handle {
+    fn new(handle: SC_HANDLE) -> io::Result<Handle> {
+        if handle == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(Handle(handle))
+        }
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        // SAFETY: 句柄由 OpenSCManagerW / OpenServiceW / CreateServiceW 返回, 只关闭一次
+        unsafe { CloseServiceHandle(self.0) };
+    }
+}
+
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+fn open_manager(access: u32) -> Result<Handle> {
+    // SAFETY: 本机的默认服务数据库, 参数为空指针
+    Handle::new(unsafe { OpenSCManagerW(null(), null(), access) }).context("无法打开服务管理器 (需要以管理员身份运行)")
+}
+
+/// 注册为开机自动启动的服务, 以 --run-as-service 运行, 日志写入 log_file
+/// (默认为配置文件所在目录的 forward-optimal.log), extra 为附加的参数 (配置文件格式、日志轮转); 异常退出后自动重启
+pub fn install(config: &str, log_file: Option<&str>, extra: &str) -> Result<()> {
+    let exe = std::env::current_exe()?;
+    let config = path::absolute(config)?;
+    let log_file = match log_file {
+        Some(p) => path::absolute(p)?,
+        None => config.parent().map(PathBuf::from).unwrap_or_default().join("forward-optimal.log"),
+    };
+    let command = format!(
+        "\"{}\" --run-as-service -c \"{}\" --log-file \"{}\"{}",
+        exe.display(),
+        config.display(),
+        log_file.display(),
+        extra
+    );
+
+    let manager = open_manager(SC_MANAGER_CREATE_SERVICE)?;
+    let (name, binary) = (wide(SERVICE_NAME), wide(&command));
+    // SAFETY: 字符串均以 0 结尾, 在调用期间有效; 可选参数为空指针
+    let service = Handle::new(unsafe {
+        CreateServiceW(
+            manager.0,
+            name.as_ptr(),
+            name.as_ptr(),
+            SERVICE_ALL_ACCESS,
+            SERVICE_WIN32_OWN_PROCESS,
+            SERVICE_AUTO_START,
+            SERVICE_ERROR_NORMAL,
+            binary.as_ptr(),
+            null(),
+            null_mut(),
+            null(),
+            null(),
+            null(),
+        )
+    })
+    .context("服务安装失败")?;
+
+    let mut actions = [SC_ACTION { Type: SC_ACTION_RESTART, Delay: RESTART_DELAY }; 3];
+    let failure = SERVICE_FAILURE_ACTIONSW {
+        dwResetPeriod: FAILURE_RESET,
+        lpRebootMsg: null_mut(),
+        lpCommand: null_mut(),
+        cActions: actions.len() as u32,
+        lpsaActions: actions.as_mut_ptr(),
+    };
+    // SAFETY: failure 与 actions 在调用期间有效
+    if unsafe { ChangeServiceConfig2W(service.0, SERVICE_CONFIG_FAILURE_ACTIONS, &failure as *const _ as *const c_void) } == 0 {
+        log::warn!("无法设置异常退出后自动重启: {}", io::Error::last_os_error());
+    }
+    log::info!("服务已安装: {}", command);
+    log::info!("启动服务: sc start {}", SERVICE_NAME);
+    Ok(())
+}
+
+/// 停止并删除服务
+pub fn uninstall() -> Result<()> {
+    let manager = open_manager(SC_MANAGER_CONNECT)?;
+    let name = wide(SERVICE_NAME);
+    // SAFETY: name 以 0 结尾, 在调用期间有效
+    let service = Handle::new(unsafe { OpenServiceW(manager.0, name.as_ptr(), SERVICE_STOP | SERVICE_QUERY_STATUS | DELETE) })
+        .context("无法打开服务 (未安装或需要以管理员身份运行)")?;
+    let mut last = status(SERVICE_STOPPED, false);
+    // 未运行时停止失败, 不影响删除
+    // SAFETY: last 为有效的输出参数
+    unsafe { ControlService(service.0, SERVICE_CONTROL_STOP, &mut last) };
+    // SAFETY: 句柄有效, 带有 DELETE 权限
+    if unsafe { DeleteService(service.0) } == 0 {
+        return Err(io::Error::last_os_error()).context("服务删除失败");
+    }
+    log::info!("服务已删除: {}", SERVICE_NAME);
+    Ok(())
+}
+
+/// 连接服务管理器并在后台线程运行服务调度, 服务入口注册完成后返回;
+/// 之后由 stop_requested 通知停止, 退出前调用 stopped
+pub fn start() -> Result<()> {
+    let (tx, rx) = mpsc::channel();
+    *STARTED.lock().unwrap() = Some(tx.clone());
+    std::thread::spawn(move || {
+        let mut name = wide(SERVICE_NAME);
+        let table = [
+            SERVICE_TABLE_ENTRYW { lpServiceName: name.as_mut_ptr(), lpServiceProc: Some(service_main) },
+            SERVICE_TABLE_ENTRYW { lpServiceName: null_mut(), lpServiceProc: None },
+        ];
+        // SAFETY: 服务表以空项结尾, 在调度返回 (服务停止) 前一直有效
+        if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+            let _ = tx.send(Err(io::Error::last_os_error()));
+        }
+    });
+    rx.recv()?.context("无法连接服务管理器, --run-as-service 只能由服务管理器启动")
+}
+
+extern "system" fn service_main(_argc: u32, _argv: *mut PWSTR) {
+    let name = wide(SERVICE_NAME);
+    // SAFETY: name 以 0 结尾; 处理函数不使用上下文参数
+    let handle = unsafe { RegisterServiceCtrlHandlerExW(name.as_ptr(), Some(control_handler), null()) };
+    let started = if handle == 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        let _ = STATUS.set(handle);
+        set_status(SERVICE_RUNNING, false);
+        Ok(())
+    };
+    if let Some(tx) = STARTED.lock().unwrap().take() {
+        let _ = tx.send(started);
+    }
+}
+
+extern "system" fn control_handler(control: u32, _event: u32, _data: *mut c_void, _context: *mut c_void) -> u32 {
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            set_status(SERVICE_STOP_PENDING, false);
+            STOP.notify_one();
+            NO_ERROR
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+/// 等待服务管理器的停止或关机请求; 不以服务运行时一直等待
+pub async fn stop_requested() {
+    STOP.notified().await;
+}
+
+/// 向服务管理器报告已停止, failed 时带上失败的退出码
+pub fn stopped(failed: bool) {
+    set_status(SERVICE_STOPPED, failed);
+}
+
+fn status(state: SERVICE_STATUS_CURRENT_STATE, failed: bool) -> SERVICE_STATUS {
+    SERVICE_STATUS {
+        dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+        dwCurrentState: state,
+        dwControlsAccepted: if state == SERVICE_RUNNING { SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN } else { 0 },
+        dwWin32ExitCode: if failed { ERROR_SERVICE_SPECIFIC_ERROR } else { NO_ERROR },
+        dwServiceSpecificExitCode: failed as u32,
+        dwCheckPoint: 0,
+        dwWaitHint: if state == SERVICE_STOP_PENDING { STOP_WAIT_HINT } else { 0 },
+    }
+}
+
+fn set_status(state: SERVICE_STATUS_CURRENT_STATE, failed: bool) {
+    let Some(&handle) = STATUS.get() else { return };
+    // SAFETY: 句柄由 RegisterServiceCtrlHandlerExW 返回, 在进程内一直有效
+    unsafe { SetServiceStatus(handle, &status(state, failed)) };
+}